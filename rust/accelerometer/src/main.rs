@@ -4,6 +4,7 @@
 #![no_std]
 #![no_main]
 
+use core::ptr::addr_of_mut;
 use k210_hal::pac::Peripherals;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
@@ -11,31 +12,37 @@ use k210_shared::board::def::{io,DISP_WIDTH,DISP_HEIGHT,MSA300_SLV_ADDR,MSA300_A
 use k210_shared::board::lcd::{LCD,LCDHL,self};
 use k210_shared::board::lcd_colors;
 use k210_shared::board::lcd_render::render_image;
-use k210_shared::board::msa300::Accelerometer;
+use k210_shared::board::msa300::{self, Accelerometer};
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::i2c::{I2C,I2CExt};
+use k210_shared::soc::i2c::sampler::{Burst, Sampler};
+use k210_shared::soc::i2c::transfer::I2CIrq;
+use k210_shared::soc::plic;
+use k210_shared::soc::pwm::Channel;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
 use libm::F32Ext;
 use riscv_rt::entry;
 
+/** Accelerometer sample rate (Hz) */
+const SAMPLE_RATE: u32 = 100;
+
+/** Ring buffer for accelerometer samples */
+static mut SAMPLES: [u8; msa300::SAMPLE_LEN * 16] = [0; msa300::SAMPLE_LEN * 16];
+
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* I2C0 for touch-screen */
-    fpioa::set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK);
-    fpioa::set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA);
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
 
-    sysctl::set_spi0_dvp_data(true);
+    lcd_pins
 }
 
 /** Set correct voltage for pins */
@@ -68,12 +75,13 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
@@ -81,10 +89,29 @@ fn main() -> ! {
     writeln!(stdout, "MSA300 init").unwrap();
     let i2c = p.I2C0.constrain();
     i2c.init(MSA300_SLV_ADDR, MSA300_ADDR_BITS, MSA300_CLK);
-    let acc = Accelerometer::init(i2c).unwrap();
-
+    Accelerometer::init(&i2c).unwrap();
+
+    // Sample in the background, so that reading doesn't hold up rendering
+    plic::init();
+    let i2c = I2CIrq::new(i2c, None);
+    let burst = Burst {
+        address: MSA300_SLV_ADDR,
+        register: msa300::SAMPLE_REGISTER,
+        len: msa300::SAMPLE_LEN,
+    };
+    let sampler = Sampler::start(i2c, p.TIMER1, Channel::CH1, burst, SAMPLE_RATE,
+                                 unsafe { &mut *addr_of_mut!(SAMPLES) });
+
+    let mut sample = [0u8; msa300::SAMPLE_LEN];
+    let (mut x, mut y, mut z): (f32, f32, f32) = (0.0, 0.0, 0.0);
     loop {
-        let (x, y, z) = acc.measure().unwrap();
+        // use the latest sample
+        while sampler.pop(&mut sample) {
+            let (sx, sy, sz) = msa300::convert(&sample);
+            x = sx;
+            y = sy;
+            z = sz;
+        }
         let mag = (x*x+y*y+z*z).sqrt();
         // writeln!(stdout, "m/s^2 x={} y={} z={} (size={})", x, y, z, mag).unwrap();
 
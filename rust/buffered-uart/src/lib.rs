@@ -3,15 +3,18 @@
 /** Buffered UART, using interrupts — currently only receiving is buffered because this is most
  * important, avoiding loss of data when the FIFO fills up. Buffered sending is slightly less
  * interesting without a fully fledged scheduling OS.
+ *
+ * The receive interrupt goes through `plic`, so the application has to dispatch PLIC interrupts,
+ * for example by enabling the `plic-handler` feature of `k210-shared`.
  */
 // Yep, this is an awful hack, many things are hardcoded that should not be, just a proof of concept…
-use bare_metal::Nr;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use k210_hal::pac;
+use k210_shared::soc::plic;
 use k210_shared::soc::sysctl;
 use pac::interrupt::Interrupt;
 use riscv::asm;
-use riscv::register::{mhartid, mie, mip, mstatus};
+use riscv::register::mie;
 
 const UART_BUFSIZE: usize = 8192;
 /** UART ring buffer */
@@ -49,7 +52,7 @@ const UART_SEND_FIFO_8: u32 = 3;
 const UART_IER_ERBFI: u32 = 1;
 
 /** Handle UARTx interrupt */
-fn interrupt_uart1() {
+fn interrupt_uart1(_: Interrupt) {
     unsafe {
         let uart = pac::UART1::ptr();
         let irecv = &mut UART1_INSTANCE_RECV;
@@ -80,64 +83,6 @@ fn interrupt_uart1() {
     }
 }
 
-/** PLIC interrupts */
-#[allow(non_snake_case)]
-#[no_mangle]
-fn MachineExternal() {
-    if mip::read().mext() {
-        unsafe {
-            let hartid = mhartid::read();
-            let plic = pac::PLIC::ptr();
-            let target = &(*plic).targets[hartid * 2];
-            let int_num = target.claim.read().bits();
-            let int = Interrupt::try_from(int_num as u8).unwrap();
-
-            // Does this really need the 'disable other interrupts, change threshold' dance
-            // as done in handle_irq_m_ext in plic.c?
-            match int {
-                Interrupt::UART1 => interrupt_uart1(),
-                // We'll get a spurious UARTHS interrupt, ignore it
-                Interrupt::UARTHS => {}
-                _ => {
-                    panic!(
-                        "unknown machineexternal hart {}, int {:?}",
-                        hartid, int
-                    );
-                }
-            }
-
-            // Perform IRQ complete
-            target.claim.write(|w| w.bits(int_num));
-        }
-    }
-}
-
-/** Enable or disable a PLIC interrupt for the current core */
-fn plic_irq_enable(interrupt: Interrupt, enabled: bool) {
-    let targetid = mhartid::read() * 2;
-    let irq_nr = interrupt.nr();
-    unsafe {
-        let plic = pac::PLIC::ptr();
-        let bit = 1 << ((irq_nr as u32) % 32);
-        if enabled {
-            (*plic).target_enables[targetid].enable[(irq_nr as usize) / 32]
-                .modify(|r, w| w.bits(r.bits() | bit));
-        } else {
-            (*plic).target_enables[targetid].enable[(irq_nr as usize) / 32]
-                .modify(|r, w| w.bits(r.bits() & !bit));
-        }
-    }
-}
-
-/** Set interrupt priority (0-7) */
-fn plic_set_priority(interrupt: Interrupt, priority: u32) {
-    let irq_nr = interrupt.nr();
-    unsafe {
-        let plic = pac::PLIC::ptr();
-        (*plic).priority[irq_nr as usize].write(|w| w.bits(priority));
-    }
-}
-
 /** Initialize UART */
 fn uart_init(baud_rate: u32) {
     let uart = pac::UART1::ptr();
@@ -179,14 +124,12 @@ fn uart_enable_intr(recv: bool) {
             (*uart)
                 .dlh_ier
                 .modify(|r, w| w.bits(r.bits() | UART_IER_ERBFI));
-            plic_set_priority(Interrupt::UART1, 6);
-            plic_irq_enable(Interrupt::UART1, true);
+            plic::register(Interrupt::UART1, 6, interrupt_uart1);
         } else {
             (*uart)
                 .dlh_ier
                 .modify(|r, w| w.bits(r.bits() & !UART_IER_ERBFI));
-            plic_set_priority(Interrupt::UART1, 0);
-            plic_irq_enable(Interrupt::UART1, false);
+            plic::unregister(Interrupt::UART1);
         }
     }
 }
@@ -241,13 +184,10 @@ pub fn recv(s: &mut [u8]) -> usize {
 /** Initialize interrupts and buffered UART handling */
 pub fn init() {
     unsafe {
-        // Enable interrupts in general
-        mstatus::set_mie();
         // Set the Machine-Software bit in MIE
         mie::set_msoft();
-        // Set the Machine-External bit in MIE
-        mie::set_mext();
     }
+    plic::init();
 
     uart_init(115_200);
     uart_enable_intr(true);
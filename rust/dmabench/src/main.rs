@@ -0,0 +1,141 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![no_std]
+#![no_main]
+
+use k210_hal::pac::Peripherals;
+use k210_hal::prelude::*;
+use k210_hal::stdout::Stdout;
+use k210_shared::soc::dmac::{dma_channel, DMACExt};
+use k210_shared::soc::sleep::usleep;
+use k210_shared::soc::sysctl;
+use k210_shared::timing::clock;
+use riscv::asm;
+use riscv_rt::entry;
+
+use k210_console::console::{Console, ScreenImage, DISP_HEIGHT, DISP_PIXELS, DISP_WIDTH};
+use k210_console::{cp437, cp437_8x8};
+
+/** Number of times each operation is repeated */
+const ROUNDS: u64 = 10;
+/** Console grid size; a cell is four u16, so one u64 stands in for a cell */
+const GRID_WIDTH: usize = (DISP_WIDTH as usize) / 8;
+const GRID_CELLS: usize = GRID_WIDTH * (DISP_HEIGHT as usize) / 8;
+/** One row of console text in the screen image */
+const IMAGE_ROW: usize = 8 * (DISP_WIDTH as usize) / 2;
+const IMAGE_LEN: usize = DISP_PIXELS / 2;
+
+/** Average time in microseconds of `ROUNDS` runs of `f` */
+fn time<F: FnMut()>(mut f: F) -> u64 {
+    let start = clock();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    (clock() - start) / ROUNDS
+}
+
+fn report<W: core::fmt::Write>(stdout: &mut W, name: &str, cpu: u64, dma: u64, ok: bool) {
+    writeln!(stdout, "{:<22} cpu {:>6} us  dma {:>6} us  {}",
+             name, cpu, dma, if ok { "OK" } else { "MISMATCH" }).unwrap();
+}
+
+/** Fill with a recognizable pattern */
+fn pattern<T: From<u16>>(buf: &mut [T]) {
+    for (i, x) in buf.iter_mut().enumerate() {
+        *x = T::from(i as u16);
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let p = Peripherals::take().unwrap();
+
+    sysctl::pll_set_freq(sysctl::pll::PLL0, 800_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL1, 300_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL2, 45_158_400).unwrap();
+    let clocks = k210_hal::clock::Clocks::new();
+
+    usleep(200000);
+
+    // Configure UART
+    let serial = p.UARTHS.configure(115_200.bps(), &clocks);
+    let (mut tx, _) = serial.split();
+    let mut stdout = Stdout(&mut tx);
+
+    let dmac = p.DMAC.configure();
+    let chan = dma_channel::CHANNEL0;
+    writeln!(stdout, "DMA benchmark: CPU {} Hz, average of {} rounds",
+             sysctl::clock_get_freq(sysctl::clock::CPU), ROUNDS).unwrap();
+
+    // Console::scroll itself, for reference
+    let mut console = Console::new(&cp437::to, &cp437_8x8::FONT, None);
+    let cpu = time(|| console.scroll());
+    writeln!(stdout, "{:<22} cpu {:>6} us", "Console::scroll", cpu).unwrap();
+
+    // Scrolling the cell grid: move all rows up by one, and clear the last row
+    let mut cells_cpu = [0u64; GRID_CELLS];
+    let mut cells_dma = [0u64; GRID_CELLS];
+    pattern(&mut cells_cpu);
+    pattern(&mut cells_dma);
+    let cpu = time(|| {
+        for i in 0..GRID_CELLS - GRID_WIDTH {
+            cells_cpu[i] = cells_cpu[i + GRID_WIDTH];
+        }
+        for i in GRID_CELLS - GRID_WIDTH..GRID_CELLS {
+            cells_cpu[i] = 0;
+        }
+    });
+    let dma = time(|| {
+        dmac.copy_within(chan, &mut cells_dma, GRID_WIDTH..GRID_CELLS, 0).unwrap();
+        dmac.fill(chan, &mut cells_dma[GRID_CELLS - GRID_WIDTH..], 0).unwrap();
+    });
+    report(&mut stdout, "scroll cells", cpu, dma, cells_cpu[..] == cells_dma[..]);
+
+    // Scrolling the rendered screen image by one text row
+    let mut image_cpu: ScreenImage = [0; IMAGE_LEN];
+    let mut image_dma: ScreenImage = [0; IMAGE_LEN];
+    pattern(&mut image_cpu);
+    pattern(&mut image_dma);
+    let cpu = time(|| {
+        for i in 0..IMAGE_LEN - IMAGE_ROW {
+            image_cpu[i] = image_cpu[i + IMAGE_ROW];
+        }
+    });
+    let dma = time(|| {
+        dmac.copy_within(chan, &mut image_dma, IMAGE_ROW..IMAGE_LEN, 0).unwrap();
+    });
+    report(&mut stdout, "scroll image", cpu, dma, image_cpu[..] == image_dma[..]);
+
+    // Clearing the screen image to a background color
+    let cpu = time(|| {
+        for x in image_cpu.iter_mut() {
+            *x = 0x1234_1234;
+        }
+    });
+    let dma = time(|| {
+        dmac.fill(chan, &mut image_dma, 0x1234_1234u32).unwrap();
+    });
+    report(&mut stdout, "clear image", cpu, dma, image_cpu[..] == image_dma[..]);
+
+    // Copying a full frame between buffers
+    pattern(&mut image_cpu);
+    let cpu = time(|| {
+        for i in 0..IMAGE_LEN {
+            image_dma[i] = image_cpu[i];
+        }
+    });
+    pattern(&mut image_dma);
+    for x in image_cpu.iter_mut() {
+        *x = !*x;
+    }
+    let dma = time(|| {
+        dmac.memcpy(chan, &mut image_dma, &image_cpu).unwrap();
+    });
+    report(&mut stdout, "copy frame", cpu, dma, image_cpu[..] == image_dma[..]);
+
+    writeln!(stdout, "Done").unwrap();
+    loop {
+        unsafe { asm::wfi(); }
+    }
+}
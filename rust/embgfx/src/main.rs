@@ -4,37 +4,33 @@
 #![no_std]
 #![no_main]
 
-use embedded_graphics::fonts::Font6x8;
-use embedded_graphics::image::ImageBmp;
-use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+use embedded_graphics::image::Image;
+use embedded_graphics::mono_font::ascii::FONT_6X9;
+use embedded_graphics::mono_font::{MonoTextStyle, MonoTextStyleBuilder};
 use embedded_graphics::pixelcolor::Rgb565;
 use embedded_graphics::prelude::*;
-use embedded_graphics::primitives::{Circle, Rectangle};
-use embedded_graphics::{text_6x8, Drawing};
+use embedded_graphics::primitives::{Circle, PrimitiveStyle};
+use embedded_graphics::text::{Baseline, Text};
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
 use k210_hal::pac::Peripherals;
-use k210_shared::board::def::{io, DISP_HEIGHT, DISP_PIXELS, DISP_WIDTH};
+use k210_shared::board::def::{DISP_HEIGHT, DISP_PIXELS, DISP_WIDTH};
 use k210_shared::board::lcd::{self, LCD, LCDHL};
-use k210_shared::board::lcd_render::{AsU16, ScreenImage};
+use k210_shared::board::lcd_gfx::FrameBuffer;
+use k210_shared::board::lcd_render::ScreenImage;
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{dma_channel, DMACExt};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::mux::PinMux;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
 use riscv_rt::entry;
+use tinybmp::Bmp;
 
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    pinout::MAIX_GO.lcd.route(mux).unwrap()
 }
 
 /** Set correct voltage for pins */
@@ -44,43 +40,6 @@ fn io_set_power() {
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
 }
 
-/** Implement embedded_graphics display traits for LCD.*/
-struct Display {
-    // Currently this only supports flushing the entire screen.
-    // It's possible to improve on this by keeping track of which parts of the screen have been
-    // updated, as the display does support partial (rectangle) updates.
-    pub data: ScreenImage,
-}
-
-impl Display {
-    fn new() -> Display {
-        Display {
-            data: [0; DISP_PIXELS / 2],
-        }
-    }
-
-    fn flush<T: LCDHL>(&self, lcd: &T) {
-        lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &self.data);
-    }
-}
-
-impl Drawing<Rgb565> for Display {
-    fn draw<T>(&mut self, item: T)
-    where
-        T: IntoIterator<Item = Pixel<Rgb565>>,
-    {
-        let data = self.data.as_u16_slice_mut();
-        for Pixel(coord, color) in item {
-            let x = coord[0] as usize;
-            let y = coord[1] as usize;
-            if x < (DISP_WIDTH as usize) && y < (DISP_HEIGHT as usize) {
-                let index = x + (y * (DISP_WIDTH as usize));
-                data[index] = RawU16::from(color).into_inner();
-            }
-        }
-    }
-}
-
 #[entry]
 fn main() -> ! {
     let p = Peripherals::take().unwrap();
@@ -100,24 +59,28 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     writeln!(stdout, "First frame").unwrap();
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
 
-    let mut display = Display::new();
+    let mut image: ScreenImage = [0; DISP_PIXELS / 2];
+    let mut display = FrameBuffer::new(&mut image);
 
-    let t = Font6x8::render_str("Hello Rust!")
-        .fill(Some(Rgb565::GREEN))
-        .translate(Point::new(20, 16));
-    let image: ImageBmp<Rgb565> = ImageBmp::new(include_bytes!("./rust-pride.bmp"))
-        .unwrap()
-        .translate(Point::new(100, 20));
+    let t = Text::with_baseline(
+        "Hello Rust!",
+        Point::new(20, 16),
+        MonoTextStyle::new(&FONT_6X9, Rgb565::GREEN),
+        Baseline::Top,
+    );
+    let bmp: Bmp<Rgb565> = Bmp::from_slice(include_bytes!("./rust-pride.bmp")).unwrap();
+    let bmp_image = Image::new(&bmp, Point::new(100, 20));
 
     let mut coord = Point::new(20, 20);
     let mut dir = Point::new(3, 3);
@@ -125,42 +88,48 @@ fn main() -> ! {
         // clear screen
         // shouldn't really be necessary to clear the entire screen every frame
         // then again it redraws everything anyway
-        display.draw(
-            Rectangle::new(
-                Point::new(0, 0),
-                Point::new(i32::from(DISP_WIDTH), i32::from(DISP_HEIGHT)),
-            )
-            .fill(Some(Rgb565::BLACK)),
-        );
+        display.clear(Rgb565::BLACK).unwrap();
         // draw spot
-        let c = Circle::new(coord, 8).fill(Some(Rgb565::RED));
+        let c = Circle::new(coord, 8).into_styled(PrimitiveStyle::with_fill(Rgb565::RED));
         // draw other stuff
-        display.draw(c);
-        display.draw(t);
-        display.draw(&image);
-
-        display.draw(
-            text_6x8!("Hello world! - no background", stroke = Some(Rgb565::WHITE))
-                .translate(Point::new(15, 115)),
-        );
-
-        display.draw(
-            text_6x8!(
-                "Hello world! - filled background",
-                stroke = Some(Rgb565::YELLOW),
-                fill = Some(Rgb565::BLUE)
-            )
-            .translate(Point::new(15, 130)),
-        );
-
-        display.draw(
-            text_6x8!(
-                "Hello world! - inverse background",
-                stroke = Some(Rgb565::BLUE),
-                fill = Some(Rgb565::YELLOW)
-            )
-            .translate(Point::new(15, 145)),
-        );
+        c.draw(&mut display).unwrap();
+        t.draw(&mut display).unwrap();
+        bmp_image.draw(&mut display).unwrap();
+
+        Text::with_baseline(
+            "Hello world! - no background",
+            Point::new(15, 115),
+            MonoTextStyle::new(&FONT_6X9, Rgb565::WHITE),
+            Baseline::Top,
+        )
+        .draw(&mut display)
+        .unwrap();
+
+        Text::with_baseline(
+            "Hello world! - filled background",
+            Point::new(15, 130),
+            MonoTextStyleBuilder::new()
+                .font(&FONT_6X9)
+                .text_color(Rgb565::YELLOW)
+                .background_color(Rgb565::BLUE)
+                .build(),
+            Baseline::Top,
+        )
+        .draw(&mut display)
+        .unwrap();
+
+        Text::with_baseline(
+            "Hello world! - inverse background",
+            Point::new(15, 145),
+            MonoTextStyleBuilder::new()
+                .font(&FONT_6X9)
+                .text_color(Rgb565::BLUE)
+                .background_color(Rgb565::YELLOW)
+                .build(),
+            Baseline::Top,
+        )
+        .draw(&mut display)
+        .unwrap();
 
         display.flush(&lcd);
 
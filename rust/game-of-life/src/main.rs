@@ -10,11 +10,13 @@ use k210_hal::stdout::Stdout;
 use k210_shared::board::def::{io,DISP_WIDTH,DISP_HEIGHT,DISP_PIXELS,NS2009_SLV_ADDR,NS2009_CAL,NS2009_ADDR_BITS,NS2009_CLK};
 use k210_shared::board::lcd::{LCD,LCDHL,self};
 use k210_shared::board::lcd_colors;
-use k210_shared::board::lcd_render::ScreenImage;
+use k210_shared::board::lcd_render::{DoubleBuffer,ScreenImage};
 use k210_shared::board::ns2009::TouchScreen;
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::i2c::{I2C,I2CExt};
+use k210_shared::soc::plic;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
@@ -24,6 +26,10 @@ pub const BLK_SIZE: usize = 8;
 pub const GRID_WIDTH: usize = (DISP_WIDTH as usize) / BLK_SIZE;
 pub const GRID_HEIGHT: usize = (DISP_HEIGHT as usize) / BLK_SIZE;
 
+/** Images for double-buffering: one is rendered into while the other is sent to the LCD */
+static mut IMAGE0: ScreenImage = [0; DISP_PIXELS / 2];
+static mut IMAGE1: ScreenImage = [0; DISP_PIXELS / 2];
+
 /** Universe abstraction */
 struct Universe {
     state: [[bool; GRID_WIDTH*GRID_HEIGHT]; 2],
@@ -98,20 +104,15 @@ impl Universe {
 }
 
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* I2C0 for touch-screen */
-    fpioa::set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK);
-    fpioa::set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA);
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
 
-    sysctl::set_spi0_dvp_data(true);
+    lcd_pins
 }
 
 /** Set correct voltage for pins */
@@ -150,17 +151,19 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
 
-    let mut image: ScreenImage = [0; DISP_PIXELS / 2];
+    plic::init();
+    let mut images = DoubleBuffer::new(unsafe { &mut IMAGE0 }, unsafe { &mut IMAGE1 });
 
     writeln!(stdout, "NS2009 init").unwrap();
     let i2c = p.I2C0.constrain();
@@ -199,6 +202,7 @@ fn main() -> ! {
                 }
             }
         }
+        let image = images.back();
         for y in 0..GRID_HEIGHT {
             for x in 0..GRID_WIDTH {
                 let state = universe.get(x, y);
@@ -210,7 +214,7 @@ fn main() -> ! {
                 }
             }
         }
-        lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &image);
+        images.present(&mut lcd);
 
         universe.iterate();
     }
@@ -14,13 +14,14 @@ use k210_shared::board::def::{io,DISP_WIDTH,DISP_HEIGHT,DISP_PIXELS};
 use k210_shared::board::lcd::{LCD,LCDHL,self};
 use k210_shared::board::lcd_colors;
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
 use riscv_rt::entry;
 use k210_shared::soc::dvp::{DVPExt,sccb_addr_len,image_format};
 use k210_shared::board::ov2640;
+use k210_shared::board::pinout::{self, LcdPins};
 
 /** 64-byte aligned planar RAM */
 #[repr(C, align(64))]
@@ -42,30 +43,25 @@ static mut FRAME_AI: PlanarScreenRAM = PlanarScreenRAM {
 };
 
 /** Connect pins to internal functions */
-fn io_init() {
+fn io_init(mux: &mut PinMux) -> LcdPins {
     /* Init DVP IO map and function settings */
-    fpioa::set_function(io::DVP_RST, fpioa::function::CMOS_RST);
-    fpioa::set_function(io::DVP_PWDN, fpioa::function::CMOS_PWDN);
-    fpioa::set_function(io::DVP_XCLK, fpioa::function::CMOS_XCLK);
-    fpioa::set_function(io::DVP_VSYNC, fpioa::function::CMOS_VSYNC);
-    fpioa::set_function(io::DVP_HSYNC, fpioa::function::CMOS_HREF);
-    fpioa::set_function(io::DVP_PCLK, fpioa::function::CMOS_PCLK);
-    fpioa::set_function(io::DVP_SCL, fpioa::function::SCCB_SCLK);
-    fpioa::set_function(io::DVP_SDA, fpioa::function::SCCB_SDA);
+    mux.set_function(io::DVP_RST, fpioa::function::CMOS_RST).unwrap();
+    mux.set_function(io::DVP_PWDN, fpioa::function::CMOS_PWDN).unwrap();
+    mux.set_function(io::DVP_XCLK, fpioa::function::CMOS_XCLK).unwrap();
+    mux.set_function(io::DVP_VSYNC, fpioa::function::CMOS_VSYNC).unwrap();
+    mux.set_function(io::DVP_HSYNC, fpioa::function::CMOS_HREF).unwrap();
+    mux.set_function(io::DVP_PCLK, fpioa::function::CMOS_PCLK).unwrap();
+    mux.set_function(io::DVP_SCL, fpioa::function::SCCB_SCLK).unwrap();
+    mux.set_function(io::DVP_SDA, fpioa::function::SCCB_SDA).unwrap();
 
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* Set DVP and SPI pin to 1.8V */
     sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    lcd_pins
 }
 
 #[entry]
@@ -84,11 +80,12 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_init(&mut mux);
 
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
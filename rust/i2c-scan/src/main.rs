@@ -0,0 +1,128 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![no_std]
+#![no_main]
+
+use k210_hal::pac::Peripherals;
+use k210_hal::prelude::*;
+use k210_hal::stdout::Stdout;
+use k210_shared::board::def::{io,MSA300_SLV_ADDR,NS2009_SLV_ADDR};
+use k210_shared::board::lcd::{self, LCD, LCDHL};
+use k210_shared::board::pinout::{self, LcdPins};
+use k210_shared::soc::dmac::{DMACExt, dma_channel};
+use k210_shared::soc::fpioa::{self, mux::PinMux};
+use k210_shared::soc::i2c::scan::AddressSet;
+use k210_shared::soc::i2c::{I2C,I2CExt};
+use k210_shared::soc::sleep::usleep;
+use k210_shared::soc::spi::SPIExt;
+use k210_shared::soc::sysctl;
+use riscv_rt::entry;
+use k210_console::console::{Color, Console, ScreenImage, DISP_HEIGHT, DISP_WIDTH, DISP_PIXELS};
+use k210_console::{cp437, cp437_8x8};
+
+/** Clock for probing; slow, so that long wires and weak pull-ups work */
+const SCAN_CLK: u32 = 100_000;
+/** Row of the console where the list starts */
+const LIST_ROW: u16 = 3;
+
+/** Devices known to be on the board */
+const KNOWN: [(u16, &str); 2] = [
+    (NS2009_SLV_ADDR, "NS2009 touch screen"),
+    (MSA300_SLV_ADDR, "MSA300 accelerometer"),
+];
+
+/** Connect pins to internal functions */
+fn io_init(mux: &mut PinMux) -> LcdPins {
+    /* Init SPI IO map and function settings */
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
+
+    /* I2C0 */
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
+
+    /* Set dvp and spi pin to 1.8V */
+    sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
+    sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    lcd_pins
+}
+
+fn device_name(address: u8) -> &'static str {
+    KNOWN.iter()
+        .find(|(known, _)| *known == u16::from(address))
+        .map(|(_, name)| *name)
+        .unwrap_or("")
+}
+
+/** List the devices found, replacing the previous list */
+fn show(console: &mut Console, found: &AddressSet) {
+    let black = Color::new(0, 0, 0);
+    for y in LIST_ROW..console.height() {
+        for x in 0..console.width() {
+            console.put(x, y, black, black, ' ');
+        }
+    }
+    write!(console, "\x1b[{};1H", LIST_ROW + 1).unwrap();
+    writeln!(console, "\x1b[38;5;250m{} device(s) found\x1b[0m", found.len()).unwrap();
+    for address in found.iter() {
+        writeln!(console, " \x1b[38;5;141m0x{:02x}\x1b[0m {}", address, device_name(address))
+            .unwrap();
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let p = Peripherals::take().unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL0, 800_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL1, 300_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL2, 45_158_400).unwrap();
+    let clocks = k210_hal::clock::Clocks::new();
+
+    usleep(200000);
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_init(&mut mux);
+
+    // Configure UART
+    let serial = p.UARTHS.configure(115_200.bps(), &clocks);
+    let (mut tx, _) = serial.split();
+    let mut stdout = Stdout(&mut tx);
+
+    // LCD init
+    let dmac = p.DMAC.configure();
+    let spi = p.SPI0.constrain();
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
+    lcd.init();
+    lcd.set_direction(lcd::direction::YX_LRUD);
+    let mut image: ScreenImage = [0; DISP_PIXELS / 2];
+    let mut console: Console = Console::new(&cp437::to, &cp437_8x8::FONT, None);
+
+    writeln!(console, "\x1b[48;2;128;192;255;38;5;0m I2C SCAN \x1b[0m").unwrap();
+    writeln!(console, "I2C0, 7-bit addresses 0x08-0x77").unwrap();
+
+    // The device address given to init is not used, every probe sets its own
+    let i2c = p.I2C0.constrain();
+    i2c.init(0, 7, SCAN_CLK);
+
+    let mut previous: Option<AddressSet> = None;
+    loop {
+        let found = match i2c.scan() {
+            Ok(found) => found,
+            Err(e) => {
+                writeln!(stdout, "Scan failed: {:?}", e).unwrap();
+                usleep(1_000_000);
+                continue;
+            }
+        };
+        if previous != Some(found) {
+            for address in found.iter() {
+                writeln!(stdout, "0x{:02x} {}", address, device_name(address)).unwrap();
+            }
+            show(&mut console, &found);
+            console.render(&mut image);
+            lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &image);
+            previous = Some(found);
+        }
+        usleep(1_000_000);
+    }
+}
@@ -1,7 +1,9 @@
+use core::cmp::min;
 use core::fmt;
 
 use k210_shared::board::lcd_colors::rgb565;
 use crate::coord::Coord;
+use crate::mouse::{MouseMode, MouseTracking};
 use crate::palette_xterm256::PALETTE;
 
 pub use k210_shared::board::def::{DISP_WIDTH,DISP_HEIGHT,DISP_PIXELS};
@@ -70,6 +72,8 @@ pub struct Console {
     pub dirty: bool,
     /** Array of character cells representing console */
     cells: [Cell; GRID_CELLS],
+    /** Number of rows in use, from the top of the screen */
+    rows: u16,
     /** Cursor position */
     cursor_pos: Coord,
     /** Cursor visible flag */
@@ -88,6 +92,10 @@ pub struct Console {
     idx: usize,
     /** CSI parameters */
     num: [u16; 16],
+    /** Current CSI sequence is a DEC private mode sequence (starts with '?') */
+    csi_private: bool,
+    /** Mouse reporting mode requested by the remote program */
+    mouse_mode: MouseMode,
 }
 
 impl Console {
@@ -103,6 +111,7 @@ impl Console {
                 ch: 0,
                 flags: 0,
             }; GRID_CELLS],
+            rows: GRID_HEIGHT,
             cursor_pos: Coord::new(0, 0),
             cursor_visible: true,
             def_fg: DEF_FG,
@@ -112,6 +121,8 @@ impl Console {
             state: State::Initial,
             idx: 0,
             num: [0; 16],
+            csi_private: false,
+            mouse_mode: MouseMode::new(),
         }
     }
 
@@ -119,7 +130,7 @@ impl Console {
     pub fn render(&self, image: &mut ScreenImage) {
         let mut image_base = 0;
         let mut cell_idx = 0;
-        for y in 0..GRID_HEIGHT {
+        for y in 0..self.rows {
             for x in 0..GRID_WIDTH  {
                 let cell = &self.cells[cell_idx];
                 if (cell.flags & CellFlags::COLOR) != 0 {
@@ -162,7 +173,24 @@ impl Console {
         GRID_WIDTH
     }
     pub fn height(&self) -> u16 {
-        GRID_HEIGHT
+        self.rows
+    }
+
+    /** Use only the top `rows` rows of the screen, for example to leave room for an on-screen
+     * keyboard. Rows below are not rendered. If the cursor is below the new height, the content
+     * scrolls up to keep it visible. */
+    pub fn set_height(&mut self, rows: u16) {
+        let rows = rows.clamp(1, GRID_HEIGHT);
+        while self.cursor_pos.y >= rows {
+            self.scroll();
+        }
+        self.rows = rows;
+        self.dirty = true;
+    }
+
+    /** Mouse reporting mode, as enabled by the remote program. */
+    pub fn mouse_mode(&self) -> MouseMode {
+        self.mouse_mode
     }
 
     /** Put a char at an arbitrary position with arbitrary fg/bg color. Does not move the cursor.
@@ -245,13 +273,29 @@ impl Console {
         let param = &self.num[0..self.idx+1];
         let x = param.get(0).unwrap_or(&0);
         let y = param.get(1).unwrap_or(&0);
-        self.cursor_pos = Coord::new(x.saturating_sub(1), y.saturating_sub(1));
+        // Keep the cursor inside the rows in use
+        self.cursor_pos = Coord::new(min(x.saturating_sub(1), GRID_WIDTH - 1),
+                                     min(y.saturating_sub(1), self.rows - 1));
+    }
+
+    /** Handle DEC private mode set ('?...h') or reset ('?...l') CSI. */
+    fn handle_decset(&mut self, enable: bool) {
+        for param in &self.num[0..self.idx+1] {
+            match param {
+                9 => { self.mouse_mode.set_tracking(MouseTracking::X10, enable); }
+                1000 => { self.mouse_mode.set_tracking(MouseTracking::Normal, enable); }
+                1002 => { self.mouse_mode.set_tracking(MouseTracking::ButtonEvent, enable); }
+                1003 => { self.mouse_mode.set_tracking(MouseTracking::AnyEvent, enable); }
+                1006 => { self.mouse_mode.sgr = enable; }
+                _ => {}
+            }
+        }
     }
 
     /** Scroll (only up, currently) */
     pub fn scroll(&mut self) {
         let gw = usize::from(GRID_WIDTH);
-        let gh = usize::from(GRID_HEIGHT);
+        let gh = usize::from(self.rows);
         for i in 0..(gh-1)*gw {
             self.cells[i] = self.cells[i + gw];
         }
@@ -282,7 +326,7 @@ impl Console {
                 '\r' => { self.cursor_pos.x = 0; self.dirty = true; }
                 '\n' => {
                     self.cursor_pos.y += 1; self.cursor_pos.x = 0; self.dirty = true;
-                    if self.cursor_pos.y == GRID_HEIGHT {
+                    if self.cursor_pos.y >= self.rows {
                         self.scroll();
                     }
                 }
@@ -297,7 +341,7 @@ impl Console {
                         self.cursor_pos.x = 0;
                         self.cursor_pos.y += 1;
                     }
-                    if self.cursor_pos.y == GRID_HEIGHT {
+                    if self.cursor_pos.y >= self.rows {
                         self.scroll();
                     }
 
@@ -307,11 +351,14 @@ impl Console {
                 }
             }
             State::Escape => match ch {
-                '[' => { self.state = State::CSI; self.idx = 0; self.num[0] = 0; }
+                '[' => { self.state = State::CSI; self.idx = 0; self.num[0] = 0; self.csi_private = false; }
                 ']' => { self.state = State::Xterm; }
                 _ => { self.state = State::Initial; }
             }
             State::CSI => match ch {
+                '?' => {
+                    self.csi_private = true;
+                }
                 '0'..='9' => {
                     self.num[self.idx] = self.num[self.idx].wrapping_mul(10).wrapping_add(((ch as u8) - b'0').into());
                 }
@@ -350,6 +397,12 @@ impl Console {
                     self.handle_cup();
                     self.state = State::Initial;
                 }
+                'h' | 'l' => {
+                    if self.csi_private {
+                        self.handle_decset(ch == 'h');
+                    }
+                    self.state = State::Initial;
+                }
                 _ => {
                     self.state = State::Initial;
                 }
@@ -0,0 +1,299 @@
+//! On-screen touch keyboard
+use k210_shared::board::lcd_colors::rgb565;
+use k210_shared::board::lcd_render::AsU16;
+use k210_shared::board::ns2009::{Event, EventKind};
+
+use crate::console::{ScreenImage, DISP_WIDTH};
+
+/** Width of one key unit in pixels */
+const KEY_W: u16 = 16;
+/** Height of a key in pixels */
+const KEY_H: u16 = 16;
+/** Number of rows of keys */
+const ROWS: usize = 5;
+/** Height of the keyboard in pixels */
+pub const HEIGHT: u16 = KEY_H * (ROWS as u16);
+
+/** Label color */
+const FG: u16 = rgb565(192, 192, 192);
+/** Background color between keys */
+const BG: u16 = rgb565(0, 0, 0);
+/** Key color */
+const KEY_BG: u16 = rgb565(48, 48, 48);
+/** Color of pressed keys and active modifiers */
+const ACTIVE_BG: u16 = rgb565(0, 96, 160);
+
+/** Key function */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Key {
+    /** Character key: unshifted, shifted */
+    Char(char, char),
+    Space,
+    Backspace,
+    Tab,
+    Enter,
+    Escape,
+    /** Shift modifier (applies to the next key) */
+    Shift,
+    /** Control modifier (applies to the next key) */
+    Ctrl,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/** Key in layout */
+struct KeyDef {
+    key: Key,
+    /** Width in key units */
+    width: u16,
+}
+
+const fn k(key: Key, width: u16) -> KeyDef {
+    KeyDef { key, width }
+}
+
+const fn c(normal: char, shifted: char) -> KeyDef {
+    KeyDef { key: Key::Char(normal, shifted), width: 1 }
+}
+
+/** US-style keyboard layout. Every row is at most DISP_WIDTH / KEY_W units wide. */
+static LAYOUT: [&[KeyDef]; ROWS] = [
+    &[
+        k(Key::Escape, 3),
+        c('`', '~'), c('1', '!'), c('2', '@'), c('3', '#'), c('4', '$'), c('5', '%'), c('6', '^'),
+        c('7', '&'), c('8', '*'), c('9', '('), c('0', ')'), c('-', '_'), c('=', '+'),
+        k(Key::Backspace, 4),
+    ],
+    &[
+        k(Key::Tab, 3),
+        c('q', 'Q'), c('w', 'W'), c('e', 'E'), c('r', 'R'), c('t', 'T'), c('y', 'Y'), c('u', 'U'),
+        c('i', 'I'), c('o', 'O'), c('p', 'P'), c('[', '{'), c(']', '}'), c('\\', '|'),
+    ],
+    &[
+        k(Key::Ctrl, 4),
+        c('a', 'A'), c('s', 'S'), c('d', 'D'), c('f', 'F'), c('g', 'G'), c('h', 'H'), c('j', 'J'),
+        c('k', 'K'), c('l', 'L'), c(';', ':'), c('\'', '"'),
+        k(Key::Enter, 5),
+    ],
+    &[
+        k(Key::Shift, 5),
+        c('z', 'Z'), c('x', 'X'), c('c', 'C'), c('v', 'V'), c('b', 'B'), c('n', 'N'), c('m', 'M'),
+        c(',', '<'), c('.', '>'), c('/', '?'),
+        k(Key::Up, 2),
+        k(Key::Shift, 3),
+    ],
+    &[
+        k(Key::Space, 13),
+        k(Key::Left, 2),
+        k(Key::Down, 2),
+        k(Key::Right, 2),
+    ],
+];
+
+/** Label for non-character keys */
+fn special_label(key: Key) -> &'static str {
+    match key {
+        Key::Char(_, _) => "",
+        Key::Space => "",
+        Key::Backspace => "Bksp",
+        Key::Tab => "Tab",
+        Key::Enter => "Enter",
+        Key::Escape => "Esc",
+        Key::Shift => "Shift",
+        Key::Ctrl => "Ctrl",
+        Key::Up => "\u{2191}",
+        Key::Down => "\u{2193}",
+        Key::Left => "\u{2190}",
+        Key::Right => "\u{2192}",
+    }
+}
+
+/** On-screen keyboard widget. It occupies the full width of the screen and `HEIGHT` pixels
+ * starting at row `top`. Keys are emitted on release, so that the finger can be slid to the
+ * right key.
+ */
+pub struct Keyboard {
+    /** Map unicode character to font index and flags word. */
+    map_utf: &'static dyn Fn(char) -> (u16, u16),
+    /** Font for key labels */
+    font: &'static [[u8; 8]],
+    /** Top row of keyboard in pixels */
+    top: u16,
+    /** Shift modifier active */
+    shift: bool,
+    /** Ctrl modifier active */
+    ctrl: bool,
+    /** Currently pressed key (row, index) */
+    pressed: Option<(usize, usize)>,
+    /** Dirty flag */
+    pub dirty: bool,
+}
+
+impl Keyboard {
+    /** Create a new keyboard, using the same font as the console. */
+    pub fn new(map_utf: &'static dyn Fn(char) -> (u16, u16), font: &'static [[u8; 8]], top: u16) -> Self {
+        Self {
+            map_utf,
+            font,
+            top,
+            shift: false,
+            ctrl: false,
+            pressed: None,
+            dirty: true,
+        }
+    }
+
+    /** Return whether a screen position (in pixels) falls inside the keyboard area. */
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let top = i32::from(self.top);
+        x >= 0 && x < i32::from(DISP_WIDTH) && y >= top && y < top + i32::from(HEIGHT)
+    }
+
+    /** Find the key at a screen position. */
+    fn key_at(&self, x: i32, y: i32) -> Option<(usize, usize)> {
+        if !self.contains(x, y) {
+            return None;
+        }
+        let row = ((y - i32::from(self.top)) / i32::from(KEY_H)) as usize;
+        let mut kx = 0;
+        for (i, kd) in LAYOUT[row].iter().enumerate() {
+            kx += i32::from(kd.width * KEY_W);
+            if x < kx {
+                return Some((row, i));
+            }
+        }
+        None
+    }
+
+    /** Handle a touch screen event. Byte sequences for activated keys are passed to `sink`. */
+    pub fn handle<F>(&mut self, ev: &Event, mut sink: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        match ev.kind {
+            EventKind::Begin | EventKind::Move => {
+                let key = self.key_at(ev.x, ev.y);
+                if key != self.pressed {
+                    self.pressed = key;
+                    self.dirty = true;
+                }
+            }
+            EventKind::End => {
+                if let Some((row, i)) = self.pressed.take() {
+                    self.dirty = true;
+                    self.activate(LAYOUT[row][i].key, &mut sink);
+                }
+            }
+        }
+    }
+
+    /** Emit the byte sequence for a key, applying and then clearing modifiers. */
+    fn activate<F>(&mut self, key: Key, sink: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        match key {
+            Key::Shift => { self.shift = !self.shift; return; }
+            Key::Ctrl => { self.ctrl = !self.ctrl; return; }
+            Key::Char(normal, shifted) => {
+                self.send_char(if self.shift { shifted } else { normal }, sink);
+            }
+            Key::Space => { self.send_char(' ', sink); }
+            Key::Backspace => { sink(b"\x7f"); }
+            Key::Tab => { sink(b"\t"); }
+            Key::Enter => { sink(b"\r"); }
+            Key::Escape => { sink(b"\x1b"); }
+            Key::Up => { sink(b"\x1b[A"); }
+            Key::Down => { sink(b"\x1b[B"); }
+            Key::Right => { sink(b"\x1b[C"); }
+            Key::Left => { sink(b"\x1b[D"); }
+        }
+        self.shift = false;
+        self.ctrl = false;
+    }
+
+    /** Emit a character, as control code if the ctrl modifier is active. */
+    fn send_char<F>(&self, ch: char, sink: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.ctrl {
+            match ch {
+                'a'..='z' | '@'..='_' => { sink(&[(ch.to_ascii_uppercase() as u8) & 0x1f]); return; }
+                ' ' => { sink(&[0x00]); return; }
+                '?' => { sink(&[0x7f]); return; }
+                _ => {}
+            }
+        }
+        let mut buf = [0u8; 4];
+        sink(ch.encode_utf8(&mut buf).as_bytes());
+    }
+
+    /** Render keyboard into its part of a screen image. */
+    pub fn render(&self, image: &mut ScreenImage) {
+        let pixels = image.as_u16_slice_mut();
+        let stride = usize::from(DISP_WIDTH);
+        let base = usize::from(self.top) * stride;
+        for p in &mut pixels[base..base + usize::from(HEIGHT) * stride] {
+            *p = BG;
+        }
+        for (row, keys) in LAYOUT.iter().enumerate() {
+            let y0 = self.top + (row as u16) * KEY_H;
+            let mut x0 = 0;
+            for (i, kd) in keys.iter().enumerate() {
+                let w = kd.width * KEY_W;
+                let active = self.pressed == Some((row, i))
+                    || (kd.key == Key::Shift && self.shift)
+                    || (kd.key == Key::Ctrl && self.ctrl);
+                let bg = if active { ACTIVE_BG } else { KEY_BG };
+                // Leave a one-pixel gap around every key
+                for y in (y0 + 1)..(y0 + KEY_H - 1) {
+                    let ofs = usize::from(y) * stride;
+                    for p in &mut pixels[ofs + usize::from(x0 + 1)..ofs + usize::from(x0 + w - 1)] {
+                        *p = bg;
+                    }
+                }
+
+                let mut buf = [0u8; 4];
+                let label: &str = match kd.key {
+                    Key::Char(normal, shifted) => {
+                        (if self.shift { shifted } else { normal }).encode_utf8(&mut buf)
+                    }
+                    key => special_label(key),
+                };
+                let lw = (label.chars().count() as u16) * 8;
+                let mut lx = x0 + w.saturating_sub(lw) / 2;
+                let ly = y0 + (KEY_H - 8) / 2;
+                for ch in label.chars() {
+                    if lx + 8 > x0 + w {
+                        break;
+                    }
+                    self.draw_glyph(pixels, lx, ly, ch);
+                    lx += 8;
+                }
+                x0 += w;
+            }
+        }
+    }
+
+    /** Draw a glyph from the font in the label color, leaving the background as-is. */
+    fn draw_glyph(&self, pixels: &mut [u16], x: u16, y: u16, ch: char) {
+        let (idx, flags) = (self.map_utf)(ch);
+        if flags != 0 {
+            // Color font glyphs are not supported for labels
+            return;
+        }
+        let glyph = self.font.get(usize::from(idx)).unwrap_or(&[0u8; 8]);
+        for yi in 0..8 {
+            let ofs = usize::from(y + yi) * usize::from(DISP_WIDTH) + usize::from(x);
+            let val = glyph[usize::from(yi)];
+            for xi in 0..8 {
+                if val & (1 << xi) != 0 {
+                    pixels[ofs + xi] = FG;
+                }
+            }
+        }
+    }
+}
@@ -5,4 +5,6 @@ pub mod console;
 pub mod coord;
 pub mod cp437;
 pub mod cp437_8x8;
+pub mod keyboard;
+pub mod mouse;
 pub mod palette_xterm256;
@@ -10,11 +10,11 @@ mod lfsr;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
 use k210_hal::pac::Peripherals;
-use k210_shared::board::def::io;
 use k210_shared::board::lcd::{self, LCD, LCDHL};
 use k210_shared::board::lcd_colors;
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{dma_channel, DMACExt};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::mux::PinMux;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
@@ -27,16 +27,9 @@ use k210_console::palette_xterm256::PALETTE;
 use k210_console::{cp437, cp437_8x8};
 
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    pinout::MAIX_GO.lcd.route(mux).unwrap()
 }
 
 /** Set correct voltage for pins */
@@ -67,7 +60,8 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     writeln!(stdout, "Clocks:").unwrap();
@@ -131,7 +125,7 @@ fn main() -> ! {
     /* LCD init */
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
@@ -0,0 +1,198 @@
+//! xterm mouse reporting: translate touch screen events to mouse escape sequences
+use core::cmp::{max, min};
+use core::fmt::{self, Write};
+
+use k210_shared::board::ns2009::{Event, EventKind};
+
+/** Size of a character cell in pixels */
+const CELL_SIZE: i32 = 8;
+/** Highest cell coordinate (zero-based) that can be encoded in the legacy protocol */
+const LEGACY_MAX_COORD: u16 = 255 - 32 - 1;
+
+/** Mouse tracking mode, as requested by the remote program through DEC private modes. */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MouseTracking {
+    /** No mouse reporting */
+    Off,
+    /** X10 compatibility mode (`?9h`): report button press only */
+    X10,
+    /** Normal tracking mode (`?1000h`): report button press and release */
+    Normal,
+    /** Button-event tracking (`?1002h`): also report motion while a button is held */
+    ButtonEvent,
+    /** Any-event tracking (`?1003h`): report all motion. A touch screen only has motion
+     * while touched, so this behaves the same as `ButtonEvent`. */
+    AnyEvent,
+}
+
+/** Mouse reporting state of the console. */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MouseMode {
+    /** Which events to report */
+    pub tracking: MouseTracking,
+    /** Use SGR extended coordinates (`?1006h`) instead of the legacy byte encoding */
+    pub sgr: bool,
+}
+
+impl MouseMode {
+    pub const fn new() -> Self {
+        Self {
+            tracking: MouseTracking::Off,
+            sgr: false,
+        }
+    }
+
+    /** Enable or disable a tracking mode. Disabling a mode that is not the current one has no
+     * effect. */
+    pub fn set_tracking(&mut self, tracking: MouseTracking, enable: bool) {
+        if enable {
+            self.tracking = tracking;
+        } else if self.tracking == tracking {
+            self.tracking = MouseTracking::Off;
+        }
+    }
+}
+
+/** Small fixed-size buffer to format one escape sequence into */
+struct SeqBuf {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl SeqBuf {
+    fn new() -> Self {
+        Self { buf: [0; 32], len: 0 }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<(), fmt::Error> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[0..self.len]
+    }
+}
+
+impl Write for SeqBuf {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        self.push(s.as_bytes())
+    }
+}
+
+/** Kind of mouse report */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Report {
+    Press,
+    Drag,
+    Release,
+}
+
+/** Translates touch screen events in pixel coordinates to cell-based mouse reports. The touch
+ * screen is treated as a one-button (left button) mouse.
+ */
+pub struct TouchMouse {
+    /** Number of columns of the console */
+    cols: u16,
+    /** Number of rows of the console */
+    rows: u16,
+    /** Touch (button) is currently down */
+    pressed: bool,
+    /** Last reported cell */
+    last: (u16, u16),
+}
+
+impl TouchMouse {
+    /** Create a translator for a console of `cols`×`rows` cells. */
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            cols,
+            rows,
+            pressed: false,
+            last: (0, 0),
+        }
+    }
+
+    /** Convert pixel coordinates to cell coordinates, clamping to the console size. */
+    fn to_cell(&self, x: i32, y: i32) -> (u16, u16) {
+        (
+            max(min(x / CELL_SIZE, i32::from(self.cols) - 1), 0) as u16,
+            max(min(y / CELL_SIZE, i32::from(self.rows) - 1), 0) as u16,
+        )
+    }
+
+    /** Encode a mouse report for the given cell. Returns the escape sequence. */
+    fn encode(mode: MouseMode, report: Report, (x, y): (u16, u16)) -> SeqBuf {
+        let mut seq = SeqBuf::new();
+        if mode.sgr {
+            let (button, fin) = match report {
+                Report::Press => (0, 'M'),
+                Report::Drag => (32, 'M'),
+                Report::Release => (0, 'm'),
+            };
+            write!(seq, "\x1b[<{};{};{}{}", button, x + 1, y + 1, fin).unwrap();
+        } else {
+            let button: u8 = match report {
+                Report::Press => 0,
+                Report::Drag => 32,
+                Report::Release => 3,
+            };
+            let x = min(x, LEGACY_MAX_COORD) as u8;
+            let y = min(y, LEGACY_MAX_COORD) as u8;
+            seq.push(&[0x1b, b'[', b'M', 32 + button, 33 + x, 33 + y]).unwrap();
+        }
+        seq
+    }
+
+    /** Translate a touch screen event according to the current mouse mode of the console.
+     * The resulting escape sequence, if any, is passed to `sink`.
+     */
+    pub fn translate<F>(&mut self, mode: MouseMode, ev: &Event, mut sink: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        let cell = self.to_cell(ev.x, ev.y);
+        let report = match ev.kind {
+            EventKind::Begin => {
+                self.pressed = true;
+                Some(Report::Press)
+            }
+            EventKind::Move => {
+                if !self.pressed || cell == self.last {
+                    None
+                } else {
+                    Some(Report::Drag)
+                }
+            }
+            EventKind::End => {
+                if self.pressed {
+                    self.pressed = false;
+                    Some(Report::Release)
+                } else {
+                    None
+                }
+            }
+        };
+        let report = if let Some(report) = report {
+            report
+        } else {
+            return;
+        };
+        self.last = cell;
+
+        let wanted = match mode.tracking {
+            MouseTracking::Off => false,
+            MouseTracking::X10 => report == Report::Press,
+            MouseTracking::Normal => report != Report::Drag,
+            MouseTracking::ButtonEvent | MouseTracking::AnyEvent => true,
+        };
+        if wanted {
+            sink(Self::encode(mode, report, cell).as_bytes());
+        }
+    }
+}
@@ -1,8 +1,15 @@
 pub mod def;
+pub mod image;
 pub mod lcd;
 pub mod lcd_colors;
+pub mod lcd_gfx;
+#[cfg(test)]
+pub mod lcd_mock;
+pub mod lcd_panel;
 pub mod lcd_render;
 pub mod msa300;
 pub mod ns2009;
 pub mod ov2640;
+pub mod pinout;
 pub mod sdcard;
+pub mod spi_nor;
@@ -0,0 +1,521 @@
+//! Image decoding (BMP, QOI) to RGB565, into a `ScreenImage` or straight to the LCD
+use crate::board::def::{DISP_HEIGHT, DISP_WIDTH};
+use crate::board::lcd::LCDLL;
+use crate::board::lcd_colors::{rgb565, rgb565_dither};
+use crate::board::lcd_render::{AsU16, ScreenImage};
+
+pub mod bmp;
+pub mod qoi;
+
+/** Alpha values below this are treated as fully transparent, others as opaque */
+const ALPHA_THRESHOLD: u8 = 128;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /** Not a valid image file */
+    Format,
+    /** Valid image, but uses a feature that is not supported */
+    Unsupported,
+    /** Image data ends prematurely */
+    Truncated,
+    /** Image doesn't fit on the display at the requested position */
+    OutOfBounds,
+}
+
+/** Receives decoded pixels from a decoder. */
+pub trait PixelSink {
+    /** Pixel in source image coordinates, as RGBA. Pixels of a row are delivered left to
+     * right, but some may be skipped (for example by BMP RLE deltas). */
+    fn pixel(&mut self, x: u16, y: u16, rgba: [u8; 4]);
+    /** All pixels of row `y` have been delivered. This is called exactly once for every row,
+     * but not necessarily top to bottom. */
+    fn end_row(&mut self, y: u16);
+}
+
+/** Parsed image of either supported format. */
+pub enum Image<'a> {
+    Bmp(bmp::Bmp<'a>),
+    Qoi(qoi::Qoi<'a>),
+}
+
+impl<'a> Image<'a> {
+    /** Parse an image file, detecting the format from its header. */
+    pub fn parse(data: &'a [u8]) -> Result<Self, ImageError> {
+        if data.starts_with(b"BM") {
+            Ok(Image::Bmp(bmp::Bmp::parse(data)?))
+        } else if data.starts_with(b"qoif") {
+            Ok(Image::Qoi(qoi::Qoi::parse(data)?))
+        } else {
+            Err(ImageError::Format)
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        match self {
+            Image::Bmp(i) => i.width(),
+            Image::Qoi(i) => i.width(),
+        }
+    }
+
+    pub fn height(&self) -> u16 {
+        match self {
+            Image::Bmp(i) => i.height(),
+            Image::Qoi(i) => i.height(),
+        }
+    }
+
+    /** Decode the image, passing the pixels to `sink`. */
+    pub fn decode<S: PixelSink>(&self, sink: &mut S) -> Result<(), ImageError> {
+        match self {
+            Image::Bmp(i) => i.decode(sink),
+            Image::Qoi(i) => i.decode(sink),
+        }
+    }
+}
+
+/** Options for drawing an image */
+#[derive(Debug, Copy, Clone)]
+pub struct DrawOptions {
+    /** Scale the image to this width and height (nearest neighbour) */
+    pub size: Option<(u16, u16)>,
+    /** Use ordered dithering when converting to RGB565 */
+    pub dither: bool,
+    /** Color for transparent and skipped pixels when drawing to the LCD */
+    pub background: u16,
+}
+
+impl DrawOptions {
+    pub const fn new() -> Self {
+        Self {
+            size: None,
+            dither: false,
+            background: 0,
+        }
+    }
+}
+
+/** Maps source coordinates to (ranges of) destination coordinates. */
+#[derive(Copy, Clone)]
+struct Scaler {
+    src: (u16, u16),
+    dst: (u16, u16),
+}
+
+impl Scaler {
+    fn new(image: &Image, options: &DrawOptions) -> Self {
+        let src = (image.width(), image.height());
+        Self {
+            src,
+            dst: options.size.unwrap_or(src),
+        }
+    }
+
+    /** Destination range covered by source coordinate `s`. This is empty for source pixels that
+     * are dropped when downscaling. */
+    fn range(s: u16, src: u16, dst: u16) -> (u16, u16) {
+        let map = |v: u32| (v * u32::from(dst) / u32::from(src)) as u16;
+        (map(s.into()), map(u32::from(s) + 1))
+    }
+
+    fn x_range(&self, x: u16) -> (u16, u16) {
+        Self::range(x, self.src.0, self.dst.0)
+    }
+
+    fn y_range(&self, y: u16) -> (u16, u16) {
+        Self::range(y, self.src.1, self.dst.1)
+    }
+}
+
+/** Convert a color to RGB565 for a screen position. */
+fn to_rgb565(rgba: [u8; 4], dither: bool, x: u16, y: u16) -> u16 {
+    if dither {
+        rgb565_dither(rgba[0], rgba[1], rgba[2], x, y)
+    } else {
+        rgb565(rgba[0], rgba[1], rgba[2])
+    }
+}
+
+/** Sink that draws into a screen image, clipping to the screen. Transparent and skipped pixels
+ * are left as they are. */
+struct ScreenSink<'a> {
+    pixels: &'a mut [u16],
+    x0: i32,
+    y0: i32,
+    scaler: Scaler,
+    dither: bool,
+}
+
+impl PixelSink for ScreenSink<'_> {
+    fn pixel(&mut self, x: u16, y: u16, rgba: [u8; 4]) {
+        if rgba[3] < ALPHA_THRESHOLD {
+            return;
+        }
+        let (dx1, dx2) = self.scaler.x_range(x);
+        let (dy1, dy2) = self.scaler.y_range(y);
+        for dy in dy1..dy2 {
+            let sy = self.y0 + i32::from(dy);
+            if sy < 0 || sy >= i32::from(DISP_HEIGHT) {
+                continue;
+            }
+            for dx in dx1..dx2 {
+                let sx = self.x0 + i32::from(dx);
+                if sx < 0 || sx >= i32::from(DISP_WIDTH) {
+                    continue;
+                }
+                let (sx, sy) = (sx as u16, sy as u16);
+                self.pixels[usize::from(sy) * usize::from(DISP_WIDTH) + usize::from(sx)] =
+                    to_rgb565(rgba, self.dither, sx, sy);
+            }
+        }
+    }
+
+    fn end_row(&mut self, _y: u16) {}
+}
+
+/** Draw an image into a screen image at position (`x`, `y`), which may be partially or
+ * completely off-screen. */
+pub fn draw_image(
+    image: &Image,
+    dest: &mut ScreenImage,
+    x: i32,
+    y: i32,
+    options: &DrawOptions,
+) -> Result<(), ImageError> {
+    let mut sink = ScreenSink {
+        pixels: dest.as_u16_slice_mut(),
+        x0: x,
+        y0: y,
+        scaler: Scaler::new(image, options),
+        dither: options.dither,
+    };
+    image.decode(&mut sink)
+}
+
+/** Sink that sends rows to the LCD through an address window. One source row is collected at a
+ * time, then written to all destination rows it covers. */
+struct LCDSink<'a, L> {
+    lcd: &'a L,
+    x0: u16,
+    y0: u16,
+    scaler: Scaler,
+    dither: bool,
+    background: u16,
+    /** Current source row (scaled horizontally), None for transparent or skipped pixels */
+    row: [Option<[u8; 4]>; DISP_WIDTH as usize],
+}
+
+/** Number of words to send to the LCD at once */
+const LCD_BUF_LEN: usize = 32;
+
+impl<L: LCDLL> LCDSink<'_, L> {
+    /** Send a destination row to the display. */
+    fn write_row(&self, dy: u16) {
+        let width = self.scaler.dst.0;
+        let sy = self.y0 + dy;
+        self.lcd.set_area(self.x0, sy, self.x0 + width - 1, sy);
+        let color = |dx: u16| match self.row[usize::from(dx)] {
+            Some(rgba) => to_rgb565(rgba, self.dither, self.x0 + dx, sy),
+            None => self.background,
+        };
+        let mut buf = [0u32; LCD_BUF_LEN];
+        let mut n = 0;
+        let mut dx = 0;
+        while dx < width {
+            // For an odd width, the extra pixel wraps around to the start of the row, so repeat
+            // the first pixel.
+            let b = if dx + 1 < width { color(dx + 1) } else { color(0) };
+            buf[n] = u32::from(color(dx)) | (u32::from(b) << 16);
+            n += 1;
+            if n == LCD_BUF_LEN {
+                self.lcd.write_word(&buf);
+                n = 0;
+            }
+            dx += 2;
+        }
+        if n > 0 {
+            self.lcd.write_word(&buf[0..n]);
+        }
+    }
+}
+
+impl<L: LCDLL> PixelSink for LCDSink<'_, L> {
+    fn pixel(&mut self, x: u16, _y: u16, rgba: [u8; 4]) {
+        let value = if rgba[3] < ALPHA_THRESHOLD { None } else { Some(rgba) };
+        let (dx1, dx2) = self.scaler.x_range(x);
+        for dx in dx1..dx2 {
+            self.row[usize::from(dx)] = value;
+        }
+    }
+
+    fn end_row(&mut self, y: u16) {
+        let (dy1, dy2) = self.scaler.y_range(y);
+        for dy in dy1..dy2 {
+            self.write_row(dy);
+        }
+        for v in self.row.iter_mut() {
+            *v = None;
+        }
+    }
+}
+
+/** Draw an image directly to the LCD at position (`x`, `y`), one row at a time. This needs no
+ * screen image, but the (scaled) image must fit on the display, otherwise nothing is drawn
+ * and `OutOfBounds` returned. */
+pub fn draw_image_lcd<L: LCDLL>(
+    image: &Image,
+    lcd: &L,
+    x: u16,
+    y: u16,
+    options: &DrawOptions,
+) -> Result<(), ImageError> {
+    let scaler = Scaler::new(image, options);
+    if u32::from(x) + u32::from(scaler.dst.0) > u32::from(DISP_WIDTH)
+        || u32::from(y) + u32::from(scaler.dst.1) > u32::from(DISP_HEIGHT) {
+        return Err(ImageError::OutOfBounds);
+    }
+    let mut sink = LCDSink {
+        lcd,
+        x0: x,
+        y0: y,
+        scaler,
+        dither: options.dither,
+        background: options.background,
+        row: [None; DISP_WIDTH as usize],
+    };
+    if scaler.dst.0 == 0 || scaler.dst.1 == 0 {
+        return Ok(());
+    }
+    image.decode(&mut sink)
+}
+
+/** Read a little-endian u16 at `ofs` */
+fn read_u16le(data: &[u8], ofs: usize) -> Result<u16, ImageError> {
+    data.get(ofs..ofs + 2)
+        .map(|b| u16::from(b[0]) | (u16::from(b[1]) << 8))
+        .ok_or(ImageError::Truncated)
+}
+
+/** Read a little-endian u32 at `ofs` */
+fn read_u32le(data: &[u8], ofs: usize) -> Result<u32, ImageError> {
+    Ok(u32::from(read_u16le(data, ofs)?) | (u32::from(read_u16le(data, ofs + 2)?) << 16))
+}
+
+/** Read a big-endian u32 at `ofs` */
+fn read_u32be(data: &[u8], ofs: usize) -> Result<u32, ImageError> {
+    data.get(ofs..ofs + 4)
+        .map(|b| {
+            (u32::from(b[0]) << 24) | (u32::from(b[1]) << 16) | (u32::from(b[2]) << 8) | u32::from(b[3])
+        })
+        .ok_or(ImageError::Truncated)
+}
+
+/** Convert image dimensions to u16, rejecting empty and too large images */
+fn dimension(v: u32) -> Result<u16, ImageError> {
+    if v == 0 {
+        Err(ImageError::Format)
+    } else if v > 0xffff {
+        Err(ImageError::Unsupported)
+    } else {
+        Ok(v as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::def::DISP_PIXELS;
+    use crate::board::lcd_mock::MockLCD;
+
+    /** Sentinel value for pixels that were not written */
+    const UNTOUCHED: u16 = 0x1234;
+
+    /** Test image as generated by `testdata/gen-images.py` */
+    fn expected(x: u16, y: u16) -> [u8; 4] {
+        const PAL: [[u8; 3]; 8] = [
+            [0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255],
+            [255, 255, 0], [0, 255, 255], [255, 0, 255], [255, 255, 255],
+        ];
+        let c = if y == 0 {
+            PAL[1]
+        } else if y == 3 {
+            [(x * x) as u8, (100 + x) as u8, (50 + x) as u8]
+        } else if y == 4 && x < 4 {
+            PAL[2]
+        } else {
+            PAL[usize::from(x + 2 * y) % 8]
+        };
+        [c[0], c[1], c[2], 255]
+    }
+    const W: u16 = 7;
+    const H: u16 = 5;
+
+    fn new_screen() -> ScreenImage {
+        [(u32::from(UNTOUCHED) << 16) | u32::from(UNTOUCHED); DISP_PIXELS / 2]
+    }
+
+    fn screen_pixel(screen: &ScreenImage, x: u16, y: u16) -> u16 {
+        screen.as_u16_slice()[usize::from(y) * usize::from(DISP_WIDTH) + usize::from(x)]
+    }
+
+    /** Decode a test image at (10,20) and compare, skipping pixels for which `skip` is true. */
+    fn check_image<F: Fn(u16, u16) -> bool>(data: &[u8], skip: F) {
+        let image = Image::parse(data).unwrap();
+        assert_eq!((image.width(), image.height()), (W, H));
+        let mut screen = new_screen();
+        draw_image(&image, &mut screen, 10, 20, &DrawOptions::new()).unwrap();
+        for y in 0..H {
+            for x in 0..W {
+                let c = expected(x, y);
+                let want = if skip(x, y) { UNTOUCHED } else { rgb565(c[0], c[1], c[2]) };
+                assert_eq!(screen_pixel(&screen, 10 + x, 20 + y), want, "pixel {},{}", x, y);
+            }
+        }
+        assert_eq!(screen_pixel(&screen, 9, 20), UNTOUCHED);
+        assert_eq!(screen_pixel(&screen, 10 + W, 20), UNTOUCHED);
+        assert_eq!(screen_pixel(&screen, 10, 20 + H), UNTOUCHED);
+    }
+
+    #[test]
+    fn test_bmp_uncompressed() {
+        check_image(include_bytes!("../../testdata/rgb24.bmp"), |_, _| false);
+        check_image(include_bytes!("../../testdata/rgb32-topdown.bmp"), |_, _| false);
+        check_image(include_bytes!("../../testdata/rgb565.bmp"), |_, _| false);
+        check_image(include_bytes!("../../testdata/pal8.bmp"), |_, _| false);
+        check_image(include_bytes!("../../testdata/pal4.bmp"), |_, _| false);
+        check_image(include_bytes!("../../testdata/core24.bmp"), |_, _| false);
+    }
+
+    #[test]
+    fn test_bmp_rle() {
+        // The RLE images skip pixels 2..5 of row 2 with a delta
+        let skip = |x, y| y == 2 && x >= 2 && x < 5;
+        check_image(include_bytes!("../../testdata/rle8.bmp"), skip);
+        check_image(include_bytes!("../../testdata/rle4.bmp"), skip);
+    }
+
+    #[test]
+    fn test_qoi() {
+        // The last pixel is transparent
+        check_image(include_bytes!("../../testdata/rgba.qoi"), |x, y| x == W - 1 && y == H - 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Image::parse(b"GIF89a").err(), Some(ImageError::Format));
+        let data = include_bytes!("../../testdata/rgb24.bmp");
+        let image = Image::parse(&data[0..data.len() - 10]).unwrap();
+        let mut screen = new_screen();
+        assert_eq!(
+            draw_image(&image, &mut screen, 0, 0, &DrawOptions::new()).err(),
+            Some(ImageError::Truncated)
+        );
+        let data = include_bytes!("../../testdata/rgba.qoi");
+        let image = Image::parse(&data[0..data.len() - 20]).unwrap();
+        assert_eq!(
+            draw_image(&image, &mut screen, 0, 0, &DrawOptions::new()).err(),
+            Some(ImageError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_scaling() {
+        let image = Image::parse(include_bytes!("../../testdata/rgb24.bmp")).unwrap();
+        let mut screen = new_screen();
+        let mut options = DrawOptions::new();
+        options.size = Some((W * 2, H * 3));
+        draw_image(&image, &mut screen, 0, 0, &options).unwrap();
+        for y in 0..H * 3 {
+            for x in 0..W * 2 {
+                let c = expected(x / 2, y / 3);
+                assert_eq!(screen_pixel(&screen, x, y), rgb565(c[0], c[1], c[2]));
+            }
+        }
+        assert_eq!(screen_pixel(&screen, W * 2, 0), UNTOUCHED);
+
+        let mut screen = new_screen();
+        options.size = Some((3, 2));
+        draw_image(&image, &mut screen, 0, 0, &options).unwrap();
+        // Every destination pixel shows the one source pixel whose scaled range covers it
+        for (dy, sy) in [(0, 2), (1, 4)].iter() {
+            for (dx, sx) in [(0, 2), (1, 4), (2, 6)].iter() {
+                let c = expected(*sx, *sy);
+                assert_eq!(screen_pixel(&screen, *dx, *dy), rgb565(c[0], c[1], c[2]));
+            }
+        }
+        assert_eq!(screen_pixel(&screen, 3, 0), UNTOUCHED);
+        assert_eq!(screen_pixel(&screen, 0, 2), UNTOUCHED);
+    }
+
+    #[test]
+    fn test_clipping() {
+        let image = Image::parse(include_bytes!("../../testdata/rgb24.bmp")).unwrap();
+        let mut screen = new_screen();
+        draw_image(&image, &mut screen, -3, i32::from(DISP_HEIGHT) - 2, &DrawOptions::new()).unwrap();
+        let c = expected(3, 1);
+        assert_eq!(screen_pixel(&screen, 0, DISP_HEIGHT - 1), rgb565(c[0], c[1], c[2]));
+        assert_eq!(screen_pixel(&screen, 4, DISP_HEIGHT - 3), UNTOUCHED);
+    }
+
+    #[test]
+    fn test_dither() {
+        // 132 is halfway between two 5-bit levels, so half of the pixels of a 4×4 block go up
+        let mut count = [0; 2];
+        for y in 0..4 {
+            for x in 0..4 {
+                let v = rgb565_dither(132, 0, 0, x, y) >> 11;
+                assert!(v == 16 || v == 17);
+                count[usize::from(v - 16)] += 1;
+            }
+        }
+        assert_eq!(count, [8, 8]);
+        // Values at a level stay there
+        assert_eq!(rgb565_dither(128, 128, 128, 3, 1), rgb565(128, 128, 128));
+    }
+
+    #[test]
+    fn test_lcd() {
+        let image = Image::parse(include_bytes!("../../testdata/rle8.bmp")).unwrap();
+        let lcd = MockLCD::new();
+        let mut options = DrawOptions::new();
+        options.background = UNTOUCHED;
+        options.size = Some((W * 2, H));
+        draw_image_lcd(&image, &lcd, 1, 2, &options).unwrap();
+        for y in 0..H {
+            for x in 0..W * 2 {
+                let c = expected(x / 2, y);
+                let want = if y == 2 && x / 2 >= 2 && x / 2 < 5 {
+                    UNTOUCHED
+                } else {
+                    rgb565(c[0], c[1], c[2])
+                };
+                assert_eq!(lcd.pixel(usize::from(1 + x), usize::from(2 + y)), want, "pixel {},{}", x, y);
+            }
+        }
+        assert_eq!(lcd.pixel(0, 2), 0);
+        assert_eq!(lcd.pixel(1 + usize::from(W) * 2, 2), 0);
+
+        // Odd width: the padding pixel must not show up
+        let image = Image::parse(include_bytes!("../../testdata/rgba.qoi")).unwrap();
+        let lcd = MockLCD::new();
+        draw_image_lcd(&image, &lcd, 0, 0, &DrawOptions::new()).unwrap();
+        for y in 0..H {
+            for x in 0..W {
+                let c = expected(x, y);
+                let want = if x == W - 1 && y == H - 1 { 0 } else { rgb565(c[0], c[1], c[2]) };
+                assert_eq!(lcd.pixel(usize::from(x), usize::from(y)), want, "pixel {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lcd_below_screen() {
+        let image = Image::parse(include_bytes!("../../testdata/rgba.qoi")).unwrap();
+        let lcd = MockLCD::new();
+        let options = DrawOptions::new();
+        assert_eq!(draw_image_lcd(&image, &lcd, 0, DISP_HEIGHT - H + 1, &options),
+                   Err(ImageError::OutOfBounds));
+        assert_eq!(draw_image_lcd(&image, &lcd, DISP_WIDTH - W + 1, 0, &options),
+                   Err(ImageError::OutOfBounds));
+        assert_eq!(lcd.pixel(0, 0), 0);
+    }
+}
@@ -0,0 +1,332 @@
+//! Windows BMP decoder: 1/4/8 bit palette, 16/24/32 bit RGB (with bit fields), RLE4 and RLE8
+use super::{dimension, read_u16le, read_u32le, ImageError, PixelSink};
+
+/** Compression methods */
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+/** Size of BITMAPFILEHEADER */
+const FILE_HEADER_SIZE: usize = 14;
+/** Size of BITMAPCOREHEADER (OS/2 1.x) */
+const CORE_HEADER_SIZE: u32 = 12;
+/** Size of BITMAPINFOHEADER */
+const INFO_HEADER_SIZE: u32 = 40;
+
+/** Color channel described by a bit mask */
+#[derive(Debug, Copy, Clone)]
+struct Channel {
+    shift: u32,
+    max: u32,
+}
+
+impl Channel {
+    fn new(mask: u32) -> Self {
+        if mask == 0 {
+            Self { shift: 0, max: 0 }
+        } else {
+            let shift = mask.trailing_zeros();
+            Self { shift, max: mask >> shift }
+        }
+    }
+
+    /** Extract the channel from a pixel value, scaled to 8 bits. `default` is returned if the
+     * channel is not present. */
+    fn get(&self, v: u32, default: u8) -> u8 {
+        if self.max == 0 {
+            default
+        } else {
+            let max = u64::from(self.max);
+            ((u64::from((v >> self.shift) & self.max) * 255 + max / 2) / max) as u8
+        }
+    }
+}
+
+/** Parsed BMP image */
+pub struct Bmp<'a> {
+    data: &'a [u8],
+    width: u16,
+    height: u16,
+    /** Rows are stored top to bottom (negative height) */
+    top_down: bool,
+    bpp: u16,
+    compression: u32,
+    /** Palette entries (BGR + reserved, or BGR for OS/2 bitmaps) */
+    palette: &'a [u8],
+    palette_entry_size: usize,
+    /** Bit fields for 16 and 32 bit images */
+    channels: [Channel; 4],
+    /** Offset of pixel data */
+    pixels_ofs: usize,
+}
+
+impl<'a> Bmp<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ImageError> {
+        if !data.starts_with(b"BM") {
+            return Err(ImageError::Format);
+        }
+        let pixels_ofs = read_u32le(data, 10)? as usize;
+        let hdr = FILE_HEADER_SIZE;
+        let header_size = read_u32le(data, hdr)?;
+        let (width, height, bpp, compression, colors_used, palette_entry_size);
+        if header_size == CORE_HEADER_SIZE {
+            width = i32::from(read_u16le(data, hdr + 4)?);
+            height = i32::from(read_u16le(data, hdr + 6)?);
+            bpp = read_u16le(data, hdr + 10)?;
+            compression = BI_RGB;
+            colors_used = 0;
+            palette_entry_size = 3;
+        } else if header_size >= INFO_HEADER_SIZE {
+            width = read_u32le(data, hdr + 4)? as i32;
+            height = read_u32le(data, hdr + 8)? as i32;
+            bpp = read_u16le(data, hdr + 14)?;
+            compression = read_u32le(data, hdr + 16)?;
+            colors_used = read_u32le(data, hdr + 32)?;
+            palette_entry_size = 4;
+        } else {
+            return Err(ImageError::Format);
+        }
+        if width < 0 {
+            return Err(ImageError::Format);
+        }
+        let top_down = height < 0;
+
+        // Bit fields follow BITMAPINFOHEADER, or are part of the larger header versions, so are
+        // at the same place in both cases.
+        let masks_ofs = hdr + INFO_HEADER_SIZE as usize;
+        let (channels, extra) = match (compression, bpp) {
+            (BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) => {
+                let alpha = if header_size >= 56 { read_u32le(data, masks_ofs + 12)? } else { 0 };
+                (
+                    [
+                        Channel::new(read_u32le(data, masks_ofs)?),
+                        Channel::new(read_u32le(data, masks_ofs + 4)?),
+                        Channel::new(read_u32le(data, masks_ofs + 8)?),
+                        Channel::new(alpha),
+                    ],
+                    12,
+                )
+            }
+            (BI_ALPHABITFIELDS, 16) | (BI_ALPHABITFIELDS, 32) => (
+                [
+                    Channel::new(read_u32le(data, masks_ofs)?),
+                    Channel::new(read_u32le(data, masks_ofs + 4)?),
+                    Channel::new(read_u32le(data, masks_ofs + 8)?),
+                    Channel::new(read_u32le(data, masks_ofs + 12)?),
+                ],
+                16,
+            ),
+            (BI_RGB, 16) => (
+                [Channel::new(0x7c00), Channel::new(0x03e0), Channel::new(0x001f), Channel::new(0)],
+                0,
+            ),
+            (BI_RGB, 32) => (
+                [Channel::new(0xff0000), Channel::new(0x00ff00), Channel::new(0x0000ff), Channel::new(0)],
+                0,
+            ),
+            (BI_RGB, 1) | (BI_RGB, 4) | (BI_RGB, 8) | (BI_RGB, 24) | (BI_RLE8, 8) | (BI_RLE4, 4) => {
+                ([Channel::new(0); 4], 0)
+            }
+            _ => return Err(ImageError::Unsupported),
+        };
+        if top_down && compression != BI_RGB && compression != BI_BITFIELDS {
+            // Compressed bitmaps are always bottom-up
+            return Err(ImageError::Format);
+        }
+
+        let palette_ofs = hdr + header_size as usize
+            + if header_size == INFO_HEADER_SIZE { extra } else { 0 };
+        let palette = if bpp <= 8 {
+            let count = if colors_used == 0 || colors_used > (1 << bpp) {
+                1 << bpp
+            } else {
+                colors_used as usize
+            };
+            data.get(palette_ofs..palette_ofs + count * palette_entry_size)
+                .ok_or(ImageError::Truncated)?
+        } else {
+            &[]
+        };
+
+        Ok(Self {
+            data,
+            width: dimension(width as u32)?,
+            height: dimension(height.wrapping_abs() as u32)?,
+            top_down,
+            bpp,
+            compression,
+            palette,
+            palette_entry_size,
+            channels,
+            pixels_ofs,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /** Look up a palette entry, as RGBA. Out of range indices give black. */
+    fn palette_color(&self, idx: u8) -> [u8; 4] {
+        let ofs = usize::from(idx) * self.palette_entry_size;
+        match self.palette.get(ofs..ofs + 3) {
+            Some(c) => [c[2], c[1], c[0], 255],
+            None => [0, 0, 0, 255],
+        }
+    }
+
+    /** Image row for a row in the file, taking into account the row order. */
+    fn image_row(&self, file_row: u16) -> u16 {
+        if self.top_down {
+            file_row
+        } else {
+            self.height - 1 - file_row
+        }
+    }
+
+    pub fn decode<S: PixelSink>(&self, sink: &mut S) -> Result<(), ImageError> {
+        match self.compression {
+            BI_RLE8 | BI_RLE4 => self.decode_rle(sink),
+            _ => self.decode_uncompressed(sink),
+        }
+    }
+
+    /** Decode uncompressed image. Rows are delivered top to bottom. */
+    fn decode_uncompressed<S: PixelSink>(&self, sink: &mut S) -> Result<(), ImageError> {
+        let bpp = usize::from(self.bpp);
+        let stride = (usize::from(self.width) * bpp + 31) / 32 * 4;
+        for y in 0..self.height {
+            let file_row = self.image_row(y);
+            let ofs = self.pixels_ofs + usize::from(file_row) * stride;
+            let row = self.data.get(ofs..ofs + stride).ok_or(ImageError::Truncated)?;
+            for x in 0..self.width {
+                let xu = usize::from(x);
+                let rgba = match bpp {
+                    1 | 4 | 8 => {
+                        let bit = xu * bpp;
+                        let byte = row[bit / 8];
+                        // Leftmost pixel is in the most significant bits
+                        let idx = (byte >> (8 - bpp - bit % 8)) & ((1 << bpp) - 1) as u8;
+                        self.palette_color(idx)
+                    }
+                    16 => {
+                        let v = u32::from(row[xu * 2]) | (u32::from(row[xu * 2 + 1]) << 8);
+                        self.bitfields_color(v)
+                    }
+                    24 => [row[xu * 3 + 2], row[xu * 3 + 1], row[xu * 3], 255],
+                    _ => {
+                        let p = &row[xu * 4..xu * 4 + 4];
+                        let v = u32::from(p[0])
+                            | (u32::from(p[1]) << 8)
+                            | (u32::from(p[2]) << 16)
+                            | (u32::from(p[3]) << 24);
+                        self.bitfields_color(v)
+                    }
+                };
+                sink.pixel(x, y, rgba);
+            }
+            sink.end_row(y);
+        }
+        Ok(())
+    }
+
+    fn bitfields_color(&self, v: u32) -> [u8; 4] {
+        [
+            self.channels[0].get(v, 0),
+            self.channels[1].get(v, 0),
+            self.channels[2].get(v, 0),
+            self.channels[3].get(v, 255),
+        ]
+    }
+
+    /** Decode RLE8 or RLE4 compressed image. Rows are delivered bottom to top. Pixels skipped
+     * over by deltas and end-of-line codes are not delivered. */
+    fn decode_rle<S: PixelSink>(&self, sink: &mut S) -> Result<(), ImageError> {
+        let rle4 = self.compression == BI_RLE4;
+        let data = self.data.get(self.pixels_ofs..).ok_or(ImageError::Truncated)?;
+        let byte = |ofs: usize| data.get(ofs).cloned().ok_or(ImageError::Truncated);
+        let mut ofs = 0;
+        // Position in file order (row 0 is the bottom row)
+        let mut x: u32 = 0;
+        let mut row: u16 = 0;
+        let put = |sink: &mut S, x: u32, row: u16, idx: u8| {
+            if x < u32::from(self.width) && row < self.height {
+                sink.pixel(x as u16, self.image_row(row), self.palette_color(idx));
+            }
+        };
+        while row < self.height {
+            let n = byte(ofs)?;
+            let v = byte(ofs + 1)?;
+            ofs += 2;
+            if n > 0 {
+                // Encoded run: `n` pixels of color `v` (RLE4: alternating nibbles)
+                for i in 0..u32::from(n) {
+                    let idx = if !rle4 {
+                        v
+                    } else if i % 2 == 0 {
+                        v >> 4
+                    } else {
+                        v & 0x0f
+                    };
+                    put(sink, x, row, idx);
+                    x += 1;
+                }
+                continue;
+            }
+            match v {
+                0 => {
+                    // End of line
+                    sink.end_row(self.image_row(row));
+                    row += 1;
+                    x = 0;
+                }
+                1 => {
+                    // End of bitmap
+                    break;
+                }
+                2 => {
+                    // Delta: move right and up
+                    let dx = byte(ofs)?;
+                    let dy = byte(ofs + 1)?;
+                    ofs += 2;
+                    for _ in 0..dy {
+                        if row < self.height {
+                            sink.end_row(self.image_row(row));
+                        }
+                        row = row.saturating_add(1);
+                    }
+                    x += u32::from(dx);
+                }
+                count => {
+                    // Absolute mode: `count` literal pixels, padded to a 16-bit boundary
+                    let nbytes = if rle4 { (usize::from(count) + 1) / 2 } else { usize::from(count) };
+                    let literal = data.get(ofs..ofs + nbytes).ok_or(ImageError::Truncated)?;
+                    for i in 0..usize::from(count) {
+                        let idx = if !rle4 {
+                            literal[i]
+                        } else if i % 2 == 0 {
+                            literal[i / 2] >> 4
+                        } else {
+                            literal[i / 2] & 0x0f
+                        };
+                        put(sink, x, row, idx);
+                        x += 1;
+                    }
+                    ofs += (nbytes + 1) & !1;
+                }
+            }
+        }
+        // Finish the remaining rows after an early end of bitmap
+        while row < self.height {
+            sink.end_row(self.image_row(row));
+            row += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! QOI ("Quite OK Image") decoder
+use super::{dimension, read_u32be, ImageError, PixelSink};
+
+/** Size of the file header */
+const HEADER_SIZE: usize = 14;
+
+/** Chunk tags */
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const MASK_2: u8 = 0xc0;
+
+/** Parsed QOI image */
+pub struct Qoi<'a> {
+    data: &'a [u8],
+    width: u16,
+    height: u16,
+}
+
+/** Position of a color in the index of previously seen colors */
+fn index_position(c: [u8; 4]) -> usize {
+    (usize::from(c[0]) * 3 + usize::from(c[1]) * 5 + usize::from(c[2]) * 7 + usize::from(c[3]) * 11)
+        % 64
+}
+
+impl<'a> Qoi<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ImageError> {
+        if !data.starts_with(b"qoif") {
+            return Err(ImageError::Format);
+        }
+        let width = read_u32be(data, 4)?;
+        let height = read_u32be(data, 8)?;
+        let channels = *data.get(12).ok_or(ImageError::Truncated)?;
+        if channels != 3 && channels != 4 {
+            return Err(ImageError::Format);
+        }
+        Ok(Self {
+            data,
+            width: dimension(width)?,
+            height: dimension(height)?,
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /** Decode the image. Rows are delivered top to bottom. The colorspace field is ignored:
+     * pixel values are passed on as-is. */
+    pub fn decode<S: PixelSink>(&self, sink: &mut S) -> Result<(), ImageError> {
+        let data = self.data.get(HEADER_SIZE..).ok_or(ImageError::Truncated)?;
+        let byte = |ofs: usize| data.get(ofs).cloned().ok_or(ImageError::Truncated);
+        let mut index = [[0u8; 4]; 64];
+        let mut px = [0u8, 0, 0, 255];
+        let mut run = 0;
+        let mut ofs = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if run > 0 {
+                    run -= 1;
+                } else {
+                    let b1 = byte(ofs)?;
+                    ofs += 1;
+                    if b1 == OP_RGB {
+                        px[0] = byte(ofs)?;
+                        px[1] = byte(ofs + 1)?;
+                        px[2] = byte(ofs + 2)?;
+                        ofs += 3;
+                    } else if b1 == OP_RGBA {
+                        px[0] = byte(ofs)?;
+                        px[1] = byte(ofs + 1)?;
+                        px[2] = byte(ofs + 2)?;
+                        px[3] = byte(ofs + 3)?;
+                        ofs += 4;
+                    } else {
+                        match b1 & MASK_2 {
+                            OP_INDEX => {
+                                px = index[usize::from(b1)];
+                            }
+                            OP_DIFF => {
+                                px[0] = px[0].wrapping_add(((b1 >> 4) & 0x03).wrapping_sub(2));
+                                px[1] = px[1].wrapping_add(((b1 >> 2) & 0x03).wrapping_sub(2));
+                                px[2] = px[2].wrapping_add((b1 & 0x03).wrapping_sub(2));
+                            }
+                            OP_LUMA => {
+                                let b2 = byte(ofs)?;
+                                ofs += 1;
+                                let vg = (b1 & 0x3f).wrapping_sub(32);
+                                px[0] = px[0].wrapping_add(vg.wrapping_sub(8).wrapping_add((b2 >> 4) & 0x0f));
+                                px[1] = px[1].wrapping_add(vg);
+                                px[2] = px[2].wrapping_add(vg.wrapping_sub(8).wrapping_add(b2 & 0x0f));
+                            }
+                            _ => {
+                                // OP_RUN: this pixel and `run` more
+                                run = b1 & 0x3f;
+                            }
+                        }
+                    }
+                    index[index_position(px)] = px;
+                }
+                sink.pixel(x, y, px);
+            }
+            sink.end_row(y);
+        }
+        Ok(())
+    }
+}
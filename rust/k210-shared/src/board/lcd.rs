@@ -1,17 +1,15 @@
-//! ST7789V LCD driver
+//! LCD driver for the ST7789V and compatible controllers (see `lcd_panel`)
 use crate::soc::gpio;
 use crate::soc::gpiohs;
 use crate::soc::sleep::usleep;
 use crate::soc::spi::{SPI,work_mode,frame_format,aitm,tmod};
 use crate::soc::dmac::{DMAC,dma_channel};
+use crate::board::lcd_panel::{self, InitStep, Panel, MAX_INIT_PARAMS};
+use crate::board::lcd_render::ScreenImage;
+use crate::board::pinout::LcdPins;
 
-// These are the values used in the Kendryte SDK but should not ideally be hardcoded here, but
-// defined in the main.rs and passed to the constructor
-// (they're arbitrarily configurable by setting
-//    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS[0-3]);
-//    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-//    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-// )
+// These are the values used in the Kendryte SDK. The pads are routed to them by
+// `pinout::LcdPads::route`, which gives the `LcdPins` token the constructor requires.
 pub const SPI_CS: u32 = 3;
 pub const DCX_GPIONUM: u8 = 2;
 pub const RST_GPIONUM: u8 = 3;
@@ -20,6 +18,8 @@ pub const LCD_Y_MAX: u16 = 320;
 /** SPI clock (this seems to be the highest possible value which is reliable on both my MaixGo
  * boards) */
 pub const SPI_CLK: u32 = 18_000_000;
+/** Number of words to swap at a time for panels that take big-endian pixel data */
+const SWAP_BUF_LEN: usize = 64;
 
 #[repr(u8)]
 #[derive(Copy, Clone)]
@@ -124,6 +124,13 @@ pub enum direction {
 pub const DIR_XY_MASK: u8 = 0x20;
 pub const DIR_MASK: u8 = 0xE0;
 
+/** Swap the two pixels in every word. */
+fn swap_pixels(data: &mut [u32]) {
+    for v in data.iter_mut() {
+        *v = v.rotate_left(16);
+    }
+}
+
 pub struct LCD<'a, SPI> {
     spi: SPI,
     spi_cs: u32,
@@ -131,6 +138,15 @@ pub struct LCD<'a, SPI> {
     rst_gpionum: u8,
     dmac: &'a DMAC,
     channel: dma_channel,
+    /** Image that is currently being sent by an asynchronous flush */
+    flush_image: Option<&'static mut ScreenImage>,
+    /** A write started with `write_start` is in progress */
+    write_busy: bool,
+    /** Panel description */
+    panel: &'static Panel,
+    /** Offset of the visible area in the current direction */
+    x_offset: u16,
+    y_offset: u16,
     pub width: u16,
     pub height: u16,
 }
@@ -144,6 +160,34 @@ pub trait LCDLL {
     /** Write 32-bit words. */
     fn write_word(&self, data_buf: &[u32]);
     fn fill_data(&self, data: u32, length: usize);
+
+    /** Offset of the visible area in controller memory, added to coordinates by `set_area`. */
+    fn area_offset(&self) -> (u16, u16) {
+        (0, 0)
+    }
+
+    /** Set the address window (inclusive) and start writing pixels to display memory. */
+    fn set_area(&self, x1: u16, y1: u16, x2: u16, y2: u16) {
+        let (xo, yo) = self.area_offset();
+        let (x1, y1, x2, y2) = (x1 + xo, y1 + yo, x2 + xo, y2 + yo);
+        self.write_command(command::CASET);
+        self.write_byte(&[
+            (x1 >> 8).into(),
+            (x1 & 0xff).into(),
+            (x2 >> 8).into(),
+            (x2 & 0xff).into(),
+        ]);
+
+        self.write_command(command::RASET);
+        self.write_byte(&[
+            (y1 >> 8).into(),
+            (y1 & 0xff).into(),
+            (y2 >> 8).into(),
+            (y2 & 0xff).into(),
+        ]);
+
+        self.write_command(command::RAMWR);
+    }
 }
 
 /** High-level interface */
@@ -157,12 +201,69 @@ pub trait LCDHL {
     /** Draw a picture, filling the entire screen or part of it. `data` packs two RGB565 pixels
      * per u32 as 0xBBBBAAAA. */
     fn draw_picture(&self, x1: u16, y1: u16, width: u16, height: u16, data: &[u32]);
+    /** Fill a rectangle with a single RGB565 color. */
+    fn fill_rect(&self, x1: u16, y1: u16, width: u16, height: u16, color: u16);
+    /** Copy a rectangle from a larger image (such as a `ScreenImage`) to the same position on the
+     * screen. `stride` is the width of the source image in pixels. As pixels are packed in pairs,
+     * `x1`, `width` and `stride` must be even. */
+    fn blit_rect(&self, x1: u16, y1: u16, width: u16, height: u16, image: &[u32], stride: u16);
+    /** Define the vertical scrolling area: `top_fixed` lines at the top, `scroll` lines
+     * that scroll and `bottom_fixed` lines at the bottom. These must add up to the 320 lines of
+     * frame memory. Note that scrolling happens along the panel's native (portrait) vertical
+     * axis, which is horizontal on screen in the `YX_*` directions. */
+    fn set_scroll_area(&self, top_fixed: u16, scroll: u16, bottom_fixed: u16);
+    /** Set the frame memory line that is shown at the top of the scrolling area. */
+    fn set_scroll_start(&self, line: u16);
+    /** Enter partial mode, only showing the (native, portrait) lines `start..=end`. The rest of
+     * the display is shown black, this saves power. */
+    fn set_partial_mode(&self, start: u16, end: u16);
+    /** Leave partial or scrolling mode and go back to normal display mode. */
+    fn set_normal_mode(&self);
+    /** Enable or disable idle mode (8-color mode, for reduced power consumption). */
+    fn set_idle_mode(&self, idle: bool);
+    /** Enable or disable display inversion. */
+    fn set_inversion(&self, invert: bool);
     /** Shut down and turn off the screen. */
     fn shutdown(&mut self);
 }
 
+/** Asynchronous full-screen updates, driven by the DMA completion interrupt. This makes it
+ * possible to render the next frame while the current one is being sent to the display.
+ * While a flush is in progress no other operations can be done on the display. */
+pub trait LCDAsync {
+    /** Start sending a full-screen image to the display, and return immediately. The image is
+     * handed over for the duration of the transfer, and given back by `flush_poll` or
+     * `flush_wait`. Any previous flush must have finished. */
+    fn flush_start(&mut self, image: &'static mut ScreenImage);
+    /** Return whether a flush is in progress. */
+    fn flush_busy(&self) -> bool;
+    /** Finish the current flush if the transfer is done, returning its image. Returns `None` if
+     * no flush was started, or if it is still in progress. */
+    fn flush_poll(&mut self) -> Option<&'static mut ScreenImage>;
+    /** Wait for the current flush (if any) to finish, and return its image. */
+    fn flush_wait(&mut self) -> Option<&'static mut ScreenImage>;
+    /** Start sending pixel data to the address window set with `set_area`, and return
+     * immediately. Consecutive writes continue where the previous one ended. Any previous
+     * write or flush must have finished.
+     *
+     * The data may be changed in place to match the pixel order of the panel.
+     *
+     * # Safety
+     * `data` must not be accessed or dropped until `write_wait` returns. */
+    unsafe fn write_start(&mut self, data: &mut [u32]);
+    /** Wait for the current write (if any) to finish. */
+    fn write_wait(&mut self);
+}
+
 impl<'a, X: SPI> LCD<'a, X> {
-    pub fn new(spi: X, dmac: &'a DMAC, channel: dma_channel) -> Self {
+    /** Create a driver for the ST7789V panel of the Maix Go. */
+    pub fn new(spi: X, pins: LcdPins, dmac: &'a DMAC, channel: dma_channel) -> Self {
+        Self::with_panel(spi, pins, dmac, channel, &lcd_panel::ST7789)
+    }
+
+    /** Create a driver for a specific panel. */
+    pub fn with_panel(spi: X, _pins: LcdPins, dmac: &'a DMAC, channel: dma_channel,
+                      panel: &'static Panel) -> Self {
         Self {
             spi,
             spi_cs: SPI_CS,
@@ -170,6 +271,11 @@ impl<'a, X: SPI> LCD<'a, X> {
             rst_gpionum: RST_GPIONUM,
             dmac,
             channel,
+            flush_image: None,
+            write_busy: false,
+            panel,
+            x_offset: 0,
+            y_offset: 0,
             width: 0,
             height: 0,
         }
@@ -197,24 +303,41 @@ impl<'a, X: SPI> LCD<'a, X> {
         gpiohs::set_pin(self.rst_gpionum, val);
     }
 
-    fn set_area(&self, x1: u16, y1: u16, x2: u16, y2: u16) {
-        self.write_command(command::CASET);
-        self.write_byte(&[
-            (x1 >> 8).into(),
-            (x1 & 0xff).into(),
-            (x2 >> 8).into(),
-            (x2 & 0xff).into(),
-        ]);
+    /** Panel description. */
+    pub fn panel(&self) -> &'static Panel {
+        self.panel
+    }
 
-        self.write_command(command::RASET);
-        self.write_byte(&[
-            (y1 >> 8).into(),
-            (y1 & 0xff).into(),
-            (y2 >> 8).into(),
-            (y2 & 0xff).into(),
-        ]);
+    /** Send a command that is not in `command`, such as controller-specific ones. */
+    fn write_command_raw(&self, cmd: u8) {
+        self.set_dcx_control();
+        self.spi.configure(
+            work_mode::MODE0,
+            frame_format::OCTAL,
+            8, /*data bits*/
+            0, /*endian*/
+            8, /*instruction length*/
+            0, /*address length*/
+            0, /*wait cycles*/
+            aitm::AS_FRAME_FORMAT,
+            tmod::TRANS,
+        );
+        self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, &[cmd.into()]);
+    }
 
-        self.write_command(command::RAMWR);
+    /** Configure SPI for sending 32-bit words (two pixels per word). */
+    fn configure_word(&self) {
+        self.spi.configure(
+            work_mode::MODE0,
+            frame_format::OCTAL,
+            32, /*data bits*/
+            if self.panel.big_endian { 0 } else { 1 }, /*endian*/
+            0,  /*instruction length*/
+            32, /*address length*/
+            0,  /*wait cycles*/
+            aitm::AS_FRAME_FORMAT,
+            tmod::TRANS,
+        );
     }
 }
 
@@ -240,19 +363,11 @@ impl<X: SPI> LCDLL for LCD<'_, X> {
     }
 
     fn write_command(&self, cmd: command) {
-        self.set_dcx_control();
-        self.spi.configure(
-            work_mode::MODE0,
-            frame_format::OCTAL,
-            8, /*data bits*/
-            0, /*endian*/
-            8, /*instruction length*/
-            0, /*address length*/
-            0, /*wait cycles*/
-            aitm::AS_FRAME_FORMAT,
-            tmod::TRANS,
-        );
-        self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, &[cmd as u32]);
+        self.write_command_raw(cmd as u8);
+    }
+
+    fn area_offset(&self) -> (u16, u16) {
+        (self.x_offset, self.y_offset)
     }
 
     fn write_byte(&self, data_buf: &[u32]) {
@@ -273,18 +388,18 @@ impl<X: SPI> LCDLL for LCD<'_, X> {
 
     fn write_word(&self, data_buf: &[u32]) {
         self.set_dcx_data();
-        self.spi.configure(
-            work_mode::MODE0,
-            frame_format::OCTAL,
-            32, /*data bits*/
-            1,  /*endian*/
-            0,  /*instruction length*/
-            32, /*address length*/
-            0,  /*wait cycles*/
-            aitm::AS_FRAME_FORMAT,
-            tmod::TRANS,
-        );
-        self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, data_buf);
+        self.configure_word();
+        if self.panel.big_endian {
+            let mut buf = [0u32; SWAP_BUF_LEN];
+            for chunk in data_buf.chunks(SWAP_BUF_LEN) {
+                let buf = &mut buf[0..chunk.len()];
+                buf.copy_from_slice(chunk);
+                swap_pixels(buf);
+                self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, buf);
+            }
+        } else {
+            self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, data_buf);
+        }
     }
 
     fn fill_data(&self, data: u32, length: usize) {
@@ -304,22 +419,63 @@ impl<X: SPI> LCDLL for LCD<'_, X> {
     }
 }
 
+/* `LCDHL::fill_rect` on top of the low-level interface */
+fn fill_rect<L: LCDLL>(lcd: &L, x1: u16, y1: u16, width: u16, height: u16, color: u16) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    let data = (u32::from(color) << 16) | u32::from(color);
+
+    lcd.set_area(x1, y1, x1 + width - 1, y1 + height - 1);
+    // For an odd number of pixels, the last write wraps around to the start of the window,
+    // which is harmless as it is the same color.
+    lcd.fill_data(data, (usize::from(width) * usize::from(height) + 1) / 2);
+}
+
+/* `LCDHL::blit_rect` on top of the low-level interface */
+fn blit_rect<L: LCDLL>(lcd: &L, x1: u16, y1: u16, width: u16, height: u16, image: &[u32],
+                       stride: u16) {
+    assert!(x1 % 2 == 0 && width % 2 == 0 && stride % 2 == 0);
+    if width == 0 || height == 0 {
+        return;
+    }
+    let stride = usize::from(stride) / 2;
+    let x1h = usize::from(x1) / 2;
+    let widthh = usize::from(width) / 2;
+    assert!(image.len() >= (usize::from(y1) + usize::from(height)) * stride);
+    lcd.set_area(x1, y1, x1 + width - 1, y1 + height - 1);
+    // The controller keeps writing consecutive pixels in the window until a new command is
+    // sent, so the lines can be sent one by one.
+    for y in usize::from(y1)..usize::from(y1) + usize::from(height) {
+        let ofs = y * stride + x1h;
+        lcd.write_word(&image[ofs..ofs + widthh]);
+    }
+}
+
 /* High-level functions */
 impl<X: SPI> LCDHL for LCD<'_, X> {
     fn init(&mut self) {
         self.hard_init();
-        /*soft reset*/
-        self.write_command(command::SWRESET);
-        usleep(100000);
-        /*exit sleep*/
-        self.write_command(command::SLPOUT);
-        usleep(100000);
-        /*pixel format*/
-        self.write_command(command::RAMCTRL);
-        self.write_byte(&[0x00, 0xf0 | 0x08]); // little-endian
-
-        self.write_command(command::COLMOD);
-        self.write_byte(&[0x55]);
+        /*soft reset, exit sleep, pixel format, ...*/
+        for step in self.panel.init {
+            match step {
+                InitStep::Command(cmd, params) => {
+                    self.write_command_raw(*cmd);
+                    if !params.is_empty() {
+                        assert!(params.len() <= MAX_INIT_PARAMS, "too many parameters");
+                        let mut buf = [0u32; MAX_INIT_PARAMS];
+                        for (d, s) in buf.iter_mut().zip(params.iter()) {
+                            *d = u32::from(*s);
+                        }
+                        self.write_byte(&buf[0..params.len()]);
+                    }
+                }
+                InitStep::Delay(us) => {
+                    usleep(*us);
+                }
+            }
+        }
+        self.set_inversion(self.panel.invert);
         self.set_direction(direction::XY_LRUD);
 
         /*display on*/
@@ -331,23 +487,19 @@ impl<X: SPI> LCDHL for LCD<'_, X> {
     }
 
     fn set_direction(&mut self, dir: direction) {
-        if ((dir as u8) & DIR_XY_MASK) != 0 {
-            self.width = LCD_Y_MAX;
-            self.height = LCD_X_MAX;
-        } else {
-            self.width = LCD_X_MAX;
-            self.height = LCD_Y_MAX;
-        }
+        let (width, height) = self.panel.size(dir);
+        let (x_offset, y_offset) = self.panel.offset(dir);
+        self.width = width;
+        self.height = height;
+        self.x_offset = x_offset;
+        self.y_offset = y_offset;
 
         self.write_command(command::MADCTL);
-        self.write_byte(&[dir as u32]);
+        self.write_byte(&[self.panel.madctl(dir).into()]);
     }
 
     fn clear(&self, color: u16) {
-        let data = (u32::from(color) << 16) | u32::from(color);
-
-        self.set_area(0, 0, self.width - 1, self.height - 1);
-        self.fill_data(data, usize::from(LCD_X_MAX) * usize::from(LCD_Y_MAX) / 2);
+        self.fill_rect(0, 0, self.width, self.height, color);
     }
 
     fn draw_picture(&self, x1: u16, y1: u16, width: u16, height: u16, data: &[u32]) {
@@ -355,4 +507,149 @@ impl<X: SPI> LCDHL for LCD<'_, X> {
         assert!(data.len() == (width as usize) * (height as usize) / 2);
         self.write_word(data);
     }
+
+    fn fill_rect(&self, x1: u16, y1: u16, width: u16, height: u16, color: u16) {
+        fill_rect(self, x1, y1, width, height, color);
+    }
+
+    fn blit_rect(&self, x1: u16, y1: u16, width: u16, height: u16, image: &[u32], stride: u16) {
+        blit_rect(self, x1, y1, width, height, image, stride);
+    }
+
+    fn set_scroll_area(&self, top_fixed: u16, scroll: u16, bottom_fixed: u16) {
+        assert!(u32::from(top_fixed) + u32::from(scroll) + u32::from(bottom_fixed) == u32::from(self.panel.mem_height));
+        self.write_command(command::VSCRDEF);
+        self.write_byte(&[
+            (top_fixed >> 8).into(),
+            (top_fixed & 0xff).into(),
+            (scroll >> 8).into(),
+            (scroll & 0xff).into(),
+            (bottom_fixed >> 8).into(),
+            (bottom_fixed & 0xff).into(),
+        ]);
+    }
+
+    fn set_scroll_start(&self, line: u16) {
+        self.write_command(command::VSCRSADD);
+        self.write_byte(&[(line >> 8).into(), (line & 0xff).into()]);
+    }
+
+    fn set_partial_mode(&self, start: u16, end: u16) {
+        self.write_command(command::PTLAR);
+        self.write_byte(&[
+            (start >> 8).into(),
+            (start & 0xff).into(),
+            (end >> 8).into(),
+            (end & 0xff).into(),
+        ]);
+        self.write_command(command::PTLON);
+    }
+
+    fn set_normal_mode(&self) {
+        self.write_command(command::NORON);
+    }
+
+    fn set_idle_mode(&self, idle: bool) {
+        self.write_command(if idle { command::IDMON } else { command::IDMOFF });
+    }
+
+    fn set_inversion(&self, invert: bool) {
+        self.write_command(if invert { command::INVON } else { command::INVOF });
+    }
+}
+
+/* Asynchronous functions */
+impl<X: SPI> LCDAsync for LCD<'_, X> {
+    fn flush_start(&mut self, image: &'static mut ScreenImage) {
+        assert!(self.flush_image.is_none());
+        self.set_area(0, 0, self.width - 1, self.height - 1);
+        self.set_dcx_data();
+        self.configure_word();
+        self.dmac.completion_irq_enable(self.channel, true);
+        if self.panel.big_endian {
+            // Swapped back when the flush is done
+            swap_pixels(&mut image[..]);
+        }
+        // Safety: the image is kept in self.flush_image until the transfer is done
+        unsafe {
+            self.spi.send_data_dma_start(self.dmac, self.channel, self.spi_cs, &image[..]);
+        }
+        self.flush_image = Some(image);
+    }
+
+    fn flush_busy(&self) -> bool {
+        self.flush_image.is_some()
+    }
+
+    fn flush_poll(&mut self) -> Option<&'static mut ScreenImage> {
+        if self.flush_image.is_some() && self.dmac.is_done(self.channel) {
+            self.spi.send_data_dma_finish();
+            let image = self.flush_image.take().unwrap();
+            if self.panel.big_endian {
+                swap_pixels(&mut image[..]);
+            }
+            Some(image)
+        } else {
+            None
+        }
+    }
+
+    fn flush_wait(&mut self) -> Option<&'static mut ScreenImage> {
+        while self.flush_busy() {
+            if let Some(image) = self.flush_poll() {
+                return Some(image);
+            }
+        }
+        None
+    }
+
+    unsafe fn write_start(&mut self, data: &mut [u32]) {
+        assert!(self.flush_image.is_none() && !self.write_busy);
+        if self.panel.big_endian {
+            swap_pixels(data);
+        }
+        self.set_dcx_data();
+        self.configure_word();
+        self.spi.send_data_dma_start(self.dmac, self.channel, self.spi_cs, data);
+        self.write_busy = true;
+    }
+
+    fn write_wait(&mut self) {
+        if self.write_busy {
+            while !self.dmac.is_done(self.channel) {}
+            self.spi.send_data_dma_finish();
+            self.write_busy = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::lcd_mock::{MockLCD, H, W};
+
+    #[test]
+    fn test_rects() {
+        let lcd = MockLCD::new();
+        fill_rect(&lcd, 1, 2, 3, 2, 0x1234);
+        // empty rectangles draw nothing, even at the origin
+        fill_rect(&lcd, 0, 0, 0, 5, 0xffff);
+        fill_rect(&lcd, 0, 0, 5, 0, 0xffff);
+        let image = [0x5678_5678u32; W * H / 2];
+        blit_rect(&lcd, 0, 0, 0, 3, &image, W as u16);
+        blit_rect(&lcd, 0, 0, 4, 0, &image, W as u16);
+        blit_rect(&lcd, 8, 6, 4, 2, &image, W as u16);
+        for y in 0..H {
+            for x in 0..W {
+                let expected = if (1..4).contains(&x) && (2..4).contains(&y) {
+                    0x1234
+                } else if (8..12).contains(&x) && y >= 6 {
+                    0x5678
+                } else {
+                    0
+                };
+                assert_eq!(lcd.pixel(x, y), expected, "pixel {},{}", x, y);
+            }
+        }
+    }
 }
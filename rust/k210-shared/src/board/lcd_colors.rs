@@ -27,6 +27,28 @@ pub const fn rgb565(r: u8, g: u8, b: u8) -> u16 {
     (((r as u16) >> 3) << 11) | (((g as u16) >> 2) << 5) | ((b as u16) >> 3)
 }
 
+/** 4×4 ordered dithering (Bayer) threshold matrix, values 0..16 */
+const BAYER4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/** Convert 8 bit RGB to RGB565 with ordered dithering. `x` and `y` are the screen position of
+ * the pixel, which determines the threshold. Areas of a color between two RGB565 levels come
+ * out as a pattern of those levels, with on average the right intensity.
+ */
+pub fn rgb565_dither(r: u8, g: u8, b: u8, x: u16, y: u16) -> u16 {
+    let t = BAYER4[usize::from(y & 3)][usize::from(x & 3)];
+    // The step between levels is 8 for the 5 bit components and 4 for the 6 bit component
+    rgb565(
+        r.saturating_add(t / 2),
+        g.saturating_add(t / 4),
+        b.saturating_add(t / 2),
+    )
+}
+
 /** 32.0 minus 1ulp */
 const ALMOST_32: f32 = 31.999998f32;
 /** 64.0 minus 1ulp */
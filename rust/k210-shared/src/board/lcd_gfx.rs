@@ -0,0 +1,280 @@
+//! embedded-graphics draw targets for the LCD
+use core::convert::Infallible;
+use embedded_graphics_core::draw_target::DrawTarget;
+use embedded_graphics_core::geometry::{Dimensions, OriginDimensions, Size};
+use embedded_graphics_core::pixelcolor::{IntoStorage, Rgb565};
+use embedded_graphics_core::primitives::{PointsIter, Rectangle};
+use embedded_graphics_core::Pixel;
+
+use crate::board::def::{DISP_HEIGHT, DISP_WIDTH};
+use crate::board::lcd::{LCDHL, LCDLL};
+use crate::board::lcd_render::{AsU16, ScreenImage};
+
+/** Number of u32 words to collect before sending them to the display in direct mode */
+const DIRECT_BUF_LEN: usize = 64;
+
+/** Pack a color twice into a u32 word, for filling. */
+fn pack2(color: u16) -> u32 {
+    (u32::from(color) << 16) | u32::from(color)
+}
+
+/** Clip an area to a bounding box, returning the inclusive corner coordinates or None if the
+ * area is empty. */
+fn clip(area: &Rectangle, bounds: &Rectangle) -> Option<(u16, u16, u16, u16)> {
+    let area = area.intersection(bounds);
+    area.bottom_right().map(|br| {
+        (area.top_left.x as u16, area.top_left.y as u16, br.x as u16, br.y as u16)
+    })
+}
+
+/** Draw target that renders into a full-screen image in memory. The image can then be sent
+ * to the display in one go with `flush`, or through a `DoubleBuffer`.
+ */
+pub struct FrameBuffer<'a> {
+    image: &'a mut ScreenImage,
+}
+
+impl<'a> FrameBuffer<'a> {
+    pub fn new(image: &'a mut ScreenImage) -> Self {
+        Self { image }
+    }
+
+    /** Send the entire image to the display. */
+    pub fn flush<L: LCDHL>(&self, lcd: &L) {
+        lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, self.image);
+    }
+}
+
+impl OriginDimensions for FrameBuffer<'_> {
+    fn size(&self) -> Size {
+        Size::new(DISP_WIDTH.into(), DISP_HEIGHT.into())
+    }
+}
+
+impl DrawTarget for FrameBuffer<'_> {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let data = self.image.as_u16_slice_mut();
+        for Pixel(coord, color) in pixels {
+            let (x, y) = (coord.x as usize, coord.y as usize);
+            if x < usize::from(DISP_WIDTH) && y < usize::from(DISP_HEIGHT) {
+                data[y * usize::from(DISP_WIDTH) + x] = color.into_storage();
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        if let Some((x1, y1, x2, y2)) = clip(area, &self.bounding_box()) {
+            let data = self.image.as_u16_slice_mut();
+            let color = color.into_storage();
+            for y in usize::from(y1)..=usize::from(y2) {
+                let ofs = y * usize::from(DISP_WIDTH);
+                for p in &mut data[ofs + usize::from(x1)..=ofs + usize::from(x2)] {
+                    *p = color;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/** Draw target that draws directly to the display. Areas (filled shapes, images, text with a
+ * background) are sent through an address window, which is fast. Drawing individual pixels
+ * needs a window per pixel, which is slow.
+ */
+pub struct Direct<'a, L> {
+    lcd: &'a L,
+    size: Size,
+}
+
+impl<'a, L: LCDLL> Direct<'a, L> {
+    /** Create a direct draw target for a display with the current (after rotation) width and
+     * height. */
+    pub fn new(lcd: &'a L, width: u16, height: u16) -> Self {
+        Self {
+            lcd,
+            size: Size::new(width.into(), height.into()),
+        }
+    }
+}
+
+impl<L> OriginDimensions for Direct<'_, L> {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl<L: LCDLL> DrawTarget for Direct<'_, L> {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let bounds = self.bounding_box();
+        for Pixel(coord, color) in pixels {
+            if bounds.contains(coord) {
+                let (x, y) = (coord.x as u16, coord.y as u16);
+                self.lcd.set_area(x, y, x, y);
+                // One word holds two pixels, the second wraps around to the same position
+                self.lcd.write_word(&[pack2(color.into_storage())]);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let (x1, y1, x2, y2) = if let Some(window) = clip(area, &self.bounding_box()) {
+            window
+        } else {
+            return Ok(());
+        };
+        let window = area.intersection(&self.bounding_box());
+        self.lcd.set_area(x1, y1, x2, y2);
+
+        // Pixels outside the window are skipped, the others arrive in the same order as the
+        // display expects them.
+        let mut buf = [0u32; DIRECT_BUF_LEN];
+        let mut n = 0;
+        let mut first: Option<u16> = None;
+        for (point, color) in area.points().zip(colors) {
+            if !window.contains(point) {
+                continue;
+            }
+            let color = color.into_storage();
+            if first.is_none() {
+                first = Some(color);
+            }
+            if n % 2 == 0 {
+                buf[n / 2] = u32::from(color);
+            } else {
+                buf[n / 2] |= u32::from(color) << 16;
+            }
+            n += 1;
+            if n == DIRECT_BUF_LEN * 2 {
+                self.lcd.write_word(&buf);
+                n = 0;
+            }
+        }
+        if n % 2 == 1 {
+            // An odd number of pixels: the extra pixel wraps around to the start of the window,
+            // so repeat the first pixel.
+            buf[n / 2] |= u32::from(first.unwrap()) << 16;
+            n += 1;
+        }
+        if n > 0 {
+            self.lcd.write_word(&buf[0..n / 2]);
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        if let Some((x1, y1, x2, y2)) = clip(area, &self.bounding_box()) {
+            let count = usize::from(x2 - x1 + 1) * usize::from(y2 - y1 + 1);
+            self.lcd.set_area(x1, y1, x2, y2);
+            self.lcd.fill_data(pack2(color.into_storage()), (count + 1) / 2);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::def::DISP_PIXELS;
+    use crate::board::lcd_mock::{MockLCD, H, W};
+    use embedded_graphics_core::geometry::Point;
+    use embedded_graphics_core::pixelcolor::raw::RawU16;
+    use embedded_graphics_core::pixelcolor::RgbColor;
+
+    fn rect(x: i32, y: i32, w: u32, h: u32) -> Rectangle {
+        Rectangle::new(Point::new(x, y), Size::new(w, h))
+    }
+
+    #[test]
+    fn test_framebuffer() {
+        let mut image: ScreenImage = [0; DISP_PIXELS / 2];
+        let mut fb = FrameBuffer::new(&mut image);
+        fb.fill_solid(&rect(-2, -2, 4, 3), Rgb565::RED).unwrap();
+        fb.draw_iter([
+            Pixel(Point::new(5, 0), Rgb565::GREEN),
+            Pixel(Point::new(-1, 0), Rgb565::GREEN),
+            Pixel(Point::new(i32::from(DISP_WIDTH), 0), Rgb565::GREEN),
+        ].iter().cloned()).unwrap();
+        fb.fill_solid(&rect(318, 238, 10, 10), Rgb565::BLUE).unwrap();
+
+        let data = image.as_u16_slice();
+        let red = Rgb565::RED.into_storage();
+        assert_eq!(&data[0..3], &[red, red, 0]);
+        assert_eq!(data[5], Rgb565::GREEN.into_storage());
+        assert_eq!(data[usize::from(DISP_WIDTH)], 0);
+        assert_eq!(data[DISP_PIXELS - 1], Rgb565::BLUE.into_storage());
+        assert_eq!(data[DISP_PIXELS - 3], 0);
+    }
+
+    #[test]
+    fn test_direct_fill_solid() {
+        let lcd = MockLCD::new();
+        let mut target = Direct::new(&lcd, W as u16, H as u16);
+        target.fill_solid(&rect(1, 1, 3, 3), Rgb565::RED).unwrap();
+        target.fill_solid(&rect(14, 6, 5, 5), Rgb565::BLUE).unwrap();
+        let red = Rgb565::RED.into_storage();
+        let blue = Rgb565::BLUE.into_storage();
+        for y in 0..H {
+            for x in 0..W {
+                let expected = if (1..=3).contains(&x) && (1..=3).contains(&y) {
+                    red
+                } else if x >= 14 && y >= 6 {
+                    blue
+                } else {
+                    0
+                };
+                assert_eq!(lcd.pixel(x, y), expected, "pixel {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_direct_fill_contiguous() {
+        let lcd = MockLCD::new();
+        let mut target = Direct::new(&lcd, W as u16, H as u16);
+        // Odd number of pixels, partially outside the display
+        let area = rect(-1, 5, 3, 5);
+        let colors = (0..15u16).map(|i| Rgb565::from(RawU16::new(i + 1)));
+        target.fill_contiguous(&area, colors).unwrap();
+        for y in 0..H {
+            for x in 0..W {
+                let expected = if x <= 1 && y >= 5 {
+                    // area column x+1, row y-5
+                    ((y - 5) * 3 + (x + 1) + 1) as u16
+                } else {
+                    0
+                };
+                assert_eq!(lcd.pixel(x, y), expected, "pixel {},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_direct_draw_iter() {
+        let lcd = MockLCD::new();
+        let mut target = Direct::new(&lcd, W as u16, H as u16);
+        target.draw_iter([
+            Pixel(Point::new(3, 2), Rgb565::GREEN),
+            Pixel(Point::new(16, 2), Rgb565::GREEN),
+        ].iter().cloned()).unwrap();
+        assert_eq!(lcd.pixel(3, 2), Rgb565::GREEN.into_storage());
+        assert_eq!(lcd.pixel(4, 2), 0);
+        assert_eq!(lcd.pixel(0, 3), 0);
+    }
+}
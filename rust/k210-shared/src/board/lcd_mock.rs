@@ -0,0 +1,103 @@
+//! Mock LCD for host tests
+use core::cell::RefCell;
+
+use crate::board::lcd::{command, LCDLL};
+
+/** Size of the mock display */
+pub const W: usize = 16;
+pub const H: usize = 8;
+
+struct MockState {
+    pixels: [u16; W * H],
+    cmd: u8,
+    params: [u8; 4],
+    nparams: usize,
+    window: (usize, usize, usize, usize),
+    pos: (usize, usize),
+}
+
+/** Emulates the address window and memory write behavior of the display controller. */
+pub struct MockLCD {
+    state: RefCell<MockState>,
+}
+
+impl MockLCD {
+    pub fn new() -> Self {
+        Self {
+            state: RefCell::new(MockState {
+                pixels: [0; W * H],
+                cmd: command::NOP as u8,
+                params: [0; 4],
+                nparams: 0,
+                window: (0, 0, W - 1, H - 1),
+                pos: (0, 0),
+            }),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.state.borrow().pixels[y * W + x]
+    }
+}
+
+impl MockState {
+    fn put(&mut self, color: u16) {
+        assert!(self.cmd == command::RAMWR as u8);
+        let (x, y) = self.pos;
+        self.pixels[y * W + x] = color;
+        let (x1, y1, x2, y2) = self.window;
+        self.pos = if x < x2 {
+            (x + 1, y)
+        } else if y < y2 {
+            (x1, y + 1)
+        } else {
+            (x1, y1)
+        };
+    }
+}
+
+impl LCDLL for MockLCD {
+    fn hard_init(&self) {}
+
+    fn write_command(&self, cmd: command) {
+        let mut state = self.state.borrow_mut();
+        state.cmd = cmd as u8;
+        state.nparams = 0;
+        if state.cmd == command::RAMWR as u8 {
+            state.pos = (state.window.0, state.window.1);
+        }
+    }
+
+    fn write_byte(&self, data_buf: &[u32]) {
+        let mut state = self.state.borrow_mut();
+        for &b in data_buf {
+            let n = state.nparams;
+            state.params[n] = b as u8;
+            state.nparams += 1;
+        }
+        let p = state.params;
+        let start = (usize::from(p[0]) << 8) | usize::from(p[1]);
+        let end = (usize::from(p[2]) << 8) | usize::from(p[3]);
+        if state.cmd == command::CASET as u8 && state.nparams == 4 {
+            state.window.0 = start;
+            state.window.2 = end;
+        } else if state.cmd == command::RASET as u8 && state.nparams == 4 {
+            state.window.1 = start;
+            state.window.3 = end;
+        }
+    }
+
+    fn write_word(&self, data_buf: &[u32]) {
+        let mut state = self.state.borrow_mut();
+        for &w in data_buf {
+            state.put(w as u16);
+            state.put((w >> 16) as u16);
+        }
+    }
+
+    fn fill_data(&self, data: u32, length: usize) {
+        for _ in 0..length {
+            self.write_word(&[data]);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Descriptions of the LCD panels found on K210 boards
+use crate::board::lcd::direction;
+
+/** Order of the color components in the panel */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum color_order {
+    RGB,
+    BGR,
+}
+
+/** Largest number of parameter bytes of an initialization command */
+pub const MAX_INIT_PARAMS: usize = 16;
+
+/** Step in a panel initialization sequence */
+pub enum InitStep {
+    /** Send a command with up to `MAX_INIT_PARAMS` parameter bytes */
+    Command(u8, &'static [u8]),
+    /** Wait for a number of microseconds */
+    Delay(usize),
+}
+
+/** Panel description: controller initialization and geometry. All sizes and offsets are in the
+ * native (portrait, `direction::XY_*`) orientation of the controller.
+ */
+pub struct Panel {
+    /** Name of the panel controller */
+    pub name: &'static str,
+    /** Initialization sequence, sent after hardware reset. The display is turned on
+     * afterwards, after setting the direction. */
+    pub init: &'static [InitStep],
+    /** Visible width in pixels */
+    pub width: u16,
+    /** Visible height in pixels */
+    pub height: u16,
+    /** Width of controller memory in pixels */
+    pub mem_width: u16,
+    /** Height of controller memory in pixels */
+    pub mem_height: u16,
+    /** Column of the first visible pixel in controller memory */
+    pub x_offset: u16,
+    /** Row of the first visible pixel in controller memory */
+    pub y_offset: u16,
+    /** Color order of the panel, applied through MADCTL */
+    pub color_order: color_order,
+    /** Panel needs inverted colors (common for IPS panels) */
+    pub invert: bool,
+    /** Controller only accepts big-endian pixel data. The SPI controller can not produce the
+     * right byte order for two pixels in a word in this case, so the pixels are swapped in
+     * software before sending. */
+    pub big_endian: bool,
+}
+
+/** MADCTL bits */
+const MADCTL_MY: u8 = 0x80;
+const MADCTL_MX: u8 = 0x40;
+const MADCTL_MV: u8 = 0x20;
+const MADCTL_BGR: u8 = 0x08;
+
+impl Panel {
+    /** Visible width and height in the given direction. */
+    pub fn size(&self, dir: direction) -> (u16, u16) {
+        if (dir as u8) & MADCTL_MV != 0 {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    /** Offset of the visible area in the given direction. Mirroring an axis moves the
+     * visible area to the other end of controller memory. */
+    pub fn offset(&self, dir: direction) -> (u16, u16) {
+        let dir = dir as u8;
+        let col = if dir & MADCTL_MX != 0 {
+            self.mem_width - self.width - self.x_offset
+        } else {
+            self.x_offset
+        };
+        let row = if dir & MADCTL_MY != 0 {
+            self.mem_height - self.height - self.y_offset
+        } else {
+            self.y_offset
+        };
+        if dir & MADCTL_MV != 0 {
+            (row, col)
+        } else {
+            (col, row)
+        }
+    }
+
+    /** MADCTL value for the given direction. */
+    pub fn madctl(&self, dir: direction) -> u8 {
+        match self.color_order {
+            color_order::RGB => dir as u8,
+            color_order::BGR => (dir as u8) | MADCTL_BGR,
+        }
+    }
+}
+
+/** Sitronix ST7789V, as on the Maix Go */
+pub static ST7789: Panel = Panel {
+    name: "ST7789",
+    init: &[
+        InitStep::Command(0x01, &[]), // SWRESET
+        InitStep::Delay(100_000),
+        InitStep::Command(0x11, &[]), // SLPOUT
+        InitStep::Delay(100_000),
+        InitStep::Command(0xb0, &[0x00, 0xf0 | 0x08]), // RAMCTRL: little-endian
+        InitStep::Command(0x3a, &[0x55]), // COLMOD: 16 bit/pixel
+    ],
+    width: 240,
+    height: 320,
+    mem_width: 240,
+    mem_height: 320,
+    x_offset: 0,
+    y_offset: 0,
+    color_order: color_order::RGB,
+    invert: false,
+    big_endian: false,
+};
+
+/** Novatek NT35310, as on the Maix Bit and Dock (see `src/glyph_mapping/nt35310.c`) */
+pub static NT35310: Panel = Panel {
+    name: "NT35310",
+    init: &[
+        InitStep::Command(0x01, &[]), // SWRESET
+        InitStep::Delay(100_000),
+        InitStep::Command(0x11, &[]), // SLPOUT
+        InitStep::Delay(100_000),
+        InitStep::Command(0x3a, &[0x55]), // COLMOD: 16 bit/pixel
+    ],
+    width: 240,
+    height: 320,
+    mem_width: 240,
+    mem_height: 320,
+    x_offset: 0,
+    y_offset: 0,
+    color_order: color_order::BGR,
+    invert: false,
+    big_endian: true,
+};
+
+/** Ilitek ILI9341 */
+pub static ILI9341: Panel = Panel {
+    name: "ILI9341",
+    init: &[
+        InitStep::Command(0x01, &[]), // SWRESET
+        InitStep::Delay(120_000),
+        InitStep::Command(0xcf, &[0x00, 0xc1, 0x30]), // Power control B
+        InitStep::Command(0xed, &[0x64, 0x03, 0x12, 0x81]), // Power on sequence control
+        InitStep::Command(0xe8, &[0x85, 0x00, 0x78]), // Driver timing control A
+        InitStep::Command(0xcb, &[0x39, 0x2c, 0x00, 0x34, 0x02]), // Power control A
+        InitStep::Command(0xf7, &[0x20]), // Pump ratio control
+        InitStep::Command(0xea, &[0x00, 0x00]), // Driver timing control B
+        InitStep::Command(0xc0, &[0x23]), // Power control 1
+        InitStep::Command(0xc1, &[0x10]), // Power control 2
+        InitStep::Command(0xc5, &[0x3e, 0x28]), // VCOM control 1
+        InitStep::Command(0xc7, &[0x86]), // VCOM control 2
+        InitStep::Command(0x3a, &[0x55]), // COLMOD: 16 bit/pixel
+        InitStep::Command(0xb1, &[0x00, 0x18]), // Frame rate control
+        InitStep::Command(0xb6, &[0x08, 0x82, 0x27]), // Display function control
+        InitStep::Command(0xf2, &[0x00]), // Disable 3-gamma
+        InitStep::Command(0x26, &[0x01]), // Gamma curve 1
+        InitStep::Command(0xe0, &[
+            0x0f, 0x31, 0x2b, 0x0c, 0x0e, 0x08, 0x4e, 0xf1, 0x37, 0x07, 0x10, 0x03, 0x0e, 0x09, 0x00,
+        ]), // Positive gamma correction
+        InitStep::Command(0xe1, &[
+            0x00, 0x0e, 0x14, 0x03, 0x11, 0x07, 0x31, 0xc1, 0x48, 0x08, 0x0f, 0x0c, 0x31, 0x36, 0x0f,
+        ]), // Negative gamma correction
+        InitStep::Command(0x11, &[]), // SLPOUT
+        InitStep::Delay(120_000),
+    ],
+    width: 240,
+    height: 320,
+    mem_width: 240,
+    mem_height: 320,
+    x_offset: 0,
+    y_offset: 0,
+    color_order: color_order::BGR,
+    invert: false,
+    big_endian: true,
+};
+
+/** All known panels, for selecting one at runtime (e.g. by name from a configuration). */
+pub static PANELS: [&Panel; 3] = [&ST7789, &NT35310, &ILI9341];
+
+/** Look up a panel by name. */
+pub fn by_name(name: &str) -> Option<&'static Panel> {
+    PANELS.iter().cloned().find(|p| p.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_init_params() {
+        for panel in PANELS.iter() {
+            for step in panel.init {
+                if let InitStep::Command(cmd, params) = step {
+                    assert!(params.len() <= MAX_INIT_PARAMS,
+                            "{}: command {:#04x}", panel.name, cmd);
+                }
+            }
+        }
+    }
+}
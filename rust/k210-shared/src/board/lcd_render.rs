@@ -1,7 +1,9 @@
 //! Efficient(?) full-image rendering.
 // TODO: switch this over to embedded-graphics probably
+use core::cmp::min;
+
 use crate::board::def::{DISP_HEIGHT, DISP_WIDTH, DISP_PIXELS};
-use crate::board::lcd::LCDHL;
+use crate::board::lcd::{LCDAsync, LCDHL, LCDLL};
 
 /** Array for representing an image of the entire screen.
  * This is an array of DISP_WIDTH / 2 × DISP_HEIGHT, each two horizontally consecutive
@@ -9,6 +11,12 @@ use crate::board::lcd::LCDHL;
  */
 pub type ScreenImage = [u32; DISP_PIXELS / 2];
 
+/** Default number of lines in a strip for `render_strips` */
+pub const STRIP_LINES: usize = 16;
+
+/** Buffer for one strip of `STRIP_LINES` full-width lines, encoded like `ScreenImage`. */
+pub type StripBuffer = [u32; (DISP_WIDTH as usize) / 2 * STRIP_LINES];
+
 pub trait AsU8 {
     fn as_u8_slice(&self) -> &[u8];
     fn as_u8_slice_mut(&mut self) -> &mut [u8];
@@ -57,6 +65,110 @@ where
     // computation has to keep up with the SPI clock speed or there will be
     // glitches -- also it means that DMA cannot be used -- whereas a sufficiently
     // advanced DMA engine is indistinguishable from a GPU, the one in K210
-    // isn't that.
+    // isn't that. `render_strips` is a middle ground: it computes a strip of lines ahead
+    // while the previous one is sent by DMA.
     lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &idata);
 }
+
+/** Render lines `y0..y0+lines` into a strip buffer. */
+fn render_strip<I>(buf: &mut [u32], y0: u16, lines: u16, image: &mut I)
+where
+    I: FnMut(u16, u16) -> u16,
+{
+    let yx = (y0..y0 + lines)
+        .flat_map(|y| core::iter::repeat(y).zip(0..DISP_WIDTH / 2));
+    buf.iter_mut().zip(yx).for_each(|(v, (y, x))| {
+        *v = (u32::from(image(x * 2 + 0, y)) << 0) | (u32::from(image(x * 2 + 1, y)) << 16);
+    });
+}
+
+/** Render a full-screen image through a pair of strip buffers, without needing memory for the
+ * entire screen. A strip of lines is computed into one buffer while the other one is being sent
+ * to the display by DMA. The lines per strip follow from the buffer sizes, which must be equal
+ * and a multiple of the line length (`DISP_WIDTH / 2` words).
+ */
+pub fn render_strips_with<L, I>(lcd: &mut L, bufs: [&mut [u32]; 2], mut image: I)
+where
+    L: LCDLL + LCDAsync,
+    I: FnMut(u16, u16) -> u16,
+{
+    let line_words = usize::from(DISP_WIDTH) / 2;
+    assert!(bufs[0].len() == bufs[1].len() && bufs[0].len() % line_words == 0);
+    let strip_lines = (bufs[0].len() / line_words) as u16;
+    assert!(strip_lines > 0);
+
+    let mut cur = 0;
+    lcd.set_area(0, 0, DISP_WIDTH - 1, DISP_HEIGHT - 1);
+    let mut y0 = 0;
+    while y0 < DISP_HEIGHT {
+        let lines = min(strip_lines, DISP_HEIGHT - y0);
+        let len = usize::from(lines) * line_words;
+        // This buffer is not in use: the write that was sent from it has been waited for
+        // before starting the write from the other buffer.
+        render_strip(&mut bufs[cur][0..len], y0, lines, &mut image);
+        lcd.write_wait();
+        // Safety: the buffer is not touched until the write has been waited for, either in the
+        // next iteration (after rendering into the other buffer) or at the end.
+        unsafe {
+            lcd.write_start(&mut bufs[cur][0..len]);
+        }
+        cur = 1 - cur;
+        y0 += lines;
+    }
+    lcd.write_wait();
+}
+
+/** Render a full-screen image in strips of `STRIP_LINES` lines. This has the same interface as
+ * `render_image` but uses two small buffers instead of a `ScreenImage`, and overlaps
+ * computation with sending to the display.
+ */
+pub fn render_strips<L, I>(lcd: &mut L, image: I)
+where
+    L: LCDLL + LCDAsync,
+    I: FnMut(u16, u16) -> u16,
+{
+    let mut buf_a: StripBuffer = [0; (DISP_WIDTH as usize) / 2 * STRIP_LINES];
+    let mut buf_b: StripBuffer = [0; (DISP_WIDTH as usize) / 2 * STRIP_LINES];
+    render_strips_with(lcd, [&mut buf_a[..], &mut buf_b[..]], image);
+}
+
+/** Double-buffered rendering: one image is rendered into while the other one is being sent to
+ * the display in the background. Ownership of the images moves between the renderer and the
+ * display driver, so it is not possible to touch an image while it is being transferred.
+ */
+pub struct DoubleBuffer {
+    /** Image available for rendering */
+    back: Option<&'static mut ScreenImage>,
+    /** Unused image (only before the first `present`) */
+    spare: Option<&'static mut ScreenImage>,
+}
+
+impl DoubleBuffer {
+    pub fn new(a: &'static mut ScreenImage, b: &'static mut ScreenImage) -> Self {
+        Self {
+            back: Some(a),
+            spare: Some(b),
+        }
+    }
+
+    /** Image to render the next frame into. */
+    pub fn back(&mut self) -> &mut ScreenImage {
+        self.back.as_mut().unwrap()
+    }
+
+    /** Start sending the back image to the display. If the previous frame is still being
+     * transferred, wait for it to finish first; its image becomes the new back image.
+     * Note that the new back image contains the frame before the one just presented, not
+     * an empty image. */
+    pub fn present<L>(&mut self, lcd: &mut L)
+    where
+        L: LCDAsync,
+    {
+        let image = self.back.take().unwrap();
+        if let Some(prev) = lcd.flush_wait() {
+            self.spare = Some(prev);
+        }
+        lcd.flush_start(image);
+        self.back = self.spare.take();
+    }
+}
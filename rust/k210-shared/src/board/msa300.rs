@@ -1,8 +1,10 @@
 //! Support for MSA300 accelerometer
 /* MSA300 code based on 'accelerometer' demo by j.m.voogd@gmail.com */
+use crate::board::def::MSA300_SLV_ADDR;
 use crate::soc::i2c::I2C;
 
 /** MSA300 Registers */
+#[derive(Copy, Clone)]
 enum reg {
     /** Part ID (R) */
     PARTID = 0x01,
@@ -41,6 +43,10 @@ const DATARATE_1000_HZ: u8 = 0x0F;
 /** Gravity constant (Earth surface) */
 const GRAVITY: f32 = 9.80665;
 
+/** First register of a burst read of all three axes, and its length, for `convert` */
+pub const SAMPLE_REGISTER: u8 = reg::ACC_X_LSB as u8;
+pub const SAMPLE_LEN: usize = 6;
+
 pub struct Accelerometer<IF> {
     i2c: IF,
 }
@@ -48,7 +54,7 @@ pub struct Accelerometer<IF> {
 /** Read a register of the MSA300 via I2C */
 fn read_register<IF: I2C>(i2c: &IF, reg: reg) -> Result<u8, ()> {
     let mut reg_val = [0u8; 2];
-    if i2c.recv_data(&[reg as u8], &mut reg_val).is_ok() {
+    if i2c.transfer(MSA300_SLV_ADDR, &[reg as u8], &mut reg_val).is_ok() {
         Ok(reg_val[0])
     } else {
         Err(())
@@ -57,7 +63,7 @@ fn read_register<IF: I2C>(i2c: &IF, reg: reg) -> Result<u8, ()> {
 
 /** Set a register of the MSA300 via I2C */
 fn set_register<IF: I2C>(i2c: &IF, reg: reg, val: u8) -> Result<(), ()> {
-    i2c.send_data(&[reg as u8, val])
+    i2c.transfer(MSA300_SLV_ADDR, &[reg as u8, val], &mut []).map_err(|_| ())
 }
 
 impl<IF: I2C> Accelerometer<IF> {
@@ -104,19 +110,24 @@ impl<IF: I2C> Accelerometer<IF> {
 
     /** Return measurement in m/s^2 for x, y, z */
     pub fn measure(&self) -> Result<(f32, f32, f32), ()> {
-        // for x, y, and z: read the LSB (6 bits + 2 zero bits) and the MSB (8 bits)
-        // shift the MSB left 8 bits, add the LSB, and multiply this with a calibration constant
-        let tx = (read_register(&self.i2c, reg::ACC_X_LSB)? as i32) +
-                 ((read_register(&self.i2c, reg::ACC_X_MSB)? as i8) as i32)*256;
-        let x = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * (tx as f32);
-        let ty = (read_register(&self.i2c, reg::ACC_Y_LSB)? as i32) +
-                 ((read_register(&self.i2c, reg::ACC_Y_MSB)? as i8) as i32)*256;
-        let y = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * (ty as f32);
-        // looks like Z has a large bias -  don't know if this is general or just on my board
-        let tz = (read_register(&self.i2c, reg::ACC_Z_LSB)? as i32) +
-                 ((read_register(&self.i2c, reg::ACC_Z_MSB)? as i8) as i32)*256 + 3386;
-        let z = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * (tz as f32);
-
-        Ok((x, y, z))
+        let mut sample = [0u8; SAMPLE_LEN];
+        for (i, reg) in [reg::ACC_X_LSB, reg::ACC_X_MSB, reg::ACC_Y_LSB,
+                         reg::ACC_Y_MSB, reg::ACC_Z_LSB, reg::ACC_Z_MSB].iter().enumerate() {
+            sample[i] = read_register(&self.i2c, *reg)?;
+        }
+        Ok(convert(&sample))
     }
 }
+
+/** Convert the acceleration registers, `SAMPLE_LEN` bytes from `SAMPLE_REGISTER` on, to
+ * m/s^2 for x, y, z */
+pub fn convert(sample: &[u8]) -> (f32, f32, f32) {
+    // for x, y, and z: read the LSB (6 bits + 2 zero bits) and the MSB (8 bits)
+    // shift the MSB left 8 bits, add the LSB, and multiply this with a calibration constant
+    let axis = |i: usize| (sample[i] as i32) + ((sample[i + 1] as i8) as i32)*256;
+    let x = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * (axis(0) as f32);
+    let y = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * (axis(2) as f32);
+    // looks like Z has a large bias -  don't know if this is general or just on my board
+    let z = 0.25f32 * MG2G_MULTIPLIER_4_G * GRAVITY * ((axis(4) + 3386) as f32);
+    (x, y, z)
+}
@@ -1,6 +1,7 @@
 //! NS2009 touch screen handling
 use core::result::Result;
 
+use crate::board::def::NS2009_SLV_ADDR;
 use crate::soc::i2c::I2C;
 use crate::util::filters;
 
@@ -28,7 +29,7 @@ pub enum command {
 pub fn read<IF: I2C>(i2c: &IF, cmd: command) -> Result<u16, ()>
 {
     let mut buf = [0u8; 2];
-    if i2c.recv_data(&[cmd as u8], &mut buf).is_ok() {
+    if i2c.transfer(NS2009_SLV_ADDR, &[cmd as u8], &mut buf).is_ok() {
         Ok((u16::from(buf[0]) << 4) | (u16::from(buf[1]) >> 4))
     } else {
         Err(())
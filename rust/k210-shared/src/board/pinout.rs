@@ -0,0 +1,228 @@
+//! Pad assignments of the Sipeed Maix boards
+//!
+//! A `Pinout` describes which pads the peripherals of a board are connected to. Routing the
+//! LCD or SD card pads through the program's `PinMux` gives a token, which the driver requires
+//! as proof that its pins are set up; a dry-run mux can only check the assignments. Boards with
+//! other wiring can describe their own pads.
+use core::marker::PhantomData;
+use k210_hal::pac;
+
+use crate::board::def::io;
+use crate::board::lcd;
+use crate::soc::fpioa::mux::{MuxError, PinMux};
+use crate::soc::fpioa::{function, pull};
+use crate::soc::sysctl;
+
+/** Pads of the LCD, which is driven by SPI0 with data lines on the DVP pins */
+#[derive(Copy, Clone, Debug)]
+pub struct LcdPads {
+    /** Chip select (SPI0_SS3) */
+    pub cs: u8,
+    /** Write clock (SPI0_SCLK) */
+    pub wr: u8,
+    /** Data/command (GPIOHS `lcd::DCX_GPIONUM`) */
+    pub dc: u8,
+    /** Reset (GPIOHS `lcd::RST_GPIONUM`) */
+    pub rst: u8,
+}
+
+/** Pads of an SD card slot, used in SPI mode */
+#[derive(Copy, Clone, Debug)]
+pub struct SdCardPads {
+    pub sclk: u8,
+    /** Data to the card */
+    pub mosi: u8,
+    /** Data from the card */
+    pub miso: u8,
+    /** Chip select, driven as GPIOHS pin */
+    pub cs: u8,
+}
+
+/** Pads of an I2C bus */
+#[derive(Copy, Clone, Debug)]
+pub struct I2CPads {
+    pub scl: u8,
+    pub sda: u8,
+}
+
+/** Pads of the peripherals on a board */
+#[derive(Copy, Clone, Debug)]
+pub struct Pinout {
+    pub name: &'static str,
+    pub lcd: LcdPads,
+    pub sdcard: SdCardPads,
+    /** Bus of the on-board sensors */
+    pub i2c: Option<I2CPads>,
+    /** RGB LED: red, green, blue */
+    pub led: (u8, u8, u8),
+    /** BOOT key, low when pressed */
+    pub boot_key: u8,
+}
+
+/** Sipeed Maix Go */
+pub const MAIX_GO: Pinout = Pinout {
+    name: "Maix Go",
+    lcd: LcdPads {
+        cs: io::LCD_CS as u8,
+        wr: io::LCD_WR as u8,
+        dc: io::LCD_DC as u8,
+        rst: io::LCD_RST as u8,
+    },
+    sdcard: SdCardPads {
+        sclk: io::SPI0_SCLK as u8,
+        mosi: io::SPI0_MOSI as u8,
+        miso: io::SPI0_MISO as u8,
+        cs: io::SPI0_CS0 as u8,
+    },
+    i2c: Some(I2CPads { scl: io::I2C1_SCL as u8, sda: io::I2C1_SDA as u8 }),
+    led: (io::LED_R as u8, io::LED_G as u8, io::LED_B as u8),
+    boot_key: io::BOOT_KEY0 as u8,
+};
+
+/** Sipeed Maix Bit: same pads as the Go, without on-board sensors */
+pub const MAIX_BIT: Pinout = Pinout {
+    name: "Maix Bit",
+    i2c: None,
+    ..MAIX_GO
+};
+
+/** Sipeed Maix Dock: same pads as the Go, without on-board sensors */
+pub const MAIX_DOCK: Pinout = Pinout {
+    name: "Maix Dock",
+    i2c: None,
+    ..MAIX_GO
+};
+
+/** Proof that the LCD pins are routed, required by `LCD::new` */
+pub struct LcdPins {
+    _private: (),
+}
+
+/** Proof that the SD card pins are routed to SPI controller `IF`, required by `SDCard::new` */
+pub struct SdCardPins<IF> {
+    cs_gpionum: u8,
+    _spi: PhantomData<IF>,
+}
+
+impl<IF> SdCardPins<IF> {
+    /** GPIOHS pin driving the chip select */
+    pub fn cs_gpionum(&self) -> u8 {
+        self.cs_gpionum
+    }
+}
+
+/** SPI controller that can drive the SD card */
+pub trait SdCardSpi {
+    const SCLK: function;
+    const D0: function;
+    const D1: function;
+}
+
+impl SdCardSpi for pac::SPI0 {
+    const SCLK: function = function::SPI0_SCLK;
+    const D0: function = function::SPI0_D0;
+    const D1: function = function::SPI0_D1;
+}
+
+impl SdCardSpi for pac::SPI1 {
+    const SCLK: function = function::SPI1_SCLK;
+    const D0: function = function::SPI1_D0;
+    const D1: function = function::SPI1_D1;
+}
+
+impl LcdPads {
+    /** Route the LCD pads, and switch the SPI0 data lines to the DVP pins */
+    pub fn route(&self, mux: &mut PinMux) -> Result<LcdPins, MuxError> {
+        if !mux.writes() {
+            return Err(MuxError::DryRun);
+        }
+        self.assign(mux)?;
+        mux.issue(&[self.cs, self.wr, self.dc, self.rst])?;
+        Ok(LcdPins { _private: () })
+    }
+
+    /** Assign the LCD pads in `mux`, without giving out a token */
+    pub fn assign(&self, mux: &mut PinMux) -> Result<(), MuxError> {
+        mux.set_functions(&[
+            // outputs must be pull-down
+            (self.rst.into(), function::gpiohs(lcd::RST_GPIONUM), pull::DOWN),
+            (self.dc.into(), function::gpiohs(lcd::DCX_GPIONUM), pull::DOWN),
+            (self.cs.into(), function::SPI0_SS3, pull::NONE),
+            (self.wr.into(), function::SPI0_SCLK, pull::NONE),
+        ])?;
+        if mux.writes() {
+            sysctl::set_spi0_dvp_data(true);
+        }
+        Ok(())
+    }
+}
+
+impl SdCardPads {
+    /** Route the SD card pads to SPI controller `IF`, with GPIOHS pin `cs_gpionum` as chip
+     * select */
+    pub fn route<IF: SdCardSpi>(&self, mux: &mut PinMux, cs_gpionum: u8)
+        -> Result<SdCardPins<IF>, MuxError> {
+        if !mux.writes() {
+            return Err(MuxError::DryRun);
+        }
+        self.assign::<IF>(mux, cs_gpionum)?;
+        mux.issue(&[self.sclk, self.mosi, self.miso, self.cs])?;
+        Ok(SdCardPins { cs_gpionum, _spi: PhantomData })
+    }
+
+    /** Assign the SD card pads in `mux`, without giving out a token */
+    pub fn assign<IF: SdCardSpi>(&self, mux: &mut PinMux, cs_gpionum: u8)
+        -> Result<(), MuxError> {
+        mux.set_functions(&[
+            (self.sclk.into(), IF::SCLK, pull::NONE),
+            (self.mosi.into(), IF::D0, pull::NONE),
+            (self.miso.into(), IF::D1, pull::NONE),
+            (self.cs.into(), function::gpiohs(cs_gpionum), pull::DOWN),
+        ])
+    }
+}
+
+impl I2CPads {
+    /** Route the bus to I2C controller `n` (0-2) */
+    pub fn route(&self, mux: &mut PinMux, n: u8) -> Result<(), MuxError> {
+        let (scl, sda) = match n {
+            0 => (function::I2C0_SCLK, function::I2C0_SDA),
+            1 => (function::I2C1_SCLK, function::I2C1_SDA),
+            2 => (function::I2C2_SCLK, function::I2C2_SDA),
+            _ => panic!("no such I2C controller"),
+        };
+        mux.set_functions(&[
+            (self.scl.into(), scl, pull::NONE),
+            (self.sda.into(), sda, pull::NONE),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boards() {
+        for board in &[MAIX_GO, MAIX_BIT, MAIX_DOCK] {
+            let mut mux = PinMux::dry_run();
+            // no tokens without configuring the FPIOA
+            assert!(matches!(board.lcd.route(&mut mux), Err(MuxError::DryRun)));
+            assert!(matches!(board.sdcard.route::<pac::SPI1>(&mut mux, 7),
+                             Err(MuxError::DryRun)));
+            board.lcd.assign(&mut mux).unwrap();
+            // the LCD takes SPI0, so the SD card needs SPI1
+            assert!(matches!(board.sdcard.assign::<pac::SPI0>(&mut mux, 7),
+                             Err(MuxError::FunctionTaken { function: function::SPI0_SCLK, .. })));
+            board.sdcard.assign::<pac::SPI1>(&mut mux, 7).unwrap();
+            assert_eq!(mux.pad(function::GPIOHS7), Some(board.sdcard.cs.into()));
+            if let Some(i2c) = board.i2c {
+                i2c.route(&mut mux, 0).unwrap();
+            }
+            // a chip select on a GPIOHS pin of the LCD conflicts
+            let sdcard = SdCardPads { cs: io::IO11 as u8, ..board.sdcard };
+            assert!(matches!(sdcard.assign::<pac::SPI1>(&mut mux, lcd::DCX_GPIONUM),
+                             Err(MuxError::FunctionTaken { io: 11, .. })));
+        }
+    }
+}
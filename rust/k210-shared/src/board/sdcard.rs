@@ -1,10 +1,18 @@
 //! SD card slot access (in SPI mode) on Maix Go
+use core::cell::Cell;
 use core::convert::TryInto;
 
+use crate::board::pinout::SdCardPins;
+use crate::fs::BlockDevice;
 use crate::soc::dmac::{dma_channel, DMAC};
 use crate::soc::gpio;
 use crate::soc::gpiohs;
-use crate::soc::spi::{aitm, frame_format, tmod, work_mode, SPI};
+use crate::soc::sleep::usleep;
+use crate::soc::spi::{aitm, frame_format, tmod, work_mode, SPIImpl, SPI, SPI01};
+
+pub mod registers;
+
+use registers::{Cid, Csd, Scr, SdStatus, DEFAULT_SPEED_CLOCK, HIGH_SPEED_CLOCK};
 
 pub struct SDCard<'a, SPI> {
     spi: SPI,
@@ -12,6 +20,49 @@ pub struct SDCard<'a, SPI> {
     cs_gpionum: u8,
     dmac: &'a DMAC,
     channel: dma_channel,
+    /** Card detect GPIOHS pin and its level when a card is present */
+    detect: Option<(u8, bool)>,
+    /** Card power switch GPIOHS pin and its level to switch power on */
+    power: Option<(u8, bool)>,
+    /** Index into `DATA_CLOCKS` of the clock currently used for data transfers */
+    speed: Cell<usize>,
+    slot: Slot,
+}
+
+/* What is known about the card in the slot, updated by `init`, `poll` and transfers */
+struct Slot {
+    state: Cell<CardState>,
+    /** Number of sectors on the card, known after `init` */
+    num_sectors: Cell<u32>,
+    /** Set when a transfer failed with a transient error even after retrying */
+    needs_recovery: Cell<bool>,
+    /** Identification of the card last initialized, to notice a swap */
+    cid: Cell<Option<SD_CID>>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: Cell::new(CardState::Absent),
+            num_sectors: Cell::new(0),
+            needs_recovery: Cell::new(false),
+            cid: Cell::new(None),
+        }
+    }
+
+    /* No usable card in the slot */
+    fn clear(&self) {
+        self.state.set(CardState::Absent);
+        self.num_sectors.set(0);
+    }
+
+    /* A card was initialized: record its size and identification */
+    fn ready(&self, capacity: u64, cid: SD_CID) {
+        self.num_sectors.set((capacity / SEC_LEN as u64).try_into().unwrap_or(u32::MAX));
+        self.state.set(CardState::Ready);
+        self.needs_recovery.set(false);
+        self.cid.set(Some(cid));
+    }
 }
 
 /*
@@ -27,15 +78,56 @@ pub const SD_START_DATA_MULTIPLE_BLOCK_READ: u8 = 0xFE;
 pub const SD_START_DATA_SINGLE_BLOCK_WRITE: u8 = 0xFE;
 /** Data token start byte, Start Multiple Block Write */
 pub const SD_START_DATA_MULTIPLE_BLOCK_WRITE: u8 = 0xFC;
+/** Data token stop byte, Stop Multiple Block Write */
+pub const SD_STOP_DATA_MULTIPLE_BLOCK_WRITE: u8 = 0xFD;
 
 pub const SEC_LEN: usize = 512;
 
+/** R1 response bits */
+const R1_IDLE_STATE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_COM_CRC_ERROR: u8 = 0x08;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+/** Data error token bits (sent instead of a start token on read errors) */
+const TOKEN_OUT_OF_RANGE: u8 = 0x08;
+
+/** Second byte of R2 response (CMD13) bits */
+const R2_WP_ERASE_SKIP: u8 = 0x02;
+const R2_WP_VIOLATION: u8 = 0x20;
+const R2_OUT_OF_RANGE: u8 = 0x80;
+
+/** Data response token after writing a block: accepted, CRC error, write error */
+const DATA_RESPONSE_MASK: u8 = 0x1F;
+const DATA_RESPONSE_ACCEPTED: u8 = 0x05;
+const DATA_RESPONSE_CRC_ERROR: u8 = 0x0B;
+
+/** SPI clock rates for data transfers, fastest first. The fastest one supported by the card is
+ * used; initialization falls back to the next slower one if it fails. */
+const DATA_CLOCKS: [u32; 5] = [50_000_000, 25_000_000, 10_000_000, 4_000_000, 1_000_000];
+/** Number of polls to wait before trying again to initialize a card that failed */
+const FAILED_RETRY_POLLS: u32 = 10;
+/** Time to keep the card unpowered in a power cycle (us) */
+const POWER_OFF_US: usize = 100_000;
+/** Time for the supply to ramp up after switching on (us) */
+const POWER_ON_US: usize = 10_000;
+
+/** Number of attempts for transfers that fail with a transient error (timeout or CRC) */
+const RETRIES: usize = 3;
+/** Number of byte polls while waiting for the card to finish programming */
+const BUSY_TIMEOUT: usize = 0x000F_FFFF;
+/** Number of byte polls while waiting for an erase to finish, which can take seconds */
+const ERASE_TIMEOUT: usize = 0x0FFF_FFFF;
+
 /** SD commands */
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum CMD {
     /** Software reset */
     CMD0 = 0,
+    /** Check or switch card function */
+    CMD6 = 6,
     /** Check voltage range (SDC V2) */
     CMD8 = 8,
     /** Read CSD register */
@@ -44,6 +136,8 @@ pub enum CMD {
     CMD10 = 10,
     /** Stop to read data */
     CMD12 = 12,
+    /** Read card status; after CMD55, read SD status (ACMD13) */
+    CMD13 = 13,
     /** Change R/W block size */
     CMD16 = 16,
     /** Read block */
@@ -56,8 +150,16 @@ pub enum CMD {
     CMD24 = 24,
     /** Write multiple blocks */
     CMD25 = 25,
+    /** Set first sector to erase */
+    CMD32 = 32,
+    /** Set last sector to erase */
+    CMD33 = 33,
+    /** Erase the selected sectors */
+    CMD38 = 38,
     /** Initiate initialization process (SDC) */
     ACMD41 = 41,
+    /** Read SCR register */
+    ACMD51 = 51,
     /** Leading command for ACMD* */
     CMD55 = 55,
     /** Read OCR */
@@ -73,6 +175,115 @@ pub enum InitError {
     CannotGetCardInfo,
 }
 
+/** Hot-plug state of the card slot, updated by `init` and `poll` */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardState {
+    /** No card detected */
+    Absent,
+    /** Card initialized and ready for transfers */
+    Ready,
+    /** Card present, but initialization failed at all clock rates. It will be tried again
+     * after a few polls. */
+    Failed(u32),
+}
+
+/** Card changes reported by `poll` */
+#[derive(Debug, Copy, Clone)]
+pub enum CardEvent {
+    /** A card was inserted and initialized; filesystems can be mounted */
+    Inserted(SD_CardInfo),
+    /** The card was removed, or can no longer be used; filesystems on it must be dropped */
+    Removed,
+}
+
+/** Errors for data transfers */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SDError {
+    /** Card did not respond, or stayed busy for too long */
+    Timeout,
+    /** Card reported a CRC error in a command */
+    CommandCRC,
+    /** CRC mismatch in data read from the card, or written data rejected by the card because
+     * of a CRC error */
+    DataCRC,
+    /** Write or erase of a write-protected card or area */
+    WriteProtect,
+    /** Sector address out of range for the card */
+    OutOfRange,
+    /** Command not supported by the card */
+    IllegalCommand,
+    /** Other error bits in the R1 response to a command */
+    Status(u8),
+    /** Read failed: data error token sent by the card */
+    ReadError(u8),
+    /** Write failed: card status bits (second byte of the CMD13 response) */
+    WriteError(u8),
+    /** No initialized card in the slot */
+    NoCard,
+}
+
+impl SDError {
+    /** Errors that could go away by trying again */
+    fn is_transient(&self) -> bool {
+        matches!(self, SDError::Timeout | SDError::CommandCRC | SDError::DataCRC)
+    }
+
+    /** Check an R1 response */
+    fn from_r1(r1: u8) -> Result<(), SDError> {
+        if r1 == 0x00 {
+            Ok(())
+        } else if r1 == 0xFF {
+            Err(SDError::Timeout)
+        } else if (r1 & R1_COM_CRC_ERROR) != 0 {
+            Err(SDError::CommandCRC)
+        } else if (r1 & (R1_ADDRESS_ERROR | R1_PARAMETER_ERROR)) != 0 {
+            Err(SDError::OutOfRange)
+        } else if (r1 & R1_ILLEGAL_COMMAND) != 0 {
+            Err(SDError::IllegalCommand)
+        } else {
+            Err(SDError::Status(r1))
+        }
+    }
+
+    /** Error for a card status (second byte of R2) after a failed write or erase */
+    fn from_r2(r2: u8) -> SDError {
+        if (r2 & (R2_WP_VIOLATION | R2_WP_ERASE_SKIP)) != 0 {
+            SDError::WriteProtect
+        } else if (r2 & R2_OUT_OF_RANGE) != 0 {
+            SDError::OutOfRange
+        } else {
+            SDError::WriteError(r2)
+        }
+    }
+}
+
+/** CRC7 over a command (first five bytes), as used in the last byte of a command frame: shifted
+ * left by one, with the end bit set. */
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in 0..8 {
+            crc <<= 1;
+            if ((byte << bit) ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    ((crc & 0x7F) << 1) | 1
+}
+
+/** CRC16 (CCITT, initial value 0) over a data block */
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
 /**
  * Card Specific Data: CSD Register
  */
@@ -143,18 +354,52 @@ pub struct SD_CardInfo {
     pub SD_cid: SD_CID,
     pub CardCapacity: u64,  /* Card Capacity */
     pub CardBlockSize: u64, /* Card Block Size */
+    /** Decoded registers; SCR is missing for cards that don't support reading it */
+    pub csd: Csd,
+    pub cid: Cid,
+    pub scr: Option<Scr>,
+    /** Card was switched to high speed mode */
+    pub high_speed: bool,
+    /** SPI clock rate used for data transfers (Hz) */
+    pub clock: u32,
 }
 
-impl<'a, X: SPI> SDCard<'a, X> {
-    pub fn new(spi: X, spi_cs: u32, cs_gpionum: u8, dmac: &'a DMAC, channel: dma_channel) -> Self {
+impl<'a, IF: SPI01> SDCard<'a, SPIImpl<IF>> {
+    /** Create a driver for the card on `spi`, with the pins routed to the same controller by
+     * `pinout::SdCardPads::route` */
+    pub fn new(spi: SPIImpl<IF>, spi_cs: u32, pins: SdCardPins<IF>, dmac: &'a DMAC,
+               channel: dma_channel) -> Self {
         Self {
             spi,
             spi_cs,
-            cs_gpionum,
+            cs_gpionum: pins.cs_gpionum(),
             dmac,
             channel,
+            detect: None,
+            power: None,
+            speed: Cell::new(0),
+            slot: Slot::new(),
         }
     }
+}
+
+impl<'a, X: SPI> SDCard<'a, X> {
+    /** Use a card detect switch connected to a GPIOHS pin, which reads `present_level` when a
+     * card is inserted. Pull-up or pull-down for the pin needs to be configured in the FPIOA. */
+    pub fn with_card_detect(mut self, gpionum: u8, present_level: bool) -> Self {
+        gpiohs::set_direction(gpionum, gpio::direction::INPUT);
+        self.detect = Some((gpionum, present_level));
+        self
+    }
+
+    /** Use a GPIOHS pin that switches card power, which is on at `on_level`. This allows
+     * recovering cards that are stuck in the middle of an operation by power cycling them. */
+    pub fn with_power_control(mut self, gpionum: u8, on_level: bool) -> Self {
+        gpiohs::set_direction(gpionum, gpio::direction::OUTPUT);
+        gpiohs::set_pin(gpionum, on_level);
+        self.power = Some((gpionum, on_level));
+        self
+    }
 
     fn CS_HIGH(&self) {
         gpiohs::set_pin(self.cs_gpionum, true);
@@ -164,8 +409,16 @@ impl<'a, X: SPI> SDCard<'a, X> {
         gpiohs::set_pin(self.cs_gpionum, false);
     }
 
-    fn HIGH_SPEED_ENABLE(&self) {
-        self.spi.set_clk_rate(10000000);
+    /* Set the fastest data clock not above `max_clock`, starting at the current fallback
+     * level, and return it. */
+    fn HIGH_SPEED_ENABLE(&self, max_clock: u32) -> u32 {
+        let mut speed = self.speed.get();
+        while speed + 1 < DATA_CLOCKS.len() && DATA_CLOCKS[speed] > max_clock {
+            speed += 1;
+        }
+        self.speed.set(speed);
+        self.spi.set_clk_rate(DATA_CLOCKS[speed]);
+        DATA_CLOCKS[speed]
     }
 
     fn lowlevel_init(&self) {
@@ -236,17 +489,13 @@ impl<'a, X: SPI> SDCard<'a, X> {
     }
 
     /*
-     * Send 5 bytes command to the SD card.
+     * Send 5 bytes command to the SD card, followed by its CRC.
      * @param  cmd: The user expected command to send to SD card.
      * @param  arg: The command argument.
-     * @param  crc: The CRC.
      * @retval None
      */
-    fn send_cmd(&self, cmd: CMD, arg: u32, crc: u8) {
-        /* SD chip select low */
-        self.CS_LOW();
-        /* Send the Cmd bytes */
-        self.write_data(&[
+    fn send_cmd(&self, cmd: CMD, arg: u32) {
+        let mut frame = [
             /* Construct byte 1 */
             ((cmd as u8) | 0x40),
             /* Construct byte 2 */
@@ -258,8 +507,13 @@ impl<'a, X: SPI> SDCard<'a, X> {
             /* Construct byte 5 */
             (arg & 0xff) as u8,
             /* Construct CRC: byte 6 */
-            crc,
-        ]);
+            0,
+        ];
+        frame[5] = crc7(&frame[0..5]);
+        /* SD chip select low */
+        self.CS_LOW();
+        /* Send the Cmd bytes */
+        self.write_data(&frame);
     }
 
     /* Send end-command sequence to SD card */
@@ -293,6 +547,66 @@ impl<'a, X: SPI> SDCard<'a, X> {
         return 0xFF;
     }
 
+    /*
+     * Send a command with an R1 response and no data.
+     * @retval The SD Response, converted to an error if any error bit is set.
+     */
+    fn r1_cmd(&self, cmd: CMD, arg: u32) -> Result<(), SDError> {
+        self.send_cmd(cmd, arg);
+        let result = self.get_response();
+        self.end_cmd();
+        SDError::from_r1(result)
+    }
+
+    /*
+     * Send an application specific command (CMD55 followed by the command).
+     */
+    fn app_cmd(&self, cmd: CMD, arg: u32) -> Result<(), SDError> {
+        self.cmd55()?;
+        self.r1_cmd(cmd, arg)
+    }
+
+    /* Announce an application specific command */
+    fn cmd55(&self) -> Result<(), SDError> {
+        self.send_cmd(CMD::CMD55, 0);
+        let result = self.get_response();
+        self.end_cmd();
+        SDError::from_r1(result & !R1_IDLE_STATE)
+    }
+
+    /*
+     * Send a command that is answered with a data block, and read the block.
+     * @param  r2: The response is in R2 format (R1 followed by a status byte).
+     */
+    fn read_data_cmd(&self, cmd: CMD, arg: u32, r2: bool, data: &mut [u8]) -> Result<(), SDError> {
+        self.send_cmd(cmd, arg);
+        let mut result = SDError::from_r1(self.get_response());
+        if r2 {
+            let status = &mut [0u8];
+            self.read_data(status);
+        }
+        if result.is_ok() {
+            result = self.read_block(data);
+        }
+        self.end_cmd();
+        result
+    }
+
+    /*
+     * Read the card status (CMD13).
+     * @retval The second byte of the R2 response (0 if no errors), or an error for the first
+     *         byte.
+     */
+    fn get_status(&self) -> Result<u8, SDError> {
+        self.send_cmd(CMD::CMD13, 0);
+        let result = self.get_response();
+        let status = &mut [0u8];
+        self.read_data(status);
+        self.end_cmd();
+        SDError::from_r1(result)?;
+        Ok(status[0])
+    }
+
     /*
      * Get SD card data response.
      * @param  None
@@ -307,45 +621,59 @@ impl<'a, X: SPI> SDCard<'a, X> {
         /* Read resonse */
         self.read_data(response);
         /* Mask unused bits */
-        response[0] &= 0x1F;
-        if response[0] != 0x05 {
-            return 0xFF;
-        }
-        /* Wait null data */
-        self.read_data(response);
-        while response[0] == 0 {
+        response[0] & DATA_RESPONSE_MASK
+    }
+
+    /*
+     * Wait for the card to release the busy signal (data line held low).
+     * @param  timeout: Number of bytes to poll before giving up.
+     */
+    fn wait_ready(&self, timeout: usize) -> Result<(), SDError> {
+        let response = &mut [0u8];
+        for _ in 0..timeout {
             self.read_data(response);
+            if response[0] == 0xFF {
+                return Ok(());
+            }
+        }
+        Err(SDError::Timeout)
+    }
+
+    /* Wait for the start token of a data block, or an error token */
+    fn get_start_token(&self) -> Result<(), SDError> {
+        match self.get_response() {
+            SD_START_DATA_SINGLE_BLOCK_READ => Ok(()),
+            0xFF => Err(SDError::Timeout),
+            token if (token & TOKEN_OUT_OF_RANGE) != 0 => Err(SDError::OutOfRange),
+            token => Err(SDError::ReadError(token)),
+        }
+    }
+
+    /* Read a data block of the given size, and check its CRC */
+    fn read_block(&self, data: &mut [u8]) -> Result<(), SDError> {
+        self.get_start_token()?;
+        self.read_data(data);
+        let mut crc = [0u8; 2];
+        self.read_data(&mut crc);
+        if u16::from_be_bytes(crc) != crc16(data) {
+            return Err(SDError::DataCRC);
         }
-        /* Return response */
-        return 0;
+        Ok(())
     }
 
     /*
      * Read the CSD card register
      *         Reading the contents of the CSD register in SPI mode is a simple
      *         read-block transaction.
-     * @param  SD_csd: pointer on an SCD register structure
+     * @param  csd_tab: receives the raw register contents
      * @retval The SD Response:
      *         - `Err()`: Sequence failed
      *         - `Ok(info)`: Sequence succeed
      */
-    fn get_csdregister(&self) -> Result<SD_CSD, ()> {
-        let mut csd_tab = [0u8; 18];
-        /* Send CMD9 (CSD register) */
-        self.send_cmd(CMD::CMD9, 0, 0);
-        /* Wait for response in the R1 format (0x00 is no errors) */
-        if self.get_response() != 0x00 {
-            self.end_cmd();
-            return Err(());
-        }
-        if self.get_response() != SD_START_DATA_SINGLE_BLOCK_READ {
-            self.end_cmd();
-            return Err(());
-        }
-        /* Store CSD register value on csd_tab */
-        /* Get CRC bytes (not really needed by us, but required by SD) */
-        self.read_data(&mut csd_tab);
-        self.end_cmd();
+    fn get_csdregister(&self, csd_tab: &mut [u8; 16]) -> Result<SD_CSD, ()> {
+        /* Send CMD9 (CSD register), and store the register value on csd_tab, checking the data
+         * CRC */
+        self.read_data_cmd(CMD::CMD9, 0, false, csd_tab).map_err(|_| ())?;
         /* see also: https://cdn-shop.adafruit.com/datasheets/TS16GUSDHC6.pdf */
         return Ok(SD_CSD {
             /* Byte 0 */
@@ -407,28 +735,15 @@ impl<'a, X: SPI> SDCard<'a, X> {
      * Read the CID card register.
      *         Reading the contents of the CID register in SPI mode is a simple
      *         read-block transaction.
-     * @param  SD_cid: pointer on an CID register structure
+     * @param  cid_tab: receives the raw register contents
      * @retval The SD Response:
      *         - `Err()`: Sequence failed
      *         - `Ok(info)`: Sequence succeed
      */
-    fn get_cidregister(&self) -> Result<SD_CID, ()> {
-        let mut cid_tab = [0u8; 18];
-        /* Send CMD10 (CID register) */
-        self.send_cmd(CMD::CMD10, 0, 0);
-        /* Wait for response in the R1 format (0x00 is no errors) */
-        if self.get_response() != 0x00 {
-            self.end_cmd();
-            return Err(());
-        }
-        if self.get_response() != SD_START_DATA_SINGLE_BLOCK_READ {
-            self.end_cmd();
-            return Err(());
-        }
-        /* Store CID register value on cid_tab */
-        /* Get CRC bytes (not really needed by us, but required by SD) */
-        self.read_data(&mut cid_tab);
-        self.end_cmd();
+    fn get_cidregister(&self, cid_tab: &mut [u8; 16]) -> Result<SD_CID, ()> {
+        /* Send CMD10 (CID register), and store the register value on cid_tab, checking the data
+         * CRC */
+        self.read_data_cmd(CMD::CMD10, 0, false, cid_tab).map_err(|_| ())?;
         return Ok(SD_CID {
             /* Byte 0 */
             ManufacturerID: cid_tab[0],
@@ -458,24 +773,70 @@ impl<'a, X: SPI> SDCard<'a, X> {
     }
 
     /*
-     * Returns information about specific card.
-     * @param  cardinfo: pointer to a SD_CardInfo structure that contains all SD
-     *         card information.
+     * Returns information about specific card, and switches to the fastest data clock it
+     * supports (using high speed mode if possible).
+     * @param  speed: index into `DATA_CLOCKS` of the fastest clock to use.
      * @retval The SD Response:
      *         - `Err(())`: Sequence failed
      *         - `Ok(info)`: Sequence succeed
      */
-    fn get_cardinfo(&self) -> Result<SD_CardInfo, ()> {
-        let mut info = SD_CardInfo {
-            SD_csd: self.get_csdregister()?,
-            SD_cid: self.get_cidregister()?,
-            CardCapacity: 0,
-            CardBlockSize: 0,
-        };
-        info.CardBlockSize = 1 << u64::from(info.SD_csd.RdBlockLen);
-        info.CardCapacity = (u64::from(info.SD_csd.DeviceSize) + 1) * 1024 * info.CardBlockSize;
+    fn get_cardinfo(&self, speed: usize) -> Result<SD_CardInfo, ()> {
+        let mut csd_tab = [0u8; 16];
+        let mut cid_tab = [0u8; 16];
+        let SD_csd = self.get_csdregister(&mut csd_tab)?;
+        let SD_cid = self.get_cidregister(&mut cid_tab)?;
+        let csd = Csd::decode(&csd_tab).ok_or(())?;
+        /* Cards that fail to give their SCR are still usable, just not in high speed mode */
+        let scr = self.read_scr().ok();
+        let high_speed = csd.max_clock >= HIGH_SPEED_CLOCK
+            || (DATA_CLOCKS[speed] > csd.max_clock
+                && csd.supports_switch()
+                && matches!(scr, Some(scr) if scr.supports_switch())
+                && self.switch_high_speed() == Ok(true));
+        self.speed.set(speed);
+        let max_clock = if high_speed { HIGH_SPEED_CLOCK } else { csd.max_clock };
+        let clock = self.HIGH_SPEED_ENABLE(max_clock);
+        Ok(SD_CardInfo {
+            CardCapacity: csd.capacity,
+            CardBlockSize: 1 << u64::from(SD_csd.RdBlockLen),
+            SD_csd,
+            SD_cid,
+            csd,
+            cid: Cid::decode(&cid_tab),
+            scr,
+            high_speed,
+            clock,
+        })
+    }
 
-        Ok(info)
+    /** Read the SD card configuration register (ACMD51) */
+    pub fn read_scr(&self) -> Result<Scr, SDError> {
+        let mut raw = [0u8; 8];
+        self.cmd55()?;
+        self.read_data_cmd(CMD::ACMD51, 0, false, &mut raw)?;
+        Ok(Scr::decode(&raw))
+    }
+
+    /** Read the SD status (ACMD13), which has the speed class and allocation unit size */
+    pub fn read_sd_status(&self) -> Result<SdStatus, SDError> {
+        let mut raw = [0u8; 64];
+        self.cmd55()?;
+        self.read_data_cmd(CMD::CMD13, 0, true, &mut raw)?;
+        Ok(SdStatus::decode(&raw))
+    }
+
+    /*
+     * Switch the card to high speed mode (CMD6), if it supports it.
+     * @retval Whether the card is now in high speed mode
+     */
+    fn switch_high_speed(&self) -> Result<bool, SDError> {
+        let mut status = [0u8; 64];
+        self.read_data_cmd(CMD::CMD6, registers::SWITCH_CHECK_HIGH_SPEED, false, &mut status)?;
+        if !registers::switch_supports_high_speed(&status) {
+            return Ok(false);
+        }
+        self.read_data_cmd(CMD::CMD6, registers::SWITCH_HIGH_SPEED, false, &mut status)?;
+        Ok(registers::switch_selected(&status) == 1)
     }
 
     /*
@@ -484,12 +845,14 @@ impl<'a, X: SPI> SDCard<'a, X> {
      * @retval The SD Response info if succeeeded, otherwise Err
      */
     pub fn init(&self) -> Result<SD_CardInfo, InitError> {
+        self.slot.clear();
         /* Initialize SD_SPI */
         self.lowlevel_init();
         /* SD chip select high */
         self.CS_HIGH();
         /* NOTE: this reset doesn't always seem to work if the SD access was broken off in the
-         * middle of an operation: CMDFailed(CMD0, 127). */
+         * middle of an operation: CMDFailed(CMD0, 127). `poll` recovers from this by power
+         * cycling the card. */
 
         /* Send dummy byte 0xFF, 10 times with CS high */
         /* Rise CS and MOSI for 80 clocks cycles */
@@ -499,7 +862,7 @@ impl<'a, X: SPI> SDCard<'a, X> {
         /* SD initialized and set to SPI mode properly */
 
         /* Send software reset */
-        self.send_cmd(CMD::CMD0, 0, 0x95);
+        self.send_cmd(CMD::CMD0, 0);
         let result = self.get_response();
         self.end_cmd();
         if result != 0x01 {
@@ -507,7 +870,7 @@ impl<'a, X: SPI> SDCard<'a, X> {
         }
 
         /* Check voltage range */
-        self.send_cmd(CMD::CMD8, 0x01AA, 0x87);
+        self.send_cmd(CMD::CMD8, 0x01AA);
         /* 0x01 or 0x05 */
         let result = self.get_response();
         let mut frame = [0u8; 4];
@@ -516,17 +879,24 @@ impl<'a, X: SPI> SDCard<'a, X> {
         if result != 0x01 {
             return Err(InitError::CMDFailed(CMD::CMD8, result));
         }
+        /* Enable CRC checking of commands and data */
+        self.send_cmd(CMD::CMD59, 1);
+        let result = self.get_response();
+        self.end_cmd();
+        if result != 0x01 {
+            return Err(InitError::CMDFailed(CMD::CMD59, result));
+        }
         let mut index = 255;
         while index != 0 {
             /* <ACMD> */
-            self.send_cmd(CMD::CMD55, 0, 0);
+            self.send_cmd(CMD::CMD55, 0);
             let result = self.get_response();
             self.end_cmd();
             if result != 0x01 {
                 return Err(InitError::CMDFailed(CMD::CMD55, result));
             }
             /* Initiate SDC initialization process */
-            self.send_cmd(CMD::ACMD41, 0x40000000, 0);
+            self.send_cmd(CMD::ACMD41, 0x40000000);
             let result = self.get_response();
             self.end_cmd();
             if result == 0x00 {
@@ -541,7 +911,7 @@ impl<'a, X: SPI> SDCard<'a, X> {
         let mut frame = [0u8; 4];
         while index != 0 {
             /* Read OCR */
-            self.send_cmd(CMD::CMD58, 0, 1);
+            self.send_cmd(CMD::CMD58, 0);
             let result = self.get_response();
             self.read_data(&mut frame);
             self.end_cmd();
@@ -556,9 +926,173 @@ impl<'a, X: SPI> SDCard<'a, X> {
         if (frame[0] & 0x40) == 0 {
             return Err(InitError::CardCapacityStatusNotSet(frame));
         }
-        self.HIGH_SPEED_ENABLE();
-        self.get_cardinfo()
-            .map_err(|_| InitError::CannotGetCardInfo)
+        /* Read card information at up to default speed, then switch to the final clock */
+        let speed = self.speed.get();
+        self.HIGH_SPEED_ENABLE(DEFAULT_SPEED_CLOCK);
+        let info = self.get_cardinfo(speed)
+            .map_err(|_| InitError::CannotGetCardInfo)?;
+        self.slot.ready(info.CardCapacity, info.SD_cid);
+        Ok(info)
+    }
+
+    /* Whether the card detect pin reports a card; always true without a card detect pin */
+    fn card_detected(&self) -> bool {
+        match self.detect {
+            Some((pin, level)) => gpiohs::get_pin(pin) == level,
+            None => true,
+        }
+    }
+
+    /*
+     * Get a card out of whatever operation it was stuck in: switch power off and on again, or
+     * without power control, clock out a block's worth of data and stop any transmission.
+     */
+    fn power_cycle(&self) {
+        if let Some((pin, level)) = self.power {
+            self.CS_LOW();
+            gpiohs::set_pin(pin, !level);
+            usleep(POWER_OFF_US);
+            gpiohs::set_pin(pin, level);
+            usleep(POWER_ON_US);
+            self.CS_HIGH();
+        } else {
+            let mut dummy = [0u8; SEC_LEN + 16];
+            self.CS_LOW();
+            self.read_data(&mut dummy);
+            self.send_cmd(CMD::CMD12, 0);
+            self.get_response();
+            self.end_cmd();
+        }
+    }
+
+    /*
+     * Initialize, falling back to slower clocks (and power cycling in between) on failure.
+     * @param  speed: index into `DATA_CLOCKS` of the first clock rate to try.
+     */
+    fn init_with_fallback(&self, speed: usize) -> Result<SD_CardInfo, InitError> {
+        let mut result = Err(InitError::CannotGetCardInfo);
+        let mut speed = speed;
+        while speed < DATA_CLOCKS.len() {
+            self.speed.set(speed);
+            result = self.init();
+            match result {
+                Ok(_) => break,
+                /* Nothing on the bus answers: no card */
+                Err(InitError::CMDFailed(CMD::CMD0, 0xFF)) => break,
+                Err(_) => self.power_cycle(),
+            }
+            /* Continue below the clock that failed */
+            speed = self.speed.get() + 1;
+        }
+        result
+    }
+
+    /** Current state of the card slot */
+    pub fn state(&self) -> CardState {
+        self.slot.state.get()
+    }
+
+    /**
+     * Check for card insertion and removal, and recover cards that stopped responding. Call
+     * this periodically, between transfers.
+     *
+     * Inserted cards are initialized. Without a card detect pin, presence is checked by
+     * sending a command to the card, and removal is noticed only when the card does not
+     * respond, or on the next poll after a failed transfer. A card that stopped responding is
+     * re-initialized, at a slower clock rate if possible; if that fails it is reported as
+     * removed. Polling an empty slot without card detect pin takes a few hundred
+     * milliseconds, as it waits for the card to answer at the initialization clock rate.
+     */
+    pub fn poll(&self) -> Option<CardEvent> {
+        match self.slot.state.get() {
+            CardState::Ready => {
+                if !self.card_detected() {
+                    self.slot.clear();
+                    return Some(CardEvent::Removed);
+                }
+                if !self.slot.needs_recovery.get()
+                    && (self.detect.is_some() || self.get_status() != Err(SDError::Timeout))
+                {
+                    return None;
+                }
+                /* Card stuck or gone: try again, slower */
+                let old_cid = self.slot.cid.get();
+                let speed = core::cmp::min(self.speed.get() + 1, DATA_CLOCKS.len() - 1);
+                self.power_cycle();
+                match self.init_with_fallback(speed) {
+                    Ok(info) if same_card(old_cid, info.SD_cid) => None,
+                    Ok(_) => {
+                        /* Different card: report it as inserted on the next poll */
+                        self.slot.clear();
+                        Some(CardEvent::Removed)
+                    }
+                    Err(e) => {
+                        self.set_failed(e);
+                        Some(CardEvent::Removed)
+                    }
+                }
+            }
+            CardState::Absent | CardState::Failed(0) => {
+                if !self.card_detected() {
+                    self.slot.state.set(CardState::Absent);
+                    return None;
+                }
+                match self.init_with_fallback(0) {
+                    Ok(info) => Some(CardEvent::Inserted(info)),
+                    Err(e) => {
+                        self.set_failed(e);
+                        None
+                    }
+                }
+            }
+            CardState::Failed(n) => {
+                self.slot.state.set(if self.card_detected() {
+                    CardState::Failed(n - 1)
+                } else {
+                    CardState::Absent
+                });
+                None
+            }
+        }
+    }
+
+    /* Set state after initialization failed */
+    fn set_failed(&self, e: InitError) {
+        self.slot.state.set(match e {
+            InitError::CMDFailed(CMD::CMD0, 0xFF) if self.detect.is_none() => CardState::Absent,
+            _ => CardState::Failed(FAILED_RETRY_POLLS),
+        });
+    }
+
+    /* Fail with `OutOfRange` if the sectors are beyond the end of the card */
+    fn check_range(&self, sector: u32, count: usize) -> Result<(), SDError> {
+        let num_sectors = self.slot.num_sectors.get();
+        if num_sectors != 0 && u64::from(sector) + count as u64 > u64::from(num_sectors) {
+            Err(SDError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    /* Run an operation, trying again up to `RETRIES` times total on transient errors. If
+     * it keeps failing, the card is marked to be recovered on the next `poll`. */
+    fn retry<F: FnMut() -> Result<(), SDError>>(&self, mut f: F) -> Result<(), SDError> {
+        if self.slot.state.get() != CardState::Ready {
+            return Err(SDError::NoCard);
+        }
+        let mut result = f();
+        for _ in 1..RETRIES {
+            match result {
+                Err(e) if e.is_transient() => result = f(),
+                _ => break,
+            }
+        }
+        if let Err(e) = result {
+            if e.is_transient() {
+                self.slot.needs_recovery.set(true);
+            }
+        }
+        result
     }
 
     /*
@@ -566,29 +1100,34 @@ impl<'a, X: SPI> SDCard<'a, X> {
      * @param  data_buf: slice that receives the data read from the SD.
      * @param  sector: SD's internal address to read from.
      * @retval The SD Response:
-     *         - `Err(())`: Sequence failed
+     *         - `Err(e)`: Sequence failed, after retrying on transient errors
      *         - `Ok(())`: Sequence succeed
      */
-    pub fn read_sector(&self, data_buf: &mut [u8], sector: u32) -> Result<(), ()> {
+    pub fn read_sector(&self, data_buf: &mut [u8], sector: u32) -> Result<(), SDError> {
         assert!(data_buf.len() >= SEC_LEN && (data_buf.len() % SEC_LEN) == 0);
+        self.check_range(sector, data_buf.len() / SEC_LEN)?;
+        self.retry(|| self.read_sector_once(data_buf, sector))
+    }
+
+    fn read_sector_once(&self, data_buf: &mut [u8], sector: u32) -> Result<(), SDError> {
         /* Send CMD17 to read one block, or CMD18 for multiple */
         let flag = if data_buf.len() == SEC_LEN {
-            self.send_cmd(CMD::CMD17, sector, 0);
+            self.send_cmd(CMD::CMD17, sector);
             false
         } else {
-            self.send_cmd(CMD::CMD18, sector, 0);
+            self.send_cmd(CMD::CMD18, sector);
             true
         };
         /* Check if the SD acknowledged the read block command: R1 response (0x00: no errors) */
-        if self.get_response() != 0x00 {
+        if let Err(e) = SDError::from_r1(self.get_response()) {
             self.end_cmd();
-            return Err(());
+            return Err(e);
         }
-        let mut error = false;
+        let mut result = Ok(());
         let mut dma_chunk = [0u32; SEC_LEN];
         for chunk in data_buf.chunks_mut(SEC_LEN) {
-            if self.get_response() != SD_START_DATA_SINGLE_BLOCK_READ {
-                error = true;
+            if let Err(e) = self.get_start_token() {
+                result = Err(e);
                 break;
             }
             /* Read the SD block data : read NumByteToRead data */
@@ -597,23 +1136,23 @@ impl<'a, X: SPI> SDCard<'a, X> {
             for (a, b) in chunk.iter_mut().zip(dma_chunk.iter()) {
                 *a = (b & 0xff) as u8;
             }
-            /* Get CRC bytes (not really needed by us, but required by SD) */
+            /* Get and check CRC bytes */
             let mut frame = [0u8; 2];
             self.read_data(&mut frame);
+            if u16::from_be_bytes(frame) != crc16(chunk) {
+                result = Err(SDError::DataCRC);
+                break;
+            }
         }
         self.end_cmd();
         if flag {
-            self.send_cmd(CMD::CMD12, 0, 0);
+            self.send_cmd(CMD::CMD12, 0);
             self.get_response();
             self.end_cmd();
             self.end_cmd();
         }
         /* It is an error if not everything requested was read */
-        if error {
-            Err(())
-        } else {
-            Ok(())
-        }
+        result
     }
 
     /*
@@ -621,31 +1160,34 @@ impl<'a, X: SPI> SDCard<'a, X> {
      * @param  data_buf: slice containing the data to be written to the SD.
      * @param  sector: address to write on.
      * @retval The SD Response:
-     *         - `Err(())`: Sequence failed
+     *         - `Err(e)`: Sequence failed, after retrying on transient errors
      *         - `Ok(())`: Sequence succeed
      */
-    pub fn write_sector(&self, data_buf: &[u8], sector: u32) -> Result<(), ()> {
+    pub fn write_sector(&self, data_buf: &[u8], sector: u32) -> Result<(), SDError> {
         assert!(data_buf.len() >= SEC_LEN && (data_buf.len() % SEC_LEN) == 0);
+        self.check_range(sector, data_buf.len() / SEC_LEN)?;
+        self.retry(|| self.write_sector_once(data_buf, sector))
+    }
+
+    fn write_sector_once(&self, data_buf: &[u8], sector: u32) -> Result<(), SDError> {
         let mut frame = [0xff, 0x00];
-        if data_buf.len() == SEC_LEN {
+        let flag = if data_buf.len() == SEC_LEN {
             frame[1] = SD_START_DATA_SINGLE_BLOCK_WRITE;
-            self.send_cmd(CMD::CMD24, sector, 0);
+            self.send_cmd(CMD::CMD24, sector);
+            false
         } else {
             frame[1] = SD_START_DATA_MULTIPLE_BLOCK_WRITE;
-            self.send_cmd(
-                CMD::ACMD23,
-                (data_buf.len() / SEC_LEN).try_into().unwrap(),
-                0,
-            );
-            self.get_response();
-            self.end_cmd();
-            self.send_cmd(CMD::CMD25, sector, 0);
-        }
+            /* Set number of blocks to pre-erase */
+            self.app_cmd(CMD::ACMD23, (data_buf.len() / SEC_LEN).try_into().unwrap())?;
+            self.send_cmd(CMD::CMD25, sector);
+            true
+        };
         /* Check if the SD acknowledged the write block command: R1 response (0x00: no errors) */
-        if self.get_response() != 0x00 {
+        if let Err(e) = SDError::from_r1(self.get_response()) {
             self.end_cmd();
-            return Err(());
+            return Err(e);
         }
+        let mut result = Ok(());
         let mut dma_chunk = [0u32; SEC_LEN];
         for chunk in data_buf.chunks(SEC_LEN) {
             /* Send the data token to signify the start of the data */
@@ -654,17 +1196,168 @@ impl<'a, X: SPI> SDCard<'a, X> {
             for (a, &b) in dma_chunk.iter_mut().zip(chunk.iter()) {
                 *a = b.into();
             }
-            self.write_data_dma(&mut dma_chunk);
-            /* Put dummy CRC bytes */
-            self.write_data(&[0xff, 0xff]);
+            self.write_data_dma(&dma_chunk);
+            /* Put CRC bytes */
+            self.write_data(&crc16(chunk).to_be_bytes());
             /* Read data response */
-            if self.get_dataresponse() != 0x00 {
-                self.end_cmd();
-                return Err(());
+            match self.get_dataresponse() {
+                DATA_RESPONSE_ACCEPTED => {}
+                DATA_RESPONSE_CRC_ERROR => result = Err(SDError::DataCRC),
+                _ => result = Err(SDError::WriteError(0)),
+            }
+            /* Wait for programming to finish */
+            if let Err(e) = self.wait_ready(BUSY_TIMEOUT) {
+                result = result.and(Err(e));
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        if flag {
+            if result.is_ok() {
+                /* Stop token ends a multiple block write */
+                self.write_data(&[SD_STOP_DATA_MULTIPLE_BLOCK_WRITE, 0xff]);
+            } else {
+                /* Abort transmission after a rejected block */
+                self.send_cmd(CMD::CMD12, 0);
+                self.get_response();
+            }
+            if let Err(e) = self.wait_ready(BUSY_TIMEOUT) {
+                result = result.and(Err(e));
             }
         }
         self.end_cmd();
-        self.end_cmd();
-        Ok(())
+        match result {
+            /* Check card status for errors that happened during programming, or for the reason
+             * a block was rejected */
+            Ok(()) | Err(SDError::WriteError(_)) => match self.get_status()? {
+                0 => result,
+                status => Err(SDError::from_r2(status)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /*
+     * Erase a range of sectors. Their contents will read as all zeros or all ones, depending on
+     * the card.
+     * @param  start: first sector to erase.
+     * @param  end: last sector to erase (inclusive).
+     * @retval The SD Response:
+     *         - `Err(e)`: Sequence failed, after retrying on transient errors
+     *         - `Ok(())`: Sequence succeed
+     */
+    pub fn erase(&self, start: u32, end: u32) -> Result<(), SDError> {
+        assert!(start <= end);
+        self.check_range(start, (end - start) as usize + 1)?;
+        self.retry(|| {
+            self.r1_cmd(CMD::CMD32, start)?;
+            self.r1_cmd(CMD::CMD33, end)?;
+            self.send_cmd(CMD::CMD38, 0);
+            let result =
+                SDError::from_r1(self.get_response()).and_then(|_| self.wait_ready(ERASE_TIMEOUT));
+            self.end_cmd();
+            result?;
+            match self.get_status()? {
+                0 => Ok(()),
+                status => Err(SDError::from_r2(status)),
+            }
+        })
+    }
+}
+
+/* Whether two CIDs identify the same card */
+fn same_card(a: Option<SD_CID>, b: SD_CID) -> bool {
+    match a {
+        Some(a) => {
+            a.ManufacturerID == b.ManufacturerID
+                && a.OEM_AppliID == b.OEM_AppliID
+                && a.ProdSN == b.ProdSN
+                && a.ManufactDate == b.ManufactDate
+        }
+        None => false,
+    }
+}
+
+/** Block device access, for use with the filesystem layer. The card must have been
+ * initialized with `init` first. */
+impl<'a, X: SPI> BlockDevice for SDCard<'a, X> {
+    fn read_blocks(&self, buf: &mut [u8], block: u32) -> Result<(), ()> {
+        self.read_sector(buf, block).map_err(|_| ())
+    }
+
+    fn write_blocks(&self, buf: &[u8], block: u32) -> Result<(), ()> {
+        self.write_sector(buf, block).map_err(|_| ())
+    }
+
+    fn num_blocks(&self) -> u32 {
+        self.slot.num_sectors.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc7() {
+        /* Known command frames: CMD0, CMD8 with 0x1AA, CMD17 sector 0 */
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]), 0x95);
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xAA]), 0x87);
+        assert_eq!(crc7(&[0x51, 0, 0, 0, 0]), 0x55);
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(&[0xFF; SEC_LEN]), 0x7FA1);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(SDError::from_r1(0x00), Ok(()));
+        assert_eq!(SDError::from_r1(0xFF), Err(SDError::Timeout));
+        assert_eq!(SDError::from_r1(0x09), Err(SDError::CommandCRC));
+        assert_eq!(SDError::from_r1(0x40), Err(SDError::OutOfRange));
+        assert_eq!(SDError::from_r1(0x04), Err(SDError::IllegalCommand));
+        assert_eq!(SDError::from_r2(0x20), SDError::WriteProtect);
+        assert_eq!(SDError::from_r2(0x80), SDError::OutOfRange);
+        assert!(SDError::DataCRC.is_transient());
+        assert!(!SDError::WriteProtect.is_transient());
+    }
+
+    fn cid(serial: u32) -> SD_CID {
+        SD_CID {
+            ManufacturerID: 0x03,
+            OEM_AppliID: 0x5344,
+            ProdName1: 0x5344_3136,
+            ProdName2: 0x47,
+            ProdRev: 0x80,
+            ProdSN: serial,
+            Reserved1: 0,
+            ManufactDate: 0x0139,
+            CID_CRC: 0x3a,
+            Reserved2: 1,
+        }
+    }
+
+    #[test]
+    fn test_slot() {
+        let slot = Slot::new();
+        assert!(!same_card(slot.cid.get(), cid(1)));
+        /* What `init` records must let `poll` recognize the card after recovering it */
+        slot.ready(8 << 30, cid(1));
+        assert_eq!(slot.state.get(), CardState::Ready);
+        assert_eq!(slot.num_sectors.get(), 16 << 20);
+        assert!(same_card(slot.cid.get(), cid(1)));
+        assert!(!same_card(slot.cid.get(), cid(2)));
+        slot.needs_recovery.set(true);
+        slot.clear();
+        assert_eq!(slot.state.get(), CardState::Absent);
+        assert_eq!(slot.num_sectors.get(), 0);
+        slot.ready(4 << 40, cid(2));
+        assert!(!slot.needs_recovery.get());
+        assert_eq!(slot.num_sectors.get(), u32::MAX);
+        assert!(same_card(slot.cid.get(), cid(2)));
     }
 }
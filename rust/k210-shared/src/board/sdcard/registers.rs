@@ -0,0 +1,361 @@
+//! Decoding of SD card registers: CSD, CID, SCR, SD status and switch function status
+use core::str;
+
+/** Clock rate limit for cards in default speed mode (Hz) */
+pub const DEFAULT_SPEED_CLOCK: u32 = 25_000_000;
+/** Clock rate limit for cards switched to high speed mode (Hz) */
+pub const HIGH_SPEED_CLOCK: u32 = 50_000_000;
+
+/** Card Specific Data, decoded from either CSD structure version */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Csd {
+    /** CSD structure version: 1 for standard capacity cards, 2 for SDHC/SDXC */
+    pub version: u8,
+    /** Capacity in bytes */
+    pub capacity: u64,
+    /** Maximum clock rate for data transfers in Hz (TRAN_SPEED) */
+    pub max_clock: u32,
+    /** Supported command classes, one bit per class */
+    pub command_classes: u16,
+    /** Whether single blocks can be erased; if not, erases are done in whole sectors */
+    pub erase_single_block: bool,
+    /** Erase sector size in 512-byte blocks */
+    pub erase_sector_blocks: u32,
+    /** Permanently or temporarily write protected */
+    pub write_protected: bool,
+}
+
+/** TRAN_SPEED time values (multiplied by 10), indexed by bits 6:3 */
+const TRAN_SPEED_MULT: [u32; 16] = [0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80];
+
+impl Csd {
+    /** Decode the 16 bytes of the CSD register. Returns `None` for unknown structure
+     * versions (including SDUC cards, which can't be addressed in SPI mode). */
+    pub fn decode(raw: &[u8; 16]) -> Option<Csd> {
+        let read_bl_len = u32::from(raw[5] & 0x0F);
+        let capacity = match raw[0] >> 6 {
+            0 => {
+                let c_size = (u64::from(raw[6] & 0x03) << 10)
+                    | (u64::from(raw[7]) << 2)
+                    | u64::from(raw[8] >> 6);
+                let c_size_mult = (u32::from(raw[9] & 0x03) << 1) | u32::from(raw[10] >> 7);
+                (c_size + 1) << (c_size_mult + 2 + read_bl_len)
+            }
+            1 => {
+                let c_size = (u64::from(raw[7] & 0x3F) << 16)
+                    | (u64::from(raw[8]) << 8)
+                    | u64::from(raw[9]);
+                (c_size + 1) * 512 * 1024
+            }
+            _ => return None,
+        };
+        let unit = [100_000u32, 1_000_000, 10_000_000, 100_000_000][usize::from(raw[3] & 0x03)];
+        let sector_size = (u32::from(raw[10] & 0x3F) << 1) | u32::from(raw[11] >> 7);
+        let write_bl_len = (u32::from(raw[12] & 0x03) << 2) | u32::from(raw[13] >> 6);
+        Some(Csd {
+            version: (raw[0] >> 6) + 1,
+            capacity,
+            max_clock: TRAN_SPEED_MULT[usize::from((raw[3] >> 3) & 0x0F)] * (unit / 10),
+            command_classes: (u16::from(raw[4]) << 4) | u16::from(raw[5] >> 4),
+            erase_single_block: (raw[10] & 0x40) != 0,
+            erase_sector_blocks: ((sector_size + 1) << write_bl_len) / 512,
+            write_protected: (raw[14] & 0x30) != 0,
+        })
+    }
+
+    /** Whether the card supports switch functions (CMD6, command class 10) */
+    pub fn supports_switch(&self) -> bool {
+        (self.command_classes & (1 << 10)) != 0
+    }
+}
+
+/** Card Identification */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cid {
+    /** Manufacturer ID, assigned by the SD association */
+    pub manufacturer_id: u8,
+    /** OEM/application ID, two ASCII characters */
+    pub oem_id: [u8; 2],
+    /** Product name, five ASCII characters */
+    pub product_name: [u8; 5],
+    /** Product revision, major and minor */
+    pub revision: (u8, u8),
+    /** Product serial number */
+    pub serial: u32,
+    /** Manufacturing date */
+    pub year: u16,
+    pub month: u8,
+}
+
+impl Cid {
+    /** Decode the 16 bytes of the CID register */
+    pub fn decode(raw: &[u8; 16]) -> Cid {
+        Cid {
+            manufacturer_id: raw[0],
+            oem_id: [raw[1], raw[2]],
+            product_name: [raw[3], raw[4], raw[5], raw[6], raw[7]],
+            revision: (raw[8] >> 4, raw[8] & 0x0F),
+            serial: u32::from_be_bytes([raw[9], raw[10], raw[11], raw[12]]),
+            year: 2000 + ((u16::from(raw[13] & 0x0F) << 4) | u16::from(raw[14] >> 4)),
+            month: raw[14] & 0x0F,
+        }
+    }
+
+    /** OEM/application ID as string, or "?" if it isn't ASCII */
+    pub fn oem(&self) -> &str {
+        ascii_str(&self.oem_id)
+    }
+
+    /** Product name as string, or "?" if it isn't ASCII */
+    pub fn product(&self) -> &str {
+        ascii_str(&self.product_name)
+    }
+
+    /** Name of the manufacturer, for some well-known manufacturer IDs. The list of IDs isn't
+     * published, these are collected from cards in the wild. */
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        Some(match self.manufacturer_id {
+            0x01 => "Panasonic",
+            0x02 => "Toshiba",
+            0x03 => "SanDisk",
+            0x1B => "Samsung",
+            0x1D => "ADATA",
+            0x27 => "Phison",
+            0x28 => "Lexar",
+            0x31 => "Silicon Power",
+            0x41 => "Kingston",
+            0x74 => "Transcend",
+            0x76 => "Patriot",
+            0x82 => "Sony",
+            _ => return None,
+        })
+    }
+}
+
+fn ascii_str(bytes: &[u8]) -> &str {
+    match str::from_utf8(bytes) {
+        Ok(s) if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') => s,
+        _ => "?",
+    }
+}
+
+/** SD Card Configuration Register */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Scr {
+    /** Physical layer specification version, major and minor, for example (3, 0) */
+    pub spec_version: (u8, u8),
+    /** Erased blocks read as all ones (otherwise all zeros) */
+    pub erased_ones: bool,
+    /** Security specification version (0: none, 2: SDSC, 3: SDHC, 4: SDXC) */
+    pub security: u8,
+    /** Supported bus widths (bit 0: 1 bit, bit 2: 4 bits) */
+    pub bus_widths: u8,
+    /** Support bits for optional commands (bit 0: CMD20, bit 1: CMD23, ...) */
+    pub cmd_support: u8,
+}
+
+impl Scr {
+    /** Decode the 8 bytes of the SCR register */
+    pub fn decode(raw: &[u8; 8]) -> Scr {
+        let sd_spec = raw[0] & 0x0F;
+        let sd_spec3 = (raw[2] & 0x80) != 0;
+        let sd_spec4 = (raw[2] & 0x04) != 0;
+        let sd_specx = ((raw[2] & 0x03) << 2) | (raw[3] >> 6);
+        let spec_version = match (sd_spec, sd_spec3, sd_spec4, sd_specx) {
+            (0, _, _, _) => (1, 0),
+            (1, _, _, _) => (1, 10),
+            (2, true, _, x) if x > 0 => (4 + x, 0),
+            (2, true, true, _) => (4, 0),
+            (2, true, false, _) => (3, 0),
+            _ => (2, 0),
+        };
+        Scr {
+            spec_version,
+            erased_ones: (raw[1] & 0x80) != 0,
+            security: (raw[1] >> 4) & 0x07,
+            bus_widths: raw[1] & 0x0F,
+            cmd_support: raw[3] & 0x0F,
+        }
+    }
+
+    /** Whether the card supports switch functions (CMD6), which came with version 1.10 */
+    pub fn supports_switch(&self) -> bool {
+        self.spec_version >= (1, 10)
+    }
+}
+
+/** SD Status register (ACMD13) */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SdStatus {
+    /** Current bus width in bits */
+    pub bus_width: u8,
+    pub secured_mode: bool,
+    pub card_type: u16,
+    /** Size of the protected area in bytes */
+    pub protected_area: u32,
+    /** Speed class: 0 (not defined), 2, 4, 6 or 10, minimum sequential write speed in MB/s */
+    pub speed_class: u8,
+    /** UHS speed grade: 0, 1 (10 MB/s) or 3 (30 MB/s) */
+    pub uhs_speed_grade: u8,
+    /** Video speed class in MB/s, 0 if not supported */
+    pub video_speed_class: u8,
+    /** Allocation unit size in bytes, 0 if not defined */
+    pub au_size: u32,
+    /** Number of allocation units for which `erase_timeout` is given */
+    pub erase_size: u16,
+    /** Timeout in seconds for erasing `erase_size` allocation units, plus `erase_offset` */
+    pub erase_timeout: u8,
+    pub erase_offset: u8,
+}
+
+/** AU_SIZE values in KiB */
+const AU_SIZE_KB: [u32; 16] = [
+    0, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 12288, 16384, 24576, 32768, 65536,
+];
+
+impl SdStatus {
+    /** Decode the 64 bytes of the SD status */
+    pub fn decode(raw: &[u8; 64]) -> SdStatus {
+        SdStatus {
+            bus_width: if (raw[0] >> 6) == 2 { 4 } else { 1 },
+            secured_mode: (raw[0] & 0x20) != 0,
+            card_type: u16::from_be_bytes([raw[2], raw[3]]),
+            protected_area: u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]),
+            speed_class: match raw[8] {
+                1 => 2,
+                2 => 4,
+                3 => 6,
+                4 => 10,
+                _ => 0,
+            },
+            uhs_speed_grade: raw[14] >> 4,
+            video_speed_class: raw[15],
+            au_size: AU_SIZE_KB[usize::from(raw[10] >> 4)] * 1024,
+            erase_size: u16::from_be_bytes([raw[11], raw[12]]),
+            erase_timeout: raw[13] >> 2,
+            erase_offset: raw[13] & 0x03,
+        }
+    }
+}
+
+/** CMD6 argument: check (mode 0) or switch (mode 1) function group 1 to high speed, and keep
+ * the other groups unchanged */
+pub(crate) const SWITCH_CHECK_HIGH_SPEED: u32 = 0x00FF_FFF1;
+pub(crate) const SWITCH_HIGH_SPEED: u32 = 0x80FF_FFF1;
+
+/** Whether a switch function status reports support for high speed in function group 1 */
+pub(crate) fn switch_supports_high_speed(status: &[u8; 64]) -> bool {
+    (status[13] & 0x02) != 0
+}
+
+/** Function selected in group 1 by a switch function status (0xF: error) */
+pub(crate) fn switch_selected(status: &[u8; 64]) -> u8 {
+    status[16] & 0x0F
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csd() {
+        /* SDHC card, 16 GB */
+        let csd = Csd::decode(&[
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, 0x00, 0x76, 0x93, 0x7f, 0x80, 0x0a, 0x40,
+            0x00, 0x8b,
+        ])
+        .unwrap();
+        assert_eq!(csd.version, 2);
+        assert_eq!(csd.capacity, 30356 * 512 * 1024);
+        assert_eq!(csd.max_clock, 25_000_000);
+        assert_eq!(csd.command_classes, 0x5b5);
+        assert!(csd.supports_switch());
+        assert!(csd.erase_single_block);
+        assert_eq!(csd.erase_sector_blocks, 128);
+        assert!(!csd.write_protected);
+
+        /* Standard capacity card, 1024-byte blocks, C_SIZE 3839, C_SIZE_MULT 7 */
+        let csd = Csd::decode(&[
+            0x00, 0x26, 0x00, 0x5a, 0x5f, 0x5a, 0x83, 0xbf, 0xff, 0xff, 0xff, 0xff, 0x92, 0x80,
+            0x10, 0x01,
+        ])
+        .unwrap();
+        assert_eq!(csd.version, 1);
+        assert_eq!(csd.capacity, 3840 * 512 * 1024);
+        assert_eq!(csd.max_clock, 50_000_000);
+        assert_eq!(csd.erase_sector_blocks, 256);
+        assert!(csd.write_protected);
+
+        let mut raw = [0u8; 16];
+        raw[0] = 0x80;
+        assert_eq!(Csd::decode(&raw), None);
+    }
+
+    #[test]
+    fn test_cid() {
+        let cid = Cid::decode(&[
+            0x03, b'S', b'D', b'S', b'U', b'1', b'6', b'G', 0x80, 0x12, 0x34, 0x56, 0x78, 0x01,
+            0x03, 0x01,
+        ]);
+        assert_eq!(cid.manufacturer(), Some("SanDisk"));
+        assert_eq!(cid.oem(), "SD");
+        assert_eq!(cid.product(), "SU16G");
+        assert_eq!(cid.revision, (8, 0));
+        assert_eq!(cid.serial, 0x1234_5678);
+        assert_eq!((cid.year, cid.month), (2016, 3));
+
+        let cid = Cid::decode(&[0xEE, 0, 0, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(cid.manufacturer(), None);
+        assert_eq!(cid.product(), "?");
+    }
+
+    #[test]
+    fn test_scr() {
+        let scr = Scr::decode(&[0x02, 0x35, 0x80, 0x00, 0, 0, 0, 0]);
+        assert_eq!(scr.spec_version, (3, 0));
+        assert_eq!(scr.security, 3);
+        assert_eq!(scr.bus_widths, 5);
+        assert!(!scr.erased_ones);
+        assert!(scr.supports_switch());
+
+        let scr = Scr::decode(&[0x02, 0xb5, 0x84, 0x83, 0, 0, 0, 0]);
+        assert_eq!(scr.spec_version, (6, 0));
+        assert_eq!(scr.cmd_support, 3);
+        assert!(scr.erased_ones);
+
+        assert_eq!(Scr::decode(&[0x01, 0x25, 0, 0, 0, 0, 0, 0]).spec_version, (1, 10));
+        assert!(!Scr::decode(&[0x00, 0x25, 0, 0, 0, 0, 0, 0]).supports_switch());
+    }
+
+    #[test]
+    fn test_sd_status() {
+        let mut raw = [0u8; 64];
+        raw[..16].copy_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x04, 0x00, 0x90, 0x00, 0x0f, 0x29,
+            0x10, 0x0a,
+        ]);
+        let status = SdStatus::decode(&raw);
+        assert_eq!(status.bus_width, 1);
+        assert_eq!(status.protected_area, 0x10000);
+        assert_eq!(status.speed_class, 10);
+        assert_eq!(status.uhs_speed_grade, 1);
+        assert_eq!(status.video_speed_class, 10);
+        assert_eq!(status.au_size, 4 * 1024 * 1024);
+        assert_eq!(status.erase_size, 15);
+        assert_eq!(status.erase_timeout, 10);
+        assert_eq!(status.erase_offset, 1);
+    }
+
+    #[test]
+    fn test_switch_status() {
+        let mut status = [0u8; 64];
+        status[13] = 0x03;
+        status[16] = 0x01;
+        assert!(switch_supports_high_speed(&status));
+        assert_eq!(switch_selected(&status), 1);
+        status[13] = 0x01;
+        status[16] = 0x0F;
+        assert!(!switch_supports_high_speed(&status));
+        assert_eq!(switch_selected(&status), 0xF);
+    }
+}
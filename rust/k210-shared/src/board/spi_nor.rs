@@ -0,0 +1,429 @@
+//! SPI NOR flash, such as the on-board flash on SPI3
+//!
+//! The flash is identified with its JEDEC ID, and its parameters (size, erase types, fast read
+//! instructions) are read from SFDP. Reads use quad or dual output fast read when the flash
+//! supports it. Only 3-byte addresses are used, so at most the first 16 MiB are accessible.
+use core::cell::Cell;
+
+use crate::soc::dmac::{dma_channel, DMAC};
+use crate::soc::sleep::usleep;
+use crate::soc::spi::{aitm, frame_format, tmod, work_mode, MAX_RX_FRAMES, SPI};
+
+pub mod sfdp;
+
+use sfdp::{BasicParams, EraseType, FastRead, ParameterHeader, QuadEnable};
+
+/** SPI clock rate for all operations (Hz) */
+const FLASH_CLOCK: u32 = 25_000_000;
+/** Longest instruction sent with `command`: page program with address and a full page */
+const MAX_COMMAND_LEN: usize = 4 + 256;
+/** Largest flash size that 3-byte addresses can reach */
+const MAX_SIZE: u32 = 1 << 24;
+
+/** Instructions */
+const CMD_WRITE_STATUS: u8 = 0x01;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_WRITE_STATUS2: u8 = 0x31;
+const CMD_READ_STATUS2: u8 = 0x35;
+const CMD_READ_SFDP: u8 = 0x5A;
+const CMD_READ_JEDEC_ID: u8 = 0x9F;
+const CMD_CHIP_ERASE: u8 = 0xC7;
+
+/** Status register 1 bits */
+const STATUS_BUSY: u8 = 0x01;
+
+/** Timeouts (us) */
+const TIMEOUT_STATUS: usize = 20_000;
+const TIMEOUT_PROGRAM: usize = 10_000;
+const TIMEOUT_ERASE: usize = 2_000_000;
+const TIMEOUT_CHIP_ERASE: usize = 400_000_000;
+
+/** Erase types assumed for flash without SFDP */
+const DEFAULT_ERASE_TYPES: [Option<EraseType>; 4] = [
+    Some(EraseType { opcode: 0x20, size: 4096 }),
+    Some(EraseType { opcode: 0xD8, size: 65536 }),
+    None,
+    None,
+];
+
+/** Errors for flash operations */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlashError {
+    /** No flash answered to the JEDEC ID instruction, or `init` wasn't called */
+    NoDevice,
+    /** Flash stayed busy for too long */
+    Timeout,
+    /** Address range beyond the end of the flash */
+    OutOfRange,
+    /** Erase range not aligned to the smallest erase size */
+    Alignment,
+}
+
+/** How data is read */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadMode {
+    /** Read instruction, everything on one line */
+    Standard,
+    /** Fast read with data on two lines (1-1-2) */
+    Dual(FastRead),
+    /** Fast read with data on four lines (1-1-4) */
+    Quad(FastRead),
+}
+
+/** Flash identification and parameters, known after `init` */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FlashInfo {
+    /** Manufacturer ID, memory type and capacity code */
+    pub jedec_id: [u8; 3],
+    /** Accessible size in bytes */
+    pub size: u32,
+    /** Page size in bytes for programming */
+    pub page_size: u32,
+    /** Erase types, unused ones `None` */
+    pub erase_types: [Option<EraseType>; 4],
+    pub read_mode: ReadMode,
+    /** Basic flash parameters, if the flash supports SFDP */
+    pub params: Option<BasicParams>,
+}
+
+impl FlashInfo {
+    /** Size of the smallest erase type */
+    pub fn sector_size(&self) -> u32 {
+        self.erase_types.iter().flatten().map(|e| e.size).min().unwrap_or(4096)
+    }
+}
+
+pub struct SPINorFlash<'a, SPI> {
+    spi: SPI,
+    spi_cs: u32,
+    dmac: &'a DMAC,
+    channel: dma_channel,
+    info: Cell<Option<FlashInfo>>,
+}
+
+impl<'a, X: SPI> SPINorFlash<'a, X> {
+    /** Flash on hardware SS line `spi_cs` of `spi`. For the on-board flash, this is SPI3 and
+     * SS line 0. */
+    pub fn new(spi: X, spi_cs: u32, dmac: &'a DMAC, channel: dma_channel) -> Self {
+        Self {
+            spi,
+            spi_cs,
+            dmac,
+            channel,
+            info: Cell::new(None),
+        }
+    }
+
+    /** Configure the controller for standard mode frames of `data_bits` */
+    fn configure_standard(&self, data_bits: u8) {
+        self.spi.configure(
+            work_mode::MODE0,
+            frame_format::STANDARD,
+            data_bits,
+            0, /* endian */
+            0, /*instruction length*/
+            0, /*address length*/
+            0, /*wait cycles*/
+            aitm::STANDARD,
+            tmod::TRANS,
+        );
+    }
+
+    /** Configure the controller for a dual or quad fast read with `data_bits` frames, with
+     * instruction and address sent on one line */
+    fn configure_fast_read(&self, format: frame_format, read: FastRead, data_bits: u8) {
+        self.spi.configure(
+            work_mode::MODE0,
+            format,
+            data_bits,
+            0, /* endian */
+            8, /*instruction length*/
+            24, /*address length*/
+            read.wait_cycles,
+            aitm::STANDARD,
+            tmod::RECV,
+        );
+    }
+
+    /** Send an instruction with optional data, nothing is received. This goes through DMA:
+     * the controller deasserts SS as soon as its transmit FIFO runs empty, which silently ends
+     * a page program if the CPU doesn't keep up. */
+    fn command(&self, data: &[u8]) {
+        let mut frames = [0u32; MAX_COMMAND_LEN];
+        for (frame, &byte) in frames.iter_mut().zip(data) {
+            *frame = byte.into();
+        }
+        self.configure_standard(8);
+        self.spi.send_data_dma(self.dmac, self.channel, self.spi_cs, &frames[..data.len()]);
+    }
+
+    /** Send an instruction (at most 32 bytes including address and dummy bytes), then
+     * receive `rx` */
+    fn command_read(&self, command: &[u8], rx: &mut [u8]) {
+        self.configure_standard(8);
+        self.spi.recv_data_command(self.spi_cs, command, rx);
+    }
+
+    fn read_status(&self, instruction: u8) -> u8 {
+        let mut status = [0u8];
+        self.command_read(&[instruction], &mut status);
+        status[0]
+    }
+
+    fn write_enable(&self) {
+        self.command(&[CMD_WRITE_ENABLE]);
+    }
+
+    /** Wait for a program or erase to finish */
+    fn wait_busy(&self, timeout_us: usize) -> Result<(), FlashError> {
+        let mut waited = 0;
+        while (self.read_status(CMD_READ_STATUS) & STATUS_BUSY) != 0 {
+            if waited >= timeout_us {
+                return Err(FlashError::Timeout);
+            }
+            usleep(10);
+            waited += 10;
+        }
+        Ok(())
+    }
+
+    fn read_jedec_id(&self) -> [u8; 3] {
+        let mut id = [0u8; 3];
+        self.command_read(&[CMD_READ_JEDEC_ID], &mut id);
+        id
+    }
+
+    /** Read from the SFDP area (3-byte address, 8 dummy cycles) */
+    fn read_sfdp(&self, address: u32, buf: &mut [u8]) {
+        let a = address.to_be_bytes();
+        self.command_read(&[CMD_READ_SFDP, a[1], a[2], a[3], 0], buf);
+    }
+
+    /** Find and read the basic flash parameter table */
+    fn read_basic_params(&self) -> Option<BasicParams> {
+        let mut header = [0u8; 8];
+        self.read_sfdp(0, &mut header);
+        let num_headers = sfdp::decode_header(&header)?;
+        // use the newest JEDEC basic table with a known major version
+        let mut basic: Option<ParameterHeader> = None;
+        for i in 0..num_headers {
+            self.read_sfdp(8 + 8 * i as u32, &mut header);
+            let param = ParameterHeader::decode(&header);
+            if param.id == sfdp::BASIC_PARAMS_ID && param.version.0 == 1
+                && basic.is_none_or(|b| param.version > b.version) {
+                basic = Some(param);
+            }
+        }
+        let basic = basic?;
+        let len = basic.len.min(sfdp::BASIC_PARAMS_LEN);
+        let mut raw = [0u8; 4 * sfdp::BASIC_PARAMS_LEN];
+        self.read_sfdp(basic.pointer, &mut raw[..4 * len]);
+        let mut dwords = [0u32; sfdp::BASIC_PARAMS_LEN];
+        for (dword, bytes) in dwords.iter_mut().zip(raw[..4 * len].chunks_exact(4)) {
+            *dword = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        BasicParams::decode(&dwords[..len])
+    }
+
+    /** Set the quad enable bit, as described by `method`. Returns whether quad mode can be
+     * used. */
+    fn quad_enable(&self, method: QuadEnable) -> Result<bool, FlashError> {
+        match method {
+            QuadEnable::NotNeeded => return Ok(true),
+            QuadEnable::Status2Bit1 => {
+                let status1 = self.read_status(CMD_READ_STATUS);
+                let status2 = self.read_status(CMD_READ_STATUS2);
+                if (status2 & 0x02) == 0 {
+                    self.write_enable();
+                    self.command(&[CMD_WRITE_STATUS, status1, status2 | 0x02]);
+                }
+            }
+            QuadEnable::Status1Bit6 => {
+                let status1 = self.read_status(CMD_READ_STATUS);
+                if (status1 & 0x40) == 0 {
+                    self.write_enable();
+                    self.command(&[CMD_WRITE_STATUS, status1 | 0x40]);
+                }
+            }
+            QuadEnable::Status2Bit1Separate => {
+                let status2 = self.read_status(CMD_READ_STATUS2);
+                if (status2 & 0x02) == 0 {
+                    self.write_enable();
+                    self.command(&[CMD_WRITE_STATUS2, status2 | 0x02]);
+                }
+            }
+            QuadEnable::Other(_) => return Ok(false),
+        }
+        self.wait_busy(TIMEOUT_STATUS)?;
+        Ok(match method {
+            QuadEnable::Status1Bit6 => (self.read_status(CMD_READ_STATUS) & 0x40) != 0,
+            _ => (self.read_status(CMD_READ_STATUS2) & 0x02) != 0,
+        })
+    }
+
+    /** Identify the flash and read its parameters, and enable quad mode if the flash supports
+     * it. */
+    pub fn init(&self) -> Result<FlashInfo, FlashError> {
+        self.info.set(None);
+        self.spi.set_clk_rate(FLASH_CLOCK);
+        let jedec_id = self.read_jedec_id();
+        if jedec_id == [0x00; 3] || jedec_id == [0xFF; 3] {
+            return Err(FlashError::NoDevice);
+        }
+        self.wait_busy(TIMEOUT_CHIP_ERASE)?;
+
+        let params = self.read_basic_params();
+        let info = if let Some(params) = params {
+            let read_mode = match (params.read_1_1_4, params.read_1_1_2) {
+                (Some(read), _) if self.quad_enable(params.quad_enable)? => ReadMode::Quad(read),
+                (_, Some(read)) => ReadMode::Dual(read),
+                _ => ReadMode::Standard,
+            };
+            FlashInfo {
+                jedec_id,
+                size: params.size.min(u64::from(MAX_SIZE)) as u32,
+                page_size: params.page_size,
+                erase_types: params.erase_types,
+                read_mode,
+                params: Some(params),
+            }
+        } else {
+            // no SFDP: most manufacturers encode the size as a power of two in the last byte
+            FlashInfo {
+                jedec_id,
+                size: 1u32.checked_shl(u32::from(jedec_id[2])).unwrap_or(MAX_SIZE).min(MAX_SIZE),
+                page_size: 256,
+                erase_types: DEFAULT_ERASE_TYPES,
+                read_mode: ReadMode::Standard,
+                params: None,
+            }
+        };
+        self.info.set(Some(info));
+        Ok(info)
+    }
+
+    /** Flash information, if initialized */
+    pub fn info(&self) -> Option<FlashInfo> {
+        self.info.get()
+    }
+
+    /** Check that `len` bytes at `address` are on the flash */
+    fn check_range(&self, address: u32, len: usize) -> Result<FlashInfo, FlashError> {
+        let info = self.info.get().ok_or(FlashError::NoDevice)?;
+        if u64::from(address) + len as u64 > u64::from(info.size) {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(info)
+    }
+
+    /** Read `buf.len()` bytes starting at `address` */
+    pub fn read(&self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let info = self.check_range(address, buf.len())?;
+        let mut address = address;
+        for chunk in buf.chunks_mut(MAX_RX_FRAMES) {
+            match info.read_mode {
+                ReadMode::Standard => {
+                    let a = address.to_be_bytes();
+                    self.command_read(&[CMD_READ, a[1], a[2], a[3]], chunk);
+                }
+                ReadMode::Dual(read) => {
+                    self.configure_fast_read(frame_format::DUAL, read, 8);
+                    self.spi.recv_data_enhanced(self.spi_cs, read.opcode.into(), address, chunk);
+                }
+                ReadMode::Quad(read) => {
+                    self.configure_fast_read(frame_format::QUAD, read, 8);
+                    self.spi.recv_data_enhanced(self.spi_cs, read.opcode.into(), address, chunk);
+                }
+            }
+            address += chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /** Read `4 * buf.len()` bytes starting at `address` using DMA, in 32-bit frames. The words
+     * are stored so that the buffer holds the flash contents in memory order. Uses the fast
+     * read mode; flash that only supports standard reads is read without DMA. */
+    pub fn read_dma(&self, address: u32, buf: &mut [u32]) -> Result<(), FlashError> {
+        let info = self.check_range(address, 4 * buf.len())?;
+        let (format, read) = match info.read_mode {
+            ReadMode::Standard => {
+                let bytes = unsafe {
+                    core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, 4 * buf.len())
+                };
+                return self.read(address, bytes);
+            }
+            ReadMode::Dual(read) => (frame_format::DUAL, read),
+            ReadMode::Quad(read) => (frame_format::QUAD, read),
+        };
+        let mut address = address;
+        for chunk in buf.chunks_mut(MAX_RX_FRAMES) {
+            self.configure_fast_read(format, read, 32);
+            self.spi.recv_data_enhanced_dma(self.dmac, self.channel, self.spi_cs,
+                                            read.opcode.into(), address, chunk);
+            // the first byte received ends up in the most significant byte of a frame
+            for word in chunk.iter_mut() {
+                *word = u32::from_be(*word);
+            }
+            address += 4 * chunk.len() as u32;
+        }
+        Ok(())
+    }
+
+    /** Program `data` starting at `address`. The area must have been erased, programming
+     * can only clear bits. */
+    pub fn program(&self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let info = self.check_range(address, data.len())?;
+        let page_size = info.page_size.min(256) as usize;
+        let mut address = address;
+        let mut data = data;
+        let mut frame = [0u8; MAX_COMMAND_LEN];
+        while !data.is_empty() {
+            // a program wraps around at the end of a page, so never cross one
+            let len = (page_size - (address as usize % page_size)).min(data.len());
+            let a = address.to_be_bytes();
+            frame[..4].copy_from_slice(&[CMD_PAGE_PROGRAM, a[1], a[2], a[3]]);
+            frame[4..4 + len].copy_from_slice(&data[..len]);
+            self.write_enable();
+            self.command(&frame[..4 + len]);
+            self.wait_busy(TIMEOUT_PROGRAM)?;
+            address += len as u32;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /** Erase `len` bytes starting at `address`, both aligned to the smallest erase size,
+     * using the largest erase type that fits each part. Erased bytes read as 0xFF. */
+    pub fn erase(&self, address: u32, len: u32) -> Result<(), FlashError> {
+        let info = self.check_range(address, len as usize)?;
+        let sector_size = info.sector_size();
+        if (address % sector_size) != 0 || (len % sector_size) != 0 {
+            return Err(FlashError::Alignment);
+        }
+        let mut address = address;
+        let end = address + len;
+        while address < end {
+            let erase = info.erase_types.iter().flatten()
+                .filter(|e| (address % e.size) == 0 && e.size <= end - address)
+                .max_by_key(|e| e.size)
+                .ok_or(FlashError::Alignment)?;
+            let a = address.to_be_bytes();
+            self.write_enable();
+            self.command(&[erase.opcode, a[1], a[2], a[3]]);
+            self.wait_busy(TIMEOUT_ERASE)?;
+            address += erase.size;
+        }
+        Ok(())
+    }
+
+    /** Erase the whole flash. This can take minutes. */
+    pub fn erase_chip(&self) -> Result<(), FlashError> {
+        self.info.get().ok_or(FlashError::NoDevice)?;
+        self.write_enable();
+        self.command(&[CMD_CHIP_ERASE]);
+        self.wait_busy(TIMEOUT_CHIP_ERASE)
+    }
+}
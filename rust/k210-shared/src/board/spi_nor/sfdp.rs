@@ -0,0 +1,240 @@
+//! Decoding of the Serial Flash Discoverable Parameters (JESD216)
+
+/** "SFDP" signature at the start of the SFDP area */
+const SIGNATURE: u32 = 0x5044_4653;
+/** Parameter ID of the JEDEC basic flash parameter table */
+pub const BASIC_PARAMS_ID: u16 = 0xFF00;
+/** Number of dwords of the basic flash parameter table that are decoded */
+pub const BASIC_PARAMS_LEN: usize = 16;
+
+/** Decode the 8-byte SFDP header, returning the number of parameter headers that follow it,
+ * or `None` if the signature doesn't match. */
+pub fn decode_header(raw: &[u8; 8]) -> Option<usize> {
+    if u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) != SIGNATURE {
+        return None;
+    }
+    Some(usize::from(raw[6]) + 1)
+}
+
+/** Parameter header, describing one parameter table */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParameterHeader {
+    pub id: u16,
+    /** Table version, major and minor */
+    pub version: (u8, u8),
+    /** Table length in dwords */
+    pub len: usize,
+    /** Address of the table in the SFDP area */
+    pub pointer: u32,
+}
+
+impl ParameterHeader {
+    /** Decode an 8-byte parameter header */
+    pub fn decode(raw: &[u8; 8]) -> ParameterHeader {
+        ParameterHeader {
+            id: u16::from_le_bytes([raw[0], raw[7]]),
+            version: (raw[2], raw[1]),
+            len: usize::from(raw[3]),
+            pointer: u32::from_le_bytes([raw[4], raw[5], raw[6], 0]),
+        }
+    }
+}
+
+/** Fast read instruction */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FastRead {
+    pub opcode: u8,
+    /** Dummy and mode clock cycles between address and data */
+    pub wait_cycles: u8,
+}
+
+impl FastRead {
+    /** Decode the 16-bit fast read field of DWORD3 or DWORD4 */
+    fn decode(field: u32) -> Option<FastRead> {
+        let opcode = (field >> 8) as u8;
+        if opcode == 0 || opcode == 0xFF {
+            return None;
+        }
+        Some(FastRead {
+            opcode,
+            wait_cycles: ((field & 0x1F) + ((field >> 5) & 0x07)) as u8,
+        })
+    }
+}
+
+/** Erase instruction and the size it erases */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EraseType {
+    pub opcode: u8,
+    /** Size in bytes */
+    pub size: u32,
+}
+
+impl EraseType {
+    /** Decode the 16-bit erase type field of DWORD8 or DWORD9 */
+    fn decode(field: u32) -> Option<EraseType> {
+        let exponent = field & 0xFF;
+        if exponent == 0 || exponent >= 32 {
+            return None;
+        }
+        Some(EraseType { opcode: (field >> 8) as u8, size: 1 << exponent })
+    }
+}
+
+/** How the quad enable bit is set (Quad Enable Requirements, DWORD15 bits 22:20) */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QuadEnable {
+    /** No quad enable bit, or it is not known (tables before JESD216A) */
+    NotNeeded,
+    /** Bit 1 of status register 2, read with 0x35 and written together with status register
+     * 1 with 0x01 */
+    Status2Bit1,
+    /** Bit 6 of status register 1 */
+    Status1Bit6,
+    /** Bit 1 of status register 2, read with 0x35 and written with 0x31 */
+    Status2Bit1Separate,
+    /** Other methods, not supported */
+    Other(u8),
+}
+
+impl QuadEnable {
+    fn decode(qer: u32) -> QuadEnable {
+        match qer {
+            0 => QuadEnable::NotNeeded,
+            1 | 4 | 5 => QuadEnable::Status2Bit1,
+            2 => QuadEnable::Status1Bit6,
+            6 => QuadEnable::Status2Bit1Separate,
+            _ => QuadEnable::Other(qer as u8),
+        }
+    }
+}
+
+/** JEDEC basic flash parameters */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BasicParams {
+    /** Flash size in bytes */
+    pub size: u64,
+    /** Whether the flash can be addressed with 3-byte addresses */
+    pub three_byte_address: bool,
+    /** 4 KiB erase opcode, if uniform 4 KiB erase is supported */
+    pub erase_4k: Option<u8>,
+    /** Erase types, unused ones `None` */
+    pub erase_types: [Option<EraseType>; 4],
+    /** 1-1-2 (dual output) fast read */
+    pub read_1_1_2: Option<FastRead>,
+    /** 1-1-4 (quad output) fast read */
+    pub read_1_1_4: Option<FastRead>,
+    /** Page size in bytes for programming */
+    pub page_size: u32,
+    pub quad_enable: QuadEnable,
+}
+
+impl BasicParams {
+    /** Decode the dwords of the basic flash parameter table. The first nine dwords (JESD216)
+     * are required, the page size and quad enable method (JESD216A) are decoded if present.
+     * Returns `None` if the table is too short. */
+    pub fn decode(dwords: &[u32]) -> Option<BasicParams> {
+        if dwords.len() < 9 {
+            return None;
+        }
+        let density = dwords[1];
+        let size = if (density & 0x8000_0000) == 0 {
+            (u64::from(density) + 1) / 8
+        } else {
+            let exponent = density & 0x7FFF_FFFF;
+            if !(3..=66).contains(&exponent) {
+                return None;
+            }
+            1 << (exponent - 3)
+        };
+        let erase_4k = if (dwords[0] & 0x03) == 0x01 {
+            Some((dwords[0] >> 8) as u8)
+        } else {
+            None
+        };
+        let page_size = match dwords.get(10) {
+            Some(dword) => 1 << ((dword >> 4) & 0x0F),
+            None => 256,
+        };
+        let quad_enable = match dwords.get(14) {
+            Some(dword) => QuadEnable::decode((dword >> 20) & 0x07),
+            None => QuadEnable::NotNeeded,
+        };
+        Some(BasicParams {
+            size,
+            three_byte_address: ((dwords[0] >> 17) & 0x03) != 0x02,
+            erase_4k,
+            erase_types: [
+                EraseType::decode(dwords[7] & 0xFFFF),
+                EraseType::decode(dwords[7] >> 16),
+                EraseType::decode(dwords[8] & 0xFFFF),
+                EraseType::decode(dwords[8] >> 16),
+            ],
+            read_1_1_2: if (dwords[0] & (1 << 16)) != 0 {
+                FastRead::decode(dwords[3] & 0xFFFF)
+            } else {
+                None
+            },
+            read_1_1_4: if (dwords[0] & (1 << 22)) != 0 {
+                FastRead::decode(dwords[2] >> 16)
+            } else {
+                None
+            },
+            page_size,
+            quad_enable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        assert_eq!(decode_header(&[b'S', b'F', b'D', b'P', 0x06, 0x01, 0x01, 0xFF]), Some(2));
+        assert_eq!(decode_header(&[0xFF; 8]), None);
+        let header = ParameterHeader::decode(&[0x00, 0x06, 0x01, 0x10, 0x80, 0x00, 0x00, 0xFF]);
+        assert_eq!(header.id, BASIC_PARAMS_ID);
+        assert_eq!(header.version, (1, 6));
+        assert_eq!(header.len, 16);
+        assert_eq!(header.pointer, 0x80);
+    }
+
+    #[test]
+    fn test_basic_params() {
+        /* W25Q128JV */
+        let dwords = [
+            0xFFF320E5, 0x07FFFFFF, 0x6B08EB44, 0xBB423B08, 0xFFFFFFFE, 0xFF00FFFF, 0xEB40FFFF,
+            0x520F200C, 0xFF00D810, 0x00A60236, 0x00EA1481, 0xA0920D06, 0x33000000, 0x0F040000,
+            0x00400000, 0x01001000,
+        ];
+        let params = BasicParams::decode(&dwords).unwrap();
+        assert_eq!(params.size, 16 * 1024 * 1024);
+        assert!(params.three_byte_address);
+        assert_eq!(params.erase_4k, Some(0x20));
+        assert_eq!(params.erase_types, [
+            Some(EraseType { opcode: 0x20, size: 4096 }),
+            Some(EraseType { opcode: 0x52, size: 32768 }),
+            Some(EraseType { opcode: 0xD8, size: 65536 }),
+            None,
+        ]);
+        assert_eq!(params.read_1_1_2, Some(FastRead { opcode: 0x3B, wait_cycles: 8 }));
+        assert_eq!(params.read_1_1_4, Some(FastRead { opcode: 0x6B, wait_cycles: 8 }));
+        assert_eq!(params.page_size, 256);
+        assert_eq!(params.quad_enable, QuadEnable::Status2Bit1);
+
+        /* JESD216 table without the later dwords, density given as a power of two */
+        let mut dwords = [0u32; 9];
+        dwords.copy_from_slice(&[
+            0xFFF120E5, 0x8000_0021, 0xFFFFFFFF, 0xFFFFFFFF, 0, 0, 0, 0x0000200C, 0,
+        ]);
+        let params = BasicParams::decode(&dwords).unwrap();
+        assert_eq!(params.size, 1 << 30);
+        assert_eq!(params.read_1_1_4, None);
+        assert_eq!(params.page_size, 256);
+        assert_eq!(params.quad_enable, QuadEnable::NotNeeded);
+
+        assert_eq!(BasicParams::decode(&dwords[..8]), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Block devices and filesystems: partition tables (MBR/GPT) and FAT16/FAT32
+pub mod fat;
+#[cfg(test)]
+pub mod file_block;
+pub mod partition;
+
+/** Size of a block in bytes. Only 512-byte blocks are supported, as used by SD cards. */
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsError {
+    /** Underlying device reported an error, or access outside the device */
+    Io,
+    /** No (supported) partition table or filesystem found */
+    NoFilesystem,
+    /** Valid filesystem, but uses a feature that is not supported (e.g. FAT12) */
+    Unsupported,
+    /** Inconsistent filesystem structure (bad cluster chain, checksum mismatch) */
+    Corrupt,
+    /** File or directory does not exist */
+    NotFound,
+    /** Path component is not a directory */
+    NotADirectory,
+    /** Tried to create a file or directory that already exists */
+    AlreadyExists,
+    /** Tried to open a directory as file */
+    IsADirectory,
+    /** File name is empty, too long or contains invalid characters */
+    InvalidName,
+    /** Tried to write to a file opened for reading only */
+    ReadOnly,
+    /** No free clusters left */
+    DiskFull,
+    /** No room for new entries in a fixed-size (FAT16 root) directory */
+    DirectoryFull,
+}
+
+/** Device that can read and write blocks of `BLOCK_SIZE` bytes. Buffers are a multiple of
+ * `BLOCK_SIZE` long, and may span multiple blocks. */
+pub trait BlockDevice {
+    /** Read `buf.len() / BLOCK_SIZE` blocks starting at `block` */
+    fn read_blocks(&self, buf: &mut [u8], block: u32) -> Result<(), ()>;
+    /** Write `buf.len() / BLOCK_SIZE` blocks starting at `block` */
+    fn write_blocks(&self, buf: &[u8], block: u32) -> Result<(), ()>;
+    /** Total number of blocks on the device */
+    fn num_blocks(&self) -> u32;
+}
+
+impl<T: BlockDevice> BlockDevice for &T {
+    fn read_blocks(&self, buf: &mut [u8], block: u32) -> Result<(), ()> {
+        (**self).read_blocks(buf, block)
+    }
+
+    fn write_blocks(&self, buf: &[u8], block: u32) -> Result<(), ()> {
+        (**self).write_blocks(buf, block)
+    }
+
+    fn num_blocks(&self) -> u32 {
+        (**self).num_blocks()
+    }
+}
+
+/** Helpers for little-endian fields in on-disk structures */
+pub(crate) fn read_u16le(data: &[u8], ofs: usize) -> u16 {
+    u16::from(data[ofs]) | (u16::from(data[ofs + 1]) << 8)
+}
+
+pub(crate) fn read_u32le(data: &[u8], ofs: usize) -> u32 {
+    u32::from(read_u16le(data, ofs)) | (u32::from(read_u16le(data, ofs + 2)) << 16)
+}
+
+pub(crate) fn write_u16le(data: &mut [u8], ofs: usize, v: u16) {
+    data[ofs] = v as u8;
+    data[ofs + 1] = (v >> 8) as u8;
+}
+
+pub(crate) fn write_u32le(data: &mut [u8], ofs: usize, v: u32) {
+    write_u16le(data, ofs, v as u16);
+    write_u16le(data, ofs + 2, (v >> 16) as u16);
+}
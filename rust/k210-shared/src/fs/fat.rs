@@ -0,0 +1,768 @@
+//! FAT16/FAT32 filesystem with long file names. No heap is needed: the filesystem keeps a cache
+//! of one FAT sector, other accesses go through buffers on the stack.
+use core::cell::{Cell, RefCell};
+
+use super::partition::is_fat_boot_sector;
+use super::{read_u16le, read_u32le, write_u32le, BlockDevice, FsError, BLOCK_SIZE};
+
+mod dir;
+mod file;
+
+pub use dir::{Dir, DirEntry};
+pub use file::{File, OpenMode, SeekFrom};
+
+/** Directory entry attributes */
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+/** Cluster counts that determine the FAT type */
+const MIN_CLUSTERS_FAT16: u32 = 4085;
+const MIN_CLUSTERS_FAT32: u32 = 65525;
+
+/** FSInfo sector signatures */
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT_OFS: usize = 488;
+const FSINFO_NEXT_FREE_OFS: usize = 492;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/** Cached sector of the (first) FAT */
+struct FatCache {
+    /** Sector within the FAT, if any loaded */
+    sector: Option<u32>,
+    dirty: bool,
+    buf: [u8; BLOCK_SIZE],
+}
+
+/** Start of a directory */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DirLoc {
+    /** Fixed-size root directory of FAT16 */
+    Root16,
+    /** Directory stored in a cluster chain (FAT32 root and all subdirectories) */
+    Chain(u32),
+}
+
+/** Mounted FAT filesystem */
+pub struct FileSystem<D> {
+    dev: D,
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    /** First sector of the first FAT */
+    fat_start: u32,
+    /** Size of one FAT in sectors */
+    fat_size: u32,
+    num_fats: u32,
+    /** FAT16 root directory sectors */
+    root_start: u32,
+    root_sectors: u32,
+    /** FAT32 root directory cluster */
+    root_cluster: u32,
+    /** First sector of cluster 2 */
+    data_start: u32,
+    /** Number of data clusters, valid cluster numbers are 2..num_clusters+2 */
+    num_clusters: u32,
+    /** FAT32 FSInfo sector, if present */
+    fs_info: Option<u32>,
+    /** FSInfo free count has been invalidated */
+    fs_info_dirty: Cell<bool>,
+    /** Where to start looking for a free cluster */
+    next_free: Cell<u32>,
+    fat_cache: RefCell<FatCache>,
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /** Mount the FAT filesystem on a device (usually a `Partition`). */
+    pub fn mount(dev: D) -> Result<Self, FsError> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.read_blocks(&mut buf, 0).map_err(|_| FsError::Io)?;
+        if buf[510] != 0x55 || buf[511] != 0xaa || !is_fat_boot_sector(&buf) {
+            return Err(FsError::NoFilesystem);
+        }
+        if usize::from(read_u16le(&buf, 11)) != BLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let sectors_per_cluster = u32::from(buf[13]);
+        let reserved = u32::from(read_u16le(&buf, 14));
+        let num_fats = u32::from(buf[16]);
+        let root_entries = u32::from(read_u16le(&buf, 17));
+        let total = match read_u16le(&buf, 19) {
+            0 => read_u32le(&buf, 32),
+            n => u32::from(n),
+        };
+        let fat_size16 = u32::from(read_u16le(&buf, 22));
+        let fat_size = if fat_size16 != 0 { fat_size16 } else { read_u32le(&buf, 36) };
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0 || fat_size == 0 {
+            return Err(FsError::Corrupt);
+        }
+        let root_sectors = (root_entries * 32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        let root_start = reserved + num_fats * fat_size;
+        let data_start = root_start + root_sectors;
+        if data_start >= total {
+            return Err(FsError::Corrupt);
+        }
+        let num_clusters = (total - data_start) / sectors_per_cluster;
+        let fat_type = if num_clusters < MIN_CLUSTERS_FAT16 {
+            return Err(FsError::Unsupported); // FAT12
+        } else if num_clusters < MIN_CLUSTERS_FAT32 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let entry_size = if fat_type == FatType::Fat16 { 2 } else { 4 };
+        if (u64::from(num_clusters) + 2) * entry_size > u64::from(fat_size) * BLOCK_SIZE as u64 {
+            return Err(FsError::Corrupt);
+        }
+
+        let mut fs = Self {
+            dev,
+            fat_type,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_size,
+            num_fats,
+            root_start,
+            root_sectors,
+            root_cluster: 0,
+            data_start,
+            num_clusters,
+            fs_info: None,
+            fs_info_dirty: Cell::new(false),
+            next_free: Cell::new(2),
+            fat_cache: RefCell::new(FatCache {
+                sector: None,
+                dirty: false,
+                buf: [0; BLOCK_SIZE],
+            }),
+        };
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 || fat_size16 != 0 {
+                return Err(FsError::Corrupt);
+            }
+            fs.root_cluster = read_u32le(&buf, 44);
+            if !fs.valid_cluster(fs.root_cluster) {
+                return Err(FsError::Corrupt);
+            }
+            let fs_info = u32::from(read_u16le(&buf, 48));
+            if fs_info != 0 && fs_info < reserved {
+                fs.read_sector(&mut buf, fs_info)?;
+                if read_u32le(&buf, 0) == FSINFO_LEAD_SIG && read_u32le(&buf, 484) == FSINFO_STRUCT_SIG {
+                    fs.fs_info = Some(fs_info);
+                    let hint = read_u32le(&buf, FSINFO_NEXT_FREE_OFS);
+                    if fs.valid_cluster(hint) {
+                        fs.next_free.set(hint);
+                    }
+                }
+            }
+        } else if root_entries == 0 {
+            return Err(FsError::Corrupt);
+        }
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /** Size of a cluster in bytes */
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    /** Number of free clusters. This scans the whole FAT. */
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        let mut count = 0;
+        for c in 2..self.num_clusters + 2 {
+            if self.fat_entry(c)? == 0 {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /** Write out pending FAT changes. File operations that modify the filesystem do this
+     * before returning, so it only needs to be called after using lower-level functions. */
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.flush_fat(&mut self.fat_cache.borrow_mut())
+    }
+
+    /** Unmount, giving back the device */
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    pub(crate) fn read_sector(&self, buf: &mut [u8], sector: u32) -> Result<(), FsError> {
+        self.dev.read_blocks(buf, sector).map_err(|_| FsError::Io)
+    }
+
+    pub(crate) fn write_sector(&self, buf: &[u8], sector: u32) -> Result<(), FsError> {
+        self.dev.write_blocks(buf, sector).map_err(|_| FsError::Io)
+    }
+
+    pub(crate) fn root(&self) -> DirLoc {
+        match self.fat_type {
+            FatType::Fat16 => DirLoc::Root16,
+            FatType::Fat32 => DirLoc::Chain(self.root_cluster),
+        }
+    }
+
+    /** First sector of a cluster. Cluster numbers from disk must have gone through
+     * `check_cluster`. */
+    pub(crate) fn cluster_sector(&self, cluster: u32) -> u32 {
+        debug_assert!(self.valid_cluster(cluster));
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.num_clusters + 2
+    }
+
+    /** Check a cluster number read from disk */
+    pub(crate) fn check_cluster(&self, cluster: u32) -> Result<u32, FsError> {
+        if self.valid_cluster(cluster) {
+            Ok(cluster)
+        } else {
+            Err(FsError::Corrupt)
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn is_end_of_chain(&self, v: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => v >= 0xfff8,
+            FatType::Fat32 => v >= 0x0fff_fff8,
+        }
+    }
+
+    /** Make the FAT sector `sector` (relative to the start of the FAT) current in the cache */
+    fn load_fat(&self, cache: &mut FatCache, sector: u32) -> Result<(), FsError> {
+        if cache.sector == Some(sector) {
+            return Ok(());
+        }
+        self.flush_fat(cache)?;
+        cache.sector = None;
+        self.read_sector(&mut cache.buf, self.fat_start + sector)?;
+        cache.sector = Some(sector);
+        Ok(())
+    }
+
+    /** Write the cached FAT sector to all FAT copies, if modified */
+    fn flush_fat(&self, cache: &mut FatCache) -> Result<(), FsError> {
+        if let (Some(sector), true) = (cache.sector, cache.dirty) {
+            for i in 0..self.num_fats {
+                self.write_sector(&cache.buf, self.fat_start + i * self.fat_size + sector)?;
+            }
+            cache.dirty = false;
+        }
+        Ok(())
+    }
+
+    /** Byte offset of the FAT entry for a cluster */
+    fn fat_offset(&self, cluster: u32) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let ofs = self.fat_offset(self.check_cluster(cluster)?);
+        let cache = &mut *self.fat_cache.borrow_mut();
+        self.load_fat(cache, ofs / BLOCK_SIZE as u32)?;
+        let ofs = (ofs % BLOCK_SIZE as u32) as usize;
+        Ok(match self.fat_type {
+            FatType::Fat16 => u32::from(read_u16le(&cache.buf, ofs)),
+            FatType::Fat32 => read_u32le(&cache.buf, ofs) & 0x0fff_ffff,
+        })
+    }
+
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let ofs = self.fat_offset(self.check_cluster(cluster)?);
+        self.invalidate_fs_info()?;
+        let cache = &mut *self.fat_cache.borrow_mut();
+        self.load_fat(cache, ofs / BLOCK_SIZE as u32)?;
+        let ofs = (ofs % BLOCK_SIZE as u32) as usize;
+        match self.fat_type {
+            FatType::Fat16 => {
+                cache.buf[ofs] = value as u8;
+                cache.buf[ofs + 1] = (value >> 8) as u8;
+            }
+            FatType::Fat32 => {
+                // Upper four bits are reserved and must be preserved
+                let old = read_u32le(&cache.buf, ofs);
+                write_u32le(&mut cache.buf, ofs, (old & 0xf000_0000) | (value & 0x0fff_ffff));
+            }
+        }
+        cache.dirty = true;
+        Ok(())
+    }
+
+    /** The FSInfo free cluster count is not maintained; mark it as unknown before the first
+     * change to the FAT. */
+    fn invalidate_fs_info(&self) -> Result<(), FsError> {
+        if let (Some(sector), false) = (self.fs_info, self.fs_info_dirty.get()) {
+            let mut buf = [0u8; BLOCK_SIZE];
+            self.read_sector(&mut buf, sector)?;
+            write_u32le(&mut buf, FSINFO_FREE_COUNT_OFS, 0xffff_ffff);
+            self.write_sector(&buf, sector)?;
+            self.fs_info_dirty.set(true);
+        }
+        Ok(())
+    }
+
+    /** Next cluster in a chain, or `None` at the end of the chain */
+    pub(crate) fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let v = self.fat_entry(cluster)?;
+        if self.is_end_of_chain(v) {
+            Ok(None)
+        } else {
+            self.check_cluster(v).map(Some)
+        }
+    }
+
+    /** Allocate a cluster and append it to the chain ending in `prev`, if given. The contents
+     * of the cluster are zeroed if `zero` is set. */
+    pub(crate) fn alloc_cluster(&self, prev: Option<u32>, zero: bool) -> Result<u32, FsError> {
+        let start = self.next_free.get() - 2;
+        for i in 0..self.num_clusters {
+            let c = 2 + (start + i) % self.num_clusters;
+            if self.fat_entry(c)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(c, self.end_of_chain())?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, c)?;
+            }
+            self.next_free.set(if c + 1 < self.num_clusters + 2 { c + 1 } else { 2 });
+            if zero {
+                let buf = [0u8; BLOCK_SIZE];
+                let sector = self.cluster_sector(c);
+                for s in 0..self.sectors_per_cluster {
+                    self.write_sector(&buf, sector + s)?;
+                }
+            }
+            return Ok(c);
+        }
+        Err(FsError::DiskFull)
+    }
+
+    /** Free a cluster chain starting at `cluster` */
+    pub(crate) fn free_chain(&self, mut cluster: u32) -> Result<(), FsError> {
+        // Bound the number of steps, in case of a loop in the chain
+        for _ in 0..self.num_clusters {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            match next {
+                Some(next) => cluster = next,
+                None => return Ok(()),
+            }
+        }
+        Err(FsError::Corrupt)
+    }
+
+    /** Terminate a chain at `cluster`, freeing any clusters after it */
+    pub(crate) fn truncate_chain(&self, cluster: u32) -> Result<(), FsError> {
+        if let Some(next) = self.next_cluster(cluster)? {
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            self.free_chain(next)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::file_block::FileBlock;
+    use crate::fs::partition::find_fat;
+    use crate::fs::{write_u16le, FsError};
+    use std::vec::Vec;
+
+    /** Create an empty FAT filesystem, like mkfs.fat would. */
+    fn format<D: BlockDevice>(dev: &D, fat32: bool, sectors_per_cluster: u32, root_entries: u32) {
+        let total = dev.num_blocks();
+        let (reserved, root_entries, entry_size) = if fat32 { (32, 0, 4) } else { (1, root_entries, 2) };
+        let root_sectors = root_entries * 32 / BLOCK_SIZE as u32;
+        let mut fat_size = 1;
+        loop {
+            let clusters = (total - reserved - 2 * fat_size - root_sectors) / sectors_per_cluster;
+            let needed = ((clusters + 2) * entry_size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+            if needed <= fat_size {
+                break;
+            }
+            fat_size = needed;
+        }
+
+        let mut bs = [0u8; BLOCK_SIZE];
+        bs[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        bs[3..11].copy_from_slice(b"MSWIN4.1");
+        write_u16le(&mut bs, 11, BLOCK_SIZE as u16);
+        bs[13] = sectors_per_cluster as u8;
+        write_u16le(&mut bs, 14, reserved as u16);
+        bs[16] = 2;
+        write_u16le(&mut bs, 17, root_entries as u16);
+        bs[21] = 0xf8;
+        write_u32le(&mut bs, 32, total);
+        if fat32 {
+            write_u32le(&mut bs, 36, fat_size);
+            write_u32le(&mut bs, 44, 2);
+            write_u16le(&mut bs, 48, 1);
+            write_u16le(&mut bs, 50, 6);
+            bs[66] = 0x29;
+            bs[82..90].copy_from_slice(b"FAT32   ");
+        } else {
+            write_u16le(&mut bs, 22, fat_size as u16);
+            bs[38] = 0x29;
+            bs[54..62].copy_from_slice(b"FAT16   ");
+        }
+        bs[510] = 0x55;
+        bs[511] = 0xaa;
+        let zero = [0u8; BLOCK_SIZE];
+        for s in 0..reserved {
+            dev.write_blocks(&zero, s).unwrap();
+        }
+        dev.write_blocks(&bs, 0).unwrap();
+        if fat32 {
+            dev.write_blocks(&bs, 6).unwrap();
+            let mut info = [0u8; BLOCK_SIZE];
+            write_u32le(&mut info, 0, FSINFO_LEAD_SIG);
+            write_u32le(&mut info, 484, FSINFO_STRUCT_SIG);
+            write_u32le(&mut info, FSINFO_FREE_COUNT_OFS, 0xffff_ffff);
+            write_u32le(&mut info, FSINFO_NEXT_FREE_OFS, 3);
+            write_u32le(&mut info, 508, 0xaa55_0000);
+            dev.write_blocks(&info, 1).unwrap();
+        }
+
+        let mut fat = [0u8; BLOCK_SIZE];
+        if fat32 {
+            write_u32le(&mut fat, 0, 0x0fff_fff8);
+            write_u32le(&mut fat, 4, 0x0fff_ffff);
+            write_u32le(&mut fat, 8, 0x0fff_ffff);
+        } else {
+            write_u16le(&mut fat, 0, 0xfff8);
+            write_u16le(&mut fat, 2, 0xffff);
+        }
+        for i in 0..2 {
+            let start = reserved + i * fat_size;
+            dev.write_blocks(&fat, start).unwrap();
+            for s in 1..fat_size {
+                dev.write_blocks(&zero, start + s).unwrap();
+            }
+        }
+        // Root directory (FAT16) or first cluster (FAT32)
+        let root_start = reserved + 2 * fat_size;
+        for s in 0..core::cmp::max(root_sectors, sectors_per_cluster) {
+            dev.write_blocks(&zero, root_start + s).unwrap();
+        }
+    }
+
+    fn names<D: BlockDevice>(fs: &FileSystem<D>, path: &str) -> Vec<std::string::String> {
+        fs.read_dir(path)
+            .unwrap()
+            .map(|e| std::string::String::from(e.unwrap().name()))
+            .collect()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn read_all<D: BlockDevice>(fs: &FileSystem<D>, path: &str) -> Vec<u8> {
+        let mut f = fs.open(path, OpenMode::Read).unwrap();
+        let mut data = std::vec![0u8; f.len() as usize];
+        f.read_exact(&mut data).unwrap();
+        assert_eq!(f.read(&mut [0u8; 16]), Ok(0));
+        data
+    }
+
+    #[test]
+    fn test_fat16_files() {
+        let dev = FileBlock::temp(8192);
+        format(&dev, false, 1, 512);
+        let fs = FileSystem::mount(&dev).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat16);
+        let free = fs.free_clusters().unwrap();
+
+        // Short lower-case name: no long name entries needed
+        let mut f = fs.open("hello.txt", OpenMode::Create).unwrap();
+        assert_eq!(f.write(b"Hello, world!"), Ok(13));
+        let e = fs.metadata("/HELLO.TXT").unwrap();
+        assert_eq!((e.name(), e.short_name(), e.len()), ("hello.txt", "HELLO.TXT", 13));
+        assert_eq!(read_all(&fs, "hello.txt"), b"Hello, world!");
+
+        // Multi-cluster file written and read in odd-sized pieces
+        let data = pattern(5000);
+        let mut f = fs.open("A long file name.data", OpenMode::Create).unwrap();
+        for chunk in data.chunks(700) {
+            f.write(chunk).unwrap();
+        }
+        assert_eq!(fs.free_clusters().unwrap(), free - 1 - 10);
+        assert_eq!(read_all(&fs, "a LONG file name.DATA"), data);
+        let e = fs.metadata("A long file name.data").unwrap();
+        assert_eq!(e.short_name(), "ALONGF~1.DAT");
+        assert_eq!(read_all(&fs, "ALONGF~1.DAT"), data);
+
+        let mut f = fs.open("A long file name.data", OpenMode::Read).unwrap();
+        let mut buf = [0u8; 1200];
+        assert_eq!(f.seek(SeekFrom::Start(3000)), Ok(3000));
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[3000..4200]);
+        assert_eq!(f.seek(SeekFrom::Current(-4000)), Ok(200));
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[200..1400]);
+        assert_eq!(f.seek(SeekFrom::End(-100)), Ok(4900));
+        assert_eq!(f.read(&mut buf), Ok(100));
+        assert_eq!(f.seek(SeekFrom::End(100)), Ok(5000));
+        assert_eq!(f.write(b"x"), Err(FsError::ReadOnly));
+
+        // Overwrite in the middle, truncate, append
+        let mut f = fs.open("A long file name.data", OpenMode::ReadWrite).unwrap();
+        f.seek(SeekFrom::Start(510)).unwrap();
+        f.write(b"abcd").unwrap();
+        f.seek(SeekFrom::Start(1000)).unwrap();
+        f.truncate().unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 1 - 2);
+        let mut f = fs.open("A long file name.data", OpenMode::Append).unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write(b"xyz").unwrap();
+        let mut expected = data[..1000].to_vec();
+        expected[510..514].copy_from_slice(b"abcd");
+        expected.extend_from_slice(b"xyz");
+        assert_eq!(read_all(&fs, "A long file name.data"), expected);
+
+        // Re-creating truncates
+        fs.open("A long file name.data", OpenMode::Create).unwrap();
+        assert_eq!(fs.metadata("A long file name.data").unwrap().len(), 0);
+        assert_eq!(fs.free_clusters().unwrap(), free - 1);
+        assert_eq!(names(&fs, "/"), ["hello.txt", "A long file name.data"]);
+
+        // Everything is on disk after remounting
+        let fs = FileSystem::mount(fs.into_inner()).unwrap();
+        assert_eq!(read_all(&fs, "hello.txt"), b"Hello, world!");
+        assert_eq!(fs.free_clusters().unwrap(), free - 1);
+
+        // Fill the disk
+        let mut f = fs.open("big", OpenMode::Create).unwrap();
+        let block = [0x55u8; 4096];
+        let err = loop {
+            if let Err(e) = f.write(&block) {
+                break e;
+            }
+        };
+        assert_eq!(err, FsError::DiskFull);
+        assert_eq!(fs.free_clusters().unwrap(), 0);
+        assert_eq!(f.len(), (free - 1) * 512);
+    }
+
+    #[test]
+    fn test_fat32_dirs() {
+        let dev = FileBlock::temp(70000);
+        let mut mbr = [0u8; BLOCK_SIZE];
+        mbr[446 + 4] = 0x0c;
+        write_u32le(&mut mbr, 446 + 8, 2048);
+        write_u32le(&mut mbr, 446 + 12, 70000 - 2048);
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        dev.write_blocks(&mbr, 0).unwrap();
+        let part = find_fat(&dev).unwrap();
+        assert_eq!(part.start(), 2048);
+        format(&part, true, 1, 0);
+
+        let fs = FileSystem::mount(part).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat32);
+        fs.create_dir("Documents").unwrap();
+        fs.create_dir("/documents/sub").unwrap();
+        assert_eq!(fs.create_dir("Documents"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.create_dir("missing/sub"), Err(FsError::NotFound));
+        fs.open("Documents/sub/file.bin", OpenMode::Create)
+            .unwrap()
+            .write(&pattern(3000))
+            .unwrap();
+
+        // Enough long names to make the directory span several clusters
+        for i in 0..40 {
+            let name = std::format!("Documents/Long file name number {:02}.dat", i);
+            let mut f = fs.open(&name, OpenMode::Create).unwrap();
+            f.write(name.as_bytes()).unwrap();
+        }
+        let list = names(&fs, "Documents");
+        assert_eq!(list.len(), 41);
+        assert_eq!(list[0], "sub");
+        for i in 0..40 {
+            let name = std::format!("Long file name number {:02}.dat", i);
+            assert_eq!(list[i + 1], name);
+            let path = std::format!("Documents/{}", name);
+            assert_eq!(read_all(&fs, &path), path.as_bytes());
+        }
+        let e = fs.metadata("Documents/Long file name number 39.dat").unwrap();
+        assert_eq!(e.short_name(), "LONGF~40.DAT");
+
+        let e = fs.metadata("Documents/sub").unwrap();
+        assert!(e.is_dir());
+        assert_eq!(names(&fs, "/"), ["Documents"]);
+        assert_eq!(names(&fs, "/Documents/sub"), ["file.bin"]);
+        assert_eq!(read_all(&fs, "Documents/sub/file.bin"), pattern(3000));
+
+        assert_eq!(fs.open("Documents/sub", OpenMode::Read).err(), Some(FsError::IsADirectory));
+        assert_eq!(fs.open("Documents/none", OpenMode::Read).err(), Some(FsError::NotFound));
+        assert_eq!(fs.read_dir("Documents/sub/file.bin").err(), Some(FsError::NotADirectory));
+        assert_eq!(fs.open("bad*name", OpenMode::Create).err(), Some(FsError::InvalidName));
+
+        // FSInfo free count was invalidated
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.read_blocks(&mut buf, 2048 + 1).unwrap();
+        assert_eq!(read_u32le(&buf, FSINFO_FREE_COUNT_OFS), 0xffff_ffff);
+        // Both FATs are identical
+        let part = fs.into_inner();
+        let mut fat2 = [0u8; BLOCK_SIZE];
+        let fat_size = {
+            part.read_blocks(&mut buf, 0).unwrap();
+            read_u32le(&buf, 36)
+        };
+        for s in 0..4 {
+            part.read_blocks(&mut buf, 32 + s).unwrap();
+            part.read_blocks(&mut fat2, 32 + fat_size + s).unwrap();
+            assert_eq!(&buf[..], &fat2[..]);
+        }
+    }
+
+    #[test]
+    fn test_long_names() {
+        let dev = FileBlock::temp(32768);
+        format(&dev, false, 4, 32);
+        let fs = FileSystem::mount(&dev).unwrap();
+
+        // Entries as written by another implementation (example from the FAT specification)
+        let mut root = [0u8; BLOCK_SIZE];
+        root[0..32].copy_from_slice(&[
+            0x42, 0x77, 0x00, 0x6e, 0x00, 0x2e, 0x00, 0x66, 0x00, 0x6f, 0x00, 0x0f, 0x00, 0x07,
+            0x78, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0xff,
+        ]);
+        root[32..64].copy_from_slice(&[
+            0x01, 0x54, 0x00, 0x68, 0x00, 0x65, 0x00, 0x20, 0x00, 0x71, 0x00, 0x0f, 0x00, 0x07,
+            0x75, 0x00, 0x69, 0x00, 0x63, 0x00, 0x6b, 0x00, 0x20, 0x00, 0x62, 0x00, 0x00, 0x00,
+            0x72, 0x00, 0x6f, 0x00,
+        ]);
+        root[64..75].copy_from_slice(b"THEQUI~1FOX");
+        // Orphaned long name entry (checksum does not match the short name)
+        root.copy_within(32..64, 96);
+        root[96 + 13] = 0x08;
+        root[96] = 0x41;
+        root[128..139].copy_from_slice(b"PLAIN   TXT");
+        let root_start = fs.root_start;
+        dev.write_blocks(&root, root_start).unwrap();
+        assert_eq!(names(&fs, ""), ["The quick brown.fox", "PLAIN.TXT"]);
+        assert_eq!(fs.metadata("the quick BROWN.fox").unwrap().short_name(), "THEQUI~1.FOX");
+
+        // Names that need a long name entry: mixed case, non-ASCII, long extension,
+        // leading dot, exactly 13 and 26 characters
+        let long_names = [
+            "MixedCase.txt",
+            "Grüße ✓.txt",
+            "archive.tar.gz",
+            ".hidden",
+            "abcdefghijklm",
+            "abcdefghijklmnopqrstuvwxyz",
+        ];
+        for name in long_names.iter() {
+            fs.open(name, OpenMode::Create).unwrap();
+        }
+        let list = names(&fs, "");
+        assert_eq!(&list[2..], &long_names[..]);
+        let short: Vec<_> = fs
+            .read_dir("")
+            .unwrap()
+            .skip(2)
+            .map(|e| std::string::String::from(e.unwrap().short_name()))
+            .collect();
+        assert_eq!(
+            short,
+            ["MIXEDC~1.TXT", "GR__E_~1.TXT", "ARCHIV~1.GZ", "HIDDEN~1", "ABCDEF~1", "ABCDEF~2"]
+        );
+
+        // The root directory holds 32 entries, 19 are used: 13 free slots left
+        let name = "n".repeat(13 * 12 + 1);
+        assert_eq!(fs.open(&name, OpenMode::Create).err(), Some(FsError::DirectoryFull));
+        let name = "m".repeat(12 * 12);
+        fs.open(&name, OpenMode::Create).unwrap();
+        assert_eq!(fs.open("X", OpenMode::Create).err(), Some(FsError::DirectoryFull));
+        assert_eq!(names(&fs, "").len(), 9);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dev = FileBlock::temp(8192);
+        format(&dev, false, 1, 512);
+        let fs = FileSystem::mount(&dev).unwrap();
+        fs.open("file", OpenMode::Create).unwrap().write(&pattern(2000)).unwrap();
+        fs.create_dir("dir").unwrap();
+        let free = fs.free_clusters().unwrap();
+        let e = fs.metadata("file").unwrap();
+        let first = e.first_cluster();
+        // Overwrite the first cluster (high and low word) and size of a short name entry
+        let set_entry = |e: &DirEntry, high: u16, low: u16, size: u32| {
+            let mut buf = [0u8; BLOCK_SIZE];
+            dev.read_blocks(&mut buf, e.sector).unwrap();
+            write_u16le(&mut buf, e.offset + 20, high);
+            write_u16le(&mut buf, e.offset + 26, low);
+            write_u32le(&mut buf, e.offset + 28, size);
+            dev.write_blocks(&buf, e.sector).unwrap();
+        };
+
+        // The high word is not used on FAT16
+        set_entry(&e, 0xdead, first as u16, 2000);
+        assert_eq!(read_all(&fs, "file"), pattern(2000));
+
+        // Out-of-range first clusters, also when truncating
+        for &low in [1, 0xfff0].iter() {
+            set_entry(&e, 0, low, 2000);
+            assert_eq!(fs.open("file", OpenMode::Read).err(), Some(FsError::Corrupt));
+            assert_eq!(fs.open("file", OpenMode::Create).err(), Some(FsError::Corrupt));
+        }
+        set_entry(&fs.metadata("dir").unwrap(), 0, 1, 0);
+        assert_eq!(fs.read_dir("dir").err(), Some(FsError::Corrupt));
+
+        // Link out of range
+        set_entry(&e, 0, first as u16, 2000);
+        fs.set_fat_entry(first + 1, 0xfff0).unwrap();
+        let mut buf = [0u8; 2000];
+        assert_eq!(fs.open("file", OpenMode::Read).unwrap().read(&mut buf), Err(FsError::Corrupt));
+
+        // A loop in the chain ends the walk
+        fs.set_fat_entry(first + 1, first).unwrap();
+        set_entry(&e, 0, first as u16, 0xffff_0000);
+        let mut f = fs.open("file", OpenMode::Read).unwrap();
+        f.seek(SeekFrom::Start(0xfff0_0000)).unwrap();
+        assert_eq!(f.read(&mut buf), Err(FsError::Corrupt));
+        assert_eq!(fs.open("file", OpenMode::Create).err(), Some(FsError::Corrupt));
+        // Freeing stopped at the loop, after the two clusters in it
+        assert_eq!(fs.free_clusters().unwrap(), free + 2);
+    }
+
+    #[test]
+    fn test_mount_errors() {
+        let dev = FileBlock::temp(8192);
+        assert_eq!(FileSystem::mount(&dev).err(), Some(FsError::NoFilesystem));
+        // Too few clusters for FAT16
+        let small = FileBlock::temp(4000);
+        format(&small, false, 1, 512);
+        assert_eq!(FileSystem::mount(&small).err(), Some(FsError::Unsupported));
+    }
+}
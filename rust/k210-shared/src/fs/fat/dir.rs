@@ -0,0 +1,711 @@
+//! Directories: iteration, long file names, path lookup and creating entries
+use core::char::{decode_utf16, REPLACEMENT_CHARACTER};
+
+use super::{DirLoc, FatType, FileSystem, ATTR_DIRECTORY, ATTR_VOLUME_ID};
+use crate::fs::{read_u16le, read_u32le, write_u16le, write_u32le, BlockDevice, FsError, BLOCK_SIZE};
+
+/** Size of a directory entry */
+pub(crate) const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (BLOCK_SIZE / ENTRY_SIZE) as u32;
+/** Attribute combination that marks a long file name entry */
+const ATTR_LONG_NAME: u8 = 0x0f;
+/** First name byte of a deleted entry */
+const DELETED: u8 = 0xe5;
+/** Flag in the first byte of the physically first (last in sequence) long name entry */
+const LAST_LONG_ENTRY: u8 = 0x40;
+/** Characters per long name entry */
+const LFN_CHARS: usize = 13;
+/** Position of the characters within a long name entry */
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/** Maximum long name length in UTF-16 units */
+const MAX_LFN: usize = 255;
+/** Maximum name length in UTF-8 bytes */
+pub const MAX_NAME_LEN: usize = MAX_LFN * 3;
+/** NT case flags for short names: base name and extension are lower case */
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/** Date stamp for new entries (2000-01-01), there is no real-time clock to take it from */
+const DEFAULT_DATE: u16 = (20 << 9) | (1 << 5) | 1;
+
+/** Directory entry of a file or subdirectory */
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    short_name: [u8; 12],
+    short_name_len: usize,
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    /** Sector and byte offset of the short name entry */
+    pub(crate) sector: u32,
+    pub(crate) offset: usize,
+}
+
+impl DirEntry {
+    /** Name of the entry: the long name if present, otherwise the short name */
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /** Short (8.3) name, as stored on disk, e.g. `LONGFI~1.TXT` */
+    pub fn short_name(&self) -> &str {
+        core::str::from_utf8(&self.short_name[..self.short_name_len]).unwrap_or("")
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.attr
+    }
+
+    pub fn is_dir(&self) -> bool {
+        (self.attr & ATTR_DIRECTORY) != 0
+    }
+
+    /** Size of the file in bytes (0 for directories) */
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub(crate) fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /** Directory location, if this is a directory. Cluster 0 refers to the root directory
+     * (in ".." entries). */
+    pub(crate) fn dir_loc<D: BlockDevice>(&self, fs: &FileSystem<D>) -> Result<DirLoc, FsError> {
+        if !self.is_dir() {
+            Err(FsError::NotADirectory)
+        } else if self.first_cluster == 0 {
+            Ok(fs.root())
+        } else {
+            Ok(DirLoc::Chain(fs.check_cluster(self.first_cluster)?))
+        }
+    }
+
+    /** Does `name` refer to this entry? Matches long and short name, ignoring case. */
+    fn matches(&self, name: &str) -> bool {
+        names_equal(self.name(), name) || names_equal(self.short_name(), name)
+    }
+
+    /** Parse a short name entry. The high word of the first cluster is only used on FAT32. */
+    fn from_raw(e: &[u8], fat_type: FatType, sector: u32, offset: usize) -> Self {
+        let high = match fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => u32::from(read_u16le(e, 20)) << 16,
+        };
+        let mut entry = Self {
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            short_name: [0; 12],
+            short_name_len: 0,
+            attr: e[11],
+            first_cluster: high | u32::from(read_u16le(e, 26)),
+            size: read_u32le(e, 28),
+            sector,
+            offset,
+        };
+        let case = e[12];
+        let mut push = |c: u8, lower: bool| {
+            // Bytes outside ASCII are in an unknown OEM code page
+            let c = if c >= 0x80 { b'?' } else { c };
+            entry.short_name[entry.short_name_len] = c;
+            entry.short_name_len += 1;
+            let c = if lower { c.to_ascii_lowercase() } else { c };
+            entry.name[entry.name_len] = c;
+            entry.name_len += 1;
+        };
+        for (i, &c) in e[0..8].iter().enumerate() {
+            if c != b' ' {
+                push(if i == 0 && c == 0x05 { DELETED } else { c }, (case & CASE_LOWER_BASE) != 0);
+            }
+        }
+        if e[8] != b' ' {
+            push(b'.', false);
+            for &c in e[8..11].iter().filter(|&&c| c != b' ') {
+                push(c, (case & CASE_LOWER_EXT) != 0);
+            }
+        }
+        entry
+    }
+
+    /** Replace the name with a long name */
+    fn set_long_name(&mut self, units: &[u16]) {
+        let mut len = 0;
+        for c in decode_utf16(units.iter().cloned()) {
+            let c = c.unwrap_or(REPLACEMENT_CHARACTER);
+            len += c.encode_utf8(&mut self.name[len..]).len();
+        }
+        self.name_len = len;
+    }
+}
+
+/** Compare names case-insensitively */
+fn names_equal(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/** Checksum of a short name, stored in the long name entries that belong to it */
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11]
+        .iter()
+        .fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/** Position in a directory, for walking its entries one by one */
+pub(crate) struct Cursor {
+    loc: DirLoc,
+    /** Index of the next entry */
+    index: u32,
+    /** Current cluster and its index in the chain */
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl Cursor {
+    pub(crate) fn new(loc: DirLoc) -> Self {
+        Self {
+            loc,
+            index: 0,
+            cluster: if let DirLoc::Chain(c) = loc { c } else { 0 },
+            cluster_index: 0,
+        }
+    }
+
+    /** Sector holding entry `self.index`, or `None` past the end of the directory. Only moves
+     * forward through the cluster chain. */
+    fn sector<D: BlockDevice>(&mut self, fs: &FileSystem<D>) -> Result<Option<u32>, FsError> {
+        let sector = self.index / ENTRIES_PER_SECTOR;
+        match self.loc {
+            DirLoc::Root16 => Ok(if sector < fs.root_sectors { Some(fs.root_start + sector) } else { None }),
+            DirLoc::Chain(_) => {
+                let cluster_index = sector / fs.sectors_per_cluster;
+                while self.cluster_index < cluster_index {
+                    match fs.next_cluster(self.cluster)? {
+                        Some(c) => self.cluster = c,
+                        None => return Ok(None),
+                    }
+                    self.cluster_index += 1;
+                    // A chain longer than the number of clusters has a loop
+                    if self.cluster_index >= fs.num_clusters {
+                        return Err(FsError::Corrupt);
+                    }
+                }
+                Ok(Some(fs.cluster_sector(self.cluster) + sector % fs.sectors_per_cluster))
+            }
+        }
+    }
+}
+
+/** Raw directory slot */
+struct Slot {
+    sector: u32,
+    offset: usize,
+}
+
+/** Reads raw directory slots through a sector buffer */
+struct SlotReader {
+    cursor: Cursor,
+    buf: [u8; BLOCK_SIZE],
+    buf_sector: Option<u32>,
+}
+
+impl SlotReader {
+    fn new(loc: DirLoc) -> Self {
+        Self {
+            cursor: Cursor::new(loc),
+            buf: [0; BLOCK_SIZE],
+            buf_sector: None,
+        }
+    }
+
+    /** Read the next slot, if any. The entry is at `self.buf[slot.offset..]`. */
+    fn next<D: BlockDevice>(&mut self, fs: &FileSystem<D>) -> Result<Option<Slot>, FsError> {
+        let sector = match self.cursor.sector(fs)? {
+            Some(sector) => sector,
+            None => return Ok(None),
+        };
+        if self.buf_sector != Some(sector) {
+            self.buf_sector = None;
+            fs.read_sector(&mut self.buf, sector)?;
+            self.buf_sector = Some(sector);
+        }
+        let offset = (self.cursor.index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+        self.cursor.index += 1;
+        Ok(Some(Slot { sector, offset }))
+    }
+
+    fn entry(&self, slot: &Slot) -> &[u8] {
+        &self.buf[slot.offset..slot.offset + ENTRY_SIZE]
+    }
+}
+
+/** Iterator over the entries of a directory. The "." and ".." entries and the volume label
+ * are skipped. */
+pub struct Dir<'a, D> {
+    fs: &'a FileSystem<D>,
+    reader: SlotReader,
+    done: bool,
+    /** Long name being collected */
+    lfn: [u16; MAX_LFN + LFN_CHARS],
+    /** Sequence number of the last long name entry seen, 0 if none */
+    lfn_seq: u8,
+    lfn_checksum: u8,
+}
+
+impl<'a, D: BlockDevice> Dir<'a, D> {
+    pub(crate) fn new(fs: &'a FileSystem<D>, loc: DirLoc) -> Self {
+        Self {
+            fs,
+            reader: SlotReader::new(loc),
+            done: false,
+            lfn: [0; MAX_LFN + LFN_CHARS],
+            lfn_seq: 0,
+            lfn_checksum: 0,
+        }
+    }
+
+    /** Collect the characters from a long name entry */
+    fn long_entry(&mut self, e: &[u8]) {
+        let seq = e[0] & !LAST_LONG_ENTRY;
+        if (e[0] & LAST_LONG_ENTRY) != 0 {
+            if seq == 0 || usize::from(seq) * LFN_CHARS > MAX_LFN + LFN_CHARS {
+                self.lfn_seq = 0;
+                return;
+            }
+            // Mark the end, in case the name fills the last entry exactly
+            let end = usize::from(seq) * LFN_CHARS;
+            if end < self.lfn.len() {
+                self.lfn[end] = 0;
+            }
+            self.lfn_checksum = e[13];
+        } else if self.lfn_seq <= 1 || seq != self.lfn_seq - 1 || e[13] != self.lfn_checksum {
+            self.lfn_seq = 0;
+            return;
+        }
+        self.lfn_seq = seq;
+        let base = usize::from(seq - 1) * LFN_CHARS;
+        for (i, &ofs) in LFN_OFFSETS.iter().enumerate() {
+            self.lfn[base + i] = read_u16le(e, ofs);
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FsError> {
+        while !self.done {
+            let slot = match self.reader.next(self.fs)? {
+                Some(slot) => slot,
+                None => break,
+            };
+            let e = self.reader.entry(&slot);
+            let attr = e[11];
+            if e[0] == 0 {
+                self.done = true;
+            } else if e[0] == DELETED {
+                self.lfn_seq = 0;
+            } else if attr == ATTR_LONG_NAME {
+                let mut raw = [0u8; ENTRY_SIZE];
+                raw.copy_from_slice(e);
+                self.long_entry(&raw);
+            } else if (attr & ATTR_VOLUME_ID) != 0 || e[0] == b'.' {
+                self.lfn_seq = 0;
+            } else {
+                let mut entry = DirEntry::from_raw(e, self.fs.fat_type, slot.sector, slot.offset);
+                if self.lfn_seq == 1 && self.lfn_checksum == short_name_checksum(e) {
+                    let len = self.lfn.iter().take(MAX_LFN).position(|&c| c == 0).unwrap_or(MAX_LFN);
+                    entry.set_long_name(&self.lfn[..len]);
+                }
+                self.lfn_seq = 0;
+                return Ok(Some(entry));
+            }
+        }
+        self.done = true;
+        Ok(None)
+    }
+}
+
+impl<'a, D: BlockDevice> Iterator for Dir<'a, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => None,
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/** Split a path into parent directory and final component */
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+/** Is `c` allowed in a short name? */
+fn valid_short_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/** Is `c` allowed in a long name? */
+fn valid_long_char(c: char) -> bool {
+    c >= ' ' && !"\"*/:<>?\\|".contains(c)
+}
+
+/** Short name for `name`, if it can be stored as 8.3 name without a long name: all characters
+ * valid, and each part either all upper or all lower case. Returns the name and case flags. */
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.ends_with('.')) {
+        return None;
+    }
+    let mut raw = [b' '; 11];
+    let mut case = 0;
+    for (part, field, flag) in [(base, 0, CASE_LOWER_BASE), (ext, 8, CASE_LOWER_EXT)].iter() {
+        if !part.chars().all(valid_short_char) {
+            return None;
+        }
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        if has_upper && has_lower {
+            return None;
+        }
+        if has_lower {
+            case |= flag;
+        }
+        for (i, c) in part.bytes().enumerate() {
+            raw[field + i] = c.to_ascii_uppercase();
+        }
+    }
+    Some((raw, case))
+}
+
+/** Basis for a generated short name ("numeric tail" is added later): invalid characters are
+ * replaced, and base name and extension truncated. */
+fn short_name_basis(name: &str) -> ([u8; 11], usize) {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    let mut raw = [b' '; 11];
+    let mut base_len = 0;
+    let conv = |c: char| {
+        if valid_short_char(c) {
+            c.to_ascii_uppercase() as u8
+        } else {
+            b'_'
+        }
+    };
+    for c in base.chars().filter(|&c| c != ' ' && c != '.') {
+        if base_len == 8 {
+            break;
+        }
+        raw[base_len] = conv(c);
+        base_len += 1;
+    }
+    if base_len == 0 {
+        raw[0] = b'_';
+        base_len = 1;
+    }
+    for (i, c) in ext.chars().filter(|&c| c != ' ').take(3).enumerate() {
+        raw[8 + i] = conv(c);
+    }
+    (raw, base_len)
+}
+
+/** Add numeric tail `~n` to a short name basis */
+fn with_numeric_tail(basis: &[u8; 11], base_len: usize, n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut ndigits = 0;
+    let mut v = n;
+    while v > 0 || ndigits == 0 {
+        digits[ndigits] = b'0' + (v % 10) as u8;
+        ndigits += 1;
+        v /= 10;
+    }
+    let mut raw = *basis;
+    let start = core::cmp::min(base_len, 8 - 1 - ndigits);
+    raw[start] = b'~';
+    for i in 0..ndigits {
+        raw[start + 1 + i] = digits[ndigits - 1 - i];
+    }
+    for c in raw[start + 1 + ndigits..8].iter_mut() {
+        *c = b' ';
+    }
+    raw
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /** Iterate over the entries of the directory at `path` ("" or "/" for the root). */
+    pub fn read_dir(&self, path: &str) -> Result<Dir<'_, D>, FsError> {
+        Ok(Dir::new(self, self.find_dir(path)?))
+    }
+
+    /** Look up the entry for a file or directory. */
+    pub fn metadata(&self, path: &str) -> Result<DirEntry, FsError> {
+        let (parent, name) = split_path(path);
+        if name.is_empty() {
+            // The root directory has no entry
+            return Err(FsError::InvalidName);
+        }
+        self.find_in(self.find_dir(parent)?, name)
+    }
+
+    /** Create a directory. The parent directory must exist. */
+    pub fn create_dir(&self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_path(path);
+        let parent = self.find_dir(parent)?;
+        match self.find_in(parent, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let cluster = self.alloc_cluster(None, true)?;
+        let result = self.init_dir(parent, cluster);
+        let result = result.and_then(|_| self.create_entry(parent, name, ATTR_DIRECTORY, cluster));
+        if result.is_err() {
+            // Give back the cluster; the original error is the interesting one
+            let _ = self.free_chain(cluster);
+        }
+        self.sync()?;
+        result.map(|_| ())
+    }
+
+    /** Write the "." and ".." entries of a new directory */
+    fn init_dir(&self, parent: DirLoc, cluster: u32) -> Result<(), FsError> {
+        let parent_cluster = match parent {
+            DirLoc::Chain(c) if c != self.root_cluster => c,
+            _ => 0,
+        };
+        let mut buf = [0u8; BLOCK_SIZE];
+        for (i, (name, c)) in [(&b".          "[..], cluster), (&b"..         "[..], parent_cluster)]
+            .iter()
+            .enumerate()
+        {
+            let e = &mut buf[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+            e[0..11].copy_from_slice(name);
+            e[11] = ATTR_DIRECTORY;
+            write_u16le(e, 20, (c >> 16) as u16);
+            write_u16le(e, 24, DEFAULT_DATE);
+            write_u16le(e, 26, *c as u16);
+        }
+        self.write_sector(&buf, self.cluster_sector(cluster))
+    }
+
+    /** Find a directory by path */
+    pub(crate) fn find_dir(&self, path: &str) -> Result<DirLoc, FsError> {
+        let mut loc = self.root();
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            loc = self.find_in(loc, name)?.dir_loc(self)?;
+        }
+        Ok(loc)
+    }
+
+    /** Find an entry by name in a directory */
+    pub(crate) fn find_in(&self, dir: DirLoc, name: &str) -> Result<DirEntry, FsError> {
+        for entry in Dir::new(self, dir) {
+            let entry = entry?;
+            if entry.matches(name) {
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /** Split a path into parent directory location and name */
+    pub(crate) fn parent_of<'p>(&self, path: &'p str) -> Result<(DirLoc, &'p str), FsError> {
+        let (parent, name) = split_path(path);
+        Ok((self.find_dir(parent)?, name))
+    }
+
+    /** Is a short name used in a directory? */
+    fn short_name_exists(&self, dir: DirLoc, raw: &[u8; 11]) -> Result<bool, FsError> {
+        let mut reader = SlotReader::new(dir);
+        while let Some(slot) = reader.next(self)? {
+            let e = reader.entry(&slot);
+            if e[0] == 0 {
+                break;
+            }
+            if e[0] != DELETED && e[11] != ATTR_LONG_NAME && &e[0..11] == raw {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /** Find `count` consecutive free slots in a directory, extending it if needed. Returns the
+     * index of the first slot, and whether the run includes the end-of-directory marker. */
+    fn find_free_slots(&self, dir: DirLoc, count: u32) -> Result<(u32, bool), FsError> {
+        let mut reader = SlotReader::new(dir);
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut at_end = false;
+        loop {
+            let index = reader.cursor.index;
+            let slot = match reader.next(self)? {
+                Some(slot) => slot,
+                None => break,
+            };
+            let first = reader.entry(&slot)[0];
+            if first == 0 {
+                at_end = true;
+            }
+            if at_end || first == DELETED {
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+                if run_len == count {
+                    return Ok((run_start, at_end));
+                }
+            } else {
+                run_len = 0;
+            }
+        }
+        // Out of slots: add clusters to the directory
+        let last = match dir {
+            DirLoc::Root16 => return Err(FsError::DirectoryFull),
+            DirLoc::Chain(_) => reader.cursor.cluster,
+        };
+        let per_cluster = ENTRIES_PER_SECTOR * self.sectors_per_cluster;
+        let total = (reader.cursor.cluster_index + 1) * per_cluster;
+        if run_len == 0 {
+            run_start = total;
+        }
+        let mut prev = last;
+        let mut available = run_len;
+        while available < count {
+            prev = self.alloc_cluster(Some(prev), true)?;
+            available += per_cluster;
+        }
+        Ok((run_start, true))
+    }
+
+    /** Write a raw entry into slot `index` of a directory */
+    fn write_slot(&self, dir: DirLoc, index: u32, entry: &[u8]) -> Result<(u32, usize), FsError> {
+        let mut cursor = Cursor::new(dir);
+        cursor.index = index;
+        let sector = cursor.sector(self)?.ok_or(FsError::Corrupt)?;
+        let offset = (index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+        let mut buf = [0u8; BLOCK_SIZE];
+        self.read_sector(&mut buf, sector)?;
+        buf[offset..offset + entry.len()].copy_from_slice(entry);
+        self.write_sector(&buf, sector)?;
+        Ok((sector, offset))
+    }
+
+    /** Create a new entry in a directory. A long name is added if the name can not be stored
+     * as a short name. */
+    pub(crate) fn create_entry(&self, dir: DirLoc, name: &str, attr: u8, cluster: u32) -> Result<DirEntry, FsError> {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.ends_with(' ')
+            || name.ends_with('.')
+            || !name.chars().all(valid_long_char)
+        {
+            return Err(FsError::InvalidName);
+        }
+        let mut lfn = [0u16; MAX_LFN];
+        let mut lfn_len = 0;
+        for c in name.encode_utf16() {
+            if lfn_len == MAX_LFN {
+                return Err(FsError::InvalidName);
+            }
+            lfn[lfn_len] = c;
+            lfn_len += 1;
+        }
+
+        let (raw, case, lfn_entries) = match exact_short_name(name) {
+            Some((raw, case)) => (raw, case, 0),
+            None => {
+                let (basis, base_len) = short_name_basis(name);
+                let mut n = 1;
+                let raw = loop {
+                    let raw = with_numeric_tail(&basis, base_len, n);
+                    if !self.short_name_exists(dir, &raw)? {
+                        break raw;
+                    }
+                    n += 1;
+                    if n > 999_999 {
+                        return Err(FsError::DirectoryFull);
+                    }
+                };
+                (raw, 0, (lfn_len + LFN_CHARS - 1) / LFN_CHARS)
+            }
+        };
+
+        let (start, at_end) = self.find_free_slots(dir, lfn_entries as u32 + 1)?;
+        let checksum = short_name_checksum(&raw);
+        for i in 0..lfn_entries {
+            // Long name entries are stored in reverse order
+            let seq = lfn_entries - i;
+            let mut e = [0u8; ENTRY_SIZE];
+            e[0] = seq as u8 | if i == 0 { LAST_LONG_ENTRY } else { 0 };
+            e[11] = ATTR_LONG_NAME;
+            e[13] = checksum;
+            for (j, &ofs) in LFN_OFFSETS.iter().enumerate() {
+                let pos = (seq - 1) * LFN_CHARS + j;
+                let c = if pos < lfn_len {
+                    lfn[pos]
+                } else if pos == lfn_len {
+                    0
+                } else {
+                    0xffff
+                };
+                write_u16le(&mut e, ofs, c);
+            }
+            self.write_slot(dir, start + i as u32, &e)?;
+        }
+
+        let mut e = [0u8; ENTRY_SIZE];
+        e[0..11].copy_from_slice(&raw);
+        e[11] = attr;
+        e[12] = case;
+        write_u16le(&mut e, 16, DEFAULT_DATE);
+        write_u16le(&mut e, 18, DEFAULT_DATE);
+        write_u16le(&mut e, 20, (cluster >> 16) as u16);
+        write_u16le(&mut e, 24, DEFAULT_DATE);
+        write_u16le(&mut e, 26, cluster as u16);
+        let index = start + lfn_entries as u32;
+        let (sector, offset) = self.write_slot(dir, index, &e)?;
+        if at_end {
+            // Slots after the end marker are not necessarily zeroed; keep the marker after the
+            // new entry.
+            let mut cursor = Cursor::new(dir);
+            cursor.index = index + 1;
+            if cursor.sector(self)?.is_some() {
+                self.write_slot(dir, index + 1, &[0])?;
+            }
+        }
+
+        let mut entry = DirEntry::from_raw(&e, self.fat_type, sector, offset);
+        if lfn_entries > 0 {
+            entry.set_long_name(&lfn[..lfn_len]);
+        }
+        Ok(entry)
+    }
+
+    /** Update size and first cluster in the short name entry of a file */
+    pub(crate) fn update_entry(&self, sector: u32, offset: usize, cluster: u32, size: u32) -> Result<(), FsError> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        self.read_sector(&mut buf, sector)?;
+        let e = &mut buf[offset..offset + ENTRY_SIZE];
+        write_u16le(e, 20, (cluster >> 16) as u16);
+        write_u16le(e, 24, DEFAULT_DATE);
+        write_u16le(e, 26, cluster as u16);
+        write_u32le(e, 28, size);
+        e[11] |= super::ATTR_ARCHIVE;
+        self.write_sector(&buf, sector)
+    }
+}
@@ -0,0 +1,272 @@
+//! Open files: read, write, seek and truncate
+use super::{FileSystem, ATTR_ARCHIVE};
+use crate::fs::{BlockDevice, FsError, BLOCK_SIZE};
+
+/** How to open a file */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenMode {
+    /** Existing file, read only */
+    Read,
+    /** Existing file, read and write */
+    ReadWrite,
+    /** Create the file, or truncate it if it exists */
+    Create,
+    /** Create the file if it does not exist; all writes go to the end */
+    Append,
+}
+
+/** Position to seek to */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u32),
+    End(i32),
+    Current(i32),
+}
+
+/** Open file. Changes to the size are written to the directory entry on every write or
+ * truncate, there is no need to close the file. */
+pub struct File<'a, D> {
+    fs: &'a FileSystem<D>,
+    /** Location of the directory entry */
+    entry_sector: u32,
+    entry_offset: usize,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /** Last visited cluster and its index in the chain (0 if none), to avoid walking the chain
+     * from the start for sequential access */
+    cluster: u32,
+    cluster_index: u32,
+    mode: OpenMode,
+}
+
+impl<'a, D: BlockDevice> File<'a, D> {
+    /** Size of the file in bytes */
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    /** Current position */
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+
+    /** Move the read/write position. Positions beyond the end of the file are clamped to the
+     * end. Returns the new position. */
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u32, FsError> {
+        let pos = match pos {
+            SeekFrom::Start(p) => i64::from(p),
+            SeekFrom::End(d) => i64::from(self.size) + i64::from(d),
+            SeekFrom::Current(d) => i64::from(self.pos) + i64::from(d),
+        };
+        self.pos = if pos < 0 {
+            0
+        } else if pos > i64::from(self.size) {
+            self.size
+        } else {
+            pos as u32
+        };
+        Ok(self.pos)
+    }
+
+    /** Cluster `index` in the file's chain, or `None` if the chain is shorter */
+    fn cluster_at(&mut self, index: u32) -> Result<Option<u32>, FsError> {
+        if self.first_cluster == 0 {
+            return Ok(None);
+        }
+        if self.cluster == 0 || index < self.cluster_index {
+            self.cluster = self.first_cluster;
+            self.cluster_index = 0;
+        }
+        while self.cluster_index < index {
+            match self.fs.next_cluster(self.cluster)? {
+                Some(c) => self.cluster = c,
+                None => return Ok(None),
+            }
+            self.cluster_index += 1;
+            // A chain longer than the number of clusters has a loop
+            if self.cluster_index >= self.fs.num_clusters {
+                return Err(FsError::Corrupt);
+            }
+        }
+        Ok(Some(self.cluster))
+    }
+
+    /** Read from the current position. Returns the number of bytes read, which is less than
+     * the length of `buf` only at the end of the file. */
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let cluster_size = self.fs.cluster_size();
+        let len = core::cmp::min(buf.len(), (self.size - self.pos) as usize);
+        let mut done = 0;
+        let mut tmp = [0u8; BLOCK_SIZE];
+        while done < len {
+            let cluster = self
+                .cluster_at(self.pos / cluster_size)?
+                .ok_or(FsError::Corrupt)?;
+            let ofs = self.pos % cluster_size;
+            let sector = self.fs.cluster_sector(cluster) + ofs / BLOCK_SIZE as u32;
+            let sector_ofs = (ofs % BLOCK_SIZE as u32) as usize;
+            let n = if sector_ofs == 0 && len - done >= BLOCK_SIZE {
+                // Whole sectors up to the end of the cluster go straight into the buffer
+                let left_in_cluster = ((cluster_size - ofs) as usize) / BLOCK_SIZE;
+                let n = core::cmp::min((len - done) / BLOCK_SIZE, left_in_cluster) * BLOCK_SIZE;
+                self.fs.read_sector(&mut buf[done..done + n], sector)?;
+                n
+            } else {
+                let n = core::cmp::min(BLOCK_SIZE - sector_ofs, len - done);
+                self.fs.read_sector(&mut tmp, sector)?;
+                buf[done..done + n].copy_from_slice(&tmp[sector_ofs..sector_ofs + n]);
+                n
+            };
+            done += n;
+            self.pos += n as u32;
+        }
+        Ok(done)
+    }
+
+    /** Fill `buf` completely, failing with `Corrupt` if the file is too short. */
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), FsError> {
+        if self.read(buf)? == buf.len() {
+            Ok(())
+        } else {
+            Err(FsError::Corrupt)
+        }
+    }
+
+    /** Write at the current position (or at the end, for files opened with
+     * `OpenMode::Append`), extending the file as needed. */
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        if self.mode == OpenMode::Read {
+            return Err(FsError::ReadOnly);
+        }
+        if self.mode == OpenMode::Append {
+            self.pos = self.size;
+        }
+        let result = self.write_data(buf);
+        // Record whatever was written, also on failure
+        self.update_entry()?;
+        result.map(|_| buf.len())
+    }
+
+    fn write_data(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        let cluster_size = self.fs.cluster_size();
+        if u64::from(self.pos) + buf.len() as u64 > u64::from(u32::MAX) {
+            return Err(FsError::DiskFull);
+        }
+        let mut done = 0;
+        let mut tmp = [0u8; BLOCK_SIZE];
+        while done < buf.len() {
+            let index = self.pos / cluster_size;
+            let cluster = match self.cluster_at(index)? {
+                Some(c) => c,
+                None => {
+                    let prev = if index == 0 { None } else { self.cluster_at(index - 1)? };
+                    if index > 0 && prev.is_none() {
+                        return Err(FsError::Corrupt);
+                    }
+                    let c = self.fs.alloc_cluster(prev, false)?;
+                    if prev.is_none() {
+                        self.first_cluster = c;
+                    }
+                    self.cluster = c;
+                    self.cluster_index = index;
+                    c
+                }
+            };
+            let ofs = self.pos % cluster_size;
+            let sector = self.fs.cluster_sector(cluster) + ofs / BLOCK_SIZE as u32;
+            let sector_ofs = (ofs % BLOCK_SIZE as u32) as usize;
+            let n = if sector_ofs == 0 && buf.len() - done >= BLOCK_SIZE {
+                let left_in_cluster = ((cluster_size - ofs) as usize) / BLOCK_SIZE;
+                let n = core::cmp::min((buf.len() - done) / BLOCK_SIZE, left_in_cluster) * BLOCK_SIZE;
+                self.fs.write_sector(&buf[done..done + n], sector)?;
+                n
+            } else {
+                let n = core::cmp::min(BLOCK_SIZE - sector_ofs, buf.len() - done);
+                if self.pos - (sector_ofs as u32) < self.size {
+                    // Sector has existing data
+                    self.fs.read_sector(&mut tmp, sector)?;
+                } else {
+                    tmp = [0; BLOCK_SIZE];
+                }
+                tmp[sector_ofs..sector_ofs + n].copy_from_slice(&buf[done..done + n]);
+                self.fs.write_sector(&tmp, sector)?;
+                n
+            };
+            done += n;
+            self.pos += n as u32;
+            if self.pos > self.size {
+                self.size = self.pos;
+            }
+        }
+        Ok(())
+    }
+
+    /** Cut off the file at the current position, freeing the clusters after it. */
+    pub fn truncate(&mut self) -> Result<(), FsError> {
+        if self.mode == OpenMode::Read {
+            return Err(FsError::ReadOnly);
+        }
+        let cluster_size = self.fs.cluster_size();
+        if self.pos == 0 {
+            if self.first_cluster != 0 {
+                self.fs.free_chain(self.first_cluster)?;
+            }
+            self.first_cluster = 0;
+        } else {
+            let last = self
+                .cluster_at((self.pos - 1) / cluster_size)?
+                .ok_or(FsError::Corrupt)?;
+            self.fs.truncate_chain(last)?;
+        }
+        self.cluster = 0;
+        self.size = self.pos;
+        self.update_entry()
+    }
+
+    /** Write size and first cluster to the directory entry, and flush the FAT */
+    fn update_entry(&mut self) -> Result<(), FsError> {
+        self.fs
+            .update_entry(self.entry_sector, self.entry_offset, self.first_cluster, self.size)?;
+        self.fs.sync()
+    }
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /** Open or create a file. The parent directory must exist. */
+    pub fn open(&self, path: &str, mode: OpenMode) -> Result<File<'_, D>, FsError> {
+        let (dir, name) = self.parent_of(path)?;
+        let entry = match self.find_in(dir, name) {
+            Ok(entry) => entry,
+            Err(FsError::NotFound) if mode == OpenMode::Create || mode == OpenMode::Append => {
+                let entry = self.create_entry(dir, name, ATTR_ARCHIVE, 0);
+                self.sync()?;
+                entry?
+            }
+            Err(e) => return Err(e),
+        };
+        if entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let mut file = File {
+            fs: self,
+            entry_sector: entry.sector,
+            entry_offset: entry.offset,
+            first_cluster: match entry.first_cluster() {
+                0 => 0,
+                c => self.check_cluster(c)?,
+            },
+            size: entry.len(),
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+            mode,
+        };
+        if mode == OpenMode::Create && (file.size != 0 || file.first_cluster != 0) {
+            file.truncate()?;
+        } else if mode == OpenMode::Append {
+            file.pos = file.size;
+        }
+        Ok(file)
+    }
+}
@@ -0,0 +1,85 @@
+//! Block device backed by a disk image file, for testing on the host
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{BlockDevice, BLOCK_SIZE};
+
+pub struct FileBlock {
+    file: File,
+    num_blocks: u32,
+    /** Temporary file to remove on drop */
+    temp: Option<PathBuf>,
+}
+
+impl FileBlock {
+    /** Open an existing disk image. */
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let num_blocks = (file.metadata()?.len() / BLOCK_SIZE as u64) as u32;
+        Ok(Self {
+            file,
+            num_blocks,
+            temp: None,
+        })
+    }
+
+    /** Create an empty (sparse) temporary disk image of `num_blocks` blocks, which is removed
+     * when the device is dropped. */
+    pub fn temp(num_blocks: u32) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(std::format!(
+            "k210-shared-test-{}-{}.img",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(u64::from(num_blocks) * BLOCK_SIZE as u64).unwrap();
+        Self {
+            file,
+            num_blocks,
+            temp: Some(path),
+        }
+    }
+
+    fn seek(&self, len: usize, block: u32) -> Result<(), ()> {
+        if len % BLOCK_SIZE != 0 || u64::from(block) + (len / BLOCK_SIZE) as u64 > u64::from(self.num_blocks) {
+            return Err(());
+        }
+        (&self.file)
+            .seek(SeekFrom::Start(u64::from(block) * BLOCK_SIZE as u64))
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+impl BlockDevice for FileBlock {
+    fn read_blocks(&self, buf: &mut [u8], block: u32) -> Result<(), ()> {
+        self.seek(buf.len(), block)?;
+        (&self.file).read_exact(buf).map_err(|_| ())
+    }
+
+    fn write_blocks(&self, buf: &[u8], block: u32) -> Result<(), ()> {
+        self.seek(buf.len(), block)?;
+        (&self.file).write_all(buf).map_err(|_| ())
+    }
+
+    fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+}
+
+impl Drop for FileBlock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.temp {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! Partition tables: MBR (primary partitions) and GPT
+use super::{read_u16le, read_u32le, BlockDevice, FsError, BLOCK_SIZE};
+
+/** MBR partition type of a GPT protective MBR */
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+/** Offset of the partition entries in the MBR */
+const MBR_ENTRIES_OFS: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_NUM_ENTRIES: usize = 4;
+/** Offset of the 0x55 0xaa boot signature, in both MBR and FAT boot sector */
+const BOOT_SIGNATURE_OFS: usize = 510;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/** Minimum size of the GPT header */
+const GPT_HEADER_SIZE: usize = 92;
+
+/** GPT type GUIDs, in on-disk byte order */
+pub const GUID_BASIC_DATA: [u8; 16] = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7,
+];
+pub const GUID_EFI_SYSTEM: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/** Type of a partition, as stored in the partition table */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionType {
+    /** MBR partition type byte */
+    Mbr(u8),
+    /** GPT partition type GUID, in on-disk byte order */
+    Gpt([u8; 16]),
+}
+
+/** Partition table entry */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /** First block of the partition */
+    pub start: u32,
+    /** Length of the partition in blocks */
+    pub len: u32,
+    pub ptype: PartitionType,
+}
+
+impl PartitionInfo {
+    /** Whether the partition type indicates a FAT filesystem. For GPT this includes the
+     * basic data type, which can also hold other filesystems. */
+    pub fn is_fat(&self) -> bool {
+        match self.ptype {
+            PartitionType::Mbr(t) => matches!(t, 0x01 | 0x04 | 0x06 | 0x0b | 0x0c | 0x0e),
+            PartitionType::Gpt(guid) => guid == GUID_BASIC_DATA || guid == GUID_EFI_SYSTEM,
+        }
+    }
+}
+
+/** Block device restricted to one partition of an underlying device */
+pub struct Partition<D> {
+    dev: D,
+    start: u32,
+    len: u32,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    pub fn new(dev: D, info: &PartitionInfo) -> Self {
+        Self {
+            dev,
+            start: info.start,
+            len: info.len,
+        }
+    }
+
+    /** Partition spanning the whole device (for "superfloppy" disks without partition
+     * table). */
+    pub fn whole(dev: D) -> Self {
+        let len = dev.num_blocks();
+        Self { dev, start: 0, len }
+    }
+
+    /** First block of the partition on the underlying device */
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    fn check(&self, len: usize, block: u32) -> Result<u32, ()> {
+        let count = (len / BLOCK_SIZE) as u32;
+        if block >= self.len || count > self.len - block {
+            return Err(());
+        }
+        Ok(self.start + block)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn read_blocks(&self, buf: &mut [u8], block: u32) -> Result<(), ()> {
+        let block = self.check(buf.len(), block)?;
+        self.dev.read_blocks(buf, block)
+    }
+
+    fn write_blocks(&self, buf: &[u8], block: u32) -> Result<(), ()> {
+        let block = self.check(buf.len(), block)?;
+        self.dev.write_blocks(buf, block)
+    }
+
+    fn num_blocks(&self) -> u32 {
+        self.len
+    }
+}
+
+/** Update a CRC-32 (IEEE 802.3, as used by GPT) with more data. Start with `0`. */
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/** Convert a 64-bit LBA to a block number, if it fits */
+fn lba(data: &[u8], ofs: usize) -> Option<u32> {
+    if read_u32le(data, ofs + 4) == 0 {
+        Some(read_u32le(data, ofs))
+    } else {
+        None
+    }
+}
+
+/** Whether a sector looks like a FAT boot sector rather than an MBR. A FAT boot sector starts
+ * with a jump instruction and has a sane bytes-per-sector value. */
+pub(crate) fn is_fat_boot_sector(sector: &[u8]) -> bool {
+    (sector[0] == 0xeb || sector[0] == 0xe9)
+        && read_u16le(sector, 11).is_power_of_two()
+        && read_u16le(sector, 11) >= 512
+}
+
+/** Read the partition table of a device into `out`. Returns the number of partitions found
+ * (limited to the length of `out`). Empty slots are skipped. GPT is used if the MBR is a
+ * protective MBR; MBR extended partitions are not followed. */
+pub fn read_partitions<D: BlockDevice>(dev: &D, out: &mut [PartitionInfo]) -> Result<usize, FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    dev.read_blocks(&mut buf, 0).map_err(|_| FsError::Io)?;
+    if buf[BOOT_SIGNATURE_OFS] != 0x55 || buf[BOOT_SIGNATURE_OFS + 1] != 0xaa || is_fat_boot_sector(&buf) {
+        return Err(FsError::NoFilesystem);
+    }
+    let entries = &buf[MBR_ENTRIES_OFS..MBR_ENTRIES_OFS + MBR_NUM_ENTRIES * MBR_ENTRY_SIZE];
+    if entries
+        .chunks(MBR_ENTRY_SIZE)
+        .any(|e| e[4] == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return read_gpt(dev, out);
+    }
+    let mut count = 0;
+    for e in entries.chunks(MBR_ENTRY_SIZE) {
+        if e[0] != 0x00 && e[0] != 0x80 {
+            // Invalid boot indicator: this is not a partition table
+            return Err(FsError::NoFilesystem);
+        }
+        let (ptype, start, len) = (e[4], read_u32le(e, 8), read_u32le(e, 12));
+        if ptype == 0 || len == 0 || count == out.len() {
+            continue;
+        }
+        out[count] = PartitionInfo {
+            start,
+            len,
+            ptype: PartitionType::Mbr(ptype),
+        };
+        count += 1;
+    }
+    Ok(count)
+}
+
+/** Read the primary GPT. Header and partition array checksums are verified. */
+fn read_gpt<D: BlockDevice>(dev: &D, out: &mut [PartitionInfo]) -> Result<usize, FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    dev.read_blocks(&mut buf, 1).map_err(|_| FsError::Io)?;
+    if !buf.starts_with(GPT_SIGNATURE) {
+        return Err(FsError::NoFilesystem);
+    }
+    let header_size = read_u32le(&buf, 12) as usize;
+    if !(GPT_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+        return Err(FsError::Corrupt);
+    }
+    let header_crc = read_u32le(&buf, 16);
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..header_size].copy_from_slice(&buf[..header_size]);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(0, &header[..header_size]) != header_crc {
+        return Err(FsError::Corrupt);
+    }
+    let entries_lba = lba(&buf, 72).ok_or(FsError::Unsupported)?;
+    let num_entries = read_u32le(&buf, 80) as usize;
+    let entry_size = read_u32le(&buf, 84) as usize;
+    let entries_crc = read_u32le(&buf, 88);
+    if entry_size < 128 || !entry_size.is_power_of_two() || entry_size > BLOCK_SIZE {
+        return Err(FsError::Unsupported);
+    }
+
+    let per_block = BLOCK_SIZE / entry_size;
+    let num_blocks = (num_entries + per_block - 1) / per_block;
+    let mut crc = 0;
+    let mut count = 0;
+    for i in 0..num_blocks {
+        dev.read_blocks(&mut buf, entries_lba + i as u32)
+            .map_err(|_| FsError::Io)?;
+        let in_block = core::cmp::min(per_block, num_entries - i * per_block);
+        crc = crc32(crc, &buf[..in_block * entry_size]);
+        for e in buf[..in_block * entry_size].chunks(entry_size) {
+            let mut guid = [0u8; 16];
+            guid.copy_from_slice(&e[0..16]);
+            if guid == [0; 16] || count == out.len() {
+                continue;
+            }
+            let (first, last) = match (lba(e, 32), lba(e, 40)) {
+                (Some(first), Some(last)) if last >= first => (first, last),
+                _ => continue, // Not addressable with 32-bit block numbers, or invalid
+            };
+            out[count] = PartitionInfo {
+                start: first,
+                len: last - first + 1,
+                ptype: PartitionType::Gpt(guid),
+            };
+            count += 1;
+        }
+    }
+    if crc != entries_crc {
+        return Err(FsError::Corrupt);
+    }
+    Ok(count)
+}
+
+/** Find the volume holding a FAT filesystem: the first FAT partition, or the whole device if
+ * it has no partition table but starts with a FAT boot sector. */
+pub fn find_fat<D: BlockDevice>(dev: D) -> Result<Partition<D>, FsError> {
+    let mut parts = [PartitionInfo {
+        start: 0,
+        len: 0,
+        ptype: PartitionType::Mbr(0),
+    }; 8];
+    match read_partitions(&dev, &mut parts) {
+        Ok(n) => parts[..n]
+            .iter()
+            .find(|p| p.is_fat())
+            .map(|p| Partition::new(dev, p))
+            .ok_or(FsError::NoFilesystem),
+        Err(FsError::NoFilesystem) => {
+            let mut buf = [0u8; BLOCK_SIZE];
+            dev.read_blocks(&mut buf, 0).map_err(|_| FsError::Io)?;
+            if is_fat_boot_sector(&buf) {
+                Ok(Partition::whole(dev))
+            } else {
+                Err(FsError::NoFilesystem)
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::file_block::FileBlock;
+    use crate::fs::write_u32le;
+
+    fn mbr_entry(buf: &mut [u8], idx: usize, ptype: u8, start: u32, len: u32) {
+        let e = &mut buf[MBR_ENTRIES_OFS + idx * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        e[4] = ptype;
+        write_u32le(e, 8, start);
+        write_u32le(e, 12, len);
+    }
+
+    fn empty() -> PartitionInfo {
+        PartitionInfo {
+            start: 0,
+            len: 0,
+            ptype: PartitionType::Mbr(0),
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_mbr() {
+        let dev = FileBlock::temp(64);
+        let mut buf = [0u8; BLOCK_SIZE];
+        mbr_entry(&mut buf, 0, 0x0c, 8, 16);
+        mbr_entry(&mut buf, 2, 0x83, 24, 40);
+        buf[510] = 0x55;
+        buf[511] = 0xaa;
+        dev.write_blocks(&buf, 0).unwrap();
+
+        let mut parts = [empty(); 4];
+        assert_eq!(read_partitions(&dev, &mut parts), Ok(2));
+        assert_eq!(parts[0], PartitionInfo { start: 8, len: 16, ptype: PartitionType::Mbr(0x0c) });
+        assert_eq!(parts[1], PartitionInfo { start: 24, len: 40, ptype: PartitionType::Mbr(0x83) });
+        assert!(parts[0].is_fat() && !parts[1].is_fat());
+
+        // Partition device bounds
+        let part = Partition::new(&dev, &parts[0]);
+        assert_eq!(part.num_blocks(), 16);
+        part.write_blocks(&[0x5a; BLOCK_SIZE * 2], 14).unwrap();
+        assert!(part.write_blocks(&[0x5a; BLOCK_SIZE * 2], 15).is_err());
+        assert!(part.read_blocks(&mut buf, 16).is_err());
+        dev.read_blocks(&mut buf, 23).unwrap();
+        assert_eq!(buf[0], 0x5a);
+
+        // Not a partition table
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.write_blocks(&buf, 0).unwrap();
+        assert_eq!(read_partitions(&dev, &mut parts), Err(FsError::NoFilesystem));
+        buf[0] = 0xeb;
+        buf[11] = 0x00;
+        buf[12] = 0x02;
+        buf[510] = 0x55;
+        buf[511] = 0xaa;
+        dev.write_blocks(&buf, 0).unwrap();
+        assert_eq!(read_partitions(&dev, &mut parts), Err(FsError::NoFilesystem));
+        assert_eq!(find_fat(&dev).unwrap().num_blocks(), 64);
+    }
+
+    /** Write a GPT with the given partitions (type, first, last) */
+    fn write_gpt(dev: &FileBlock, parts: &[([u8; 16], u32, u32)]) {
+        let mut mbr = [0u8; BLOCK_SIZE];
+        mbr_entry(&mut mbr, 0, MBR_TYPE_GPT_PROTECTIVE, 1, dev.num_blocks() - 1);
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        dev.write_blocks(&mbr, 0).unwrap();
+
+        // 128 entries of 128 bytes in blocks 2..34
+        let mut entries = [0u8; 128 * 128];
+        for (i, (guid, first, last)) in parts.iter().enumerate() {
+            let e = &mut entries[i * 128..];
+            e[0..16].copy_from_slice(guid);
+            e[16] = i as u8 + 1;
+            write_u32le(e, 32, *first);
+            write_u32le(e, 40, *last);
+        }
+        dev.write_blocks(&entries, 2).unwrap();
+
+        let mut hdr = [0u8; BLOCK_SIZE];
+        hdr[0..8].copy_from_slice(GPT_SIGNATURE);
+        write_u32le(&mut hdr, 8, 0x0001_0000);
+        write_u32le(&mut hdr, 12, GPT_HEADER_SIZE as u32);
+        write_u32le(&mut hdr, 24, 1);
+        write_u32le(&mut hdr, 32, dev.num_blocks() - 1);
+        write_u32le(&mut hdr, 40, 34);
+        write_u32le(&mut hdr, 48, dev.num_blocks() - 34);
+        write_u32le(&mut hdr, 72, 2);
+        write_u32le(&mut hdr, 80, 128);
+        write_u32le(&mut hdr, 84, 128);
+        write_u32le(&mut hdr, 88, crc32(0, &entries));
+        let crc = crc32(0, &hdr[..GPT_HEADER_SIZE]);
+        write_u32le(&mut hdr, 16, crc);
+        dev.write_blocks(&hdr, 1).unwrap();
+    }
+
+    #[test]
+    fn test_gpt() {
+        let dev = FileBlock::temp(128);
+        write_gpt(&dev, &[([0x42; 16], 34, 39), (GUID_BASIC_DATA, 40, 99)]);
+        let mut parts = [empty(); 4];
+        assert_eq!(read_partitions(&dev, &mut parts), Ok(2));
+        assert_eq!(parts[0], PartitionInfo { start: 34, len: 6, ptype: PartitionType::Gpt([0x42; 16]) });
+        assert_eq!(parts[1], PartitionInfo { start: 40, len: 60, ptype: PartitionType::Gpt(GUID_BASIC_DATA) });
+        assert!(!parts[0].is_fat() && parts[1].is_fat());
+
+        // Only as many as fit
+        assert_eq!(read_partitions(&dev, &mut parts[..1]), Ok(1));
+
+        // Corrupt partition array
+        let mut buf = [0u8; BLOCK_SIZE];
+        dev.read_blocks(&mut buf, 2).unwrap();
+        buf[200] ^= 1;
+        dev.write_blocks(&buf, 2).unwrap();
+        assert_eq!(read_partitions(&dev, &mut parts), Err(FsError::Corrupt));
+    }
+}
@@ -3,9 +3,14 @@
 #![allow(non_camel_case_types)]
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 pub mod board;
 #[cfg(not(test))]
 pub mod debug;
+pub mod fs;
+#[cfg(not(test))]
 pub mod panic;
 pub mod soc;
 pub mod timing;
@@ -5,6 +5,7 @@ pub mod fpioa;
 pub mod gpio;
 pub mod gpiohs;
 pub mod i2c;
+pub mod plic;
 pub mod pwm;
 pub mod sha256;
 pub mod sleep;
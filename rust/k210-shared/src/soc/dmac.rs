@@ -1,10 +1,19 @@
 //! DMAC peripheral
+use core::mem::ManuallyDrop;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
 use k210_hal::pac;
 use pac::dmac::channel::cfg::{TT_FC_A,HS_SEL_SRC_A};
 use pac::dmac::channel::ctl::{SMS_A};
+use pac::interrupt::Interrupt;
 
+use crate::soc::plic;
 use crate::soc::sysctl;
 
+pub mod lli;
+pub mod mem;
+use lli::{Chain, LLI};
+
 /** Extension trait for adding configure() to DMAC peripheral */
 pub trait DMACExt: Sized {
     /// Constrains DVP peripheral
@@ -20,16 +29,33 @@ pub struct DMAC {
     dmac: pac::DMAC,
 }
 
-/*
-typedef struct _dmac_context
-{
-    dmac_channel_number_t dmac_channel;
-    plic_irq_callback_t callback;
-    void *ctx;
-} dmac_context_t;
-
-dmac_context_t dmac_context[6];
-*/
+/** Errors reported by a DMA channel */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DMAError {
+    /** Source or destination address is not mapped */
+    Decode,
+    /** Source or destination responded with an error */
+    Slave,
+    /** Error reading or writing back a linked-list item, or invalid item */
+    LinkedList,
+    /** Invalid channel configuration */
+    Config,
+    /** Buffer is outside SRAM, or two buffers are the same memory through different aliases */
+    Address,
+    /** Buffer lengths do not match, or a range is out of bounds */
+    Length,
+    /** Buffers overlap in a way the transfer would corrupt */
+    Overlap,
+    /** Element size cannot be transferred as a single item */
+    Alignment,
+}
+
+/** Called from the interrupt handler when a transfer on a channel completes or fails */
+pub type Callback = fn(dma_channel, Result<(), DMAError>);
+
+/** Called from the interrupt handler when a block of a circular chain completes, with the
+ * index of the block */
+pub type BlockCallback = fn(dma_channel, usize);
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum src_dst_select {
@@ -44,6 +70,126 @@ pub type address_increment = pac::dmac::channel::ctl::SINC_A;
 pub type burst_length = pac::dmac::channel::ctl::SRC_MSIZE_A;
 pub type transfer_width = pac::dmac::channel::ctl::SRC_TR_WIDTH_A;
 
+/** Number of DMA channels */
+const NUM_CHANNELS: usize = 6;
+
+/** Multi-block transfer types for channel cfg */
+const MULTBLK_CONTIGUOUS: u8 = 0;
+const MULTBLK_LINKED_LIST: u8 = 3;
+
+/** Channel interrupt status bits */
+const INT_BLOCK_TFR_DONE: u32 = 1 << 0;
+const INT_DMA_TFR_DONE: u32 = 1 << 1;
+const INT_DECODE_ERR: u32 = (1 << 5) | (1 << 6);
+const INT_SLAVE_ERR: u32 = (1 << 7) | (1 << 8);
+const INT_LLI_ERR: u32 = 0x1F << 9;
+const INT_CONFIG_ERR: u32 = 1 << 14;
+const INT_ERRORS: u32 = INT_DECODE_ERR | INT_SLAVE_ERR | INT_LLI_ERR | INT_CONFIG_ERR;
+
+/** Per-channel interrupt status bits collected by the interrupt handler since the last transfer
+ * was started */
+static CHANNEL_STATUS: [AtomicU32; NUM_CHANNELS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/** Per-channel completion callbacks */
+static mut CALLBACKS: [Option<Callback>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+
+/** Per-channel block completion callbacks for circular chains */
+static mut BLOCK_CALLBACKS: [Option<BlockCallback>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+
+/** Descriptors of the circular chain running on each channel, if any */
+static mut CIRCULAR: [Option<(*mut LLI, usize)>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+
+/** Number of blocks of the circular chain completed on each channel */
+static BLOCK_COUNT: [AtomicU32; NUM_CHANNELS] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/** Outcome of a transfer from channel interrupt status bits, `None` if it is still running */
+fn status_result(status: u32) -> Option<Result<(), DMAError>> {
+    if (status & INT_ERRORS) != 0 {
+        Some(Err(if (status & INT_DECODE_ERR) != 0 {
+            DMAError::Decode
+        } else if (status & INT_SLAVE_ERR) != 0 {
+            DMAError::Slave
+        } else if (status & INT_LLI_ERR) != 0 {
+            DMAError::LinkedList
+        } else {
+            DMAError::Config
+        }))
+    } else if (status & INT_DMA_TFR_DONE) != 0 {
+        Some(Ok(()))
+    } else {
+        None
+    }
+}
+
+/** PLIC interrupt for a DMA channel */
+fn channel_interrupt(channel_num: dma_channel) -> Interrupt {
+    use dma_channel::*;
+    match channel_num {
+        CHANNEL0 => Interrupt::DMA0,
+        CHANNEL1 => Interrupt::DMA1,
+        CHANNEL2 => Interrupt::DMA2,
+        CHANNEL3 => Interrupt::DMA3,
+        CHANNEL4 => Interrupt::DMA4,
+        CHANNEL5 => Interrupt::DMA5,
+    }
+}
+
+/** DMA channel for a PLIC interrupt */
+fn interrupt_channel(interrupt: Interrupt) -> Option<dma_channel> {
+    use dma_channel::*;
+    match interrupt {
+        Interrupt::DMA0 => Some(CHANNEL0),
+        Interrupt::DMA1 => Some(CHANNEL1),
+        Interrupt::DMA2 => Some(CHANNEL2),
+        Interrupt::DMA3 => Some(CHANNEL3),
+        Interrupt::DMA4 => Some(CHANNEL4),
+        Interrupt::DMA5 => Some(CHANNEL5),
+        _ => None,
+    }
+}
+
+/** Handle DMA channel interrupt */
+fn interrupt_dma(interrupt: Interrupt) {
+    if let Some(channel_num) = interrupt_channel(interrupt) {
+        unsafe {
+            let ch = &(*pac::DMAC::ptr()).channel[channel_num.idx()];
+            let status = ch.intstatus.read().bits() as u32;
+            ch.intclear.write(|w| w.bits(0xffffffff));
+            CHANNEL_STATUS[channel_num.idx()].fetch_or(status, Ordering::SeqCst);
+            if (status & INT_BLOCK_TFR_DONE) != 0 {
+                if let Some((items, len)) = CIRCULAR[channel_num.idx()] {
+                    // re-arm descriptors before the controller comes around to them again
+                    lli::revalidate(items, len);
+                    let block = BLOCK_COUNT[channel_num.idx()].fetch_add(1, Ordering::SeqCst);
+                    let block = block as usize % len;
+                    if let Some(callback) = BLOCK_CALLBACKS[channel_num.idx()] {
+                        callback(channel_num, block);
+                    }
+                }
+            }
+            if let Some(result) = status_result(status) {
+                if let Some(callback) = CALLBACKS[channel_num.idx()] {
+                    callback(channel_num, result);
+                }
+            }
+        }
+    }
+}
+
 /** Return whether a specific address considered considered memory or peripheral */
 fn is_memory(address: u64) -> bool {
     let mem_len = 6 * 1024 * 1024;
@@ -221,13 +367,17 @@ impl DMAC {
         unsafe {
             let ch = &self.dmac.channel[channel_num.idx()];
             ch.intclear.write(|w| w.bits(0xffffffff));
-            ch.intstatus_en.write(|w| w.bits(0x2));
+            ch.intsignal_en.write(|w| {
+                w.bits(u64::from(INT_BLOCK_TFR_DONE | INT_DMA_TFR_DONE | INT_ERRORS))
+            });
         }
     }
 
+    /** Disable the interrupt signal for a channel. Status bits are still collected, so
+     * completion can be polled. */
     pub fn disable_channel_interrupt(&self, channel_num: dma_channel) {
         unsafe {
-            self.dmac.channel[channel_num.idx()].intstatus_en.write(
+            self.dmac.channel[channel_num.idx()].intsignal_en.write(
                 |w| w.bits(0x0));
         }
     }
@@ -239,27 +389,22 @@ impl DMAC {
         }
     }
 
-    /** Set DMA channel parameters. */
-    pub fn set_channel_param(&self, channel_num: dma_channel,
-                               src: u64, dest: u64, src_inc: address_increment, dest_inc: address_increment,
-                               burst_size: burst_length,
-                               trans_width: transfer_width,
-                               block_size: u32) {
+    /** Set flow control, handshaking and multi-block type for a channel. */
+    fn configure_channel(&self, channel_num: dma_channel, src_is_mem: bool, dest_is_mem: bool,
+                         multblk_type: u8) {
+        let ch = &self.dmac.channel[channel_num.idx()];
+        let flow_control = match (src_is_mem, dest_is_mem) {
+            (false, false) => TT_FC_A::PRF2PRF_DMA,
+            (true, false) => TT_FC_A::MEM2PRF_DMA,
+            (false, true) => TT_FC_A::PRF2MEM_DMA,
+            (true, true) => TT_FC_A::MEM2MEM_DMA,
+        };
+
+        /*
+         * cfg register must configure before ts_block and
+         * sar dar register
+         */
         unsafe {
-            let ch = &self.dmac.channel[channel_num.idx()];
-            let src_is_mem = is_memory(src);
-            let dest_is_mem = is_memory(dest);
-            let flow_control = match (src_is_mem, dest_is_mem) {
-                (false, false) => TT_FC_A::PRF2PRF_DMA,
-                (true, false) => TT_FC_A::MEM2PRF_DMA,
-                (false, true) => TT_FC_A::PRF2MEM_DMA,
-                (true, true) => TT_FC_A::MEM2MEM_DMA,
-            };
-
-            /*
-             * cfg register must configure before ts_block and
-             * sar dar register
-             */
             ch.cfg.modify(|_,w|
                 w.tt_fc().variant(flow_control)
                  .hs_sel_src().variant(if src_is_mem { HS_SEL_SRC_A::SOFTWARE } else { HS_SEL_SRC_A::HARDWARE } )
@@ -271,9 +416,21 @@ impl DMAC {
                  // another channel.
                  .src_per().bits(channel_num as u8)
                  .dst_per().bits(channel_num as u8)
-                 .src_multblk_type().bits(0)
-                 .dst_multblk_type().bits(0)
+                 .src_multblk_type().bits(multblk_type)
+                 .dst_multblk_type().bits(multblk_type)
             );
+        }
+    }
+
+    /** Set DMA channel parameters. */
+    pub fn set_channel_param(&self, channel_num: dma_channel,
+                               src: u64, dest: u64, src_inc: address_increment, dest_inc: address_increment,
+                               burst_size: burst_length,
+                               trans_width: transfer_width,
+                               block_size: u32) {
+        unsafe {
+            let ch = &self.dmac.channel[channel_num.idx()];
+            self.configure_channel(channel_num, is_memory(src), is_memory(dest), MULTBLK_CONTIGUOUS);
 
             ch.sar.write(|w| w.bits(src));
             ch.dar.write(|w| w.bits(dest));
@@ -390,18 +547,6 @@ pub void set_flow_control(&self, dmac_channel_number_t channel_num,
 
     writeq(cfg_u.data, &dmac->channel[channel_num].cfg);
 }
-*/
-
-/*
-pub void set_linked_list_addr_point(&self, dmac_channel_number_t channel_num,
-                                     uint64_t *addr) {
-    dmac_ch_llp_u_t llp_u;
-
-    llp_u.data = readq(&dmac->channel[channel_num].llp);
-    /* Cast pointer to uint64_t */
-    llp_u.llp.loc = (uint64_t)addr;
-    writeq(llp_u.data, &dmac->channel[channel_num].llp);
-}
 */
 
     /** Initialize DMA controller */
@@ -448,132 +593,20 @@ pub void set_linked_list_addr_point(&self, dmac_channel_number_t channel_num,
              .ch5_en_we().set_bit()
              );
 
-        self.enable();
-    }
-
-// TODO: list (scatter/gather) functionality
-
-/*
-static void list_add(struct list_head_t *new, struct list_head_t *prev,
-                     struct list_head_t *next) {
-    next->prev = new;
-    new->next = next;
-    new->prev = prev;
-    prev->next = new;
-}
-*/
-
-/*
-pub void list_add_tail(struct list_head_t *new, struct list_head_t *head) {
-    list_add(new, head->prev, head);
-}
-*/
-
-/*
-pub void INIT_LIST_HEAD(struct list_head_t *list) {
-    list->next = list;
-    list->prev = list;
-}
-*/
-
-/*
-pub void link_list_item(dmac_channel_number_t channel_num,
-                         uint8_t LLI_row_num, int8_t LLI_last_row,
-                         dmac_lli_item_t *lli_item,
-                         dmac_channel_config_t *cfg_param) {
-    dmac_ch_ctl_u_t ctl;
-    dmac_ch_llp_u_t llp_u;
-
-    lli_item[LLI_row_num].sar = cfg_param->sar;
-    lli_item[LLI_row_num].dar = cfg_param->dar;
-
-    ctl.data = readq(&dmac->channel[channel_num].ctl);
-    ctl.ch_ctl.sms = cfg_param->ctl_sms;
-    ctl.ch_ctl.dms = cfg_param->ctl_dms;
-    ctl.ch_ctl.sinc = cfg_param->ctl_sinc;
-    ctl.ch_ctl.dinc = cfg_param->ctl_dinc;
-    ctl.ch_ctl.src_tr_width = cfg_param->ctl_src_tr_width;
-    ctl.ch_ctl.dst_tr_width = cfg_param->ctl_dst_tr_width;
-    ctl.ch_ctl.src_msize = cfg_param->ctl_src_msize;
-    ctl.ch_ctl.dst_msize = cfg_param->ctl_drc_msize;
-    ctl.ch_ctl.src_stat_en = cfg_param->ctl_src_stat_en;
-    ctl.ch_ctl.dst_stat_en = cfg_param->ctl_dst_stat_en;
-
-    if(LLI_last_row != LAST_ROW)
-    {
-        ctl.ch_ctl.shadowreg_or_lli_valid = 1;
-        ctl.ch_ctl.shadowreg_or_lli_last = 0;
-    } else
-    {
-        ctl.ch_ctl.shadowreg_or_lli_valid = 1;
-        ctl.ch_ctl.shadowreg_or_lli_last = 1;
-    }
-
-    lli_item[LLI_row_num].ctl = ctl.data;
-
-    lli_item[LLI_row_num].ch_block_ts = cfg_param->ctl_block_ts;
-    lli_item[LLI_row_num].sstat = 0;
-    lli_item[LLI_row_num].dstat = 0;
-
-    llp_u.data = readq(&dmac->channel[channel_num].llp);
-
-    if(LLI_last_row != LAST_ROW)
-        llp_u.llp.loc = ((uint64_t)&lli_item[LLI_row_num + 1]) >> 6;
-    else
-        llp_u.llp.loc = 0;
-
-    lli_item[LLI_row_num].llp = llp_u.data;
-}
-
-pub void update_shadow_register(&self, dmac_channel_number_t channel_num,
-                                  int8_t last_block, dmac_channel_config_t *cfg_param) {
-    dmac_ch_ctl_u_t ctl_u;
+        /* collect completion and error status for polling; no interrupt signals until
+         * requested */
+        for ch in self.dmac.channel.iter() {
+            unsafe {
+                ch.intstatus_en.write(|w| {
+                    w.bits(u64::from(INT_BLOCK_TFR_DONE | INT_DMA_TFR_DONE | INT_ERRORS))
+                });
+                ch.intsignal_en.write(|w| w.bits(0));
+            }
+        }
 
-    do
-    {
-        ctl_u.data = readq(&dmac->channel[channel_num].ctl);
-    } while(ctl_u.ch_ctl.shadowreg_or_lli_valid);
-
-    writeq(cfg_param->sar, &dmac->channel[channel_num].sar);
-    writeq(cfg_param->dar, &dmac->channel[channel_num].dar);
-    writeq(cfg_param->ctl_block_ts, &dmac->channel[channel_num].block_ts);
-
-    ctl_u.ch_ctl.sms = cfg_param->ctl_sms;
-    ctl_u.ch_ctl.dms = cfg_param->ctl_dms;
-    ctl_u.ch_ctl.sinc = cfg_param->ctl_sinc;
-    ctl_u.ch_ctl.dinc = cfg_param->ctl_dinc;
-    ctl_u.ch_ctl.src_tr_width = cfg_param->ctl_src_tr_width;
-    ctl_u.ch_ctl.dst_tr_width = cfg_param->ctl_dst_tr_width;
-    ctl_u.ch_ctl.src_msize = cfg_param->ctl_src_msize;
-    ctl_u.ch_ctl.dst_msize = cfg_param->ctl_drc_msize;
-    ctl_u.ch_ctl.src_stat_en = cfg_param->ctl_src_stat_en;
-    ctl_u.ch_ctl.dst_stat_en = cfg_param->ctl_dst_stat_en;
-    if(last_block != LAST_ROW)
-    {
-        ctl_u.ch_ctl.shadowreg_or_lli_valid = 1;
-        ctl_u.ch_ctl.shadowreg_or_lli_last = 0;
-    } else
-    {
-        ctl_u.ch_ctl.shadowreg_or_lli_valid = 1;
-        ctl_u.ch_ctl.shadowreg_or_lli_last = 1;
+        self.enable();
     }
 
-    writeq(ctl_u.data, &dmac->channel[channel_num].ctl);
-    writeq(0, &dmac->channel[channel_num].blk_tfr);
-}
-*/
-
-/*
-pub void set_shadow_invalid_flag(&self, dmac_channel_number_t channel_num) {
-    dmac_ch_ctl_u_t ctl_u;
-
-    ctl_u.data = readq(&dmac->channel[channel_num].ctl);
-    ctl_u.ch_ctl.shadowreg_or_lli_valid = 1;
-    ctl_u.ch_ctl.shadowreg_or_lli_last = 0;
-    writeq(ctl_u.data, &dmac->channel[channel_num].ctl);
-}
-*/
-
     /** Start a single DMA transfer. */
     pub fn set_single_mode(&self, channel_num: dma_channel,
                               src: u64, dest: u64, src_inc: address_increment,
@@ -581,23 +614,79 @@ pub void set_shadow_invalid_flag(&self, dmac_channel_number_t channel_num) {
                               burst_size: burst_length,
                               trans_width: transfer_width,
                               block_size: u32) {
+        CHANNEL_STATUS[channel_num.idx()].store(0, Ordering::SeqCst);
         self.channel_interrupt_clear(channel_num);
         self.channel_disable(channel_num);
         self.wait_idle(channel_num);
+        self.set_circular(channel_num, None);
         self.set_channel_param(channel_num, src, dest, src_inc, dest_inc,
                                burst_size, trans_width, block_size);
         self.enable();
         self.channel_enable(channel_num);
     }
 
-/*
-pub int is_done(&self, dmac_channel_number_t channel_num) {
-    if(readq(&dmac->channel[channel_num].intstatus) & 0x2)
-        return 1;
-    else
-        return 0;
-}
-*/
+    /** Start a linked-list transfer of the blocks of `chain`. A chain that is not circular
+     * completes like a single transfer. A circular chain runs until the channel is disabled; it
+     * needs the completion interrupt, which re-arms the descriptors and calls the block
+     * callback after every block.
+     * Unsafe because the buffers referenced by the chain, and its descriptors, must stay valid
+     * and must not be accessed until the transfer has completed or the channel is disabled. */
+    pub unsafe fn set_chain_mode(&self, channel_num: dma_channel, chain: &mut Chain) {
+        CHANNEL_STATUS[channel_num.idx()].store(0, Ordering::SeqCst);
+        BLOCK_COUNT[channel_num.idx()].store(0, Ordering::SeqCst);
+        self.channel_interrupt_clear(channel_num);
+        self.channel_disable(channel_num);
+        self.wait_idle(channel_num);
+
+        // descriptors of a chain that ran before had their valid bits cleared on write-back
+        lli::revalidate(chain.head(), chain.num_blocks());
+        self.set_circular(channel_num,
+            if chain.is_circular() { Some((chain.head(), chain.num_blocks())) } else { None });
+        let (src_is_mem, dest_is_mem) = chain.flow();
+        self.configure_channel(channel_num, src_is_mem, dest_is_mem, MULTBLK_LINKED_LIST);
+        // descriptors must be in memory before the controller fetches them
+        fence(Ordering::SeqCst);
+        self.dmac.channel[channel_num.idx()].llp.write(|w| w.bits(chain.head() as u64));
+        self.enable();
+        self.channel_enable(channel_num);
+    }
+
+    /** Set the circular chain descriptors re-armed by the interrupt handler for a channel */
+    fn set_circular(&self, channel_num: dma_channel, items: Option<(*mut LLI, usize)>) {
+        riscv::interrupt::free(|_| unsafe {
+            CIRCULAR[channel_num.idx()] = items;
+        });
+    }
+
+    /** Return whether the last transfer started on a channel has completed. With the completion
+     * interrupt enabled this is set by the interrupt handler, otherwise the channel status is
+     * checked directly. */
+    pub fn is_done(&self, channel_num: dma_channel) -> bool {
+        self.result(channel_num).is_some()
+    }
+
+    /** Return the outcome of the last transfer started on a channel, or `None` if it is still
+     * running. A transfer that failed counts as done. */
+    pub fn result(&self, channel_num: dma_channel) -> Option<Result<(), DMAError>> {
+        let status = CHANNEL_STATUS[channel_num.idx()].load(Ordering::SeqCst)
+            | self.dmac.channel[channel_num.idx()].intstatus.read().bits() as u32;
+        status_result(status)
+    }
+
+    /** Wait for the last transfer started on a channel to complete or fail, and for the
+     * channel to become idle. */
+    fn finish(&self, channel_num: dma_channel) -> Result<(), DMAError> {
+        let result = loop {
+            if let Some(result) = self.result(channel_num) {
+                break result;
+            }
+        };
+        if result.is_err() {
+            self.channel_disable(channel_num);
+        }
+        self.wait_idle(channel_num);
+        result
+    }
 
     /** Wait for dmac work done. */
     pub fn wait_done(&self, channel_num: dma_channel) {
@@ -628,6 +717,102 @@ pub void set_src_dest_length(&self, dmac_channel_number_t channel_num, const voi
 }
 */
 
-// TODO: completion IRQ functionality
+    /** Set or remove the function called from the interrupt handler when a transfer on a
+     * channel completes or fails. Only has effect while the completion interrupt is enabled;
+     * set it before starting the transfer. */
+    pub fn set_callback(&self, channel_num: dma_channel, callback: Option<Callback>) {
+        riscv::interrupt::free(|_| unsafe {
+            CALLBACKS[channel_num.idx()] = callback;
+        });
+    }
+
+    /** Set or remove the function called from the interrupt handler after every block of a
+     * circular chain on a channel. */
+    pub fn set_block_callback(&self, channel_num: dma_channel, callback: Option<BlockCallback>) {
+        riscv::interrupt::free(|_| unsafe {
+            BLOCK_CALLBACKS[channel_num.idx()] = callback;
+        });
+    }
+
+    /** Enable or disable the transfer completion and error interrupts for a channel. When
+     * enabled, `is_done` can be used to check for completion without spinning on the channel,
+     * and the callback is called. This requires interrupts to have been enabled with
+     * `plic::init`. */
+    pub fn completion_irq_enable(&self, channel_num: dma_channel, enabled: bool) {
+        let interrupt = channel_interrupt(channel_num);
+        if enabled {
+            self.enable_channel_interrupt(channel_num);
+            plic::register(interrupt, 1, interrupt_dma);
+        } else {
+            plic::unregister(interrupt);
+            self.disable_channel_interrupt(channel_num);
+        }
+    }
+}
+
+/** Memory that can be handed to the DMA controller. The memory must stay valid, at the same
+ * address, for as long as the value exists, even when the value is moved; this holds for
+ * `'static` references. */
+pub unsafe trait DMABuffer: 'static {
+    type Word;
+    /** Start address and length in words */
+    fn dma_region(&self) -> (*const Self::Word, usize);
+}
+
+unsafe impl<T> DMABuffer for &'static [T] {
+    type Word = T;
+    fn dma_region(&self) -> (*const T, usize) {
+        (self.as_ptr(), self.len())
+    }
+}
 
+unsafe impl<T> DMABuffer for &'static mut [T] {
+    type Word = T;
+    fn dma_region(&self) -> (*const T, usize) {
+        (self.as_ptr(), self.len())
+    }
+}
+
+/** DMA transfer in progress on a channel. The transfer owns its buffer, which is given back
+ * when it is done. Dropping the handle before then stops the transfer. */
+pub struct Transfer<'a, B: DMABuffer> {
+    dmac: &'a DMAC,
+    channel_num: dma_channel,
+    buffer: B,
+}
+
+impl<'a, B: DMABuffer> Transfer<'a, B> {
+    /** Wrap a transfer that has been started on a channel.
+     * Unsafe because the transfer must access no other memory than that of `buffer` (besides
+     * peripheral registers). */
+    pub unsafe fn new(dmac: &'a DMAC, channel_num: dma_channel, buffer: B) -> Self {
+        Self { dmac, channel_num, buffer }
+    }
+
+    /** Channel the transfer is running on */
+    pub fn channel(&self) -> dma_channel {
+        self.channel_num
+    }
+
+    /** Return whether the transfer has completed or failed */
+    pub fn is_done(&self) -> bool {
+        self.dmac.is_done(self.channel_num)
+    }
+
+    /** Wait for the transfer to complete, and give back the buffer with the outcome. */
+    pub fn wait(self) -> (B, Result<(), DMAError>) {
+        let result = self.dmac.finish(self.channel_num);
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is not dropped, so the buffer is moved out only once
+        (unsafe { ptr::read(&this.buffer) }, result)
+    }
+}
+
+impl<'a, B: DMABuffer> Drop for Transfer<'a, B> {
+    fn drop(&mut self) {
+        if !self.is_done() {
+            self.dmac.channel_disable(self.channel_num);
+        }
+        self.dmac.wait_idle(self.channel_num);
+    }
 }
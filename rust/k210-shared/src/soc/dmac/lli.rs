@@ -0,0 +1,351 @@
+//! DMAC linked-list (LLI) descriptor chains, for multi-block, scatter-gather and circular
+//! transfers
+use core::marker::PhantomData;
+use core::ptr;
+
+use super::{burst_length, is_memory, transfer_width};
+
+/** Maximum number of items in one block */
+pub const MAX_BLOCK_TS: usize = 0x40_0000;
+
+/* CTL bits, as stored in linked-list items */
+const CTL_DMS_AXI_MASTER_2: u64 = 1 << 2;
+const CTL_SINC_NOCHANGE: u64 = 1 << 4;
+const CTL_DINC_NOCHANGE: u64 = 1 << 6;
+const CTL_SRC_TR_WIDTH_SHIFT: u32 = 8;
+const CTL_DST_TR_WIDTH_SHIFT: u32 = 11;
+const CTL_SRC_MSIZE_SHIFT: u32 = 14;
+const CTL_DST_MSIZE_SHIFT: u32 = 18;
+const CTL_IOC_BLKTFR: u64 = 1 << 58;
+const CTL_LLI_LAST: u64 = 1 << 62;
+const CTL_LLI_VALID: u64 = 1 << 63;
+
+/** Linked-list item: describes one block of a chain. The DMA controller reads these from
+ * memory, and writes back status to them, so they must not be touched while a chain is
+ * running. */
+#[repr(C, align(64))]
+#[derive(Copy, Clone, Debug)]
+pub struct LLI {
+    sar: u64,
+    dar: u64,
+    block_ts: u64,
+    llp: u64,
+    ctl: u64,
+    sstat: u32,
+    dstat: u32,
+    reserved: u64,
+}
+
+impl LLI {
+    /** Unused item, for initializing descriptor storage */
+    pub const EMPTY: LLI = LLI {
+        sar: 0,
+        dar: 0,
+        block_ts: 0,
+        llp: 0,
+        ctl: 0,
+        sstat: 0,
+        dstat: 0,
+        reserved: 0,
+    };
+}
+
+/** Errors building a descriptor chain */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChainError {
+    /** Address is not aligned to the transfer width */
+    Alignment,
+    /** Block is empty, larger than `MAX_BLOCK_TS` items, or overruns its buffer */
+    Length,
+    /** No descriptors left in storage */
+    Full,
+    /** Chain has no blocks */
+    Empty,
+    /** Block mixes memory and peripheral endpoints differently from the first block; the
+     * flow control is set once per channel */
+    FlowControl,
+}
+
+/** Source or destination of a block */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    address: u64,
+    increment: bool,
+    memory: bool,
+    /** Size in bytes, if known */
+    len: Option<usize>,
+}
+
+impl Endpoint {
+    /** Buffer, read or written sequentially */
+    pub fn buffer<T>(buf: &[T]) -> Self {
+        Self {
+            address: buf.as_ptr() as u64,
+            increment: true,
+            memory: true,
+            len: Some(core::mem::size_of_val(buf)),
+        }
+    }
+
+    /** Buffer to be written by the DMA controller */
+    pub fn buffer_mut<T>(buf: &mut [T]) -> Self {
+        Self::buffer(buf)
+    }
+
+    /** Fixed address such as a peripheral data register, accessed for every item */
+    pub fn fixed(address: u64) -> Self {
+        Self { address, increment: false, memory: is_memory(address), len: None }
+    }
+
+    fn check(&self, width_bytes: usize, count: usize) -> Result<(), ChainError> {
+        if (self.address % width_bytes as u64) != 0 {
+            return Err(ChainError::Alignment);
+        }
+        match (self.increment, self.len) {
+            (true, Some(len)) if count * width_bytes > len => Err(ChainError::Length),
+            (false, Some(len)) if width_bytes > len => Err(ChainError::Length),
+            _ => Ok(()),
+        }
+    }
+}
+
+/** Builder for a descriptor chain in caller-provided storage. All blocks share a transfer
+ * width and burst length. */
+pub struct ChainBuilder<'d> {
+    items: &'d mut [LLI],
+    len: usize,
+    width: transfer_width,
+    burst: burst_length,
+    /** Whether source and destination are memory, fixed by the first block */
+    flow: Option<(bool, bool)>,
+}
+
+impl<'d> ChainBuilder<'d> {
+    /** Start a chain using `items` as descriptor storage */
+    pub fn new(items: &'d mut [LLI], width: transfer_width, burst: burst_length) -> Self {
+        Self { items, len: 0, width, burst, flow: None }
+    }
+
+    /** Size of one item in bytes */
+    fn width_bytes(&self) -> usize {
+        1 << (self.width as u32)
+    }
+
+    /** Number of items of the transfer width in `buf`, which must be a whole number */
+    fn items_in<T>(&self, buf: &[T]) -> Result<usize, ChainError> {
+        let bytes = core::mem::size_of_val(buf);
+        if (bytes % self.width_bytes()) != 0 {
+            return Err(ChainError::Length);
+        }
+        Ok(bytes / self.width_bytes())
+    }
+
+    /** Append a block transferring `count` items of the transfer width from `src` to
+     * `dst` */
+    pub fn block(&mut self, src: Endpoint, dst: Endpoint, count: usize)
+        -> Result<&mut Self, ChainError> {
+        if self.len == self.items.len() {
+            return Err(ChainError::Full);
+        }
+        if count == 0 || count > MAX_BLOCK_TS {
+            return Err(ChainError::Length);
+        }
+        let width_bytes = self.width_bytes();
+        src.check(width_bytes, count)?;
+        dst.check(width_bytes, count)?;
+        let flow = (src.memory, dst.memory);
+        if *self.flow.get_or_insert(flow) != flow {
+            return Err(ChainError::FlowControl);
+        }
+
+        let width = self.width as u64;
+        let burst = self.burst as u64;
+        let mut ctl = CTL_DMS_AXI_MASTER_2
+            | (width << CTL_SRC_TR_WIDTH_SHIFT)
+            | (width << CTL_DST_TR_WIDTH_SHIFT)
+            | (burst << CTL_SRC_MSIZE_SHIFT)
+            | (burst << CTL_DST_MSIZE_SHIFT);
+        if !src.increment {
+            ctl |= CTL_SINC_NOCHANGE;
+        }
+        if !dst.increment {
+            ctl |= CTL_DINC_NOCHANGE;
+        }
+        self.items[self.len] = LLI {
+            sar: src.address,
+            dar: dst.address,
+            block_ts: (count - 1) as u64,
+            ctl,
+            ..LLI::EMPTY
+        };
+        self.len += 1;
+        Ok(self)
+    }
+
+    /** Gather `bufs` into consecutive items at a fixed destination, such as a peripheral
+     * data register */
+    pub fn gather<T>(&mut self, bufs: &[&[T]], dst: u64) -> Result<&mut Self, ChainError> {
+        for buf in bufs {
+            let count = self.items_in(buf)?;
+            self.block(Endpoint::buffer(buf), Endpoint::fixed(dst), count)?;
+        }
+        Ok(self)
+    }
+
+    /** Scatter items from a fixed source, such as a peripheral data register, over `bufs` */
+    pub fn scatter<T>(&mut self, src: u64, bufs: &mut [&mut [T]]) -> Result<&mut Self, ChainError> {
+        for buf in bufs.iter_mut() {
+            let count = self.items_in(buf)?;
+            self.block(Endpoint::fixed(src), Endpoint::buffer_mut(buf), count)?;
+        }
+        Ok(self)
+    }
+
+    /** Link the blocks into a chain that ends after the last block */
+    pub fn finish(self) -> Result<Chain<'d>, ChainError> {
+        self.link(false)
+    }
+
+    /** Link the blocks into a ring that restarts at the first block after the last one, and
+     * runs until the channel is disabled. Every block raises a block interrupt. */
+    pub fn finish_circular(self) -> Result<Chain<'d>, ChainError> {
+        self.link(true)
+    }
+
+    fn link(self, circular: bool) -> Result<Chain<'d>, ChainError> {
+        let (src_is_mem, dst_is_mem) = self.flow.ok_or(ChainError::Empty)?;
+        let len = self.len;
+        let base = self.items.as_mut_ptr();
+        for (i, item) in self.items[..len].iter_mut().enumerate() {
+            if i + 1 < len {
+                item.llp = base.wrapping_add(i + 1) as u64;
+            } else if circular {
+                item.llp = base as u64;
+            } else {
+                item.llp = 0;
+                item.ctl |= CTL_LLI_LAST;
+            }
+            if circular {
+                item.ctl |= CTL_IOC_BLKTFR;
+            }
+            item.ctl |= CTL_LLI_VALID;
+        }
+        Ok(Chain { items: base, len, circular, src_is_mem, dst_is_mem, _items: PhantomData })
+    }
+}
+
+/** Linked descriptor chain, ready to be started with `DMAC::set_chain_mode` */
+pub struct Chain<'d> {
+    items: *mut LLI,
+    len: usize,
+    circular: bool,
+    src_is_mem: bool,
+    dst_is_mem: bool,
+    _items: PhantomData<&'d mut [LLI]>,
+}
+
+impl<'d> Chain<'d> {
+    /** Number of blocks */
+    pub fn num_blocks(&self) -> usize {
+        self.len
+    }
+
+    /** Whether the chain restarts after the last block */
+    pub fn is_circular(&self) -> bool {
+        self.circular
+    }
+
+    /** Whether the first block's source and destination are memory */
+    pub(super) fn flow(&self) -> (bool, bool) {
+        (self.src_is_mem, self.dst_is_mem)
+    }
+
+    /** Address of the first item, to program into the channel */
+    pub(super) fn head(&self) -> *mut LLI {
+        self.items
+    }
+
+    /** Descriptors, for inspection while the chain is not running */
+    pub fn items(&self) -> &[LLI] {
+        unsafe { core::slice::from_raw_parts(self.items, self.len) }
+    }
+}
+
+/** Mark `len` items starting at `items` as valid again. The DMA controller clears the valid
+ * bit when writing back an item after its block completes, so this is needed before reusing a
+ * chain, and for every pass over a circular chain. */
+pub(super) unsafe fn revalidate(items: *mut LLI, len: usize) {
+    for i in 0..len {
+        let ctl = ptr::addr_of_mut!((*items.add(i)).ctl);
+        ptr::write_volatile(ctl, ptr::read_volatile(ctl) | CTL_LLI_VALID);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPI_DR: u64 = 0x5200_0060;
+
+    #[test]
+    fn test_gather() {
+        let mut items = [LLI::EMPTY; 4];
+        let a = [0u32; 16];
+        let b = [0u32; 8];
+        let mut builder =
+            ChainBuilder::new(&mut items, transfer_width::WIDTH_32, burst_length::LENGTH_4);
+        builder.gather(&[&a[..], &b[..]], SPI_DR).unwrap();
+        let chain = builder.finish().unwrap();
+        assert_eq!(chain.num_blocks(), 2);
+        assert_eq!(chain.flow(), (true, false));
+        let items = chain.items();
+        assert_eq!(items[0].sar, a.as_ptr() as u64);
+        assert_eq!(items[0].dar, SPI_DR);
+        assert_eq!(items[0].block_ts, 15);
+        assert_eq!(items[0].llp, &items[1] as *const LLI as u64);
+        assert_eq!(items[0].ctl & (CTL_LLI_VALID | CTL_LLI_LAST), CTL_LLI_VALID);
+        assert_eq!(items[0].ctl & (CTL_SINC_NOCHANGE | CTL_DINC_NOCHANGE), CTL_DINC_NOCHANGE);
+        assert_eq!(items[1].block_ts, 7);
+        assert_eq!(items[1].llp, 0);
+        assert_eq!(items[1].ctl & (CTL_LLI_VALID | CTL_LLI_LAST), CTL_LLI_VALID | CTL_LLI_LAST);
+    }
+
+    #[test]
+    fn test_circular() {
+        let mut items = [LLI::EMPTY; 2];
+        let mut ring = [0u16; 64];
+        let (first, second) = ring.split_at_mut(32);
+        let mut builder =
+            ChainBuilder::new(&mut items, transfer_width::WIDTH_16, burst_length::LENGTH_1);
+        builder.scatter(SPI_DR, &mut [first, second]).unwrap();
+        let chain = builder.finish_circular().unwrap();
+        let items = chain.items();
+        assert_eq!(items[1].llp, &items[0] as *const LLI as u64);
+        for item in items {
+            assert_eq!(item.block_ts, 31);
+            assert_eq!(item.ctl & (CTL_LLI_LAST | CTL_IOC_BLKTFR), CTL_IOC_BLKTFR);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut items = [LLI::EMPTY; 1];
+        let buf = [0u32; 4];
+        let mut builder =
+            ChainBuilder::new(&mut items, transfer_width::WIDTH_32, burst_length::LENGTH_1);
+        assert_eq!(builder.block(Endpoint::buffer(&buf), Endpoint::fixed(SPI_DR + 2), 4).err(),
+                   Some(ChainError::Alignment));
+        assert_eq!(builder.block(Endpoint::buffer(&buf), Endpoint::fixed(SPI_DR), 5).err(),
+                   Some(ChainError::Length));
+        assert_eq!(builder.block(Endpoint::buffer(&buf), Endpoint::fixed(SPI_DR), 0).err(),
+                   Some(ChainError::Length));
+        builder.block(Endpoint::buffer(&buf), Endpoint::fixed(SPI_DR), 4).unwrap();
+        assert_eq!(builder.block(Endpoint::buffer(&buf), Endpoint::fixed(SPI_DR), 4).err(),
+                   Some(ChainError::Full));
+
+        let mut none: [LLI; 0] = [];
+        let builder =
+            ChainBuilder::new(&mut none, transfer_width::WIDTH_32, burst_length::LENGTH_1);
+        assert_eq!(builder.finish().err(), Some(ChainError::Empty));
+    }
+}
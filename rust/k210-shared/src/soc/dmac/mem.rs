@@ -0,0 +1,196 @@
+//! Memory-to-memory DMA: copy and fill
+//!
+//! Both buffers must be in SRAM, either through the cached mapping at 0x80000000 or the
+//! uncached alias at 0x40000000. Transfers use the widest item size (up to 64 bits) that the
+//! buffer addresses and length are aligned to. The functions block until the transfer is done,
+//! with memory fenced before and after so that CPU and DMA accesses are ordered.
+use core::mem;
+use core::ops::Range;
+use core::sync::atomic::{fence, Ordering};
+
+use super::{address_increment, burst_length, dma_channel, transfer_width, DMAError, DMAC};
+use super::lli::MAX_BLOCK_TS;
+
+/** Cached SRAM mapping */
+const SRAM_CACHED: u64 = 0x8000_0000;
+const SRAM_CACHED_LEN: u64 = 6 * 1024 * 1024;
+/** Uncached SRAM alias */
+const SRAM_UNCACHED: u64 = 0x4000_0000;
+const SRAM_UNCACHED_LEN: u64 = 8 * 1024 * 1024;
+
+/** Offsets into SRAM of the `len` bytes at `address`, independent of the mapping used, or
+ * `None` if they are not all in SRAM */
+fn sram_range(address: u64, len: usize) -> Option<Range<u64>> {
+    let end = address.checked_add(len as u64)?;
+    for &(base, size) in &[(SRAM_CACHED, SRAM_CACHED_LEN), (SRAM_UNCACHED, SRAM_UNCACHED_LEN)] {
+        if address >= base && end <= base + size {
+            return Some((address - base)..(end - base));
+        }
+    }
+    None
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/** Widest item size in bytes that all `addresses` and `len` are aligned to */
+fn copy_width(addresses: &[u64], len: usize) -> usize {
+    let bits = addresses.iter().fold(len as u64 | 8, |acc, a| acc | a);
+    1 << bits.trailing_zeros()
+}
+
+fn width_variant(width: usize) -> transfer_width {
+    match width {
+        1 => transfer_width::WIDTH_8,
+        2 => transfer_width::WIDTH_16,
+        4 => transfer_width::WIDTH_32,
+        _ => transfer_width::WIDTH_64,
+    }
+}
+
+/** 64-bit word repeating the bytes of `value`, whose size must divide 8 */
+fn fill_pattern(value: &[u8]) -> u64 {
+    let mut pattern = [0u8; 8];
+    for (i, b) in pattern.iter_mut().enumerate() {
+        *b = value[i % value.len()];
+    }
+    u64::from_ne_bytes(pattern)
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+impl DMAC {
+    /** Copy `len` bytes from `src` to `dest` in items of `width` bytes, splitting into as many
+     * blocks as needed. If `src_inc` is false the same source item is repeated. */
+    fn copy_raw(&self, channel_num: dma_channel, dest: u64, src: u64, len: usize, width: usize,
+                src_inc: bool) -> Result<(), DMAError> {
+        let src_inc_mode = if src_inc {
+            address_increment::INCREMENT
+        } else {
+            address_increment::NOCHANGE
+        };
+        let mut done = 0;
+        fence(Ordering::SeqCst);
+        while done < len {
+            let items = ((len - done) / width).min(MAX_BLOCK_TS);
+            let src_addr = if src_inc { src + done as u64 } else { src };
+            self.set_single_mode(channel_num, src_addr, dest + done as u64,
+                                 src_inc_mode, address_increment::INCREMENT,
+                                 burst_length::LENGTH_4, width_variant(width), items as u32);
+            self.finish(channel_num)?;
+            done += items * width;
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /** Copy `src` into `dest`, which must have the same length, using a DMA channel. */
+    pub fn memcpy<T: Copy>(&self, channel_num: dma_channel, dest: &mut [T], src: &[T])
+        -> Result<(), DMAError> {
+        if dest.len() != src.len() {
+            return Err(DMAError::Length);
+        }
+        let len = mem::size_of_val(src);
+        if len == 0 {
+            return Ok(());
+        }
+        let dest_addr = dest.as_mut_ptr() as u64;
+        let src_addr = src.as_ptr() as u64;
+        let dest_range = sram_range(dest_addr, len).ok_or(DMAError::Address)?;
+        let src_range = sram_range(src_addr, len).ok_or(DMAError::Address)?;
+        if overlaps(&dest_range, &src_range) {
+            // same memory through the cached and uncached mapping
+            return Err(DMAError::Address);
+        }
+        let width = copy_width(&[dest_addr, src_addr], len);
+        self.copy_raw(channel_num, dest_addr, src_addr, len, width, true)
+    }
+
+    /** Copy elements `src` of `buf` to the position starting at `dest`, like
+     * `slice::copy_within`. The DMA controller copies forwards, so an overlapping destination
+     * must come before the source, as when scrolling up. */
+    pub fn copy_within<T: Copy>(&self, channel_num: dma_channel, buf: &mut [T], src: Range<usize>,
+                                dest: usize) -> Result<(), DMAError> {
+        if src.start > src.end || src.end > buf.len() || dest > buf.len() - (src.end - src.start) {
+            return Err(DMAError::Length);
+        }
+        if dest > src.start && dest < src.end {
+            return Err(DMAError::Overlap);
+        }
+        let size = mem::size_of::<T>();
+        let len = (src.end - src.start) * size;
+        if len == 0 || dest == src.start {
+            return Ok(());
+        }
+        let base = buf.as_mut_ptr() as u64;
+        let src_addr = base + (src.start * size) as u64;
+        let dest_addr = base + (dest * size) as u64;
+        sram_range(base, mem::size_of_val(buf)).ok_or(DMAError::Address)?;
+        let width = copy_width(&[dest_addr, src_addr], len);
+        self.copy_raw(channel_num, dest_addr, src_addr, len, width, true)
+    }
+
+    /** Fill `dest` with copies of `value`. The size of `T` must be 1, 2, 4 or 8 bytes, and
+     * `dest` aligned to it. */
+    pub fn fill<T: Copy>(&self, channel_num: dma_channel, dest: &mut [T], value: T)
+        -> Result<(), DMAError> {
+        let size = mem::size_of::<T>();
+        if !(size == 1 || size == 2 || size == 4 || size == 8) {
+            return Err(DMAError::Alignment);
+        }
+        let len = mem::size_of_val(dest);
+        if len == 0 {
+            return Ok(());
+        }
+        let dest_addr = dest.as_mut_ptr() as u64;
+        sram_range(dest_addr, len).ok_or(DMAError::Address)?;
+        // source is one item repeating the value, read for every destination item
+        let pattern = fill_pattern(as_bytes(&value));
+        let src_addr = &pattern as *const u64 as u64;
+        sram_range(src_addr, 8).ok_or(DMAError::Address)?;
+        // every item reads the start of the pattern, so it must hold whole values
+        let width = copy_width(&[dest_addr], len);
+        if width < size {
+            return Err(DMAError::Alignment);
+        }
+        self.copy_raw(channel_num, dest_addr, src_addr, len, width, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sram_range() {
+        assert_eq!(sram_range(0x8000_0100, 0x100), Some(0x100..0x200));
+        assert_eq!(sram_range(0x4000_0100, 0x100), Some(0x100..0x200));
+        assert_eq!(sram_range(0x805f_ff00, 0x100), Some(0x5f_ff00..0x60_0000));
+        assert_eq!(sram_range(0x805f_ff00, 0x101), None);
+        assert_eq!(sram_range(0x3fff_ff00, 0x200), None);
+        assert_eq!(sram_range(0x5200_0060, 4), None);
+        // cached and uncached mapping of the same memory
+        assert!(overlaps(&sram_range(0x8000_1000, 0x100).unwrap(),
+                         &sram_range(0x4000_1080, 0x100).unwrap()));
+        assert!(!overlaps(&sram_range(0x8000_1000, 0x100).unwrap(),
+                          &sram_range(0x4000_1100, 0x100).unwrap()));
+    }
+
+    #[test]
+    fn test_copy_width() {
+        assert_eq!(copy_width(&[0x8000_0000, 0x8000_1000], 0x1000), 8);
+        assert_eq!(copy_width(&[0x8000_0004, 0x8000_1000], 0x1000), 4);
+        assert_eq!(copy_width(&[0x8000_0000, 0x8000_1000], 0x1002), 2);
+        assert_eq!(copy_width(&[0x8000_0001], 0x1000), 1);
+    }
+
+    #[test]
+    fn test_fill_pattern() {
+        assert_eq!(fill_pattern(&[0xab]), 0xabab_abab_abab_abab);
+        assert_eq!(fill_pattern(as_bytes(&0x1234u16)), 0x1234_1234_1234_1234);
+        assert_eq!(fill_pattern(as_bytes(&0xdead_beefu32)), 0xdead_beef_dead_beef);
+    }
+}
@@ -1,7 +1,14 @@
 //! FPIOA peripheral
+use core::fmt;
 use k210_hal::pac;
 
-#[derive(Copy, Clone)]
+pub mod mux;
+
+/** Number of IO pads */
+pub const IO_COUNT: usize = 48;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum function {
     JTAG_TCLK = 0,        /* JTAG Test Clock */
     JTAG_TDI = 1,         /* JTAG Test Data In */
@@ -301,9 +308,33 @@ impl function {
             _ => panic!("no such GPIO pin"),
         }
     }
+
+    /** GPIO pin to function */
+    pub fn gpio(num: u8) -> function {
+        use function::*;
+        match num {
+            0 => GPIO0,
+            1 => GPIO1,
+            2 => GPIO2,
+            3 => GPIO3,
+            4 => GPIO4,
+            5 => GPIO5,
+            6 => GPIO6,
+            7 => GPIO7,
+            _ => panic!("no such GPIO pin"),
+        }
+    }
+}
+
+impl From<u8> for function {
+    /** Function by number, as in the `ch_sel` field of a pad */
+    fn from(ch_sel: u8) -> function {
+        // every value from 0 to 255 is a function
+        unsafe { core::mem::transmute(ch_sel) }
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum pull {
     /** No Pull */
     NONE,
@@ -367,3 +398,160 @@ pub fn set_io_pull<N: Into<usize>>(number: N, pull: pull) {
         });
     }
 }
+
+/** Configuration of an IO pad, with the fields of its FPIOA register */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PadConfig {
+    /** Function routed to the pad (`ch_sel`) */
+    pub function: function,
+    /** Drive strength (0-15) */
+    pub ds: u8,
+    /** Enable output */
+    pub oe_en: bool,
+    /** Invert output enable */
+    pub oe_inv: bool,
+    /** Output the output enable signal of the function instead of its data */
+    pub do_sel: bool,
+    /** Invert output data */
+    pub do_inv: bool,
+    pub pull: pull,
+    /** Slew rate control */
+    pub sl: bool,
+    /** Enable input */
+    pub ie_en: bool,
+    /** Invert input enable */
+    pub ie_inv: bool,
+    /** Invert input data */
+    pub di_inv: bool,
+    /** Schmitt trigger on the input */
+    pub st: bool,
+}
+
+impl PadConfig {
+    /** Default configuration for a function, as written by `set_function` */
+    pub fn default_for(function: function) -> Self {
+        Self::from_bits(FUNCTION_DEFAULTS[function as usize])
+    }
+
+    /** Decode a pad register value. Pull-up and pull-down both set reads as `pull::UP`. */
+    pub fn from_bits(bits: u32) -> Self {
+        let bit = |n: u32| bits & (1 << n) != 0;
+        Self {
+            function: function::from(bits as u8),
+            ds: ((bits >> 8) & 0xf) as u8,
+            oe_en: bit(12),
+            oe_inv: bit(13),
+            do_sel: bit(14),
+            do_inv: bit(15),
+            pull: match (bit(16), bit(17)) {
+                (false, false) => pull::NONE,
+                (false, true) => pull::DOWN,
+                (true, _) => pull::UP,
+            },
+            sl: bit(19),
+            ie_en: bit(20),
+            ie_inv: bit(21),
+            di_inv: bit(22),
+            st: bit(23),
+        }
+    }
+
+    /** Encode as pad register value */
+    pub fn bits(&self) -> u32 {
+        let bit = |value: bool, n: u32| u32::from(value) << n;
+        (self.function as u32)
+            | (u32::from(self.ds & 0xf) << 8)
+            | bit(self.oe_en, 12)
+            | bit(self.oe_inv, 13)
+            | bit(self.do_sel, 14)
+            | bit(self.do_inv, 15)
+            | bit(self.pull == pull::UP, 16)
+            | bit(self.pull == pull::DOWN, 17)
+            | bit(self.sl, 19)
+            | bit(self.ie_en, 20)
+            | bit(self.ie_inv, 21)
+            | bit(self.di_inv, 22)
+            | bit(self.st, 23)
+    }
+}
+
+impl fmt::Display for PadConfig {
+    /** One line: function, drive strength, pull and the flags that are set */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({}) ds={} pull={:?}", self.function, self.function as u8, self.ds,
+               self.pull)?;
+        let flags = [
+            (self.oe_en, "oe_en"),
+            (self.oe_inv, "oe_inv"),
+            (self.do_sel, "do_sel"),
+            (self.do_inv, "do_inv"),
+            (self.sl, "sl"),
+            (self.ie_en, "ie_en"),
+            (self.ie_inv, "ie_inv"),
+            (self.di_inv, "di_inv"),
+            (self.st, "st"),
+        ];
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/** Configure a pad completely */
+pub fn set_config<N: Into<usize>>(number: N, config: PadConfig) {
+    unsafe {
+        let ptr = pac::FPIOA::ptr();
+        (*ptr).io[number.into()].write(|w| w.bits(config.bits()));
+    }
+}
+
+/** Read back the configuration of a pad */
+pub fn get_config<N: Into<usize>>(number: N) -> PadConfig {
+    unsafe {
+        let ptr = pac::FPIOA::ptr();
+        PadConfig::from_bits((*ptr).io[number.into()].read().bits())
+    }
+}
+
+/** Input value of a pad (`pad_di`) */
+pub fn get_input<N: Into<usize>>(number: N) -> bool {
+    unsafe {
+        let ptr = pac::FPIOA::ptr();
+        (*ptr).io[number.into()].read().bits() & (1 << 31) != 0
+    }
+}
+
+/** Print the configuration of all pads, for example to the debug UART (`Stdout`) */
+pub fn dump<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    for io in 0..IO_COUNT {
+        writeln!(w, "IO{:02} {} {}", io, u8::from(get_input(io)), get_config(io))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Bits of the pad register that `PadConfig` covers */
+    const CONFIG_MASK: u32 = 0x00fb_ffff;
+
+    #[test]
+    fn test_pad_config() {
+        for (n, &bits) in FUNCTION_DEFAULTS.iter().enumerate() {
+            let config = PadConfig::from_bits(bits);
+            assert_eq!(config.function as usize, n);
+            assert_eq!(config.bits(), bits & CONFIG_MASK);
+        }
+        let config = PadConfig {
+            ds: 7,
+            pull: pull::DOWN,
+            sl: true,
+            ..PadConfig::default_for(function::SPI0_SCLK)
+        };
+        assert_eq!(PadConfig::from_bits(config.bits()), config);
+        assert_eq!(std::format!("{}", PadConfig::default_for(function::JTAG_TCLK)),
+                   "JTAG_TCLK (0) ds=0 pull=NONE ie_en st");
+    }
+}
@@ -0,0 +1,220 @@
+//! Pin-mux configuration with conflict detection
+//!
+//! `PinMux` records which function is routed to which pad, and refuses assignments that
+//! conflict with earlier ones: a second function for a pad, or a second pad for a function.
+//! There is one for the whole program: `PinMux::new` takes the FPIOA peripheral, and configures
+//! it as assignments are made. Made with `PinMux::dry_run`, it only records them, which allows
+//! checking a configuration on the host.
+use k210_hal::pac;
+
+use super::{function, pull, set_config, set_function, set_io_pull, PadConfig, IO_COUNT};
+
+/** Function that stands for "no function", which any number of pads can have */
+const NO_FUNCTION: function = function::RESV0;
+
+/** Conflicting or invalid assignment */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MuxError {
+    /** No pad with this number */
+    NoSuchPad(usize),
+    /** The pad already has another function */
+    PadTaken { io: usize, function: function, previous: function },
+    /** The function is already routed to another pad */
+    FunctionTaken { function: function, io: usize, previous: usize },
+    /** Pins can only be handed to drivers by a mux that configures the FPIOA */
+    DryRun,
+    /** A driver was already handed a token for the pad */
+    TokenIssued(usize),
+}
+
+/** Record of pad assignments */
+pub struct PinMux {
+    functions: [Option<function>; IO_COUNT],
+    /** The peripheral, if assignments configure it */
+    fpioa: Option<pac::FPIOA>,
+    /** Bit mask of pads that were handed to a driver */
+    issued: u64,
+}
+
+impl PinMux {
+    /** Start with no pads assigned, configuring the FPIOA for every assignment */
+    pub fn new(fpioa: pac::FPIOA) -> Self {
+        Self { functions: [None; IO_COUNT], fpioa: Some(fpioa), issued: 0 }
+    }
+
+    /** Start with no pads assigned, without touching the FPIOA */
+    pub const fn dry_run() -> Self {
+        Self { functions: [None; IO_COUNT], fpioa: None, issued: 0 }
+    }
+
+    /** Whether assignments configure the FPIOA */
+    pub fn writes(&self) -> bool {
+        self.fpioa.is_some()
+    }
+
+    /** Function routed to a pad */
+    pub fn function<N: Into<usize>>(&self, io: N) -> Option<function> {
+        self.functions.get(io.into()).copied().flatten()
+    }
+
+    /** Pad a function is routed to */
+    pub fn pad(&self, function: function) -> Option<usize> {
+        self.functions.iter().position(|f| *f == Some(function))
+    }
+
+    /** Check that `function` can be routed to pad `io` */
+    pub fn check<N: Into<usize>>(&self, io: N, function: function) -> Result<(), MuxError> {
+        let io = io.into();
+        match self.functions.get(io) {
+            None => Err(MuxError::NoSuchPad(io)),
+            Some(Some(previous)) if *previous != function => {
+                Err(MuxError::PadTaken { io, function, previous: *previous })
+            }
+            _ => match self.pad(function) {
+                Some(previous) if previous != io && function != NO_FUNCTION => {
+                    Err(MuxError::FunctionTaken { function, io, previous })
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /** Route `function` to pad `io`. Assigning the same function to a pad again is allowed. */
+    pub fn set_function<N: Into<usize>>(&mut self, io: N, function: function)
+        -> Result<(), MuxError> {
+        let io = io.into();
+        self.check(io, function)?;
+        if self.writes() && self.functions[io] != Some(function) {
+            set_function(io, function);
+        }
+        self.functions[io] = Some(function);
+        Ok(())
+    }
+
+    /** Set the pull of a pad */
+    pub fn set_io_pull<N: Into<usize>>(&mut self, io: N, pull: pull) -> Result<(), MuxError> {
+        let io = io.into();
+        if io >= IO_COUNT {
+            return Err(MuxError::NoSuchPad(io));
+        }
+        if self.writes() {
+            set_io_pull(io, pull);
+        }
+        Ok(())
+    }
+
+    /** Configure pad `io` completely, with the same checks for its function as `set_function` */
+    pub fn set_config<N: Into<usize>>(&mut self, io: N, config: PadConfig)
+        -> Result<(), MuxError> {
+        let io = io.into();
+        self.check(io, config.function)?;
+        if self.writes() {
+            set_config(io, config);
+        }
+        self.functions[io] = Some(config.function);
+        Ok(())
+    }
+
+    /** Route a group of functions, with the pull for each pad. Either all of them are
+     * assigned, or none if any conflicts. */
+    pub fn set_functions(&mut self, assignments: &[(usize, function, pull)])
+        -> Result<(), MuxError> {
+        for (i, &(io, function, _)) in assignments.iter().enumerate() {
+            self.check(io, function)?;
+            // conflicts within the group
+            for &(other_io, other_function, _) in &assignments[..i] {
+                if other_io == io && other_function != function {
+                    return Err(MuxError::PadTaken { io, function, previous: other_function });
+                }
+                if other_function == function && other_io != io && function != NO_FUNCTION {
+                    return Err(MuxError::FunctionTaken { function, io, previous: other_io });
+                }
+            }
+        }
+        for &(io, function, pull) in assignments {
+            self.set_function(io, function)?;
+            self.set_io_pull(io, pull)?;
+        }
+        Ok(())
+    }
+
+    /** Record that the pads are handed to a driver, which can only happen once */
+    pub(crate) fn issue(&mut self, pads: &[u8]) -> Result<(), MuxError> {
+        let mut mask = 0;
+        for &io in pads {
+            let io = usize::from(io);
+            if io >= IO_COUNT {
+                return Err(MuxError::NoSuchPad(io));
+            }
+            if self.issued & (1 << io) != 0 {
+                return Err(MuxError::TokenIssued(io));
+            }
+            mask |= 1 << io;
+        }
+        self.issued |= mask;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflicts() {
+        let mut mux = PinMux::dry_run();
+        assert_eq!(mux.set_function(30usize, function::I2C0_SCLK), Ok(()));
+        assert_eq!(mux.set_function(31usize, function::I2C0_SDA), Ok(()));
+        // the same again is fine
+        assert_eq!(mux.set_function(30usize, function::I2C0_SCLK), Ok(()));
+        assert_eq!(mux.set_function(30usize, function::SPI0_SCLK),
+                   Err(MuxError::PadTaken { io: 30, function: function::SPI0_SCLK,
+                                            previous: function::I2C0_SCLK }));
+        assert_eq!(mux.set_function(12usize, function::I2C0_SDA),
+                   Err(MuxError::FunctionTaken { function: function::I2C0_SDA, io: 12,
+                                                 previous: 31 }));
+        assert_eq!(mux.set_function(48usize, function::GPIO0), Err(MuxError::NoSuchPad(48)));
+        assert_eq!(mux.set_io_pull(48usize, pull::UP), Err(MuxError::NoSuchPad(48)));
+        // any number of pads can be unassigned
+        assert_eq!(mux.set_function(0usize, function::RESV0), Ok(()));
+        assert_eq!(mux.set_function(1usize, function::RESV0), Ok(()));
+        assert_eq!(mux.function(30usize), Some(function::I2C0_SCLK));
+        assert_eq!(mux.function(12usize), None);
+        assert_eq!(mux.pad(function::I2C0_SDA), Some(31));
+    }
+
+    #[test]
+    fn test_group() {
+        let mut mux = PinMux::dry_run();
+        mux.set_function(38usize, function::GPIOHS2).unwrap();
+        // nothing of a conflicting group is assigned
+        let group = [
+            (36, function::SPI0_SS3, pull::NONE),
+            (38, function::GPIOHS3, pull::DOWN),
+        ];
+        assert!(matches!(mux.set_functions(&group), Err(MuxError::PadTaken { io: 38, .. })));
+        assert_eq!(mux.function(36usize), None);
+        // conflicts within the group
+        let group = [
+            (36, function::SPI0_SS3, pull::NONE),
+            (37, function::SPI0_SS3, pull::NONE),
+        ];
+        assert!(matches!(mux.set_functions(&group), Err(MuxError::FunctionTaken { io: 37, .. })));
+        let group = [
+            (36, function::SPI0_SS3, pull::NONE),
+            (39, function::SPI0_SCLK, pull::NONE),
+        ];
+        assert_eq!(mux.set_functions(&group), Ok(()));
+        assert_eq!(mux.pad(function::SPI0_SCLK), Some(39));
+    }
+
+    #[test]
+    fn test_issue() {
+        let mut mux = PinMux::dry_run();
+        assert_eq!(mux.issue(&[36, 39]), Ok(()));
+        // a second token for the same pads is refused, and marks nothing
+        assert_eq!(mux.issue(&[37, 39]), Err(MuxError::TokenIssued(39)));
+        assert_eq!(mux.issue(&[37, 48]), Err(MuxError::NoSuchPad(48)));
+        assert_eq!(mux.issue(&[37]), Ok(()));
+    }
+}
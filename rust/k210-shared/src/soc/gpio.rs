@@ -1,8 +1,243 @@
 //! GPIO peripheral
+//!
+//! The low-speed GPIO controller has 8 pins. Like GPIOHS, they can be used through functions
+//! taking a pin number, or as typed `Pin` objects that implement the embedded-hal digital
+//! traits. All pins share one PLIC interrupt, which calls the handler registered for each pin
+//! that has its condition (set with `register`) pending.
+use core::marker::PhantomData;
+use core::ptr;
+use k210_hal::pac;
+use pac::interrupt::Interrupt;
+
+use crate::soc::fpioa;
+use crate::soc::plic;
+use crate::soc::utils::{set_bit,get_bit};
+
+pub mod hal;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum direction {
     INPUT,
     OUTPUT,
 }
 
-// TODO
+/** Condition for a pin interrupt */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum trigger {
+    /** Rising edge */
+    RISING,
+    /** Falling edge */
+    FALLING,
+    /** Both edges */
+    BOTH,
+    /** While high */
+    HIGH,
+    /** While low */
+    LOW,
+}
+
+/** Typed pin mode: input */
+pub struct Input;
+/** Typed pin mode: output */
+pub struct Output;
+
+/** Number of GPIO pins */
+pub const PIN_COUNT: u8 = 8;
+
+/** Pin interrupt handler, gets passed the pin number */
+pub type Handler = fn(pin: u8);
+
+/** Set input/output direction for a GPIO pin */
+pub fn set_direction(pin: u8, direction: direction) {
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        (*ptr)
+            .direction
+            .modify(|r, w| w.bits(set_bit(r.bits(), pin, direction == direction::OUTPUT)));
+    }
+}
+
+/** Set output value for a GPIO pin */
+pub fn set_pin(pin: u8, value: bool) {
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        (*ptr)
+            .data_output
+            .modify(|r, w| w.bits(set_bit(r.bits(), pin, value)));
+    }
+}
+
+/** Get input value for a GPIO pin */
+pub fn get_pin(pin: u8) -> bool {
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        get_bit((*ptr).data_input.read().bits(), pin)
+    }
+}
+
+/** Get the output value set for a GPIO pin */
+pub fn get_output(pin: u8) -> bool {
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        get_bit((*ptr).data_output.read().bits(), pin)
+    }
+}
+
+/** Invert the output value of a GPIO pin */
+pub fn toggle_pin(pin: u8) {
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        (*ptr)
+            .data_output
+            .modify(|r, w| w.bits(r.bits() ^ (1 << u32::from(pin))));
+    }
+}
+
+/** Registered pin handlers and their trigger */
+static mut HANDLERS: [Option<(trigger, Handler)>; PIN_COUNT as usize] =
+    [None; PIN_COUNT as usize];
+
+/** Set up and enable, or disable the interrupt of a pin. Pending edges are cleared. */
+fn set_trigger(pin: u8, trigger: trigger, enabled: bool) {
+    let (edge, high, both) = match trigger {
+        trigger::RISING => (true, true, false),
+        trigger::FALLING => (true, false, false),
+        trigger::BOTH => (true, false, true),
+        trigger::HIGH => (false, true, false),
+        trigger::LOW => (false, false, false),
+    };
+    unsafe {
+        let ptr = pac::GPIO::ptr();
+        (*ptr).interrupt_enable.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+        (*ptr).interrupt_level.modify(|r, w| w.bits(set_bit(r.bits(), pin, edge)));
+        (*ptr).interrupt_polarity.modify(|r, w| w.bits(set_bit(r.bits(), pin, high)));
+        (*ptr).interrupt_bothedge.modify(|r, w| w.bits(set_bit(r.bits(), pin, both)));
+        (*ptr).interrupt_clear.write(|w| w.bits(1 << u32::from(pin)));
+        (*ptr).interrupt_mask.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+        (*ptr).interrupt_enable.modify(|r, w| w.bits(set_bit(r.bits(), pin, enabled)));
+    }
+}
+
+/** GPIO interrupt: call the handlers of the pins with a pending condition. Edges are
+ * acknowledged first, so an edge during the handler triggers again. Levels can't be
+ * acknowledged: they trigger again as long as they persist after the handler. */
+fn interrupt_gpio(_interrupt: Interrupt) {
+    let status = unsafe {
+        let ptr = pac::GPIO::ptr();
+        let status = (*ptr).interrupt_status.read().bits();
+        (*ptr).interrupt_clear.write(|w| w.bits(status));
+        status
+    };
+    for pin in 0..PIN_COUNT {
+        if get_bit(status, pin) {
+            if let Some((_, handler)) = unsafe { (*ptr::addr_of!(HANDLERS))[usize::from(pin)] } {
+                handler(pin);
+            }
+        }
+    }
+}
+
+/** Call `handler` from interrupt context whenever `trigger` happens on a pin. The pin has to
+ * be an input. The PLIC interrupt is shared by all pins, and gets `priority` when the first
+ * handler is registered. This requires interrupts to have been enabled with `plic::init`. */
+pub fn register(pin: u8, trigger: trigger, priority: u32, handler: Handler) {
+    assert!(pin < PIN_COUNT, "no such GPIO pin");
+    unregister(pin);
+    let first = riscv::interrupt::free(|_| unsafe {
+        let handlers = &mut *ptr::addr_of_mut!(HANDLERS);
+        let first = handlers.iter().all(|h| h.is_none());
+        handlers[usize::from(pin)] = Some((trigger, handler));
+        first
+    });
+    set_trigger(pin, trigger, true);
+    if first {
+        plic::register(Interrupt::GPIO, priority, interrupt_gpio);
+    }
+}
+
+/** Disable the interrupt of a pin and remove its handler */
+pub fn unregister(pin: u8) {
+    assert!(pin < PIN_COUNT, "no such GPIO pin");
+    let (previous, last) = riscv::interrupt::free(|_| unsafe {
+        let handlers = &mut *ptr::addr_of_mut!(HANDLERS);
+        let previous = handlers[usize::from(pin)].take();
+        (previous, handlers.iter().all(|h| h.is_none()))
+    });
+    if let Some((trigger, _)) = previous {
+        set_trigger(pin, trigger, false);
+        if last {
+            plic::unregister(Interrupt::GPIO);
+        }
+    }
+}
+
+/** GPIO pin routed to an IO pad, with its mode in the type */
+pub struct Pin<MODE> {
+    pin: u8,
+    io: usize,
+    _mode: PhantomData<MODE>,
+}
+
+impl<MODE> Pin<MODE> {
+    /** GPIO pin number */
+    pub fn number(&self) -> u8 {
+        self.pin
+    }
+
+    /** Make the pin an input, with `pull` on the pad */
+    pub fn into_input(self, pull: fpioa::pull) -> Pin<Input> {
+        fpioa::set_io_pull(self.io, pull);
+        set_direction(self.pin, direction::INPUT);
+        Pin { pin: self.pin, io: self.io, _mode: PhantomData }
+    }
+
+    /** Make the pin an output, starting at `high` */
+    pub fn into_output(self, high: bool) -> Pin<Output> {
+        fpioa::set_io_pull(self.io, fpioa::pull::DOWN); // outputs must be pull-down
+        set_pin(self.pin, high);
+        set_direction(self.pin, direction::OUTPUT);
+        Pin { pin: self.pin, io: self.io, _mode: PhantomData }
+    }
+}
+
+impl Pin<Input> {
+    /** Route GPIO `pin` to pad `io` through the FPIOA, as an input without pull */
+    pub fn new<N: Into<usize>>(pin: u8, io: N) -> Self {
+        assert!(pin < PIN_COUNT, "no such GPIO pin");
+        let io = io.into();
+        fpioa::set_function(io, fpioa::function::gpio(pin));
+        Pin::<Input> { pin, io, _mode: PhantomData }.into_input(fpioa::pull::NONE)
+    }
+
+    /** Read the pin */
+    pub fn is_high(&self) -> bool {
+        get_pin(self.pin)
+    }
+
+    /** Call `handler` from interrupt context whenever `trigger` happens, see `register` */
+    pub fn listen(&mut self, trigger: trigger, priority: u32, handler: Handler) {
+        register(self.pin, trigger, priority, handler);
+    }
+
+    /** Stop calling the handler */
+    pub fn unlisten(&mut self) {
+        unregister(self.pin);
+    }
+}
+
+impl Pin<Output> {
+    /** Set the output */
+    pub fn set(&mut self, high: bool) {
+        set_pin(self.pin, high);
+    }
+
+    /** Output value set */
+    pub fn is_set_high(&self) -> bool {
+        get_output(self.pin)
+    }
+
+    /** Invert the output */
+    pub fn toggle(&mut self) {
+        toggle_pin(self.pin);
+    }
+}
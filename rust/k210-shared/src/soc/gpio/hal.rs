@@ -0,0 +1,101 @@
+//! embedded-hal digital traits for typed GPIO and GPIOHS pins
+use core::convert::Infallible;
+use embedded_hal::digital::v2 as digital02;
+use embedded_hal_1::digital as digital1;
+
+use super::{Input, Output};
+use crate::soc::{gpio, gpiohs};
+
+/* Both kinds of pin have the same inherent methods, which the traits map to */
+macro_rules! impl_digital {
+    ($m:ident) => {
+        impl digital02::InputPin for $m::Pin<Input> {
+            type Error = Infallible;
+
+            fn is_high(&self) -> Result<bool, Infallible> {
+                Ok($m::Pin::is_high(self))
+            }
+
+            fn is_low(&self) -> Result<bool, Infallible> {
+                Ok(!$m::Pin::is_high(self))
+            }
+        }
+
+        impl digital02::OutputPin for $m::Pin<Output> {
+            type Error = Infallible;
+
+            fn set_low(&mut self) -> Result<(), Infallible> {
+                self.set(false);
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Infallible> {
+                self.set(true);
+                Ok(())
+            }
+        }
+
+        impl digital02::StatefulOutputPin for $m::Pin<Output> {
+            fn is_set_high(&self) -> Result<bool, Infallible> {
+                Ok($m::Pin::is_set_high(self))
+            }
+
+            fn is_set_low(&self) -> Result<bool, Infallible> {
+                Ok(!$m::Pin::is_set_high(self))
+            }
+        }
+
+        impl digital02::ToggleableOutputPin for $m::Pin<Output> {
+            type Error = Infallible;
+
+            fn toggle(&mut self) -> Result<(), Infallible> {
+                $m::Pin::toggle(self);
+                Ok(())
+            }
+        }
+
+        impl<MODE> digital1::ErrorType for $m::Pin<MODE> {
+            type Error = Infallible;
+        }
+
+        impl digital1::InputPin for $m::Pin<Input> {
+            fn is_high(&mut self) -> Result<bool, Infallible> {
+                Ok($m::Pin::is_high(self))
+            }
+
+            fn is_low(&mut self) -> Result<bool, Infallible> {
+                Ok(!$m::Pin::is_high(self))
+            }
+        }
+
+        impl digital1::OutputPin for $m::Pin<Output> {
+            fn set_low(&mut self) -> Result<(), Infallible> {
+                self.set(false);
+                Ok(())
+            }
+
+            fn set_high(&mut self) -> Result<(), Infallible> {
+                self.set(true);
+                Ok(())
+            }
+        }
+
+        impl digital1::StatefulOutputPin for $m::Pin<Output> {
+            fn is_set_high(&mut self) -> Result<bool, Infallible> {
+                Ok($m::Pin::is_set_high(self))
+            }
+
+            fn is_set_low(&mut self) -> Result<bool, Infallible> {
+                Ok(!$m::Pin::is_set_high(self))
+            }
+
+            fn toggle(&mut self) -> Result<(), Infallible> {
+                $m::Pin::toggle(self);
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_digital!(gpio);
+impl_digital!(gpiohs);
@@ -1,10 +1,24 @@
 //! GPIOHS peripheral
+//!
+//! Pins can be used through the functions taking a pin number, or as typed `Pin` objects that
+//! implement the embedded-hal digital traits. Every pin has its own PLIC interrupt; a handler
+//! registered with `register` is called for the condition set with it.
+use bare_metal::Nr;
+use core::marker::PhantomData;
+use core::ptr;
 use k210_hal::pac;
+use pac::interrupt::Interrupt;
 
-use crate::soc::gpio;
+use crate::soc::fpioa;
+use crate::soc::gpio::{self, Input, Output};
+use crate::soc::plic;
 use crate::soc::utils::{set_bit,get_bit};
 
-// TODO embedded-hal::digital::v2::{InputPin, OutputPin}
+/** Number of GPIOHS pins */
+pub const PIN_COUNT: u8 = 32;
+
+/** Pin interrupt handler, gets passed the pin number */
+pub type Handler = fn(pin: u8);
 
 /** Set input/output direction for a GPIOHS pin */
 pub fn set_direction(pin: u8, direction: gpio::direction) {
@@ -36,3 +50,179 @@ pub fn get_pin(pin: u8) -> bool {
         get_bit((*ptr).input_val.read().bits(), pin)
     }
 }
+
+/** Get the output value set for a GPIOHS pin */
+pub fn get_output(pin: u8) -> bool {
+    unsafe {
+        let ptr = pac::GPIOHS::ptr();
+        get_bit((*ptr).output_val.read().bits(), pin)
+    }
+}
+
+/** Invert the output value of a GPIOHS pin */
+pub fn toggle_pin(pin: u8) {
+    unsafe {
+        let ptr = pac::GPIOHS::ptr();
+        (*ptr)
+            .output_val
+            .modify(|r, w| w.bits(r.bits() ^ (1 << u32::from(pin))));
+    }
+}
+
+/** Registered pin handlers and their trigger */
+static mut HANDLERS: [Option<(gpio::trigger, Handler)>; PIN_COUNT as usize] =
+    [None; PIN_COUNT as usize];
+
+/** PLIC interrupt of a pin */
+fn interrupt(pin: u8) -> Interrupt {
+    assert!(pin < PIN_COUNT, "no such GPIO pin");
+    Interrupt::try_from(Interrupt::GPIOHS0.nr() + pin).unwrap()
+}
+
+/** Enable or disable the interrupt conditions of `trigger` for a pin. Disabling also clears
+ * pending conditions. */
+fn set_trigger(pin: u8, trigger: gpio::trigger, enabled: bool) {
+    let (rise, fall, high, low) = match trigger {
+        gpio::trigger::RISING => (true, false, false, false),
+        gpio::trigger::FALLING => (false, true, false, false),
+        gpio::trigger::BOTH => (true, true, false, false),
+        gpio::trigger::HIGH => (false, false, true, false),
+        gpio::trigger::LOW => (false, false, false, true),
+    };
+    let bit = 1 << u32::from(pin);
+    unsafe {
+        let ptr = pac::GPIOHS::ptr();
+        // pending bits are cleared by writing 1, and only take effect with the enable bit off
+        if rise {
+            (*ptr).rise_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+            (*ptr).rise_ip.write(|w| w.bits(bit));
+            (*ptr).rise_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, enabled)));
+        }
+        if fall {
+            (*ptr).fall_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+            (*ptr).fall_ip.write(|w| w.bits(bit));
+            (*ptr).fall_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, enabled)));
+        }
+        if high {
+            (*ptr).high_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+            (*ptr).high_ip.write(|w| w.bits(bit));
+            (*ptr).high_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, enabled)));
+        }
+        if low {
+            (*ptr).low_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, false)));
+            (*ptr).low_ip.write(|w| w.bits(bit));
+            (*ptr).low_ie.modify(|r, w| w.bits(set_bit(r.bits(), pin, enabled)));
+        }
+    }
+}
+
+/** Pin interrupt: acknowledge the condition and call the handler. For edges this happens
+ * first, so an edge during the handler triggers again. A level triggers again as long as it
+ * persists after the handler. */
+fn interrupt_gpiohs(interrupt: Interrupt) {
+    let pin = interrupt.nr() - Interrupt::GPIOHS0.nr();
+    if let Some((trigger, handler)) = unsafe { (*ptr::addr_of!(HANDLERS))[usize::from(pin)] } {
+        let level = trigger == gpio::trigger::HIGH || trigger == gpio::trigger::LOW;
+        if !level {
+            set_trigger(pin, trigger, true);
+        }
+        handler(pin);
+        if level {
+            set_trigger(pin, trigger, true);
+        }
+    }
+}
+
+/** Call `handler` from interrupt context whenever `trigger` happens on a pin, at PLIC
+ * `priority`. The pin has to be an input. This requires interrupts to have been enabled with
+ * `plic::init`. */
+pub fn register(pin: u8, trigger: gpio::trigger, priority: u32, handler: Handler) {
+    let interrupt = interrupt(pin);
+    unregister(pin);
+    riscv::interrupt::free(|_| unsafe {
+        (*ptr::addr_of_mut!(HANDLERS))[usize::from(pin)] = Some((trigger, handler));
+    });
+    set_trigger(pin, trigger, true);
+    plic::register(interrupt, priority, interrupt_gpiohs);
+}
+
+/** Disable the interrupt of a pin and remove its handler */
+pub fn unregister(pin: u8) {
+    plic::unregister(interrupt(pin));
+    let previous = riscv::interrupt::free(|_| unsafe {
+        (*ptr::addr_of_mut!(HANDLERS))[usize::from(pin)].take()
+    });
+    if let Some((trigger, _)) = previous {
+        set_trigger(pin, trigger, false);
+    }
+}
+
+/** GPIOHS pin routed to an IO pad, with its mode in the type */
+pub struct Pin<MODE> {
+    pin: u8,
+    io: usize,
+    _mode: PhantomData<MODE>,
+}
+
+impl<MODE> Pin<MODE> {
+    /** GPIOHS pin number */
+    pub fn number(&self) -> u8 {
+        self.pin
+    }
+
+    /** Make the pin an input, with `pull` on the pad */
+    pub fn into_input(self, pull: fpioa::pull) -> Pin<Input> {
+        fpioa::set_io_pull(self.io, pull);
+        set_direction(self.pin, gpio::direction::INPUT);
+        Pin { pin: self.pin, io: self.io, _mode: PhantomData }
+    }
+
+    /** Make the pin an output, starting at `high` */
+    pub fn into_output(self, high: bool) -> Pin<Output> {
+        fpioa::set_io_pull(self.io, fpioa::pull::DOWN); // outputs must be pull-down
+        set_pin(self.pin, high);
+        set_direction(self.pin, gpio::direction::OUTPUT);
+        Pin { pin: self.pin, io: self.io, _mode: PhantomData }
+    }
+}
+
+impl Pin<Input> {
+    /** Route GPIOHS `pin` to pad `io` through the FPIOA, as an input without pull */
+    pub fn new<N: Into<usize>>(pin: u8, io: N) -> Self {
+        let io = io.into();
+        fpioa::set_function(io, fpioa::function::gpiohs(pin));
+        Pin::<Input> { pin, io, _mode: PhantomData }.into_input(fpioa::pull::NONE)
+    }
+
+    /** Read the pin */
+    pub fn is_high(&self) -> bool {
+        get_pin(self.pin)
+    }
+
+    /** Call `handler` from interrupt context whenever `trigger` happens, see `register` */
+    pub fn listen(&mut self, trigger: gpio::trigger, priority: u32, handler: Handler) {
+        register(self.pin, trigger, priority, handler);
+    }
+
+    /** Stop calling the handler */
+    pub fn unlisten(&mut self) {
+        unregister(self.pin);
+    }
+}
+
+impl Pin<Output> {
+    /** Set the output */
+    pub fn set(&mut self, high: bool) {
+        set_pin(self.pin, high);
+    }
+
+    /** Output value set */
+    pub fn is_set_high(&self) -> bool {
+        get_output(self.pin)
+    }
+
+    /** Invert the output */
+    pub fn toggle(&mut self) {
+        toggle_pin(self.pin);
+    }
+}
@@ -1,11 +1,78 @@
 //! I2C peripherals
+use core::cell::Cell;
 use core::cmp;
 use core::convert::TryInto;
 use core::ops::Deref;
 use core::result::Result;
+use embedded_hal_1::i2c::Operation;
 use k210_hal::pac::{I2C0,I2C1,I2C2,i2c0};
+use k210_hal::pac::interrupt::Interrupt;
 
 use crate::soc::sysctl;
+use crate::timing::clock;
+
+pub mod hal;
+pub mod sampler;
+pub mod scan;
+pub mod slave;
+pub mod transfer;
+
+/** Depth of the TX and RX FIFOs */
+const FIFO_DEPTH: usize = 8;
+/** Default time (us) a transfer may go without progress before it is given up */
+const DEFAULT_TIMEOUT_US: u64 = 10_000;
+/** TAR bit to address the target with 10 bits */
+const TAR_10BITADDR_MASTER: u32 = 1 << 12;
+
+/** TX_ABRT_SOURCE bits */
+const ABRT_7B_ADDR_NOACK: u32 = 1 << 0;
+const ABRT_10ADDR1_NOACK: u32 = 1 << 1;
+const ABRT_10ADDR2_NOACK: u32 = 1 << 2;
+const ABRT_TXDATA_NOACK: u32 = 1 << 3;
+const ABRT_GCALL_NOACK: u32 = 1 << 4;
+const ABRT_ARB_LOST: u32 = 1 << 12;
+/** Abort reasons, without the count of flushed TX FIFO entries in the upper bits */
+const ABRT_SOURCE_MASK: u32 = 0x1ffff;
+
+/** Reasons for a failed transfer */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum I2CError {
+    /** No device acknowledged the address (either byte of a 10-bit address) */
+    AddressNack,
+    /** The device did not acknowledge a data byte */
+    DataNack,
+    /** No device acknowledged a general call */
+    GeneralCallNack,
+    /** Another master won arbitration of the bus */
+    ArbitrationLost,
+    /** The transfer made no progress in time, for example a device holds SCL low */
+    Timeout,
+    /** The controller ran out of bytes to send and ended the transfer early, because the
+     * interrupt handler feeding it was held up */
+    Underrun,
+    /** Other abort, with the raw `tx_abrt_source` bits */
+    Abort(u32),
+}
+
+impl I2CError {
+    /** Decode the `tx_abrt_source` register */
+    pub fn from_abort_source(source: u32) -> Result<(), I2CError> {
+        let source = source & ABRT_SOURCE_MASK;
+        if source == 0 {
+            Ok(())
+        } else if (source & ABRT_ARB_LOST) != 0 {
+            Err(I2CError::ArbitrationLost)
+        } else if (source & (ABRT_7B_ADDR_NOACK | ABRT_10ADDR1_NOACK | ABRT_10ADDR2_NOACK)) != 0 {
+            Err(I2CError::AddressNack)
+        } else if (source & ABRT_TXDATA_NOACK) != 0 {
+            Err(I2CError::DataNack)
+        } else if (source & ABRT_GCALL_NOACK) != 0 {
+            Err(I2CError::GeneralCallNack)
+        } else {
+            Err(I2CError::Abort(source))
+        }
+    }
+}
 
 /// Trait for generalizing over I2C0-2
 pub trait I2CExt: Deref<Target = i2c0::RegisterBlock> + Sized {
@@ -15,6 +82,14 @@ pub trait I2CExt: Deref<Target = i2c0::RegisterBlock> + Sized {
     const DIV: sysctl::threshold;
     #[doc(hidden)]
     const RESET: sysctl::reset;
+    #[doc(hidden)]
+    const INDEX: usize;
+    #[doc(hidden)]
+    const INTERRUPT: Interrupt;
+    #[doc(hidden)]
+    const DMA_RX: sysctl::dma_select;
+    #[doc(hidden)]
+    const DMA_TX: sysctl::dma_select;
 
     /// Constrains I2C peripheral so it plays nicely with the other abstractions
     fn constrain(self) -> I2CImpl<Self>;
@@ -24,32 +99,254 @@ impl I2CExt for I2C0 {
     const CLK: sysctl::clock = sysctl::clock::I2C0;
     const DIV: sysctl::threshold = sysctl::threshold::I2C0;
     const RESET: sysctl::reset = sysctl::reset::I2C0;
+    const INDEX: usize = 0;
+    const INTERRUPT: Interrupt = Interrupt::I2C0;
+    const DMA_RX: sysctl::dma_select = sysctl::dma_select::I2C0_RX_REQ;
+    const DMA_TX: sysctl::dma_select = sysctl::dma_select::I2C0_TX_REQ;
 
-    fn constrain(self) -> I2CImpl<Self> { I2CImpl::<Self> { i2c: self } }
+    fn constrain(self) -> I2CImpl<Self> { I2CImpl::new(self) }
 }
 impl I2CExt for I2C1 {
     const CLK: sysctl::clock = sysctl::clock::I2C1;
     const DIV: sysctl::threshold = sysctl::threshold::I2C1;
     const RESET: sysctl::reset = sysctl::reset::I2C1;
+    const INDEX: usize = 1;
+    const INTERRUPT: Interrupt = Interrupt::I2C1;
+    const DMA_RX: sysctl::dma_select = sysctl::dma_select::I2C1_RX_REQ;
+    const DMA_TX: sysctl::dma_select = sysctl::dma_select::I2C1_TX_REQ;
 
-    fn constrain(self) -> I2CImpl<Self> { I2CImpl::<Self> { i2c: self } }
+    fn constrain(self) -> I2CImpl<Self> { I2CImpl::new(self) }
 }
 impl I2CExt for I2C2 {
     const CLK: sysctl::clock = sysctl::clock::I2C2;
     const DIV: sysctl::threshold = sysctl::threshold::I2C2;
     const RESET: sysctl::reset = sysctl::reset::I2C2;
+    const INDEX: usize = 2;
+    const INTERRUPT: Interrupt = Interrupt::I2C2;
+    const DMA_RX: sysctl::dma_select = sysctl::dma_select::I2C2_RX_REQ;
+    const DMA_TX: sysctl::dma_select = sysctl::dma_select::I2C2_TX_REQ;
 
-    fn constrain(self) -> I2CImpl<Self> { I2CImpl::<Self> { i2c: self } }
+    fn constrain(self) -> I2CImpl<Self> { I2CImpl::new(self) }
 }
 
 pub struct I2CImpl<IF> {
     i2c: IF,
+    /** Address width set with `init`, for `recv_data`/`send_data`/`transfer` */
+    ten_bit: Cell<bool>,
+    /** TAR register value the controller is set up with */
+    target: Cell<Option<u32>>,
+    timeout_us: u64,
+}
+
+/** Value of the TAR register to address a device */
+fn tar(address: u16, ten_bit: bool) -> u32 {
+    u32::from(address) | if ten_bit { TAR_10BITADDR_MASTER } else { 0 }
 }
 
 pub trait I2C {
+    /** Set up the controller as master. `slave_address` is the device addressed by `recv_data`
+     * and `send_data`, `address_width` (7 or 10) is also used by `transfer`. */
     fn init(&self, slave_address: u16, address_width: u32, i2c_clk: u32);
-    fn recv_data(&self, send_buf: &[u8], receive_buf: &mut [u8]) -> Result<(), ()>;
-    fn send_data(&self, send_buf: &[u8]) -> Result<(), ()>;
+    /** Write `send_buf` to the device set with `init`, then read `receive_buf` */
+    fn recv_data(&self, send_buf: &[u8], receive_buf: &mut [u8]) -> Result<(), I2CError>;
+    /** Write `send_buf` to the device set with `init` */
+    fn send_data(&self, send_buf: &[u8]) -> Result<(), I2CError>;
+    /** Write `send_buf` to the device at `address`, then read `receive_buf` after a repeated
+     * start. Either buffer may be empty. */
+    fn transfer(&self, address: u16, send_buf: &[u8], receive_buf: &mut [u8])
+        -> Result<(), I2CError>;
+}
+
+/** A reference to a controller works as well, to share one bus between device drivers */
+impl<T: I2C> I2C for &T {
+    fn init(&self, slave_address: u16, address_width: u32, i2c_clk: u32) {
+        (*self).init(slave_address, address_width, i2c_clk)
+    }
+    fn recv_data(&self, send_buf: &[u8], receive_buf: &mut [u8]) -> Result<(), I2CError> {
+        (*self).recv_data(send_buf, receive_buf)
+    }
+    fn send_data(&self, send_buf: &[u8]) -> Result<(), I2CError> {
+        (*self).send_data(send_buf)
+    }
+    fn transfer(&self, address: u16, send_buf: &[u8], receive_buf: &mut [u8])
+        -> Result<(), I2CError> {
+        (*self).transfer(address, send_buf, receive_buf)
+    }
+}
+
+impl<IF: I2CExt> I2CImpl<IF> {
+    fn new(i2c: IF) -> Self {
+        Self {
+            i2c,
+            ten_bit: Cell::new(false),
+            target: Cell::new(None),
+            timeout_us: DEFAULT_TIMEOUT_US,
+        }
+    }
+
+    /** Set the time a transfer may go without progress (a byte sent or received) before it is
+     * aborted with `I2CError::Timeout`. This includes devices stretching the clock. */
+    pub fn set_timeout(&mut self, timeout_us: u64) {
+        self.timeout_us = timeout_us;
+    }
+
+    /** Give back the peripheral */
+    pub fn free(self) -> IF {
+        self.i2c
+    }
+
+    /** Address another device */
+    fn set_target(&self, tar: u32) -> Result<(), I2CError> {
+        if self.target.get() != Some(tar) {
+            set_target(&self.i2c, tar, self.timeout_us)?;
+            self.target.set(Some(tar));
+        }
+        Ok(())
+    }
+
+    /** Run a transaction with the device at TAR value `tar`. Writes and reads follow each
+     * other with a repeated start when the direction changes, and a STOP is generated after
+     * the last operation. The TX FIFO has to be kept filled until then: the controller ends
+     * the transfer as soon as it runs empty. */
+    fn transaction(&self, tar: u32, operations: &mut [Operation<'_>]) -> Result<(), I2CError> {
+        self.set_target(tar)?;
+        // A previous abort keeps the TX FIFO flushed until cleared
+        self.i2c.clr_tx_abrt.read().bits();
+
+        // position of the next byte to push into the TX FIFO (data or read command)
+        let mut tx_op = 0;
+        let mut tx_ofs = 0;
+        // position of the next byte to take from the RX FIFO
+        let mut rx_op = 0;
+        let mut rx_ofs = 0;
+        // read commands pushed, for which no data was taken yet
+        let mut pending = 0;
+        let mut last_progress = clock();
+        loop {
+            // skip finished operations; received data only goes to reads
+            while tx_op < operations.len() && tx_ofs == op_len(&operations[tx_op]) {
+                tx_op += 1;
+                tx_ofs = 0;
+            }
+            while rx_op < operations.len()
+                && (!matches!(operations[rx_op], Operation::Read(_))
+                    || rx_ofs == op_len(&operations[rx_op])) {
+                rx_op += 1;
+                rx_ofs = 0;
+            }
+            if tx_op == operations.len() && rx_op == operations.len() {
+                break;
+            }
+            let mut progress = false;
+
+            let mut available = self.i2c.rxflr.read().bits() as usize;
+            while available > 0 && pending > 0 {
+                if let Operation::Read(buf) = &mut operations[rx_op] {
+                    buf[rx_ofs] = self.i2c.data_cmd.read().data().bits();
+                }
+                rx_ofs += 1;
+                available -= 1;
+                pending -= 1;
+                progress = true;
+                if rx_ofs == op_len(&operations[rx_op]) {
+                    break;
+                }
+            }
+
+            let mut space = FIFO_DEPTH - self.i2c.txflr.read().bits() as usize;
+            while space > 0 && tx_op < operations.len() && tx_ofs < op_len(&operations[tx_op]) {
+                match &operations[tx_op] {
+                    Operation::Write(buf) => unsafe {
+                        self.i2c.data_cmd.write(|w| w.data().bits(buf[tx_ofs]));
+                    },
+                    Operation::Read(_) => {
+                        // don't ask for more bytes than the RX FIFO can hold
+                        if pending == FIFO_DEPTH {
+                            break;
+                        }
+                        self.i2c.data_cmd.write(|w| w.cmd().bit(true));
+                        pending += 1;
+                    }
+                }
+                tx_ofs += 1;
+                space -= 1;
+                progress = true;
+            }
+
+            I2CError::from_abort_source(self.i2c.tx_abrt_source.read().bits())?;
+            if progress {
+                last_progress = clock();
+            } else if clock() - last_progress > self.timeout_us {
+                reset_transfer(&self.i2c);
+                return Err(I2CError::Timeout);
+            }
+        }
+
+        if let Err(e) = wait_idle(&self.i2c, self.timeout_us) {
+            reset_transfer(&self.i2c);
+            return Err(e);
+        }
+        // Check for errors one last time, the last byte may not have been acknowledged
+        I2CError::from_abort_source(self.i2c.tx_abrt_source.read().bits())
+    }
+}
+
+/** Whether the controller has finished its transfer and the STOP condition */
+fn is_idle(i2c: &i2c0::RegisterBlock) -> bool {
+    !i2c.status.read().activity().bit() && i2c.status.read().tfe().bit()
+}
+
+/** Wait for the controller to finish a transfer and the STOP condition */
+fn wait_idle(i2c: &i2c0::RegisterBlock, timeout_us: u64) -> Result<(), I2CError> {
+    let start = clock();
+    while !is_idle(i2c) {
+        if clock() - start > timeout_us {
+            return Err(I2CError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/** Address another device. The controller only takes a new address while disabled. */
+fn set_target(i2c: &i2c0::RegisterBlock, tar: u32, timeout_us: u64) -> Result<(), I2CError> {
+    wait_idle(i2c, timeout_us)?;
+    switch_target(i2c, tar);
+    Ok(())
+}
+
+/** Address another device right away, the controller must be idle */
+fn switch_target(i2c: &i2c0::RegisterBlock, tar: u32) {
+    unsafe {
+        i2c.enable.write(|w| w.bits(0));
+        i2c.tar.write(|w| w.bits(tar));
+        i2c.enable.write(|w| w.enable().bit(true));
+    }
+}
+
+/** Abort a stuck transfer. Disabling the controller flushes the FIFOs. */
+fn reset_transfer(i2c: &i2c0::RegisterBlock) {
+    unsafe {
+        i2c.enable.write(|w| w.bits(0));
+        i2c.clr_tx_abrt.read().bits();
+        i2c.enable.write(|w| w.enable().bit(true));
+    }
+}
+
+/** Field value for an address width of 7 or 10 bits */
+fn address_slave_width(address_width: u32) -> i2c0::con::ADDR_SLAVE_WIDTH_A {
+    use i2c0::con::ADDR_SLAVE_WIDTH_A;
+    match address_width {
+        7 => ADDR_SLAVE_WIDTH_A::B7,
+        10 => ADDR_SLAVE_WIDTH_A::B10,
+        _ => panic!("unsupported address width"),
+    }
+}
+
+fn op_len(op: &Operation<'_>) -> usize {
+    match op {
+        Operation::Read(buf) => buf.len(),
+        Operation::Write(buf) => buf.len(),
+    }
 }
 
 impl<IF: I2CExt> I2C for I2CImpl<IF> {
@@ -64,12 +361,9 @@ impl<IF: I2CExt> I2C for I2CImpl<IF> {
         let v_period_clk_cnt: u16 = v_period_clk_cnt.try_into().unwrap();
         let v_period_clk_cnt = cmp::max(v_period_clk_cnt, 1);
 
-        use i2c0::con::{ADDR_SLAVE_WIDTH_A,SPEED_A};
-        let v_width = match address_width {
-            7 => ADDR_SLAVE_WIDTH_A::B7,
-            10 => ADDR_SLAVE_WIDTH_A::B10,
-            _ => panic!("unsupported address width"),
-        };
+        use i2c0::con::SPEED_A;
+        let v_width = address_slave_width(address_width);
+        let ten_bit = address_width == 10;
         unsafe {
             self.i2c.enable.write(|w| w.bits(0));
             self.i2c.con.write(|w| w.master_mode().bit(true)
@@ -79,93 +373,51 @@ impl<IF: I2CExt> I2C for I2CImpl<IF> {
                                  .speed().variant(SPEED_A::FAST));
             self.i2c.ss_scl_hcnt.write(|w| w.count().bits(v_period_clk_cnt));
             self.i2c.ss_scl_lcnt.write(|w| w.count().bits(v_period_clk_cnt));
-            self.i2c.tar.write(|w| w.address().bits(slave_address));
+            self.i2c.tar.write(|w| w.bits(tar(slave_address, ten_bit)));
             self.i2c.intr_mask.write(|w| w.bits(0));
             self.i2c.dma_cr.write(|w| w.bits(0x3));
             self.i2c.dma_rdlr.write(|w| w.bits(0));
             self.i2c.dma_tdlr.write(|w| w.bits(4));
             self.i2c.enable.write(|w| w.enable().bit(true));
         }
+        self.ten_bit.set(ten_bit);
+        self.target.set(Some(tar(slave_address, ten_bit)));
     }
 
-    fn recv_data(&self, send_buf: &[u8], receive_buf: &mut [u8]) -> Result<(), ()> {
-        unsafe {
-            let mut txi = 0;
-            let mut tx_left = send_buf.len();
-            while tx_left != 0 {
-                let fifo_len = 8 - (self.i2c.txflr.read().bits() as usize);
-                let fifo_len = cmp::min(tx_left, fifo_len);
-                for _ in 0..fifo_len {
-                    self.i2c.data_cmd.write(|w| w.data().bits(send_buf[txi]));
-                    txi += 1;
-                }
-                if self.i2c.tx_abrt_source.read().bits() != 0 {
-                    return Err(());
-                }
-                tx_left -= fifo_len;
-            }
-
-            let mut cmd_count = receive_buf.len();
-            let mut rx_left = receive_buf.len();
-            let mut rxi = 0;
-            while cmd_count != 0 || rx_left != 0 {
-                /* XXX this is a kind of strange construction, sanity check */
-                let fifo_len = self.i2c.rxflr.read().bits() as usize;
-                let fifo_len = cmp::min(rx_left, fifo_len);
-                for _ in 0..fifo_len {
-                    receive_buf[rxi] = self.i2c.data_cmd.read().data().bits();
-                    rxi += 1;
-                }
-                rx_left -= fifo_len;
+    fn recv_data(&self, send_buf: &[u8], receive_buf: &mut [u8]) -> Result<(), I2CError> {
+        let tar = self.target.get().expect("I2C not initialized");
+        self.transaction(tar, &mut [Operation::Write(send_buf), Operation::Read(receive_buf)])
+    }
 
-                /* send 0x100 for every byte that we want to receive */
-                let fifo_len = 8 - self.i2c.txflr.read().bits() as usize;
-                let fifo_len = cmp::min(cmd_count, fifo_len);
-                for _ in 0..fifo_len {
-                    self.i2c.data_cmd.write(|w| w.cmd().bit(true));
-                }
-                if self.i2c.tx_abrt_source.read().bits() != 0 {
-                    return Err(());
-                }
-                cmd_count -= fifo_len;
-            }
-        }
-        Ok(())
+    fn send_data(&self, send_buf: &[u8]) -> Result<(), I2CError> {
+        let tar = self.target.get().expect("I2C not initialized");
+        self.transaction(tar, &mut [Operation::Write(send_buf)])
     }
 
-    fn send_data(&self, send_buf: &[u8]) -> Result<(), ()> {
-        unsafe {
-            let mut txi = 0;
-            let mut tx_left = send_buf.len();
-
-            // Clear TX abort by reading from clear register
-            // Hopefully this is not optimized out
-            self.i2c.clr_tx_abrt.read().bits();
-
-            // Send all data that is left, handle errors that occur
-            while tx_left != 0 {
-                let fifo_len = 8 - (self.i2c.txflr.read().bits() as usize);
-                let fifo_len = cmp::min(tx_left, fifo_len);
-                for _ in 0..fifo_len {
-                    self.i2c.data_cmd.write(|w| w.data().bits(send_buf[txi]));
-                    txi += 1;
-                }
-                if self.i2c.tx_abrt_source.read().bits() != 0 {
-                    return Err(());
-                }
-                tx_left -= fifo_len;
-            }
+    fn transfer(&self, address: u16, send_buf: &[u8], receive_buf: &mut [u8])
+        -> Result<(), I2CError> {
+        let tar = tar(address, self.ten_bit.get());
+        self.transaction(tar, &mut [Operation::Write(send_buf), Operation::Read(receive_buf)])
+    }
+}
 
-            // Wait for TX succeed
-            while self.i2c.status.read().activity().bit() || !self.i2c.status.read().tfe().bit() {
-                // NOP
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Check for errors one last time
-            if self.i2c.tx_abrt_source.read().bits() != 0 {
-                return Err(());
-            }
-        }
-        Ok(())
+    #[test]
+    fn test_abort_source() {
+        assert_eq!(I2CError::from_abort_source(0), Ok(()));
+        // TX_FLUSH_CNT alone is not an abort
+        assert_eq!(I2CError::from_abort_source(3 << 24), Ok(()));
+        assert_eq!(I2CError::from_abort_source(ABRT_7B_ADDR_NOACK), Err(I2CError::AddressNack));
+        assert_eq!(I2CError::from_abort_source(ABRT_10ADDR2_NOACK | (1 << 24)),
+                   Err(I2CError::AddressNack));
+        assert_eq!(I2CError::from_abort_source(ABRT_TXDATA_NOACK), Err(I2CError::DataNack));
+        assert_eq!(I2CError::from_abort_source(ABRT_GCALL_NOACK), Err(I2CError::GeneralCallNack));
+        assert_eq!(I2CError::from_abort_source(ABRT_ARB_LOST | ABRT_TXDATA_NOACK),
+                   Err(I2CError::ArbitrationLost));
+        // user abort
+        assert_eq!(I2CError::from_abort_source(1 << 16), Err(I2CError::Abort(1 << 16)));
     }
 }
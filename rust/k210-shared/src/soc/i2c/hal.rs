@@ -0,0 +1,77 @@
+//! embedded-hal I2C traits for I2C0-2
+//!
+//! Every transaction takes the address of the device, so one bus can host several devices.
+//! The traits are implemented for a reference to the controller as well, which lets multiple
+//! drivers share it. The clock rate is the one set with `I2C::init`.
+use embedded_hal::blocking::i2c as i2c02;
+use embedded_hal_1::i2c as i2c1;
+use i2c1::Operation;
+
+use super::{tar, I2CError, I2CExt, I2CImpl};
+
+impl i2c1::Error for I2CError {
+    fn kind(&self) -> i2c1::ErrorKind {
+        use i2c1::{ErrorKind, NoAcknowledgeSource};
+        match self {
+            I2CError::AddressNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            I2CError::DataNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            I2CError::GeneralCallNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            I2CError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            I2CError::Timeout => ErrorKind::Bus,
+            I2CError::Underrun => ErrorKind::Other,
+            I2CError::Abort(_) => ErrorKind::Other,
+        }
+    }
+}
+
+macro_rules! impl_i2c {
+    ($address:ty, $ten_bit:expr) => {
+        impl_i2c!(I2CImpl<IF>, $address, $ten_bit);
+        impl_i2c!(&I2CImpl<IF>, $address, $ten_bit);
+    };
+    ($t:ty, $address:ty, $ten_bit:expr) => {
+        impl<IF: I2CExt> i2c02::Read<$address> for $t {
+            type Error = I2CError;
+
+            fn read(&mut self, address: $address, buffer: &mut [u8]) -> Result<(), I2CError> {
+                self.transaction(tar(address.into(), $ten_bit), &mut [Operation::Read(buffer)])
+            }
+        }
+
+        impl<IF: I2CExt> i2c02::Write<$address> for $t {
+            type Error = I2CError;
+
+            fn write(&mut self, address: $address, bytes: &[u8]) -> Result<(), I2CError> {
+                self.transaction(tar(address.into(), $ten_bit), &mut [Operation::Write(bytes)])
+            }
+        }
+
+        impl<IF: I2CExt> i2c02::WriteRead<$address> for $t {
+            type Error = I2CError;
+
+            fn write_read(&mut self, address: $address, bytes: &[u8], buffer: &mut [u8])
+                -> Result<(), I2CError> {
+                self.transaction(tar(address.into(), $ten_bit),
+                                 &mut [Operation::Write(bytes), Operation::Read(buffer)])
+            }
+        }
+
+        impl<IF: I2CExt> i2c1::I2c<$address> for $t {
+            fn transaction(&mut self, address: $address, operations: &mut [Operation<'_>])
+                -> Result<(), I2CError> {
+                I2CImpl::transaction(self, tar(address.into(), $ten_bit), operations)
+            }
+        }
+    };
+}
+
+impl<IF: I2CExt> i2c1::ErrorType for I2CImpl<IF> {
+    type Error = I2CError;
+}
+
+impl<IF: I2CExt> i2c1::ErrorType for &I2CImpl<IF> {
+    type Error = I2CError;
+}
+
+impl_i2c!(i2c02::SevenBitAddress, false);
+impl_i2c!(i2c02::TenBitAddress, true);
@@ -0,0 +1,258 @@
+//! Periodic sampling of an I2C device into a ring buffer
+//!
+//! A timer channel interrupt starts a burst read of consecutive registers at a fixed rate,
+//! which the I2C interrupt handler completes. Samples collect in a ring buffer until taken
+//! with `Sampler::pop`; when it is full, the oldest sample is dropped. Only one sampler can
+//! run at a time.
+use core::ptr;
+use k210_hal::pac::{self, timer0};
+use pac::interrupt::Interrupt;
+use pac::timer0::channel::control::MODE_A;
+
+use crate::soc::plic;
+use crate::soc::pwm::{Channel, TimerExt};
+use crate::soc::sysctl;
+use super::transfer::{self, I2CIrq};
+use super::{I2CError, I2CExt};
+
+/** Registers to read every period */
+#[derive(Copy, Clone, Debug)]
+pub struct Burst {
+    /** Address of the device */
+    pub address: u16,
+    /** First register */
+    pub register: u8,
+    /** Number of bytes in a sample */
+    pub len: usize,
+}
+
+/** Counters of samples that did not make it into the ring buffer */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /** Dropped because the ring buffer was full */
+    pub lost: u32,
+    /** Failed reads */
+    pub errors: u32,
+    /** Periods skipped because the previous read was still running, or the controller was
+     * still busy with a transfer to another device */
+    pub skipped: u32,
+}
+
+/** Bookkeeping of a ring buffer of fixed-size samples */
+struct Ring {
+    sample_len: usize,
+    slots: usize,
+    /** Slot the next sample goes into */
+    head: usize,
+    /** Number of complete samples */
+    count: usize,
+}
+
+impl Ring {
+    fn new(sample_len: usize, buffer_len: usize) -> Self {
+        assert!(sample_len > 0 && buffer_len >= sample_len, "buffer too small");
+        Self { sample_len, slots: buffer_len / sample_len, head: 0, count: 0 }
+    }
+
+    /** Offset of the slot for the next sample. When all slots are full, the oldest sample is
+     * dropped and `false` returned with it. */
+    fn reserve(&mut self) -> (usize, bool) {
+        let dropped = self.count == self.slots;
+        if dropped {
+            self.count -= 1;
+        }
+        (self.head * self.sample_len, !dropped)
+    }
+
+    /** Add the sample written to the reserved slot */
+    fn commit(&mut self) {
+        self.head = (self.head + 1) % self.slots;
+        self.count += 1;
+    }
+
+    /** Remove the oldest sample, returning its offset */
+    fn pop(&mut self) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        let tail = (self.head + self.slots - self.count) % self.slots;
+        self.count -= 1;
+        Some(tail * self.sample_len)
+    }
+}
+
+struct State {
+    i2c_index: usize,
+    tar: u32,
+    register: u8,
+    timer: *const timer0::RegisterBlock,
+    channel: Channel,
+    buffer: *mut u8,
+    buffer_len: usize,
+    ring: Ring,
+    stats: Stats,
+}
+
+/** State of the running sampler, shared with the interrupt handlers */
+static mut STATE: Option<State> = None;
+
+fn state() -> Option<&'static mut State> {
+    unsafe { (*ptr::addr_of_mut!(STATE)).as_mut() }
+}
+
+/** Timer interrupt: start reading the next sample */
+fn interrupt_timer(_interrupt: Interrupt) {
+    let state = match state() {
+        Some(state) => state,
+        None => return,
+    };
+    let timer = unsafe { &*state.timer };
+    timer.channel[state.channel.idx()].eoi.read().bits();
+    let i2c = match transfer::state(state.i2c_index) {
+        Some(i2c) => i2c,
+        None => return,
+    };
+    // try again on the next tick rather than wait in the interrupt handler
+    if !i2c.can_start(state.tar) {
+        state.stats.skipped += 1;
+        return;
+    }
+    let (offset, kept) = state.ring.reserve();
+    if !kept {
+        state.stats.lost += 1;
+    }
+    let sample = unsafe { state.buffer.add(offset) };
+    i2c.start(state.tar, &[state.register], sample, state.ring.sample_len);
+}
+
+/** I2C completion of a sample read */
+fn sample_done(result: Result<(), I2CError>) {
+    if let Some(state) = state() {
+        match result {
+            Ok(()) => state.ring.commit(),
+            Err(_) => state.stats.errors += 1,
+        }
+    }
+}
+
+/** Running sampler */
+pub struct Sampler<IF, TIMER> {
+    i2c: I2CIrq<IF>,
+    timer: TIMER,
+    channel: Channel,
+}
+
+impl<IF: I2CExt, TIMER: TimerExt> Sampler<IF, TIMER> {
+    /** Start reading `burst` at `rate` Hz, using a channel of `timer`. `buffer` holds the ring
+     * buffer, as many samples as fit. The controller must not be running a transfer. */
+    pub fn start(mut i2c: I2CIrq<IF>, timer: TIMER, channel: Channel, burst: Burst, rate: u32,
+                 buffer: &'static mut [u8]) -> Self {
+        assert!(!i2c.is_busy(), "I2C transfer in progress");
+        let state = State {
+            i2c_index: IF::INDEX,
+            tar: i2c.target(burst.address),
+            register: burst.register,
+            timer: &*timer as *const timer0::RegisterBlock,
+            channel,
+            buffer: buffer.as_mut_ptr(),
+            buffer_len: buffer.len(),
+            ring: Ring::new(burst.len, buffer.len()),
+            stats: Stats::default(),
+        };
+        riscv::interrupt::free(|_| unsafe {
+            let slot = &mut *ptr::addr_of_mut!(STATE);
+            assert!(slot.is_none(), "sampler already running");
+            *slot = Some(state);
+        });
+        i2c.set_callback(Some(sample_done));
+
+        sysctl::clock_enable(TIMER::CLK);
+        let periods = sysctl::clock_get_freq(TIMER::CLK) / rate;
+        let ch = &timer.channel[channel.idx()];
+        unsafe {
+            ch.control.write(|w| w.interrupt().set_bit());
+            ch.load_count.write(|w| w.bits(periods));
+        }
+        plic::register(TIMER::INTERRUPTS[channel.idx() / 2], 1, interrupt_timer);
+        // interrupt left unmasked
+        ch.control.write(|w| w.mode().variant(MODE_A::USER).enable().set_bit());
+        Self { i2c, timer, channel }
+    }
+
+    /** Take the oldest sample, copying it into `sample` (at least `Burst::len` bytes). Returns
+     * false if there is none. */
+    pub fn pop(&self, sample: &mut [u8]) -> bool {
+        riscv::interrupt::free(|_| {
+            let state = state().unwrap();
+            match state.ring.pop() {
+                Some(offset) => {
+                    let len = state.ring.sample_len;
+                    let data = unsafe {
+                        core::slice::from_raw_parts(state.buffer.add(offset), len)
+                    };
+                    sample[..len].copy_from_slice(data);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /** Number of samples in the ring buffer */
+    pub fn available(&self) -> usize {
+        riscv::interrupt::free(|_| state().unwrap().ring.count)
+    }
+
+    /** Counters of missed samples */
+    pub fn stats(&self) -> Stats {
+        riscv::interrupt::free(|_| state().unwrap().stats)
+    }
+
+    /** Stop sampling, aborting a running read, and give back the controller, timer and
+     * buffer */
+    pub fn stop(mut self) -> (I2CIrq<IF>, TIMER, &'static mut [u8]) {
+        let ch = &self.timer.channel[self.channel.idx()];
+        ch.control.write(|w| w.interrupt().set_bit());
+        plic::unregister(TIMER::INTERRUPTS[self.channel.idx() / 2]);
+        let state = riscv::interrupt::free(|_| unsafe {
+            let state = (*ptr::addr_of_mut!(STATE)).take().unwrap();
+            if let Some(i2c) = transfer::state(state.i2c_index) {
+                i2c.cancel(Err(I2CError::Timeout));
+            }
+            state
+        });
+        self.i2c.set_callback(None);
+        let buffer = unsafe { core::slice::from_raw_parts_mut(state.buffer, state.buffer_len) };
+        (self.i2c, self.timer, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        // three slots of 6 bytes, two bytes unused
+        let mut ring = Ring::new(6, 20);
+        assert_eq!(ring.pop(), None);
+        assert_eq!(ring.reserve(), (0, true));
+        ring.commit();
+        assert_eq!(ring.reserve(), (6, true));
+        // failed read: the slot is used again
+        assert_eq!(ring.reserve(), (6, true));
+        ring.commit();
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.reserve(), (12, true));
+        ring.commit();
+        assert_eq!(ring.reserve(), (0, true));
+        ring.commit();
+        // full: the oldest sample (at 6) is dropped while the next one is read into its slot
+        assert_eq!(ring.reserve(), (6, false));
+        assert_eq!(ring.pop(), Some(12));
+        ring.commit();
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(6));
+        assert_eq!(ring.pop(), None);
+    }
+}
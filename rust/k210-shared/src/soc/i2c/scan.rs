@@ -0,0 +1,93 @@
+//! I2C bus scan
+//!
+//! Probes 7-bit addresses by reading a byte from them, and collects the ones that are
+//! acknowledged. Reading is safer than writing for most devices, as it doesn't change a
+//! register pointer or start a command. Addresses reserved by the I2C specification are
+//! skipped.
+use embedded_hal_1::i2c::Operation;
+
+use super::{tar, I2CError, I2CExt, I2CImpl};
+
+/** First address that is not reserved */
+pub const FIRST_ADDRESS: u8 = 0x08;
+/** Last address that is not reserved */
+pub const LAST_ADDRESS: u8 = 0x77;
+
+/** Set of 7-bit addresses */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressSet(u128);
+
+impl AddressSet {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn insert(&mut self, address: u8) {
+        self.0 |= 1 << (address & 0x7f);
+    }
+
+    pub fn contains(&self, address: u8) -> bool {
+        address < 0x80 && (self.0 & (1 << address)) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /** Addresses in the set, in ascending order */
+    pub fn iter(&self) -> impl Iterator<Item = u8> {
+        let set = *self;
+        (0..0x80).filter(move |address| set.contains(*address))
+    }
+}
+
+impl<IF: I2CExt> I2CImpl<IF> {
+    /** Whether a device acknowledges the 7-bit `address` */
+    pub fn probe(&self, address: u8) -> Result<bool, I2CError> {
+        let mut buf = [0u8; 1];
+        match self.transaction(tar(address.into(), false), &mut [Operation::Read(&mut buf)]) {
+            Ok(()) => Ok(true),
+            Err(I2CError::AddressNack) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /** Probe all non-reserved 7-bit addresses. The controller has to be set up with
+     * `I2C::init`, which determines the clock rate. */
+    pub fn scan(&self) -> Result<AddressSet, I2CError> {
+        let mut found = AddressSet::new();
+        for address in FIRST_ADDRESS..=LAST_ADDRESS {
+            if self.probe(address)? {
+                found.insert(address);
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_set() {
+        let mut set = AddressSet::new();
+        assert!(set.is_empty());
+        set.insert(0x26);
+        set.insert(0x7f);
+        set.insert(0x08);
+        set.insert(0x26);
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(0x26) && set.contains(0x7f) && !set.contains(0x48));
+        assert!(!set.contains(0xa6));
+        let mut iter = set.iter();
+        assert_eq!(iter.next(), Some(0x08));
+        assert_eq!(iter.next(), Some(0x26));
+        assert_eq!(iter.next(), Some(0x7f));
+        assert_eq!(iter.next(), None);
+    }
+}
@@ -0,0 +1,333 @@
+//! I2C slave mode
+//!
+//! `I2CSlave` makes a controller answer at its own address, so that the K210 can act as a
+//! peripheral for another master on the bus. Everything happens in the interrupt handler. With
+//! `Handler::Callbacks`, bytes written by the master are passed to the receive callback, and
+//! every byte the master reads is asked from the transmit callback; the controller stretches
+//! the clock until it is supplied.
+//!
+//! With `Handler::Registers` the slave emulates a register file like most sensors have: the
+//! first byte of a write selects a register, further bytes are stored in consecutive registers,
+//! and reads return consecutive registers from the selected one. Registers past the end read
+//! as 0xFF and ignore writes. Only the first 256 bytes can be addressed.
+//!
+//! A transfer is taken to end at a STOP or a change of direction. The handler has to keep up
+//! with the bus for this to work: bytes of two writes separated by a STOP are seen as one write
+//! if they end up in the RX FIFO together.
+use core::ptr;
+use k210_hal::pac::{self, i2c0};
+use pac::interrupt::Interrupt;
+
+use crate::soc::plic;
+use crate::soc::sysctl;
+use super::{address_slave_width, I2CExt, I2CImpl, FIFO_DEPTH};
+
+/** Interrupt bits (INTR_MASK, INTR_STAT) */
+const INTR_RX_FULL: u32 = 1 << 2;
+const INTR_RD_REQ: u32 = 1 << 5;
+const INTR_TX_ABRT: u32 = 1 << 6;
+const INTR_RX_DONE: u32 = 1 << 7;
+const INTR_STOP_DET: u32 = 1 << 9;
+
+/** What the slave does with the data */
+pub enum Handler {
+    /** Pass written bytes to `receive`, with a flag for the first bytes of a transfer. Bytes
+     * read by the master come from `transmit`, which gets a flag for the first byte. */
+    Callbacks {
+        receive: fn(data: &[u8], start: bool),
+        transmit: fn(start: bool) -> u8,
+    },
+    /** Emulate a register file, calling `written` with the first register and the number of
+     * registers after the master changed them */
+    Registers {
+        registers: &'static mut [u8],
+        written: Option<fn(register: usize, len: usize)>,
+    },
+}
+
+/** Register pointer and bookkeeping of a register file */
+struct RegisterFile {
+    len: usize,
+    /** Register accessed next */
+    pointer: usize,
+    /** Registers written in the current transfer: first register and number */
+    written: Option<(usize, usize)>,
+}
+
+impl RegisterFile {
+    fn new(len: usize) -> Self {
+        Self { len, pointer: 0, written: None }
+    }
+
+    /** Byte written by the master. The first byte of a transfer selects the register. */
+    fn write(&mut self, registers: &mut [u8], byte: u8, start: bool) {
+        if start {
+            self.pointer = usize::from(byte);
+            return;
+        }
+        if self.pointer < self.len {
+            registers[self.pointer] = byte;
+            self.written = match self.written {
+                Some((first, len)) => Some((first, len + 1)),
+                None => Some((self.pointer, 1)),
+            };
+            self.pointer += 1;
+        }
+    }
+
+    /** Byte read by the master */
+    fn read(&mut self, registers: &[u8]) -> u8 {
+        if self.pointer < self.len {
+            self.pointer += 1;
+            registers[self.pointer - 1]
+        } else {
+            0xff
+        }
+    }
+
+    /** Registers written since the last call */
+    fn take_written(&mut self) -> Option<(usize, usize)> {
+        self.written.take()
+    }
+}
+
+/** Direction of the transfer in progress */
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Receiving,
+    Transmitting,
+}
+
+enum Mode {
+    Callbacks {
+        receive: fn(&[u8], bool),
+        transmit: fn(bool) -> u8,
+    },
+    Registers {
+        registers: *mut u8,
+        file: RegisterFile,
+        written: Option<fn(usize, usize)>,
+    },
+}
+
+struct State {
+    regs: *const i2c0::RegisterBlock,
+    mode: Mode,
+    phase: Phase,
+}
+
+/** State of the controllers in slave mode, shared with the interrupt handler */
+static mut STATES: [Option<State>; 3] = [None, None, None];
+
+fn state(index: usize) -> Option<&'static mut State> {
+    unsafe { (*ptr::addr_of_mut!(STATES))[index].as_mut() }
+}
+
+impl State {
+    fn regs(&self) -> &'static i2c0::RegisterBlock {
+        unsafe { &*self.regs }
+    }
+
+    /** Pass on the bytes in the RX FIFO */
+    fn receive(&mut self) {
+        let i2c = self.regs();
+        let mut data = [0u8; FIFO_DEPTH];
+        let count = (i2c.rxflr.read().bits() as usize).min(FIFO_DEPTH);
+        if count == 0 {
+            return;
+        }
+        for byte in data[..count].iter_mut() {
+            *byte = i2c.data_cmd.read().data().bits();
+        }
+        let start = self.phase != Phase::Receiving;
+        if start {
+            self.end_transfer();
+        }
+        self.phase = Phase::Receiving;
+        match &mut self.mode {
+            Mode::Callbacks { receive, .. } => receive(&data[..count], start),
+            Mode::Registers { registers, file, .. } => {
+                let registers = unsafe { core::slice::from_raw_parts_mut(*registers, file.len) };
+                for (i, byte) in data[..count].iter().enumerate() {
+                    file.write(registers, *byte, start && i == 0);
+                }
+            }
+        }
+    }
+
+    /** Answer a read request with one byte */
+    fn transmit(&mut self) {
+        let start = self.phase != Phase::Transmitting;
+        if start {
+            self.end_transfer();
+        }
+        self.phase = Phase::Transmitting;
+        let byte = match &mut self.mode {
+            Mode::Callbacks { transmit, .. } => transmit(start),
+            Mode::Registers { registers, file, .. } => {
+                file.read(unsafe { core::slice::from_raw_parts(*registers, file.len) })
+            }
+        };
+        unsafe {
+            self.regs().data_cmd.write(|w| w.data().bits(byte));
+        }
+    }
+
+    /** Report the registers changed by a write */
+    fn end_transfer(&mut self) {
+        if let Mode::Registers { file, written: Some(written), .. } = &mut self.mode {
+            if let Some((register, len)) = file.take_written() {
+                written(register, len);
+            }
+        }
+        self.phase = Phase::Idle;
+    }
+}
+
+/** I2C controller interrupt in slave mode */
+fn interrupt_i2c_slave(interrupt: Interrupt) {
+    let index = match interrupt {
+        Interrupt::I2C0 => 0,
+        Interrupt::I2C1 => 1,
+        Interrupt::I2C2 => 2,
+        _ => return,
+    };
+    let state = match state(index) {
+        Some(state) => state,
+        None => return,
+    };
+    let i2c = state.regs();
+    let status = i2c.intr_stat.read().bits();
+
+    // written bytes come before a read request that follows with a repeated start
+    state.receive();
+    if (status & INTR_TX_ABRT) != 0 {
+        // stale bytes in the TX FIFO were flushed
+        i2c.clr_tx_abrt.read().bits();
+    }
+    if (status & INTR_RD_REQ) != 0 {
+        state.transmit();
+        i2c.clr_rd_req.read().bits();
+    }
+    if (status & INTR_RX_DONE) != 0 {
+        // master did not acknowledge the last byte read
+        i2c.clr_rx_done.read().bits();
+    }
+    if (status & INTR_STOP_DET) != 0 {
+        i2c.clr_stop_det.read().bits();
+        state.end_transfer();
+    }
+}
+
+/** I2C controller in slave mode */
+pub struct I2CSlave<IF> {
+    i2c: I2CImpl<IF>,
+}
+
+impl<IF: I2CExt> I2CSlave<IF> {
+    /** Answer at `address`, of `address_width` (7 or 10) bits, passing the data to `handler`.
+     * This requires interrupts to have been enabled with `plic::init`. */
+    pub fn new(i2c: I2CImpl<IF>, address: u16, address_width: u32, handler: Handler) -> Self {
+        let mode = match handler {
+            Handler::Callbacks { receive, transmit } => Mode::Callbacks { receive, transmit },
+            Handler::Registers { registers, written } => Mode::Registers {
+                file: RegisterFile::new(registers.len()),
+                registers: registers.as_mut_ptr(),
+                written,
+            },
+        };
+        let v_width = address_slave_width(address_width);
+        sysctl::clock_enable(IF::CLK);
+        sysctl::clock_set_threshold(IF::DIV, 3);
+        sysctl::reset(IF::RESET);
+
+        use i2c0::con::SPEED_A;
+        unsafe {
+            i2c.i2c.enable.write(|w| w.bits(0));
+            i2c.i2c.con.write(|w| w.master_mode().bit(false)
+                                .slave_disable().bit(false)
+                                .restart_en().bit(true)
+                                .addr_slave_width().variant(v_width)
+                                .speed().variant(SPEED_A::FAST));
+            i2c.i2c.sar.write(|w| w.bits(u32::from(address)));
+            i2c.i2c.rx_tl.write(|w| w.bits(0));
+            i2c.i2c.dma_cr.write(|w| w.bits(0));
+            i2c.i2c.intr_mask.write(|w| w.bits(INTR_RX_FULL | INTR_RD_REQ | INTR_TX_ABRT
+                                                | INTR_RX_DONE | INTR_STOP_DET));
+        }
+        i2c.target.set(None);
+        riscv::interrupt::free(|_| unsafe {
+            (*ptr::addr_of_mut!(STATES))[IF::INDEX] = Some(State {
+                regs: &*i2c.i2c as *const i2c0::RegisterBlock,
+                mode,
+                phase: Phase::Idle,
+            });
+        });
+        plic::register(IF::INTERRUPT, 1, interrupt_i2c_slave);
+        i2c.i2c.enable.write(|w| w.enable().bit(true));
+        Self { i2c }
+    }
+
+    /** Access the register file, with interrupts disabled. Panics for a slave with
+     * callbacks. */
+    pub fn with_registers<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> R {
+        riscv::interrupt::free(|_| match &state(IF::INDEX).unwrap().mode {
+            Mode::Registers { registers, file, .. } => {
+                f(unsafe { core::slice::from_raw_parts_mut(*registers, file.len) })
+            }
+            Mode::Callbacks { .. } => panic!("I2C slave has no register file"),
+        })
+    }
+
+    /** Stop answering, and give back the controller and handler. The controller needs
+     * `I2C::init` to be used as master again. */
+    pub fn free(self) -> (I2CImpl<IF>, Handler) {
+        unsafe {
+            self.i2c.i2c.enable.write(|w| w.bits(0));
+            self.i2c.i2c.intr_mask.write(|w| w.bits(0));
+        }
+        plic::unregister(IF::INTERRUPT);
+        let state = riscv::interrupt::free(|_| unsafe {
+            (*ptr::addr_of_mut!(STATES))[IF::INDEX].take()
+        }).unwrap();
+        let handler = match state.mode {
+            Mode::Callbacks { receive, transmit } => Handler::Callbacks { receive, transmit },
+            Mode::Registers { registers, file, written } => Handler::Registers {
+                registers: unsafe { core::slice::from_raw_parts_mut(registers, file.len) },
+                written,
+            },
+        };
+        (self.i2c, handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_file() {
+        let mut registers = [0u8; 4];
+        let mut file = RegisterFile::new(registers.len());
+        // select register 1, write two registers
+        for (i, byte) in [1, 0xaa, 0xbb].iter().enumerate() {
+            file.write(&mut registers, *byte, i == 0);
+        }
+        assert_eq!(registers, [0, 0xaa, 0xbb, 0]);
+        assert_eq!(file.take_written(), Some((1, 2)));
+        assert_eq!(file.take_written(), None);
+        // only select register 2, then read past the end
+        file.write(&mut registers, 2, true);
+        assert_eq!(file.take_written(), None);
+        assert_eq!(file.read(&registers), 0xbb);
+        assert_eq!(file.read(&registers), 0);
+        assert_eq!(file.read(&registers), 0xff);
+        // writes past the end are dropped
+        for (i, byte) in [3, 0xcc, 0xdd].iter().enumerate() {
+            file.write(&mut registers, *byte, i == 0);
+        }
+        assert_eq!(registers, [0, 0xaa, 0xbb, 0xcc]);
+        assert_eq!(file.take_written(), Some((3, 1)));
+    }
+}
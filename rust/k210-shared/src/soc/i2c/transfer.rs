@@ -0,0 +1,463 @@
+//! Interrupt-driven I2C transfers
+//!
+//! `I2CIrq` takes over an initialized controller and runs transfers from its interrupt
+//! handler, so the CPU is free while the bytes go over the bus. A transfer writes a few bytes
+//! (usually a register number) and then reads into a buffer, after a repeated start. The
+//! handler keeps the FIFOs filled and drained; with DMA channels configured, reads longer than
+//! the FIFO are done by DMA instead, and the handler only runs once at the end.
+//!
+//! Starting a transfer gives a `Transfer` handle that owns the read buffer until the transfer
+//! is done. A callback can be set to get notified from the interrupt handler.
+use core::ptr;
+use k210_hal::pac::{self, i2c0};
+use pac::interrupt::Interrupt;
+
+use crate::soc::dmac::{address_increment, burst_length, transfer_width, DMAC};
+use crate::soc::plic;
+use crate::soc::sysctl::{self, dma_channel};
+use crate::timing::clock;
+use super::{is_idle, reset_transfer, switch_target, tar, I2CError, I2CExt, I2CImpl, FIFO_DEPTH};
+
+/** Maximum number of bytes written before the read part of a transfer */
+pub const MAX_WRITE_LEN: usize = 16;
+/** Reads longer than this are done by DMA, if configured */
+const DMA_MIN_LEN: usize = FIFO_DEPTH;
+/** Time (us) allowed for the DMA controller to move the last byte after the STOP */
+const DMA_DRAIN_US: u64 = 10;
+
+/** Interrupt bits (INTR_MASK, INTR_STAT) */
+const INTR_RX_FULL: u32 = 1 << 2;
+const INTR_TX_EMPTY: u32 = 1 << 4;
+const INTR_TX_ABRT: u32 = 1 << 6;
+const INTR_STOP_DET: u32 = 1 << 9;
+/** DATA_CMD word for reading a byte */
+static READ_COMMAND: u32 = 1 << 8;
+
+/** Called from the interrupt handler when a transfer completes or fails */
+pub type Callback = fn(Result<(), I2CError>);
+
+/** DMA channels for sending read commands and receiving data */
+#[derive(Copy, Clone)]
+struct DMAChannels {
+    dmac: &'static DMAC,
+    tx: dma_channel,
+    rx: dma_channel,
+}
+
+/** Transfer being run by the interrupt handler */
+struct Current {
+    write: [u8; MAX_WRITE_LEN],
+    write_len: usize,
+    /** Bytes of `write` pushed into the TX FIFO */
+    written: usize,
+    read: *mut u8,
+    read_len: usize,
+    /** Read commands pushed into the TX FIFO */
+    commands: usize,
+    /** Bytes received */
+    received: usize,
+    dma: bool,
+}
+
+pub(super) struct State {
+    regs: *const i2c0::RegisterBlock,
+    dma: Option<DMAChannels>,
+    dma_rx: sysctl::dma_select,
+    dma_tx: sysctl::dma_select,
+    /** TAR register value the controller is set up with */
+    target: Option<u32>,
+    current: Option<Current>,
+    /** Outcome of the last transfer, `None` while one is running */
+    result: Option<Result<(), I2CError>>,
+    callback: Option<Callback>,
+}
+
+/** State of the controllers in interrupt mode, shared with the interrupt handler */
+static mut STATES: [Option<State>; 3] = [None, None, None];
+
+pub(super) fn state(index: usize) -> Option<&'static mut State> {
+    unsafe { (*ptr::addr_of_mut!(STATES))[index].as_mut() }
+}
+
+impl State {
+    fn regs(&self) -> &'static i2c0::RegisterBlock {
+        unsafe { &*self.regs }
+    }
+
+    /** Whether a transfer to `tar` can be started without waiting: none is running, and if
+     * the controller needs to be switched to another target, it is done with the last one */
+    pub(super) fn can_start(&self, tar: u32) -> bool {
+        self.current.is_none() && (self.target == Some(tar) || is_idle(self.regs()))
+    }
+
+    /** Start a transfer. This runs with interrupts disabled, and `can_start` must be true:
+     * it never waits for the controller, so that it can be called from interrupt handlers. */
+    pub(super) fn start(&mut self, tar: u32, write: &[u8], read: *mut u8, read_len: usize) {
+        assert!(write.len() <= MAX_WRITE_LEN, "too many bytes to write");
+        debug_assert!(self.can_start(tar));
+        let i2c = self.regs();
+        self.result = None;
+        if write.is_empty() && read_len == 0 {
+            // nothing would go over the bus, not even the address
+            self.finish(Ok(()));
+            return;
+        }
+        if self.target != Some(tar) {
+            switch_target(i2c, tar);
+            self.target = Some(tar);
+        }
+        let mut current = Current {
+            write: [0; MAX_WRITE_LEN],
+            write_len: write.len(),
+            written: 0,
+            read,
+            read_len,
+            commands: 0,
+            received: 0,
+            dma: self.dma.is_some() && read_len > DMA_MIN_LEN,
+        };
+        current.write[..write.len()].copy_from_slice(write);
+        i2c.clr_intr.read().bits();
+        match self.dma {
+            Some(dma) if current.dma => unsafe {
+                let data_cmd = &i2c.data_cmd as *const _ as u64;
+                // A DMA request is made for every received byte, and when four or fewer bytes
+                // are left to send
+                i2c.dma_rdlr.write(|w| w.bits(0));
+                i2c.dma_tdlr.write(|w| w.bits(4));
+                sysctl::dma_select(dma.rx, self.dma_rx);
+                dma.dmac.set_single_mode(dma.rx, data_cmd, read as u64,
+                                         address_increment::NOCHANGE, address_increment::INCREMENT,
+                                         burst_length::LENGTH_1, transfer_width::WIDTH_8,
+                                         read_len as u32);
+                // The bytes to write go first; the read commands have to follow before the
+                // controller has sent them, or it ends the transfer
+                for &b in write {
+                    i2c.data_cmd.write(|w| w.data().bits(b));
+                }
+                sysctl::dma_select(dma.tx, self.dma_tx);
+                dma.dmac.set_single_mode(dma.tx, &READ_COMMAND as *const u32 as u64, data_cmd,
+                                         address_increment::NOCHANGE, address_increment::NOCHANGE,
+                                         burst_length::LENGTH_4, transfer_width::WIDTH_32,
+                                         read_len as u32);
+                current.written = write.len();
+                current.commands = read_len;
+                self.current = Some(current);
+                i2c.intr_mask.write(|w| w.bits(INTR_TX_ABRT | INTR_STOP_DET));
+            },
+            _ => unsafe {
+                // TX_EMPTY when half of the TX FIFO is left, RX_FULL when half of the RX FIFO
+                // is filled; the last bytes are taken after the STOP
+                i2c.tx_tl.write(|w| w.bits((FIFO_DEPTH / 2) as u32));
+                i2c.rx_tl.write(|w| w.bits((FIFO_DEPTH / 2 - 1) as u32));
+                self.current = Some(current);
+                i2c.intr_mask.write(|w| {
+                    w.bits(INTR_TX_EMPTY | INTR_RX_FULL | INTR_TX_ABRT | INTR_STOP_DET)
+                });
+                self.fill();
+            },
+        }
+    }
+
+    /** Take received bytes from the RX FIFO */
+    fn drain(&mut self) {
+        let i2c = self.regs();
+        if let Some(current) = self.current.as_mut() {
+            let available = i2c.rxflr.read().bits() as usize;
+            for _ in 0..available.min(current.commands - current.received) {
+                unsafe {
+                    *current.read.add(current.received) = i2c.data_cmd.read().data().bits();
+                }
+                current.received += 1;
+            }
+        }
+    }
+
+    /** Push bytes to write and read commands into the TX FIFO, as far as there is room in both
+     * FIFOs. The TX_EMPTY interrupt is only enabled while there is more to push. */
+    fn fill(&mut self) {
+        let i2c = self.regs();
+        if let Some(current) = self.current.as_mut() {
+            let mut space = FIFO_DEPTH - i2c.txflr.read().bits() as usize;
+            while space > 0 && current.written < current.write_len {
+                unsafe {
+                    i2c.data_cmd.write(|w| w.data().bits(current.write[current.written]));
+                }
+                current.written += 1;
+                space -= 1;
+            }
+            // don't ask for more bytes than the RX FIFO can hold
+            while space > 0 && current.commands < current.read_len
+                && current.commands - current.received < FIFO_DEPTH {
+                i2c.data_cmd.write(|w| w.cmd().bit(true));
+                current.commands += 1;
+                space -= 1;
+            }
+            // wait for RX_FULL instead while nothing can be pushed
+            let blocked = current.written == current.write_len
+                && (current.commands == current.read_len
+                    || current.commands - current.received == FIFO_DEPTH);
+            i2c.intr_mask.modify(|r, w| unsafe {
+                w.bits(if blocked { r.bits() & !INTR_TX_EMPTY } else { r.bits() | INTR_TX_EMPTY })
+            });
+        }
+    }
+
+    /** End the running transfer */
+    fn finish(&mut self, result: Result<(), I2CError>) {
+        let i2c = self.regs();
+        unsafe {
+            i2c.intr_mask.write(|w| w.bits(0));
+        }
+        if let Some(current) = self.current.take() {
+            if let (true, Some(dma), Err(_)) = (current.dma, self.dma, result) {
+                dma.dmac.channel_disable(dma.tx);
+                dma.dmac.channel_disable(dma.rx);
+            }
+        }
+        self.result = Some(result);
+        if let Some(callback) = self.callback {
+            callback(result);
+        }
+    }
+
+    /** Give up on the running transfer */
+    pub(super) fn cancel(&mut self, result: Result<(), I2CError>) {
+        if self.current.is_some() {
+            reset_transfer(self.regs());
+            self.finish(result);
+        }
+    }
+
+    pub(super) fn is_busy(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub(super) fn set_callback(&mut self, callback: Option<Callback>) {
+        self.callback = callback;
+    }
+
+    /** Number of bytes transferred so far, to tell whether a transfer makes progress */
+    fn progress(&self) -> usize {
+        match &self.current {
+            Some(current) if current.dma => {
+                // the handler doesn't see DMA transfers; the FIFO levels keep changing while
+                // bytes go over the bus
+                let i2c = self.regs();
+                i2c.txflr.read().bits() as usize | (i2c.rxflr.read().bits() as usize) << 8
+            }
+            Some(current) => current.written + current.commands + current.received,
+            None => 0,
+        }
+    }
+}
+
+/** I2C controller interrupt: FIFO thresholds, abort or STOP */
+fn interrupt_i2c(interrupt: Interrupt) {
+    let index = match interrupt {
+        Interrupt::I2C0 => 0,
+        Interrupt::I2C1 => 1,
+        Interrupt::I2C2 => 2,
+        _ => return,
+    };
+    let state = match state(index) {
+        Some(state) => state,
+        None => return,
+    };
+    let i2c = state.regs();
+    let status = i2c.intr_stat.read().bits();
+    let dma = match &state.current {
+        Some(current) => current.dma,
+        None => {
+            i2c.clr_intr.read().bits();
+            return;
+        }
+    };
+
+    if (status & INTR_TX_ABRT) != 0 {
+        let source = i2c.tx_abrt_source.read().bits();
+        i2c.clr_tx_abrt.read().bits();
+        state.finish(Err(I2CError::from_abort_source(source).err().unwrap_or(I2CError::Abort(0))));
+        return;
+    }
+    if !dma {
+        state.drain();
+    }
+    if (status & INTR_STOP_DET) != 0 {
+        i2c.clr_stop_det.read().bits();
+        let complete = match (&state.current, state.dma) {
+            (Some(current), Some(channels)) if current.dma => {
+                let start = clock();
+                while !channels.dmac.is_done(channels.rx) && clock() - start < DMA_DRAIN_US {
+                    // DMA takes the last byte
+                }
+                channels.dmac.is_done(channels.rx)
+            }
+            (Some(current), _) => {
+                current.written == current.write_len && current.received == current.read_len
+            }
+            (None, _) => false,
+        };
+        state.finish(if complete { Ok(()) } else { Err(I2CError::Underrun) });
+        return;
+    }
+    if !dma {
+        state.fill();
+    }
+}
+
+/** I2C controller in interrupt mode */
+pub struct I2CIrq<IF> {
+    i2c: I2CImpl<IF>,
+}
+
+impl<IF: I2CExt> I2CIrq<IF> {
+    /** Take over a controller that has been set up with `I2C::init`. With `dma` set to a DMA
+     * controller and two channels (for sending commands and receiving data), long reads are
+     * done by DMA. This requires interrupts to have been enabled with `plic::init`. */
+    pub fn new(i2c: I2CImpl<IF>, dma: Option<(&'static DMAC, dma_channel, dma_channel)>) -> Self {
+        let state = State {
+            regs: &*i2c.i2c as *const i2c0::RegisterBlock,
+            dma: dma.map(|(dmac, tx, rx)| DMAChannels { dmac, tx, rx }),
+            dma_rx: IF::DMA_RX,
+            dma_tx: IF::DMA_TX,
+            target: i2c.target.get(),
+            current: None,
+            result: Some(Ok(())),
+            callback: None,
+        };
+        riscv::interrupt::free(|_| unsafe {
+            (*ptr::addr_of_mut!(STATES))[IF::INDEX] = Some(state);
+        });
+        plic::register(IF::INTERRUPT, 1, interrupt_i2c);
+        Self { i2c }
+    }
+
+    /** Set or remove the function called from the interrupt handler when a transfer
+     * completes or fails */
+    pub fn set_callback(&mut self, callback: Option<Callback>) {
+        riscv::interrupt::free(|_| {
+            if let Some(state) = state(IF::INDEX) {
+                state.set_callback(callback);
+            }
+        });
+    }
+
+    /** TAR register value to address a device */
+    pub(super) fn target(&self, address: u16) -> u32 {
+        tar(address, self.i2c.ten_bit.get())
+    }
+
+    /** Start writing `write` (at most `MAX_WRITE_LEN` bytes) to the device at `address`,
+     * then reading `buffer` after a repeated start. The address width is the one set with
+     * `I2C::init`. */
+    pub fn read(&mut self, address: u16, write: &[u8], buffer: &'static mut [u8])
+        -> Transfer<'_, IF> {
+        self.start(self.target(address), write, buffer)
+    }
+
+    /** Start writing `write` (at most `MAX_WRITE_LEN` bytes) to the device at `address` */
+    pub fn write(&mut self, address: u16, write: &[u8]) -> Transfer<'_, IF> {
+        self.start(self.target(address), write, &mut [])
+    }
+
+    fn start(&mut self, tar: u32, write: &[u8], buffer: &'static mut [u8]) -> Transfer<'_, IF> {
+        // Wait here for the controller to be ready for another target, with interrupts enabled
+        let begin = clock();
+        loop {
+            let started = riscv::interrupt::free(|_| {
+                let state = state(IF::INDEX).unwrap();
+                if state.can_start(tar) {
+                    state.start(tar, write, buffer.as_mut_ptr(), buffer.len());
+                    true
+                } else if clock() - begin > self.i2c.timeout_us {
+                    state.result = None;
+                    state.finish(Err(I2CError::Timeout));
+                    true
+                } else {
+                    false
+                }
+            });
+            if started {
+                break;
+            }
+        }
+        Transfer { i2c: self, buffer: Some(buffer) }
+    }
+
+    /** Whether a transfer is running */
+    pub fn is_busy(&self) -> bool {
+        riscv::interrupt::free(|_| {
+            state(IF::INDEX).map(|s| s.is_busy()).unwrap_or(false)
+        })
+    }
+
+    /** Leave interrupt mode, and give back the controller */
+    pub fn free(self) -> I2CImpl<IF> {
+        plic::unregister(IF::INTERRUPT);
+        let state = riscv::interrupt::free(|_| unsafe {
+            (*ptr::addr_of_mut!(STATES))[IF::INDEX].take()
+        }).unwrap();
+        if let Some(dma) = state.dma {
+            dma.dmac.channel_disable(dma.tx);
+            dma.dmac.channel_disable(dma.rx);
+        }
+        unsafe {
+            self.i2c.i2c.intr_mask.write(|w| w.bits(0));
+        }
+        self.i2c.target.set(state.target);
+        self.i2c
+    }
+
+    /** Outcome of the last transfer, `None` while it is running */
+    fn result(&self) -> Option<Result<(), I2CError>> {
+        riscv::interrupt::free(|_| state(IF::INDEX).and_then(|s| s.result))
+    }
+}
+
+/** Transfer in progress. The transfer owns its read buffer, which is given back when it is
+ * done. Dropping the handle before then aborts the transfer. */
+pub struct Transfer<'a, IF: I2CExt> {
+    i2c: &'a mut I2CIrq<IF>,
+    buffer: Option<&'static mut [u8]>,
+}
+
+impl<'a, IF: I2CExt> Transfer<'a, IF> {
+    /** Return whether the transfer has completed or failed */
+    pub fn is_done(&self) -> bool {
+        self.i2c.result().is_some()
+    }
+
+    /** Wait for the transfer to complete, and give back the buffer with the outcome. A
+     * transfer that makes no progress within the timeout set with `I2CImpl::set_timeout` is
+     * aborted. */
+    pub fn wait(mut self) -> (&'static mut [u8], Result<(), I2CError>) {
+        let mut progress = 0;
+        let mut last_progress = clock();
+        let result = loop {
+            if let Some(result) = self.i2c.result() {
+                break result;
+            }
+            let now = riscv::interrupt::free(|_| state(IF::INDEX).unwrap().progress());
+            if now != progress {
+                progress = now;
+                last_progress = clock();
+            } else if clock() - last_progress > self.i2c.i2c.timeout_us {
+                riscv::interrupt::free(|_| {
+                    state(IF::INDEX).unwrap().cancel(Err(I2CError::Timeout))
+                });
+            }
+        };
+        (self.buffer.take().unwrap(), result)
+    }
+}
+
+impl<'a, IF: I2CExt> Drop for Transfer<'a, IF> {
+    fn drop(&mut self) {
+        if self.buffer.is_some() {
+            riscv::interrupt::free(|_| {
+                state(IF::INDEX).unwrap().cancel(Err(I2CError::Timeout))
+            });
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! PLIC (Platform-Level Interrupt Controller) handling
+use bare_metal::Nr;
+use k210_hal::pac;
+use pac::interrupt::Interrupt;
+use riscv::register::{mhartid, mie, mip, mstatus};
+
+/** Interrupt handler function, gets passed the interrupt that triggered it */
+pub type Handler = fn(Interrupt);
+
+/** Number of PLIC interrupt sources (0 is reserved) */
+const IRQ_COUNT: usize = 66;
+
+/** Registered interrupt handlers */
+static mut HANDLERS: [Option<Handler>; IRQ_COUNT] = [None; IRQ_COUNT];
+
+/** Enable machine-mode external interrupts (and interrupts in general) on the current core. */
+pub fn init() {
+    unsafe {
+        // Enable interrupts in general
+        mstatus::set_mie();
+        // Set the Machine-External bit in MIE
+        mie::set_mext();
+    }
+}
+
+/** Register or remove the handler for an interrupt. This should be done while the interrupt is
+ * disabled. */
+pub fn set_handler(interrupt: Interrupt, handler: Option<Handler>) {
+    unsafe {
+        HANDLERS[interrupt.nr() as usize] = handler;
+    }
+}
+
+/** Enable or disable a PLIC interrupt for the current core */
+pub fn irq_enable(interrupt: Interrupt, enabled: bool) {
+    let targetid = mhartid::read() * 2;
+    let irq_nr = interrupt.nr();
+    unsafe {
+        let plic = pac::PLIC::ptr();
+        let bit = 1 << ((irq_nr as u32) % 32);
+        if enabled {
+            (*plic).target_enables[targetid].enable[(irq_nr as usize) / 32]
+                .modify(|r, w| w.bits(r.bits() | bit));
+        } else {
+            (*plic).target_enables[targetid].enable[(irq_nr as usize) / 32]
+                .modify(|r, w| w.bits(r.bits() & !bit));
+        }
+    }
+}
+
+/** Set interrupt priority (0-7) */
+pub fn set_priority(interrupt: Interrupt, priority: u32) {
+    let irq_nr = interrupt.nr();
+    unsafe {
+        let plic = pac::PLIC::ptr();
+        (*plic).priority[irq_nr as usize].write(|w| w.bits(priority));
+    }
+}
+
+/** Register a handler for an interrupt, set its priority and enable it. */
+pub fn register(interrupt: Interrupt, priority: u32, handler: Handler) {
+    set_handler(interrupt, Some(handler));
+    set_priority(interrupt, priority);
+    irq_enable(interrupt, true);
+}
+
+/** Disable an interrupt and remove its handler. */
+pub fn unregister(interrupt: Interrupt) {
+    irq_enable(interrupt, false);
+    set_priority(interrupt, 0);
+    set_handler(interrupt, None);
+}
+
+/** Claim a pending PLIC interrupt, if any, and call its registered handler. The application
+ * calls this from its `MachineExternal` handler, or enables the `plic-handler` feature of this
+ * crate to get a `MachineExternal` that does only this. */
+pub fn dispatch() {
+    if mip::read().mext() {
+        unsafe {
+            let hartid = mhartid::read();
+            let plic = pac::PLIC::ptr();
+            let target = &(*plic).targets[hartid * 2];
+            let int_num = target.claim.read().bits();
+            // Interrupts without handler (such as the spurious UARTHS interrupt that tends to
+            // come in at startup) are ignored
+            if let Ok(int) = Interrupt::try_from(int_num as u8) {
+                if let Some(handler) = HANDLERS[int_num as usize] {
+                    handler(int);
+                }
+            }
+
+            // Perform IRQ complete
+            target.claim.write(|w| w.bits(int_num));
+        }
+    }
+}
+
+/** PLIC interrupts: dispatch to registered handlers */
+#[cfg(feature = "plic-handler")]
+#[allow(non_snake_case)]
+#[no_mangle]
+fn MachineExternal() {
+    dispatch();
+}
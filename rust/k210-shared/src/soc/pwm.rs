@@ -2,6 +2,7 @@
 use k210_hal::pac;
 use core::ops::Deref;
 use pac::{timer0,TIMER0,TIMER1,TIMER2};
+use pac::interrupt::Interrupt;
 
 use crate::soc::sysctl;
 
@@ -21,6 +22,9 @@ pub trait TimerExt: Deref<Target = timer0::RegisterBlock> + Sized {
     const CLK: sysctl::clock;
     #[doc(hidden)]
     const DIV: sysctl::threshold;
+    /// Interrupts of channels 1-2 and 3-4
+    #[doc(hidden)]
+    const INTERRUPTS: [Interrupt; 2];
 
     /// Constrains TIMER peripheral for PWM use
     /// A timer channel can either be used for PWM or as a normal timer (say, for interrupt
@@ -32,18 +36,21 @@ pub trait TimerExt: Deref<Target = timer0::RegisterBlock> + Sized {
 impl TimerExt for TIMER0 {
     const CLK: sysctl::clock = sysctl::clock::TIMER0;
     const DIV: sysctl::threshold = sysctl::threshold::TIMER0;
+    const INTERRUPTS: [Interrupt; 2] = [Interrupt::TIMER0A, Interrupt::TIMER0B];
 
     fn constrain_pwm(self) -> PWMImpl<Self> { PWMImpl::<Self> { timer: self } }
 }
 impl TimerExt for TIMER1 {
     const CLK: sysctl::clock = sysctl::clock::TIMER1;
     const DIV: sysctl::threshold = sysctl::threshold::TIMER1;
+    const INTERRUPTS: [Interrupt; 2] = [Interrupt::TIMER1A, Interrupt::TIMER1B];
 
     fn constrain_pwm(self) -> PWMImpl<Self> { PWMImpl::<Self> { timer: self } }
 }
 impl TimerExt for TIMER2 {
     const CLK: sysctl::clock = sysctl::clock::TIMER2;
     const DIV: sysctl::threshold = sysctl::threshold::TIMER2;
+    const INTERRUPTS: [Interrupt; 2] = [Interrupt::TIMER2A, Interrupt::TIMER2B];
 
     fn constrain_pwm(self) -> PWMImpl<Self> { PWMImpl::<Self> { timer: self } }
 }
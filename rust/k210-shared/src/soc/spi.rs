@@ -8,7 +8,16 @@ use pac::spi0::ctrlr0;
 use pac::spi0::spi_ctrlr0;
 
 use crate::soc::sysctl::{dma_channel, self};
-use crate::soc::dmac::{DMAC, address_increment, burst_length, transfer_width};
+use crate::soc::dmac::{DMAC, DMABuffer, Transfer, address_increment, burst_length, transfer_width};
+
+pub mod bus;
+pub mod hal;
+pub mod slave;
+
+/** Depth of the transmit and receive FIFOs, in frames */
+const FIFO_DEPTH: usize = 32;
+/** Largest number of frames that can be received in one transfer (CTRLR1 has 16 bits) */
+pub const MAX_RX_FRAMES: usize = 0x10000;
 
 /// Extension trait that constrains SPI peripherals
 pub trait SPIExt: Sized {
@@ -16,7 +25,9 @@ pub trait SPIExt: Sized {
     fn constrain(self) -> SPIImpl<Self>;
 }
 
-/// Trait for generalizing over SPI0 and SPI1 (SPI2 is slave-only and SPI3 is !!!special!!!)
+/// Trait for generalizing over SPI0, SPI1 and SPI3 (SPI2 is slave-only). SPI3 is a different
+/// configuration of the same controller, with the same registers but some ctrlr0 fields at
+/// other offsets.
 pub trait SPI01: Deref<Target = spi0::RegisterBlock> {
     #[doc(hidden)]
     const CLK: sysctl::clock;
@@ -26,6 +37,14 @@ pub trait SPI01: Deref<Target = spi0::RegisterBlock> {
     const DMA_RX: sysctl::dma_select;
     #[doc(hidden)]
     const DMA_TX: sysctl::dma_select;
+    #[doc(hidden)]
+    const WORK_MODE_OFFSET: u32 = 6;
+    #[doc(hidden)]
+    const TMOD_OFFSET: u32 = 8;
+    #[doc(hidden)]
+    const FRAME_FORMAT_OFFSET: u32 = 21;
+    #[doc(hidden)]
+    const DATA_LENGTH_OFFSET: u32 = 16;
 }
 
 impl SPI01 for SPI0 {
@@ -41,6 +60,37 @@ impl SPI01 for SPI1 {
     const DMA_TX: sysctl::dma_select = sysctl::dma_select::SSI1_TX_REQ;
 }
 
+/// SPI3, which is wired to the on-board flash. Use `SPIImpl::new(SPI3::new(p.SPI3))`.
+pub struct SPI3 {
+    _spi: pac::SPI3,
+}
+
+impl SPI3 {
+    pub fn new(spi: pac::SPI3) -> Self {
+        Self { _spi: spi }
+    }
+}
+
+impl Deref for SPI3 {
+    type Target = spi0::RegisterBlock;
+
+    fn deref(&self) -> &spi0::RegisterBlock {
+        // Register offsets are the same as for SPI0 and SPI1
+        unsafe { &*(pac::SPI3::ptr() as *const spi0::RegisterBlock) }
+    }
+}
+
+impl SPI01 for SPI3 {
+    const CLK: sysctl::clock = sysctl::clock::SPI3;
+    const DIV: sysctl::threshold = sysctl::threshold::SPI3;
+    const DMA_RX: sysctl::dma_select = sysctl::dma_select::SSI3_RX_REQ;
+    const DMA_TX: sysctl::dma_select = sysctl::dma_select::SSI3_TX_REQ;
+    const WORK_MODE_OFFSET: u32 = 8;
+    const TMOD_OFFSET: u32 = 10;
+    const FRAME_FORMAT_OFFSET: u32 = 22;
+    const DATA_LENGTH_OFFSET: u32 = 0;
+}
+
 impl<SPI: SPI01> SPIExt for SPI {
     fn constrain(self) -> SPIImpl<SPI> {
         SPIImpl::<SPI>::new(self)
@@ -49,6 +99,8 @@ impl<SPI: SPI01> SPIExt for SPI {
 
 pub struct SPIImpl<IF> {
     spi: IF,
+    /** Hardware SS line asserted for embedded-hal bus transfers */
+    bus_ss: u32,
 }
 
 /** Borrow work mode from pac */
@@ -78,13 +130,118 @@ pub trait SPI {
     fn recv_data_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, rx: &mut [u32]);
     fn send_data<X: Into<u32> + Copy>(&self, chip_select: u32, tx: &[X]);
     fn send_data_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, tx: &[u32]);
+    unsafe fn send_data_dma_start(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, tx: &[u32]);
+    fn send_data_dma_finish(&self);
+    fn send_data_dma_transfer<'d, B: DMABuffer<Word = u32>>(&self, dmac: &'d DMAC, channel_num: dma_channel, chip_select: u32, tx: B) -> Transfer<'d, B>;
     fn fill_data(&self, chip_select: u32, value: u32, tx_len: usize);
     fn fill_data_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, value: u32, tx_len: usize);
+    fn transfer_data<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, tx: &[X], rx: &mut [X]);
+    fn transfer_data_in_place<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, buf: &mut [X]);
+    fn recv_data_command<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, command: &[X], rx: &mut [X]);
+    fn recv_data_enhanced<X: TruncU32>(&self, chip_select: u32, instruction: u32, address: u32, rx: &mut [X]);
+    fn recv_data_enhanced_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, instruction: u32, address: u32, rx: &mut [u32]);
+    fn send_data_enhanced<X: Into<u32> + Copy>(&self, chip_select: u32, instruction: u32, address: u32, tx: &[X]);
+    fn send_data_enhanced_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, instruction: u32, address: u32, tx: &[u32]);
 }
 
 impl<IF: SPI01> SPIImpl<IF> {
     pub fn new(spi: IF) -> Self {
-        Self { spi }
+        Self { spi, bus_ss: 0 }
+    }
+
+    /** Set the hardware SS line asserted during embedded-hal bus transfers (default 0). The
+     * controller only clocks data while an SS line is selected; with chip select handled
+     * through a GPIO by the caller, this can be a line that is not routed to any pin. */
+    pub fn set_bus_ss(&mut self, ss: u32) {
+        self.bus_ss = ss;
+    }
+
+    /** Set the frame size in bits. Only allowed while the controller is disabled, which it is
+     * between transfers. */
+    fn set_data_bit_length(&self, data_bit_length: u8) {
+        let mask = 0x1f << IF::DATA_LENGTH_OFFSET;
+        let value = u32::from(data_bit_length - 1) << IF::DATA_LENGTH_OFFSET;
+        unsafe {
+            self.spi.ctrlr0.modify(|r, w| w.bits((r.bits() & !mask) | value));
+        }
+    }
+
+    /** Set the transfer mode. Only allowed while the controller is disabled. */
+    fn set_tmod(&self, tmod: tmod) {
+        let mask = 0x3 << IF::TMOD_OFFSET;
+        let value = (tmod as u32) << IF::TMOD_OFFSET;
+        unsafe {
+            self.spi.ctrlr0.modify(|r, w| w.bits((r.bits() & !mask) | value));
+        }
+    }
+
+    /** Whether the configured frame format is dual, quad or octal */
+    fn is_enhanced(&self) -> bool {
+        let format = (self.spi.ctrlr0.read().bits() >> IF::FRAME_FORMAT_OFFSET) & 0x3;
+        format != frame_format::STANDARD as u32
+    }
+
+    /** Wait for the transmit FIFO to drain, then deselect and disable */
+    fn finish_transfer(&self) {
+        unsafe {
+            while (self.spi.sr.read().bits() & 0x05) != 0x04 {
+                // IDLE
+            }
+            self.spi.ser.write(|w| w.bits(0x00));
+            self.spi.ssienr.write(|w| w.bits(0x00));
+        }
+    }
+
+    /** Queue the instruction and address phases of an enhanced-mode transfer, as far as
+     * configured. */
+    fn write_instruction_address(&self, instruction: u32, address: u32) {
+        let spi_ctrlr0 = self.spi.spi_ctrlr0.read();
+        unsafe {
+            if spi_ctrlr0.inst_length().bits() != 0 {
+                self.spi.dr[0].write(|w| w.bits(instruction));
+            }
+            if spi_ctrlr0.addr_length().bits() != 0 {
+                self.spi.dr[0].write(|w| w.bits(address));
+            }
+        }
+    }
+
+    /** Full-duplex transfer of `len` frames: frame `i` sent is `tx(i)`, and every received
+     * frame is passed to `rx` with its index. */
+    fn transfer_frames<T, R>(&self, chip_select: u32, len: usize, mut tx: T, mut rx: R)
+        where T: FnMut(usize) -> u32, R: FnMut(usize, u32) {
+        if len == 0 {
+            return;
+        }
+        unsafe {
+            self.set_tmod(tmod::TRANS_RECV);
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+            self.spi.ssienr.write(|w| w.bits(0x01));
+
+            let mut sent = 0;
+            let mut received = 0;
+            while received < len {
+                // never have more frames in flight than the receive FIFO can hold
+                let fifo_free = FIFO_DEPTH - self.spi.txflr.read().bits() as usize;
+                let in_flight = sent - received;
+                let n = cmp::min(cmp::min(fifo_free, FIFO_DEPTH - in_flight), len - sent);
+                for _ in 0..n {
+                    self.spi.dr[0].write(|w| w.bits(tx(sent)));
+                    sent += 1;
+                }
+                let fifo_len = self.spi.rxflr.read().bits() as usize;
+                for _ in 0..fifo_len {
+                    rx(received, self.spi.dr[0].read().bits());
+                    received += 1;
+                }
+            }
+
+            while (self.spi.sr.read().bits() & 0x05) != 0x04 {
+                // IDLE
+            }
+            self.spi.ser.write(|w| w.bits(0x00));
+            self.spi.ssienr.write(|w| w.bits(0x00));
+        }
     }
 }
 
@@ -130,15 +287,12 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
             self.spi.dmardlr.write(|w| w.bits(0x00));
             self.spi.ser.write(|w| w.bits(0x00));
             self.spi.ssienr.write(|w| w.bits(0x00));
+            // fields are written by offset, as these differ for SPI3
             self.spi.ctrlr0.write(|w| {
-                w.work_mode()
-                    .variant(work_mode)
-                    .tmod()
-                    .variant(tmod)
-                    .frame_format()
-                    .variant(frame_format)
-                    .data_length()
-                    .bits(data_bit_length - 1)
+                w.bits(((work_mode as u32) << IF::WORK_MODE_OFFSET)
+                    | ((tmod as u32) << IF::TMOD_OFFSET)
+                    | ((frame_format as u32) << IF::FRAME_FORMAT_OFFSET)
+                    | (u32::from(data_bit_length - 1) << IF::DATA_LENGTH_OFFSET))
             });
             self.spi.spi_ctrlr0.write(|w| {
                 w.aitm()
@@ -158,7 +312,7 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
     fn set_clk_rate(&self, spi_clk: u32) -> u32 {
         sysctl::clock_enable(IF::CLK);
         sysctl::clock_set_threshold(IF::DIV, 0);
-        let clock_freq: u32 = sysctl::clock_get_freq(sysctl::clock::SPI0);
+        let clock_freq: u32 = sysctl::clock_get_freq(IF::CLK);
         let spi_baudr = clock_freq / spi_clk;
         // Clamp baudrate divider to valid range
         let spi_baudr = cmp::min(cmp::max(spi_baudr, 2), 65534);
@@ -248,16 +402,31 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
     /// buffers every time as the SDK does because this is highly undesirable!
     fn send_data_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, tx: &[u32]) {
         unsafe {
-            self.spi.dmacr.write(|w| w.bits(0x2));    /*enable dma transmit*/
-            self.spi.ssienr.write(|w| w.bits(0x01));
+            self.send_data_dma_start(dmac, channel_num, chip_select, tx);
+        }
+        dmac.wait_done(channel_num);
+        self.send_data_dma_finish();
+    }
 
-            sysctl::dma_select(channel_num, IF::DMA_TX);
-            dmac.set_single_mode(channel_num, tx.as_ptr() as u64, self.spi.dr.as_ptr() as u64,
-                                 address_increment::INCREMENT, address_increment::NOCHANGE,
-                                 burst_length::LENGTH_4, transfer_width::WIDTH_32, tx.len() as u32);
-            self.spi.ser.write(|w| w.bits(1 << chip_select));
-            dmac.wait_done(channel_num);
+    /// Start sending 32-bit data using DMA, without waiting for it to complete.
+    /// This is unsafe because the caller must make sure that `tx` stays valid and unmodified
+    /// until the DMA transfer is done (see `DMAC::is_done`), after which `send_data_dma_finish`
+    /// must be called. No other SPI operation can be started in the meantime.
+    unsafe fn send_data_dma_start(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, tx: &[u32]) {
+        self.spi.dmacr.write(|w| w.bits(0x2));    /*enable dma transmit*/
+        self.spi.ssienr.write(|w| w.bits(0x01));
+
+        sysctl::dma_select(channel_num, IF::DMA_TX);
+        dmac.set_single_mode(channel_num, tx.as_ptr() as u64, self.spi.dr.as_ptr() as u64,
+                             address_increment::INCREMENT, address_increment::NOCHANGE,
+                             burst_length::LENGTH_4, transfer_width::WIDTH_32, tx.len() as u32);
+        self.spi.ser.write(|w| w.bits(1 << chip_select));
+    }
 
+    /// Finish a DMA send started with `send_data_dma_start`, after the DMA transfer is done:
+    /// wait for the transmit FIFO to drain and deselect the device.
+    fn send_data_dma_finish(&self) {
+        unsafe {
             while (self.spi.sr.read().bits() & 0x05) != 0x04 {
                 // IDLE
             }
@@ -266,6 +435,17 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
         }
     }
 
+    /// Start sending 32-bit data using DMA, returning a handle that owns the buffer until the
+    /// transfer is done. After `Transfer::wait`, `send_data_dma_finish` must be called. No other
+    /// SPI operation can be started in the meantime.
+    fn send_data_dma_transfer<'d, B: DMABuffer<Word = u32>>(&self, dmac: &'d DMAC, channel_num: dma_channel, chip_select: u32, tx: B) -> Transfer<'d, B> {
+        unsafe {
+            let (ptr, len) = tx.dma_region();
+            self.send_data_dma_start(dmac, channel_num, chip_select, core::slice::from_raw_parts(ptr, len));
+            Transfer::new(dmac, channel_num, tx)
+        }
+    }
+
     /// Send repeated data
     fn fill_data(&self, chip_select: u32, value: u32, mut tx_len: usize) {
         unsafe {
@@ -289,6 +469,24 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
         }
     }
 
+    /// Send `tx` while receiving into `rx` (full duplex). If the lengths differ, the shorter
+    /// one is padded with all-ones frames or the extra frames are discarded.
+    fn transfer_data<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, tx: &[X], rx: &mut [X]) {
+        let len = cmp::max(tx.len(), rx.len());
+        self.transfer_frames(chip_select, len,
+            |i| tx.get(i).map_or(0xffffffff, |&x| x.into()),
+            |i, val| if let Some(x) = rx.get_mut(i) { *x = X::trunc(val); });
+    }
+
+    /// Send the contents of `buf` (full duplex), replacing them with the received data
+    fn transfer_data_in_place<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, buf: &mut [X]) {
+        let len = buf.len();
+        let buf = core::cell::Cell::from_mut(buf).as_slice_of_cells();
+        self.transfer_frames(chip_select, len,
+            |i| buf[i].get().into(),
+            |i, val| buf[i].set(X::trunc(val)));
+    }
+
     /// Send repeated data (using DMA)
     fn fill_data_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, value: u32, tx_len: usize) {
         unsafe {
@@ -311,5 +509,141 @@ impl<IF: SPI01> SPI for SPIImpl<IF> {
             self.spi.ssienr.write(|w| w.bits(0x00));
         }
     }
+
+    /// Send `command` then receive `rx` (standard frame format, tmod is set to EEROM). The
+    /// command must fit in the transmit FIFO. This is how serial flash and EEPROM reads work:
+    /// the device only starts answering after the command and address.
+    fn recv_data_command<X: Into<u32> + TruncU32 + Copy>(&self, chip_select: u32, command: &[X], rx: &mut [X]) {
+        assert!(!command.is_empty() && command.len() <= FIFO_DEPTH);
+        assert!(rx.len() <= MAX_RX_FRAMES);
+        if rx.is_empty() {
+            self.set_tmod(tmod::TRANS);
+            self.send_data(chip_select, command);
+            return;
+        }
+        self.set_tmod(tmod::EEROM);
+        unsafe {
+            self.spi.ctrlr1.write(|w| w.bits((rx.len() - 1).try_into().unwrap()));
+            self.spi.ssienr.write(|w| w.bits(0x01));
+            // the whole command is queued before the transfer starts: receiving begins as soon
+            // as the transmit FIFO runs empty
+            for &val in command {
+                self.spi.dr[0].write(|w| w.bits(val.into()));
+            }
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+
+            let mut fifo_len = 0;
+            for val in rx.iter_mut() {
+                while fifo_len == 0 {
+                    fifo_len = self.spi.rxflr.read().bits();
+                }
+                *val = X::trunc(self.spi.dr[0].read().bits());
+                fifo_len -= 1;
+            }
+
+            self.spi.ser.write(|w| w.bits(0x00));
+            self.spi.ssienr.write(|w| w.bits(0x00));
+        }
+    }
+
+    /// Receive data in dual, quad or octal mode (tmod is set to RECV). The instruction and
+    /// address phases are sent first, as far as configured with `configure`; after the wait
+    /// cycles, `rx.len()` frames are received on all data lines. At most `MAX_RX_FRAMES` frames
+    /// can be received at once.
+    fn recv_data_enhanced<X: TruncU32>(&self, chip_select: u32, instruction: u32, address: u32, rx: &mut [X]) {
+        debug_assert!(self.is_enhanced());
+        assert!(rx.len() <= MAX_RX_FRAMES);
+        if rx.is_empty() {
+            return;
+        }
+        self.set_tmod(tmod::RECV);
+        unsafe {
+            self.spi.ctrlr1.write(|w| w.bits((rx.len() - 1).try_into().unwrap()));
+            self.spi.ssienr.write(|w| w.bits(0x01));
+            self.write_instruction_address(instruction, address);
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+
+            let mut fifo_len = 0;
+            for val in rx.iter_mut() {
+                while fifo_len == 0 {
+                    fifo_len = self.spi.rxflr.read().bits();
+                }
+                *val = X::trunc(self.spi.dr[0].read().bits());
+                fifo_len -= 1;
+            }
+
+            self.spi.ser.write(|w| w.bits(0x00));
+            self.spi.ssienr.write(|w| w.bits(0x00));
+        }
+    }
+
+    /// Receive 32-bit frames in dual, quad or octal mode using DMA, see `recv_data_enhanced`.
+    fn recv_data_enhanced_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, instruction: u32, address: u32, rx: &mut [u32]) {
+        debug_assert!(self.is_enhanced());
+        assert!(rx.len() <= MAX_RX_FRAMES);
+        if rx.is_empty() {
+            return;
+        }
+        self.set_tmod(tmod::RECV);
+        unsafe {
+            self.spi.ctrlr1.write(|w| w.bits((rx.len() - 1).try_into().unwrap()));
+            self.spi.ssienr.write(|w| w.bits(0x01));
+            self.spi.dmacr.write(|w| w.bits(0x3));    /*enable dma receive */
+
+            sysctl::dma_select(channel_num, IF::DMA_RX);
+            dmac.set_single_mode(channel_num, self.spi.dr.as_ptr() as u64, rx.as_ptr() as u64,
+                                 address_increment::NOCHANGE, address_increment::INCREMENT,
+                                 burst_length::LENGTH_1, transfer_width::WIDTH_32, rx.len() as u32);
+            self.write_instruction_address(instruction, address);
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+            dmac.wait_done(channel_num);
+
+            self.spi.ser.write(|w| w.bits(0x00));
+            self.spi.ssienr.write(|w| w.bits(0x00));
+        }
+    }
+
+    /// Send data in dual, quad or octal mode (tmod is set to TRANS), after the instruction and
+    /// address phases as far as configured with `configure`.
+    fn send_data_enhanced<X: Into<u32> + Copy>(&self, chip_select: u32, instruction: u32, address: u32, tx: &[X]) {
+        debug_assert!(self.is_enhanced());
+        self.set_tmod(tmod::TRANS);
+        unsafe {
+            self.spi.ssienr.write(|w| w.bits(0x01));
+            // queue instruction and address before selecting, so that they go out first
+            self.write_instruction_address(instruction, address);
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+
+            let mut fifo_len = 0;
+            for &val in tx {
+                while fifo_len == 0 {
+                    fifo_len = 32 - self.spi.txflr.read().bits();
+                }
+                self.spi.dr[0].write(|f| f.bits(val.into()));
+                fifo_len -= 1;
+            }
+        }
+        self.finish_transfer();
+    }
+
+    /// Send 32-bit frames in dual, quad or octal mode using DMA, see `send_data_enhanced`.
+    fn send_data_enhanced_dma(&self, dmac: &DMAC, channel_num: dma_channel, chip_select: u32, instruction: u32, address: u32, tx: &[u32]) {
+        debug_assert!(self.is_enhanced());
+        self.set_tmod(tmod::TRANS);
+        unsafe {
+            self.spi.ssienr.write(|w| w.bits(0x01));
+            // instruction and address have to be in the FIFO before the DMA starts filling it
+            self.write_instruction_address(instruction, address);
+            self.spi.dmacr.write(|w| w.bits(0x2));    /*enable dma transmit*/
+
+            sysctl::dma_select(channel_num, IF::DMA_TX);
+            dmac.set_single_mode(channel_num, tx.as_ptr() as u64, self.spi.dr.as_ptr() as u64,
+                                 address_increment::INCREMENT, address_increment::NOCHANGE,
+                                 burst_length::LENGTH_4, transfer_width::WIDTH_32, tx.len() as u32);
+            self.spi.ser.write(|w| w.bits(1 << chip_select));
+            dmac.wait_done(channel_num);
+        }
+        self.finish_transfer();
+    }
 }
 
@@ -0,0 +1,186 @@
+//! Sharing SPI0/SPI1 between devices, each with its own chip select, mode and clock rate
+//!
+//! Every device handle implements the embedded-hal device traits (0.2 `Transfer`/`Write`,
+//! 1.0 `SpiDevice`), selecting the device and reconfiguring the controller as needed for every
+//! transaction. The bus is not protected against use from interrupt handlers.
+use core::cell::Cell;
+use core::convert::Infallible;
+use embedded_hal::blocking::spi as spi02;
+use embedded_hal_1::spi as spi1;
+use spi1::Operation;
+
+use crate::soc::gpio;
+use crate::soc::gpiohs;
+use crate::soc::sleep::usleep;
+use super::{aitm, frame_format, tmod, work_mode, SPIImpl, SPI, SPI01};
+
+/** How a device is selected */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChipSelect {
+    /** Hardware SS line, routed to the device through the FPIOA. The controller only keeps it
+     * asserted during a single transfer. */
+    Hardware(u32),
+    /** GPIOHS pin, driven to `active` for the whole transaction. `ss` is the hardware SS line
+     * the controller asserts during transfers, which need not be routed to a pin. */
+    Gpiohs { ss: u32, pin: u8, active: bool },
+}
+
+impl ChipSelect {
+    fn ss(&self) -> u32 {
+        match *self {
+            ChipSelect::Hardware(ss) => ss,
+            ChipSelect::Gpiohs { ss, .. } => ss,
+        }
+    }
+
+    fn set(&self, selected: bool) {
+        if let ChipSelect::Gpiohs { pin, active, .. } = *self {
+            gpiohs::set_pin(pin, selected == active);
+        }
+    }
+}
+
+/** SPI settings of a device */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    /** Clock polarity and phase */
+    pub work_mode: work_mode,
+    /** Clock rate in Hz; the closest rate the divider allows at or above it is used */
+    pub clk_rate: u32,
+}
+
+impl DeviceConfig {
+    pub const fn new(work_mode: work_mode, clk_rate: u32) -> Self {
+        Self { work_mode, clk_rate }
+    }
+}
+
+/** Errors of a device transaction */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusError {
+    /** Transaction has more than one transfer, which a hardware SS line cannot keep the
+     * device selected for; use a GPIOHS chip select */
+    ChipSelect,
+}
+
+impl spi1::Error for BusError {
+    fn kind(&self) -> spi1::ErrorKind {
+        spi1::ErrorKind::ChipSelectFault
+    }
+}
+
+/** SPI controller shared between devices */
+pub struct SharedBus<IF> {
+    spi: SPIImpl<IF>,
+    /** Settings the controller is configured with, if by this bus */
+    current: Cell<Option<DeviceConfig>>,
+}
+
+impl<IF: SPI01> SharedBus<IF> {
+    pub fn new(spi: SPIImpl<IF>) -> Self {
+        Self { spi, current: Cell::new(None) }
+    }
+
+    /** Add a device. A GPIOHS chip select pin is made an output and deselected. */
+    pub fn device(&self, cs: ChipSelect, config: DeviceConfig) -> BusDevice<'_, IF> {
+        if let ChipSelect::Gpiohs { pin, .. } = cs {
+            gpiohs::set_direction(pin, gpio::direction::OUTPUT);
+        }
+        cs.set(false);
+        BusDevice { bus: self, cs, config }
+    }
+
+    /** Give back the controller */
+    pub fn free(self) -> SPIImpl<IF> {
+        self.spi
+    }
+
+    /** Configure the controller for a device, unless it already is */
+    fn configure(&self, config: DeviceConfig) {
+        if self.current.get() != Some(config) {
+            self.spi.configure(
+                config.work_mode,
+                frame_format::STANDARD,
+                8, /* data bits */
+                0, /* endian */
+                0, /* instruction length */
+                0, /* address length */
+                0, /* wait cycles between addr and data */
+                aitm::STANDARD,
+                tmod::TRANS_RECV,
+            );
+            self.spi.set_clk_rate(config.clk_rate);
+            self.current.set(Some(config));
+        }
+    }
+}
+
+/** Device on a shared bus */
+pub struct BusDevice<'a, IF> {
+    bus: &'a SharedBus<IF>,
+    cs: ChipSelect,
+    config: DeviceConfig,
+}
+
+impl<'a, IF: SPI01> BusDevice<'a, IF> {
+    /** Run `f` with the device selected, passing the controller and the hardware SS line */
+    fn selected<R, F: FnOnce(&SPIImpl<IF>, u32) -> R>(&self, f: F) -> R {
+        self.bus.configure(self.config);
+        self.cs.set(true);
+        let result = f(&self.bus.spi, self.cs.ss());
+        self.cs.set(false);
+        result
+    }
+}
+
+impl<'a, IF: SPI01> spi02::Transfer<u8> for BusDevice<'a, IF> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        self.selected(|spi, ss| {
+            spi.set_data_bit_length(8);
+            spi.transfer_data_in_place(ss, words);
+        });
+        Ok(words)
+    }
+}
+
+impl<'a, IF: SPI01> spi02::Write<u8> for BusDevice<'a, IF> {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.selected(|spi, ss| {
+            spi.set_data_bit_length(8);
+            spi.transfer_data(ss, words, &mut []);
+        });
+        Ok(())
+    }
+}
+
+impl<'a, IF: SPI01> spi1::ErrorType for BusDevice<'a, IF> {
+    type Error = BusError;
+}
+
+impl<'a, IF: SPI01> spi1::SpiDevice<u8> for BusDevice<'a, IF> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), BusError> {
+        let transfers = operations.iter()
+            .filter(|op| !matches!(op, Operation::DelayNs(_)))
+            .count();
+        if transfers > 1 && matches!(self.cs, ChipSelect::Hardware(_)) {
+            return Err(BusError::ChipSelect);
+        }
+        self.selected(|spi, ss| {
+            spi.set_data_bit_length(8);
+            for op in operations.iter_mut() {
+                match op {
+                    Operation::Read(words) => spi.transfer_data(ss, &[], words),
+                    Operation::Write(words) => spi.transfer_data(ss, words, &mut []),
+                    Operation::Transfer(read, write) => spi.transfer_data(ss, write, read),
+                    Operation::TransferInPlace(words) => spi.transfer_data_in_place(ss, words),
+                    Operation::DelayNs(ns) => usleep((*ns as usize).div_ceil(1000)),
+                }
+            }
+        });
+        Ok(())
+    }
+}
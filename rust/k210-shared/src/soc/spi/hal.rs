@@ -0,0 +1,74 @@
+//! embedded-hal SPI traits for SPI0/SPI1
+//!
+//! These implement the bus-level traits: chip select is up to the caller (see `bus` for
+//! devices with their own chip select), the controller asserts the hardware SS line set with
+//! `SPIImpl::set_bus_ss` during transfers. The mode and clock rate are set with
+//! `SPI::configure` and `SPI::set_clk_rate`; transfers are always full duplex, with the frame
+//! size set from the word type.
+use core::convert::Infallible;
+use embedded_hal::blocking::spi as spi02;
+use embedded_hal_1::spi as spi1;
+
+use super::{SPIImpl, SPI, SPI01};
+
+macro_rules! impl_spi {
+    ($word:ty, $bits:expr) => {
+        impl<IF: SPI01> spi02::Transfer<$word> for SPIImpl<IF> {
+            type Error = Infallible;
+
+            fn transfer<'w>(&mut self, words: &'w mut [$word]) -> Result<&'w [$word], Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data_in_place(self.bus_ss, words);
+                Ok(words)
+            }
+        }
+
+        impl<IF: SPI01> spi02::Write<$word> for SPIImpl<IF> {
+            type Error = Infallible;
+
+            fn write(&mut self, words: &[$word]) -> Result<(), Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data(self.bus_ss, words, &mut []);
+                Ok(())
+            }
+        }
+
+        impl<IF: SPI01> spi1::SpiBus<$word> for SPIImpl<IF> {
+            fn read(&mut self, words: &mut [$word]) -> Result<(), Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data(self.bus_ss, &[], words);
+                Ok(())
+            }
+
+            fn write(&mut self, words: &[$word]) -> Result<(), Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data(self.bus_ss, words, &mut []);
+                Ok(())
+            }
+
+            fn transfer(&mut self, read: &mut [$word], write: &[$word]) -> Result<(), Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data(self.bus_ss, write, read);
+                Ok(())
+            }
+
+            fn transfer_in_place(&mut self, words: &mut [$word]) -> Result<(), Infallible> {
+                self.set_data_bit_length($bits);
+                self.transfer_data_in_place(self.bus_ss, words);
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), Infallible> {
+                // transfers only return when the controller is idle
+                Ok(())
+            }
+        }
+    };
+}
+
+impl<IF: SPI01> spi1::ErrorType for SPIImpl<IF> {
+    type Error = Infallible;
+}
+
+impl_spi!(u8, 8);
+impl_spi!(u16, 16);
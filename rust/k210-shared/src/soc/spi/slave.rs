@@ -0,0 +1,379 @@
+//! SPI slave (SPI2) with a register-map protocol
+//!
+//! The slave exposes a memory area, the register map, that the host reads and writes. The
+//! slave controller has a single data line (D0), so transfers are half duplex. Every command
+//! starts with an 8-byte header sent by the host:
+//!
+//! | byte | contents                                                |
+//! |------|---------------------------------------------------------|
+//! | 0    | command: `CMD_READ` or `CMD_WRITE`                      |
+//! | 1..4 | start address in the register map, big-endian           |
+//! | 4..7 | number of data bytes, big-endian, at least 1            |
+//! | 7    | check byte, chosen so that all eight bytes XOR to 0xFF  |
+//!
+//! The slave raises the ready pin (a GPIOHS output) once the data phase is set up. The host
+//! then writes or reads the data bytes, and waits for the ready pin to go low again before
+//! sending the next header; by then the completion callback has run. Headers that fail the
+//! check or address outside the register map are dropped, and the receive FIFO is flushed; the
+//! host notices by the ready pin not going high, and should retry after a pause.
+//!
+//! Data phases are done by DMA directly from and to the register map. The controller clock must
+//! be at least six times the SPI clock.
+use core::convert::TryInto;
+use k210_hal::pac;
+use pac::interrupt::Interrupt;
+use pac::spi0;
+
+use crate::soc::dmac::{address_increment, burst_length, transfer_width, DMAError, DMAC};
+use crate::soc::gpio;
+use crate::soc::gpiohs;
+use crate::soc::plic;
+use crate::soc::sysctl::{self, dma_channel};
+use super::work_mode;
+
+/** Host reads from the register map */
+pub const CMD_READ: u8 = 0x01;
+/** Host writes to the register map */
+pub const CMD_WRITE: u8 = 0x02;
+/** Length of a command header in bytes */
+pub const HEADER_LEN: usize = 8;
+
+/** Register bits */
+const SR_BUSY: u32 = 0x01;
+const IMR_TXE: u32 = 0x01;
+const IMR_RXF: u32 = 0x10;
+const DMACR_RDMAE: u32 = 0x01;
+const DMACR_TDMAE: u32 = 0x02;
+const CTRLR0_SLV_OE: u32 = 1 << 10;
+const TMOD_TRANS: u32 = 1;
+const TMOD_RECV: u32 = 2;
+
+/** Direction of a command */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /** Host reads from the register map */
+    Read,
+    /** Host writes to the register map */
+    Write,
+}
+
+/** A command received from the host */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub direction: Direction,
+    /** Start address in the register map */
+    pub address: usize,
+    /** Number of data bytes */
+    pub len: usize,
+}
+
+/** Errors reported to the completion callback */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SlaveError {
+    /** Header check byte mismatch or unknown command; the host is likely out of sync */
+    Header,
+    /** Command addresses bytes outside the register map, or has no data */
+    Range(Command),
+    /** DMA transfer of the data phase failed */
+    DMA(Command, DMAError),
+}
+
+/** Called from the interrupt handler after every command, or failed header */
+pub type Callback = fn(Result<Command, SlaveError>);
+
+impl Command {
+    /** Decode and check a command header against a register map of `map_len` bytes */
+    pub fn decode(header: &[u8; HEADER_LEN], map_len: usize) -> Result<Command, SlaveError> {
+        if header.iter().fold(0, |acc, b| acc ^ b) != 0xFF {
+            return Err(SlaveError::Header);
+        }
+        let direction = match header[0] {
+            CMD_READ => Direction::Read,
+            CMD_WRITE => Direction::Write,
+            _ => return Err(SlaveError::Header),
+        };
+        let command = Command {
+            direction,
+            address: u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize,
+            len: u32::from_be_bytes([0, header[4], header[5], header[6]]) as usize,
+        };
+        if command.len == 0 || command.address + command.len > map_len {
+            return Err(SlaveError::Range(command));
+        }
+        Ok(command)
+    }
+
+    /** Encode a command header, as the host sends it */
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let a = (self.address as u32).to_be_bytes();
+        let l = (self.len as u32).to_be_bytes();
+        let mut header = [
+            match self.direction {
+                Direction::Read => CMD_READ,
+                Direction::Write => CMD_WRITE,
+            },
+            a[1], a[2], a[3], l[1], l[2], l[3], 0,
+        ];
+        header[7] = header[..7].iter().fold(0xFF, |acc, b| acc ^ b);
+        header
+    }
+}
+
+/** What the slave is doing */
+#[derive(Copy, Clone)]
+enum Phase {
+    /** Waiting for a command header */
+    Header,
+    /** Data phase by DMA */
+    Data(Command),
+    /** DMA is done, the host is still reading the last bytes out of the FIFO */
+    Drain(Command),
+}
+
+struct State {
+    dmac: &'static DMAC,
+    channel: dma_channel,
+    registers: *mut u8,
+    len: usize,
+    ready_pin: u8,
+    work_mode: work_mode,
+    callback: Option<Callback>,
+    phase: Phase,
+}
+
+/** State of the slave, shared with the interrupt handlers */
+static mut STATE: Option<State> = None;
+
+fn regs() -> &'static spi0::RegisterBlock {
+    // Register offsets are the same as for SPI0 and SPI1
+    unsafe { &*(pac::SPI2::ptr() as *const spi0::RegisterBlock) }
+}
+
+/** Disable the controller (flushing the FIFOs) and set it up for receiving or sending */
+fn configure(work_mode: work_mode, send: bool) {
+    let spi = regs();
+    let (tmod, slv_oe) = if send { (TMOD_TRANS, 0) } else { (TMOD_RECV, CTRLR0_SLV_OE) };
+    unsafe {
+        spi.ssienr.write(|w| w.bits(0x00));
+        spi.imr.write(|w| w.bits(0x00));
+        spi.dmacr.write(|w| w.bits(0x00));
+        spi.ctrlr0.write(|w| {
+            w.bits(((work_mode as u32) << 6) | (tmod << 8) | slv_oe | ((8 - 1) << 16))
+        });
+        spi.ssienr.write(|w| w.bits(0x01));
+    }
+}
+
+/** Wait for the next command header */
+fn expect_header(state: &mut State) {
+    let spi = regs();
+    configure(state.work_mode, false);
+    unsafe {
+        spi.rxftlr.write(|w| w.bits((HEADER_LEN - 1) as u32));
+        spi.imr.write(|w| w.bits(IMR_RXF));
+    }
+    state.phase = Phase::Header;
+}
+
+/** Start the data phase of a command */
+fn start_data(state: &mut State, command: Command) {
+    let spi = regs();
+    let dr = spi.dr.as_ptr() as u64;
+    let mem = state.registers as u64 + command.address as u64;
+    let block_size: u32 = command.len.try_into().unwrap();
+    state.phase = Phase::Data(command);
+    match command.direction {
+        Direction::Write => unsafe {
+            spi.dmardlr.write(|w| w.bits(0x00));
+            spi.dmacr.write(|w| w.bits(DMACR_RDMAE));
+            sysctl::dma_select(state.channel, sysctl::dma_select::SSI2_RX_REQ);
+            state.dmac.set_single_mode(state.channel, dr, mem,
+                                       address_increment::NOCHANGE, address_increment::INCREMENT,
+                                       burst_length::LENGTH_1, transfer_width::WIDTH_8, block_size);
+        },
+        Direction::Read => unsafe {
+            configure(state.work_mode, true);
+            spi.dmatdlr.write(|w| w.bits(0x04));
+            spi.dmacr.write(|w| w.bits(DMACR_TDMAE));
+            sysctl::dma_select(state.channel, sysctl::dma_select::SSI2_TX_REQ);
+            state.dmac.set_single_mode(state.channel, mem, dr,
+                                       address_increment::INCREMENT, address_increment::NOCHANGE,
+                                       burst_length::LENGTH_4, transfer_width::WIDTH_8, block_size);
+            // the first byte must be ready when the host starts clocking
+            while spi.txflr.read().bits() == 0 && !state.dmac.is_done(state.channel) {
+                // FILL
+            }
+        },
+    }
+    gpiohs::set_pin(state.ready_pin, true);
+}
+
+/** Finish a command: report it, and let the host know the next header can come */
+fn finish(state: &mut State, result: Result<Command, SlaveError>) {
+    if let Some(callback) = state.callback {
+        callback(result);
+    }
+    expect_header(state);
+    gpiohs::set_pin(state.ready_pin, false);
+}
+
+/** SPI slave interrupt: a header arrived, or the transmit FIFO ran empty after a read */
+fn interrupt_spi_slave(_interrupt: Interrupt) {
+    let spi = regs();
+    let state = match unsafe { (*core::ptr::addr_of_mut!(STATE)).as_mut() } {
+        Some(state) => state,
+        None => return,
+    };
+    match state.phase {
+        Phase::Header => {
+            let mut header = [0u8; HEADER_LEN];
+            for b in header.iter_mut() {
+                *b = spi.dr[0].read().bits() as u8;
+            }
+            match Command::decode(&header, state.len) {
+                Ok(command) => {
+                    unsafe { spi.imr.write(|w| w.bits(0x00)); }
+                    start_data(state, command);
+                }
+                Err(e) => {
+                    // flush whatever else the host sent
+                    finish(state, Err(e));
+                }
+            }
+        }
+        Phase::Drain(command) => {
+            while (spi.sr.read().bits() & SR_BUSY) != 0 {
+                // last byte is shifting out
+            }
+            finish(state, Ok(command));
+        }
+        Phase::Data(_) => {}
+    }
+}
+
+/** DMA completion of a data phase */
+fn dma_done(_channel: dma_channel, result: Result<(), DMAError>) {
+    let state = match unsafe { (*core::ptr::addr_of_mut!(STATE)).as_mut() } {
+        Some(state) => state,
+        None => return,
+    };
+    if let Phase::Data(command) = state.phase {
+        match (result, command.direction) {
+            (Err(e), _) => finish(state, Err(SlaveError::DMA(command, e))),
+            (Ok(()), Direction::Write) => finish(state, Ok(command)),
+            (Ok(()), Direction::Read) => {
+                // done when the host has read everything out of the FIFO
+                state.phase = Phase::Drain(command);
+                unsafe {
+                    regs().txftlr.write(|w| w.bits(0x00));
+                    regs().imr.write(|w| w.bits(IMR_TXE));
+                }
+            }
+        }
+    }
+}
+
+/** SPI slave controller */
+pub struct SPISlave {
+    spi: pac::SPI2,
+}
+
+impl SPISlave {
+    /** Set up the slave with `registers` as register map. `ready_pin` is the GPIOHS pin used
+     * as ready signal to the host. The DMA channel is used for all data phases, with its
+     * completion interrupt. */
+    pub fn new(spi: pac::SPI2, dmac: &'static DMAC, channel: dma_channel, ready_pin: u8,
+               registers: &'static mut [u8]) -> Self {
+        gpiohs::set_direction(ready_pin, gpio::direction::OUTPUT);
+        gpiohs::set_pin(ready_pin, false);
+        riscv::interrupt::free(|_| unsafe {
+            STATE = Some(State {
+                dmac,
+                channel,
+                registers: registers.as_mut_ptr(),
+                len: registers.len(),
+                ready_pin,
+                work_mode: work_mode::MODE0,
+                callback: None,
+                phase: Phase::Header,
+            });
+        });
+        Self { spi }
+    }
+
+    /** Start answering the host, with the clock polarity and phase of `work_mode`. The callback
+     * is called from interrupt context after every command. This requires interrupts to have
+     * been enabled with `plic::init`. */
+    pub fn start(&self, work_mode: work_mode, callback: Option<Callback>) {
+        sysctl::reset(sysctl::reset::SPI2);
+        sysctl::clock_enable(sysctl::clock::SPI2);
+        sysctl::clock_set_threshold(sysctl::threshold::SPI2, 9);
+        riscv::interrupt::free(|_| unsafe {
+            if let Some(state) = (*core::ptr::addr_of_mut!(STATE)).as_mut() {
+                state.work_mode = work_mode;
+                state.callback = callback;
+                state.dmac.set_callback(state.channel, Some(dma_done));
+                state.dmac.completion_irq_enable(state.channel, true);
+                expect_header(state);
+            }
+        });
+        plic::register(Interrupt::SPI_SLAVE, 1, interrupt_spi_slave);
+    }
+
+    /** Stop answering the host, and give back the controller and register map */
+    pub fn free(self) -> (pac::SPI2, &'static mut [u8]) {
+        plic::unregister(Interrupt::SPI_SLAVE);
+        let state = riscv::interrupt::free(|_| unsafe { (*core::ptr::addr_of_mut!(STATE)).take() })
+            .unwrap();
+        unsafe {
+            regs().imr.write(|w| w.bits(0x00));
+            regs().ssienr.write(|w| w.bits(0x00));
+        }
+        state.dmac.completion_irq_enable(state.channel, false);
+        state.dmac.set_callback(state.channel, None);
+        state.dmac.channel_disable(state.channel);
+        gpiohs::set_pin(state.ready_pin, false);
+        let registers = unsafe { core::slice::from_raw_parts_mut(state.registers, state.len) };
+        (self.spi, registers)
+    }
+
+    /** Whether a command is being transferred. The register map can be accessed at any time,
+     * but values changed during a read can reach the host half-updated. */
+    pub fn is_busy(&self) -> bool {
+        riscv::interrupt::free(|_| unsafe {
+            matches!((*core::ptr::addr_of!(STATE)).as_ref().map(|s| s.phase),
+                     Some(Phase::Data(_)) | Some(Phase::Drain(_)))
+        })
+    }
+
+    /** Access the register map, with interrupts disabled */
+    pub fn with_registers<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> R {
+        riscv::interrupt::free(|_| unsafe {
+            let state = (*core::ptr::addr_of!(STATE)).as_ref().unwrap();
+            f(core::slice::from_raw_parts_mut(state.registers, state.len))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let command = Command { direction: Direction::Read, address: 0x100, len: 0x25800 };
+        let header = command.encode();
+        assert_eq!(header, [CMD_READ, 0x00, 0x01, 0x00, 0x02, 0x58, 0x00, 0xA5]);
+        assert_eq!(Command::decode(&header, 0x25900), Ok(command));
+        assert_eq!(Command::decode(&header, 0x258FF), Err(SlaveError::Range(command)));
+
+        let mut corrupted = header;
+        corrupted[3] ^= 0x10;
+        assert_eq!(Command::decode(&corrupted, 0x25900), Err(SlaveError::Header));
+        assert_eq!(Command::decode(&[0xFF; HEADER_LEN], 0x25900), Err(SlaveError::Header));
+        assert_eq!(Command::decode(&[0x00; HEADER_LEN], 0x25900), Err(SlaveError::Header));
+
+        let empty = Command { direction: Direction::Write, address: 0, len: 0 };
+        assert_eq!(Command::decode(&empty.encode(), 16), Err(SlaveError::Range(empty)));
+    }
+}
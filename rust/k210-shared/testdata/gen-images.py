@@ -0,0 +1,183 @@
+#!/usr/bin/env python3
+'''
+Generate the sample images for the board::image tests. All images show the same 7×5 picture
+(see `expected` in src/board/image.rs), in different formats.
+'''
+import struct
+
+W = 7
+H = 5
+PAL = [(0, 0, 0), (255, 0, 0), (0, 255, 0), (0, 0, 255),
+       (255, 255, 0), (0, 255, 255), (255, 0, 255), (255, 255, 255)]
+
+def pixel(x, y):
+    if y == 0:
+        return PAL[1]
+    if y == 3:
+        return (x * x, 100 + x, 50 + x)
+    if y == 4 and x < 4:
+        return PAL[2]
+    return PAL[(x + 2 * y) % 8]
+
+IMAGE = [[pixel(x, y) for x in range(W)] for y in range(H)]
+COLORS = sorted(set(c for row in IMAGE for c in row))
+INDEX = {c: i for i, c in enumerate(COLORS)}
+
+def pad4(b):
+    return b + b'\0' * (-len(b) % 4)
+
+def bmp(header, palette, pixels):
+    '''Assemble a BMP file from info header, palette and pixel data.'''
+    offset = 14 + len(header) + len(palette)
+    return (b'BM' + struct.pack('<IHHI', offset + len(pixels), 0, 0, offset) +
+            header + palette + pixels)
+
+def info_header(width, height, bpp, compression=0, colors=0):
+    return struct.pack('<IiiHHIIiiII', 40, width, height, 1, bpp, compression, 0,
+                       2835, 2835, colors, 0)
+
+def bgr(c):
+    return bytes((c[2], c[1], c[0]))
+
+def palette(entry_size=4):
+    return b''.join(bgr(c) + b'\0' * (entry_size - 3) for c in COLORS)
+
+def rows(bottom_up=True):
+    return reversed(IMAGE) if bottom_up else IMAGE
+
+def rgb24():
+    data = b''.join(pad4(b''.join(bgr(c) for c in row)) for row in rows())
+    return bmp(info_header(W, H, 24), b'', data)
+
+def core24():
+    header = struct.pack('<IHHHH', 12, W, H, 1, 24)
+    data = b''.join(pad4(b''.join(bgr(c) for c in row)) for row in rows())
+    return bmp(header, b'', data)
+
+def rgb32_topdown():
+    data = b''.join(b''.join(bgr(c) + b'\xff' for c in row) for row in rows(False))
+    return bmp(info_header(W, -H, 32), b'', data)
+
+def rgb565():
+    masks = struct.pack('<III', 0xf800, 0x07e0, 0x001f)
+    def v(c):
+        return ((c[0] >> 3) << 11) | ((c[1] >> 2) << 5) | (c[2] >> 3)
+    data = b''.join(pad4(b''.join(struct.pack('<H', v(c)) for c in row)) for row in rows())
+    return bmp(info_header(W, H, 16, 3), masks, data)
+
+def packed(row, bpp):
+    '''Pack palette indices, leftmost pixel in most significant bits.'''
+    out = []
+    per_byte = 8 // bpp
+    for i in range(0, len(row), per_byte):
+        b = 0
+        for j in range(per_byte):
+            idx = INDEX[row[i + j]] if i + j < len(row) else 0
+            b |= idx << (8 - bpp * (j + 1))
+        out.append(b)
+    return bytes(out)
+
+def pal(bpp):
+    data = b''.join(pad4(packed(row, bpp)) for row in rows())
+    return bmp(info_header(W, H, bpp, 0, len(COLORS)), palette(), data)
+
+def rle_segment(pixels, rle4):
+    '''RLE encode a sequence of pixels (without end-of-line).'''
+    out = b''
+    i = 0
+    while i < len(pixels):
+        n = 1
+        while i + n < len(pixels) and pixels[i + n] == pixels[i]:
+            n += 1
+        if n >= 2:
+            idx = INDEX[pixels[i]]
+            out += bytes((n, (idx << 4) | idx if rle4 else idx))
+            i += n
+            continue
+        # Collect a literal run up to the next repeated pixel
+        j = i
+        while j < len(pixels) and (j + 1 >= len(pixels) or pixels[j + 1] != pixels[j]):
+            j += 1
+        lit = [INDEX[c] for c in pixels[i:j]]
+        if len(lit) < 3:
+            for idx in lit:
+                out += bytes((1, idx << 4 if rle4 else idx))
+        else:
+            if rle4:
+                body = bytes(((lit[k] << 4) | (lit[k + 1] if k + 1 < len(lit) else 0))
+                             for k in range(0, len(lit), 2))
+            else:
+                body = bytes(lit)
+            out += bytes((0, len(lit))) + body + b'\0' * (len(body) % 2)
+        i = j
+    return out
+
+def rle(rle4):
+    '''RLE image, skipping pixels 2..5 of image row 2 with a delta and ending without a final
+    end-of-line.'''
+    data = b''
+    for fy, row in enumerate(rows()):
+        y = H - 1 - fy
+        if y == 2:
+            data += rle_segment(row[0:2], rle4) + bytes((0, 2, 3, 0)) + rle_segment(row[5:], rle4)
+        else:
+            data += rle_segment(row, rle4)
+        data += bytes((0, 0)) if y != 0 else bytes((0, 1))
+    bpp = 4 if rle4 else 8
+    return bmp(info_header(W, H, bpp, 2 if rle4 else 1, len(COLORS)), palette(), data)
+
+def qoi():
+    '''QOI image with alpha, the last pixel is transparent.'''
+    pixels = [c + (255,) for row in IMAGE for c in row]
+    pixels[-1] = pixels[-1][0:3] + (0,)
+    out = b'qoif' + struct.pack('>IIBB', W, H, 4, 0)
+    index = [(0, 0, 0, 0)] * 64
+    prev = (0, 0, 0, 255)
+    run = 0
+    for i, px in enumerate(pixels):
+        if px == prev:
+            run += 1
+            if run == 62 or i == len(pixels) - 1:
+                out += bytes((0xc0 | (run - 1),))
+                run = 0
+            continue
+        if run > 0:
+            out += bytes((0xc0 | (run - 1),))
+            run = 0
+        pos = (px[0] * 3 + px[1] * 5 + px[2] * 7 + px[3] * 11) % 64
+        if index[pos] == px:
+            out += bytes((pos,))
+        else:
+            index[pos] = px
+            if px[3] == prev[3]:
+                dr = (px[0] - prev[0] + 128) % 256 - 128
+                dg = (px[1] - prev[1] + 128) % 256 - 128
+                db = (px[2] - prev[2] + 128) % 256 - 128
+                dr_dg = dr - dg
+                db_dg = db - dg
+                if -2 <= dr < 2 and -2 <= dg < 2 and -2 <= db < 2:
+                    out += bytes((0x40 | ((dr + 2) << 4) | ((dg + 2) << 2) | (db + 2),))
+                elif -32 <= dg < 32 and -8 <= dr_dg < 8 and -8 <= db_dg < 8:
+                    out += bytes((0x80 | (dg + 32), ((dr_dg + 8) << 4) | (db_dg + 8)))
+                else:
+                    out += bytes((0xfe,) + px[0:3])
+            else:
+                out += bytes((0xff,) + px)
+        prev = px
+    return out + b'\0' * 7 + b'\1'
+
+FILES = {
+    'rgb24.bmp': rgb24(),
+    'core24.bmp': core24(),
+    'rgb32-topdown.bmp': rgb32_topdown(),
+    'rgb565.bmp': rgb565(),
+    'pal8.bmp': pal(8),
+    'pal4.bmp': pal(4),
+    'rle8.bmp': rle(False),
+    'rle4.bmp': rle(True),
+    'rgba.qoi': qoi(),
+}
+
+for name, data in FILES.items():
+    with open(name, 'wb') as f:
+        f.write(data)
@@ -9,11 +9,12 @@ mod palette;
 use k210_hal::pac::Peripherals;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
-use k210_shared::board::def::{io,DISP_WIDTH,DISP_HEIGHT};
+use k210_shared::board::def::{DISP_WIDTH,DISP_HEIGHT};
 use k210_shared::board::lcd::{LCD,LCDHL,self};
 use k210_shared::board::lcd_colors;
-use k210_shared::board::lcd_render::render_image;
-use k210_shared::soc::fpioa;
+use k210_shared::board::lcd_render::render_strips;
+use k210_shared::board::pinout::{self, LcdPins};
+use k210_shared::soc::fpioa::mux::PinMux;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
@@ -23,16 +24,9 @@ use riscv_rt::entry;
 use crate::palette::PALETTE;
 
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    pinout::MAIX_GO.lcd.route(mux).unwrap()
 }
 
 /** Set correct voltage for pins */
@@ -70,7 +64,8 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     writeln!(stdout, "Init DMAC").unwrap();
@@ -80,7 +75,7 @@ fn main() -> ! {
              dmac.read_id(), dmac.read_version(), dmac.read_channel_id(chan)).unwrap();
 
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, chan);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, chan);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_RLDU);
     lcd.clear(lcd_colors::PURPLE);
@@ -90,7 +85,7 @@ fn main() -> ! {
     let ofsx = 0.02997f32;
     let ofsy = 0.80386f32;
     loop {
-        render_image(&mut lcd, |x,y| {
+        render_strips(&mut lcd, |x,y| {
             let xx = 2.0 * (x as f32) / ((DISP_WIDTH-1) as f32) - 1.0;
             let yy = 2.0 * (y as f32) / ((DISP_HEIGHT-1) as f32) - 1.0;
             let i = mandelbrot(xx * zoom + ofsx, yy * zoom + ofsy, 20);
@@ -15,8 +15,9 @@ use k210_shared::board::lcd::{self, LCD, LCDHL};
 use k210_shared::board::lcd_colors::{clampf, hsv2rgb, rgbf565};
 use k210_shared::board::lcd_render::render_image;
 use k210_shared::board::ns2009::TouchScreen;
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::i2c::{I2CExt, I2C};
 use k210_shared::soc::pwm::{TimerExt, PWM, Channel};
 use k210_shared::soc::sleep::usleep;
@@ -29,32 +30,24 @@ const R_SCALE: f32 = 1.0;
 const G_SCALE: f32 = 0.16;
 const B_SCALE: f32 = 0.33;
 
-fn io_init() {
+fn io_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(
-        io::LCD_RST,
-        fpioa::function::gpiohs(lcd::RST_GPIONUM),
-    );
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* Route PWM outputs of TIMER0 to RGB leds */
-    fpioa::set_function(io::LED_R, fpioa::function::TIMER0_TOGGLE1);
-    fpioa::set_function(io::LED_G, fpioa::function::TIMER0_TOGGLE2);
-    fpioa::set_function(io::LED_B, fpioa::function::TIMER0_TOGGLE3);
-
-    sysctl::set_spi0_dvp_data(true);
+    mux.set_function(io::LED_R, fpioa::function::TIMER0_TOGGLE1).unwrap();
+    mux.set_function(io::LED_G, fpioa::function::TIMER0_TOGGLE2).unwrap();
+    mux.set_function(io::LED_B, fpioa::function::TIMER0_TOGGLE3).unwrap();
 
     /* I2C0 for touch-screen */
-    fpioa::set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK);
-    fpioa::set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA);
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
 
     /* Set DVP and SPI pins to 1.8V */
     sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    lcd_pins
 }
 
 /** Color picker */
@@ -80,7 +73,8 @@ fn main() -> ! {
     let (mut tx, _) = serial.split();
     let mut stdout = Stdout(&mut tx);
 
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_init(&mut mux);
 
     writeln!(stdout, "NS2009 init").unwrap();
     let i2c = p.I2C0.constrain();
@@ -95,7 +89,7 @@ fn main() -> ! {
     writeln!(stdout, "LCD init").unwrap();
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     render_image(&mut lcd, |x, y| {
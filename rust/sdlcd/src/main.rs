@@ -10,19 +10,25 @@
 use core::convert::TryInto;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
-use k210_hal::pac::Peripherals;
-use k210_shared::board::def::{io, DISP_HEIGHT, DISP_PIXELS, DISP_WIDTH};
+use k210_hal::pac::{Peripherals, SPI1};
+use k210_shared::board::def::{DISP_HEIGHT, DISP_PIXELS, DISP_WIDTH};
 use k210_shared::board::lcd::{self, LCD, LCDHL};
 use k210_shared::board::lcd_colors;
 use k210_shared::board::lcd_render::{AsU8, ScreenImage};
+use k210_shared::board::pinout::{self, LcdPins, SdCardPins};
 use k210_shared::board::sdcard;
+use k210_shared::fs::fat::{FileSystem, OpenMode, SeekFrom};
+use k210_shared::fs::partition;
 use k210_shared::soc::dmac::{dma_channel, DMACExt};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::mux::PinMux;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
 use riscv_rt::entry;
 
+/** Video file to play from a FAT filesystem on the card */
+const VIDEO_FILE: &str = "test.vid";
+
 /** GPIOHS GPIO number to use for controlling the SD card CS pin */
 const SD_CS_GPIONUM: u8 = 7;
 /** CS value passed to SPI controller, this is a dummy value as SPI0_CS3 is not mapped to anything
@@ -30,27 +36,18 @@ const SD_CS_GPIONUM: u8 = 7;
 const SD_CS: u32 = 3;
 
 /** Connect pins to internal functions */
-fn io_init() {
-    /* Init SD card function settings */
-    fpioa::set_function(io::SPI0_SCLK, fpioa::function::SPI1_SCLK);
-    fpioa::set_function(io::SPI0_MOSI, fpioa::function::SPI1_D0);
-    fpioa::set_function(io::SPI0_MISO, fpioa::function::SPI1_D1);
-    fpioa::set_function(io::SPI0_CS0, fpioa::function::gpiohs(SD_CS_GPIONUM));
-    fpioa::set_io_pull(io::SPI0_CS0, fpioa::pull::DOWN); // GPIO output=pull down
+fn io_init(mux: &mut PinMux) -> (SdCardPins<SPI1>, LcdPins) {
+    /* Init SD card function settings: SPI0 drives the LCD, so the card uses SPI1 */
+    let sd_pins = pinout::MAIX_GO.sdcard.route(mux, SD_CS_GPIONUM).unwrap();
 
     /* Init LCD function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* Set dvp and spi pin to 1.8V */
     sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    (sd_pins, lcd_pins)
 }
 
 #[entry]
@@ -71,27 +68,59 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let (sd_pins, lcd_pins) = io_init(&mut mux);
 
     let dmac = p.DMAC.configure();
 
     let lspi = p.SPI0.constrain();
-    let mut lcd = LCD::new(lspi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(lspi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
 
     let sspi = p.SPI1.constrain();
     writeln!(stdout, "sdcard: pre-init").unwrap();
-    let sd = sdcard::SDCard::new(sspi, SD_CS, SD_CS_GPIONUM, &dmac, dma_channel::CHANNEL1);
+    let sd = sdcard::SDCard::new(sspi, SD_CS, sd_pins, &dmac, dma_channel::CHANNEL1);
     let info = sd.init().unwrap();
     writeln!(stdout, "card info: {:?}", info).unwrap();
     let num_sectors = info.CardCapacity / 512;
     writeln!(stdout, "number of sectors on card: {}", num_sectors).unwrap();
 
     assert!(num_sectors > 0);
-    let mut sector: u64 = 0;
     let mut image: ScreenImage = [0; DISP_PIXELS / 2];
+
+    /* Play the video file in a loop, if there is one */
+    if let Ok(fs) = partition::find_fat(&sd).and_then(FileSystem::mount) {
+        if let Ok(mut f) = fs.open(VIDEO_FILE, OpenMode::Read) {
+            let frame_len = image.len() * 4;
+            if (f.len() as usize) < frame_len {
+                writeln!(stdout, "{} is shorter than one frame ({} bytes), stopping",
+                         VIDEO_FILE, frame_len).unwrap();
+                loop {}
+            }
+            writeln!(stdout, "playing {} ({} bytes)", VIDEO_FILE, f.len()).unwrap();
+            let mut rewound = false;
+            loop {
+                let n = f.read(image.as_u8_slice_mut()).unwrap();
+                if n < frame_len {
+                    if n == 0 && rewound {
+                        writeln!(stdout, "{}: nothing to read after rewinding, stopping",
+                                 VIDEO_FILE).unwrap();
+                        loop {}
+                    }
+                    f.seek(SeekFrom::Start(0)).unwrap();
+                    rewound = true;
+                    continue;
+                }
+                rewound = false;
+                lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &image);
+            }
+        }
+    }
+
+    /* Otherwise, stream raw sectors from the start of the card */
+    let mut sector: u64 = 0;
     while sector < num_sectors {
         /* Read raw image */
         sd.read_sector(image.as_u8_slice_mut(), sector.try_into().unwrap())
@@ -7,11 +7,14 @@
 use core::convert::TryInto;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
-use k210_hal::pac::Peripherals;
-use k210_shared::board::def::io;
-use k210_shared::board::sdcard;
+use k210_hal::pac::{Peripherals, SPI0};
+use k210_shared::board::pinout::{self, SdCardPins};
+use k210_shared::board::sdcard::{self, CardEvent};
+use k210_shared::fs::BlockDevice;
+use k210_shared::fs::fat::{FileSystem, OpenMode};
+use k210_shared::fs::partition::{self, PartitionInfo, PartitionType};
 use k210_shared::soc::dmac::{dma_channel, DMACExt};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::mux::PinMux;
 use k210_shared::soc::sysctl;
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
@@ -24,12 +27,8 @@ const SD_CS_GPIONUM: u8 = 7;
 const SD_CS: u32 = 3;
 
 /** Connect pins to internal functions */
-fn io_init() {
-    fpioa::set_function(io::SPI0_SCLK, fpioa::function::SPI0_SCLK);
-    fpioa::set_function(io::SPI0_MOSI, fpioa::function::SPI0_D0);
-    fpioa::set_function(io::SPI0_MISO, fpioa::function::SPI0_D1);
-    fpioa::set_function(io::SPI0_CS0, fpioa::function::gpiohs(SD_CS_GPIONUM));
-    fpioa::set_io_pull(io::SPI0_CS0, fpioa::pull::DOWN); // GPIO output=pull down
+fn io_init(mux: &mut PinMux) -> SdCardPins<SPI0> {
+    pinout::MAIX_GO.sdcard.route(mux, SD_CS_GPIONUM).unwrap()
 }
 
 fn ch(i: u8) -> char {
@@ -56,6 +55,38 @@ fn hexdump<T: core::fmt::Write>(stdout: &mut T, buffer: &[u8], base: usize) {
     }
 }
 
+/** List partitions, and the root directory of the first FAT filesystem */
+fn show_filesystem<T: core::fmt::Write, D: BlockDevice>(stdout: &mut T, dev: &D) {
+    let mut parts = [PartitionInfo { start: 0, len: 0, ptype: PartitionType::Mbr(0) }; 8];
+    match partition::read_partitions(dev, &mut parts) {
+        Ok(n) => {
+            for p in &parts[..n] {
+                writeln!(stdout, "partition: start {} len {} type {:?}", p.start, p.len, p.ptype).unwrap();
+            }
+        }
+        Err(e) => writeln!(stdout, "no partition table: {:?}", e).unwrap(),
+    }
+
+    match partition::find_fat(dev).and_then(FileSystem::mount) {
+        Ok(fs) => {
+            writeln!(stdout, "{:?} filesystem, cluster size {}", fs.fat_type(), fs.cluster_size()).unwrap();
+            for entry in fs.read_dir("/").unwrap() {
+                let entry = entry.unwrap();
+                writeln!(stdout, "{:>10} {}{}", entry.len(), entry.name(),
+                    if entry.is_dir() { "/" } else { "" }).unwrap();
+            }
+            // Show the start of a text file, if present
+            if let Ok(mut f) = fs.open("README.TXT", OpenMode::Read) {
+                let mut buffer = [0u8; 512];
+                let n = f.read(&mut buffer).unwrap();
+                writeln!(stdout, "README.TXT ({} bytes):", f.len()).unwrap();
+                hexdump(stdout, &buffer[..n & !15], 0);
+            }
+        }
+        Err(e) => writeln!(stdout, "no FAT filesystem: {:?}", e).unwrap(),
+    }
+}
+
 #[entry]
 fn main() -> ! {
     let p = Peripherals::take().unwrap();
@@ -74,15 +105,26 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let sd_pins = io_init(&mut mux);
 
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
 
     writeln!(stdout, "sdcard: pre-init").unwrap();
-    let sd = sdcard::SDCard::new(spi, SD_CS, SD_CS_GPIONUM, &dmac, dma_channel::CHANNEL0);
+    let sd = sdcard::SDCard::new(spi, SD_CS, sd_pins, &dmac, dma_channel::CHANNEL0);
     let info = sd.init().unwrap();
     writeln!(stdout, "card info: {:?}", info).unwrap();
+    writeln!(stdout, "card: {} ({:02x}) {} {} rev {}.{}, serial {:08x}, made {}-{:02}",
+        info.cid.manufacturer().unwrap_or("unknown"), info.cid.manufacturer_id,
+        info.cid.oem(), info.cid.product(), info.cid.revision.0, info.cid.revision.1,
+        info.cid.serial, info.cid.year, info.cid.month).unwrap();
+    writeln!(stdout, "clock: {} Hz{}", info.clock,
+        if info.high_speed { " (high speed)" } else { "" }).unwrap();
+    if let Ok(status) = sd.read_sd_status() {
+        writeln!(stdout, "speed class {}, allocation unit {} bytes, erase sector {} blocks",
+            status.speed_class, status.au_size, info.csd.erase_sector_blocks).unwrap();
+    }
     let num_sectors = info.CardCapacity / 512;
     writeln!(stdout, "number of sectors on card: {}", num_sectors).unwrap();
 
@@ -94,6 +136,8 @@ fn main() -> ! {
 
     hexdump(&mut stdout, &buffer, 0);
 
+    show_filesystem(&mut stdout, &sd);
+
     // Warning: uncommenting this will write to the SD card
     /*
     let msg = b"Well! I've often seen a cat without a grin', thought Alice, 'but a grin without a cat! It's the most curious thing I ever saw in my life!'";
@@ -103,5 +147,16 @@ fn main() -> ! {
     writeln!(stdout, "sector {} succesfully written", sector).unwrap();
     */
 
-    loop {}
+    // Report card changes, and show the filesystem of every newly inserted card
+    loop {
+        match sd.poll() {
+            Some(CardEvent::Inserted(info)) => {
+                writeln!(stdout, "card inserted: {} sectors", info.CardCapacity / 512).unwrap();
+                show_filesystem(&mut stdout, &sd);
+            }
+            Some(CardEvent::Removed) => writeln!(stdout, "card removed").unwrap(),
+            None => {}
+        }
+        usleep(500_000);
+    }
 }
@@ -0,0 +1,204 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![no_std]
+#![no_main]
+
+use core::mem;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use k210_hal::pac::Peripherals;
+use k210_hal::prelude::*;
+use k210_hal::stdout::Stdout;
+use k210_shared::board::def::{io,DISP_WIDTH,DISP_HEIGHT,DISP_PIXELS,MSA300_SLV_ADDR,MSA300_ADDR_BITS,MSA300_CLK};
+use k210_shared::board::msa300::Accelerometer;
+use k210_shared::board::ov2640;
+use k210_shared::soc::dmac::{DMAC, DMACExt, dma_channel};
+use k210_shared::soc::dvp::{DVPExt,sccb_addr_len,image_format};
+use k210_shared::soc::fpioa;
+use k210_shared::soc::i2c::{I2C,I2CExt};
+use k210_shared::soc::plic;
+use k210_shared::soc::sleep::usleep;
+use k210_shared::soc::spi::slave::{Command, Direction, SPISlave, SlaveError};
+use k210_shared::soc::spi::work_mode;
+use k210_shared::soc::sysctl;
+use riscv_rt::entry;
+
+/** GPIOHS pin for the ready signal */
+const READY_GPIONUM: u8 = 4;
+
+/** Register map layout */
+const REG_ID: usize = 0x00;
+const REG_FRAME_COUNT: usize = 0x04;
+const REG_ACCEL: usize = 0x08;
+const REG_IMAGE_SIZE: usize = 0x14;
+const REG_CONTROL: usize = 0x18;
+const REGS_LEN: usize = 0x100;
+
+/** Control register bits */
+const CONTROL_CAPTURE: u8 = 0x01;
+
+/** Register map shared with the host */
+#[repr(C)]
+struct RegisterMap {
+    regs: [u8; REGS_LEN],
+    image: [u32; DISP_PIXELS / 2],
+}
+
+static mut MAP: RegisterMap = RegisterMap { regs: [0; REGS_LEN], image: [0; DISP_PIXELS / 2] };
+
+/** Frame the DVP captures into, 64-byte aligned. It is copied into the register map when no
+ * command is running, so that the host never reads a frame that is being overwritten. */
+#[repr(C, align(64))]
+struct Frame([u32; DISP_PIXELS / 2]);
+
+static mut FRAME: Frame = Frame([0; DISP_PIXELS / 2]);
+
+/** The slave uses the DMA controller from interrupt handlers */
+static mut DMAC_INSTANCE: Option<DMAC> = None;
+
+/** Statistics kept by the completion callback */
+static COMMANDS: AtomicU32 = AtomicU32::new(0);
+static CONTROL_WRITES: AtomicU32 = AtomicU32::new(0);
+static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/** Called after every command from the host */
+fn command_done(result: Result<Command, SlaveError>) {
+    match result {
+        Ok(command) => {
+            COMMANDS.fetch_add(1, Ordering::SeqCst);
+            if command.direction == Direction::Write
+                && command.address <= REG_CONTROL
+                && command.address + command.len > REG_CONTROL {
+                CONTROL_WRITES.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Err(_) => {
+            ERRORS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/** Connect pins to internal functions */
+fn io_init() {
+    /* SPI slave */
+    fpioa::set_function(io::BPSK_P, fpioa::function::SPI_SLAVE_SS);
+    fpioa::set_function(io::BPSK_N, fpioa::function::SPI_SLAVE_SCLK);
+    fpioa::set_function(io::IO11, fpioa::function::SPI_SLAVE_D0);
+    fpioa::set_function(io::IO32, fpioa::function::gpiohs(READY_GPIONUM));
+
+    /* Init DVP IO map and function settings */
+    fpioa::set_function(io::DVP_RST, fpioa::function::CMOS_RST);
+    fpioa::set_function(io::DVP_PWDN, fpioa::function::CMOS_PWDN);
+    fpioa::set_function(io::DVP_XCLK, fpioa::function::CMOS_XCLK);
+    fpioa::set_function(io::DVP_VSYNC, fpioa::function::CMOS_VSYNC);
+    fpioa::set_function(io::DVP_HSYNC, fpioa::function::CMOS_HREF);
+    fpioa::set_function(io::DVP_PCLK, fpioa::function::CMOS_PCLK);
+    fpioa::set_function(io::DVP_SCL, fpioa::function::SCCB_SCLK);
+    fpioa::set_function(io::DVP_SDA, fpioa::function::SCCB_SDA);
+
+    /* I2C0 for accelerometer */
+    fpioa::set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK);
+    fpioa::set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA);
+
+    /* Set DVP pins to 1.8V */
+    sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
+    sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+}
+
+#[entry]
+fn main() -> ! {
+    let p = Peripherals::take().unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL0, 800_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL1, 300_000_000).unwrap();
+    sysctl::pll_set_freq(sysctl::pll::PLL2, 45_158_400).unwrap();
+    let clocks = k210_hal::clock::Clocks::new();
+
+    usleep(200000);
+
+    // Configure UART
+    let serial = p.UARTHS.configure(115_200.bps(), &clocks);
+    let (mut tx, _) = serial.split();
+    let mut stdout = Stdout(&mut tx);
+
+    io_init();
+
+    writeln!(stdout, "MSA300 init").unwrap();
+    let i2c = p.I2C0.constrain();
+    i2c.init(MSA300_SLV_ADDR, MSA300_ADDR_BITS, MSA300_CLK);
+    let acc = Accelerometer::init(i2c).unwrap();
+
+    writeln!(stdout, "OV2640 init").unwrap();
+    let mut dvp = p.DVP.constrain();
+    dvp.init(sccb_addr_len::W8);
+    dvp.set_xclk_rate(24000000);
+    dvp.set_image_format(image_format::RGB);
+    dvp.set_image_size(true, DISP_WIDTH, DISP_HEIGHT);
+    ov2640::init(&dvp);
+    dvp.set_ai_addr(None);
+    dvp.set_display_addr(Some(unsafe { addr_of_mut!(FRAME) as *mut u32 }));
+    dvp.set_auto(false);
+
+    let dmac: &'static DMAC = unsafe {
+        (*addr_of_mut!(DMAC_INSTANCE)).get_or_insert(p.DMAC.configure())
+    };
+    let registers = unsafe {
+        let len = mem::size_of::<RegisterMap>();
+        core::slice::from_raw_parts_mut(addr_of_mut!(MAP) as *mut u8, len)
+    };
+    let slave = SPISlave::new(p.SPI2, dmac, dma_channel::CHANNEL0, READY_GPIONUM, registers);
+    slave.with_registers(|regs| {
+        regs[REG_ID..REG_ID + 4].copy_from_slice(b"K210");
+        regs[REG_IMAGE_SIZE..REG_IMAGE_SIZE + 2].copy_from_slice(&DISP_WIDTH.to_le_bytes());
+        regs[REG_IMAGE_SIZE + 2..REG_IMAGE_SIZE + 4].copy_from_slice(&DISP_HEIGHT.to_le_bytes());
+    });
+    plic::init();
+    slave.start(work_mode::MODE0, Some(command_done));
+    writeln!(stdout, "SPI slave: register map of {} bytes, waiting for commands",
+             mem::size_of::<RegisterMap>()).unwrap();
+
+    let frame = unsafe {
+        core::slice::from_raw_parts(addr_of!(FRAME) as *const u8, mem::size_of::<Frame>())
+    };
+    let mut frames: u32 = 0;
+    // a captured frame that could not be copied to the register map yet
+    let mut pending = false;
+    let mut control_writes = 0;
+    loop {
+        let (x, y, z) = acc.measure().unwrap();
+        let capture = slave.with_registers(|regs| {
+            for (i, v) in [x, y, z].iter().enumerate() {
+                let offset = REG_ACCEL + 4 * i;
+                regs[offset..offset + 4].copy_from_slice(&((v * 1000.0) as i32).to_le_bytes());
+            }
+            (regs[REG_CONTROL] & CONTROL_CAPTURE) != 0
+        });
+
+        if capture && !pending {
+            dvp.get_image();
+            pending = true;
+        }
+        // don't overwrite the image while the host may be reading it; with interrupts
+        // disabled, no command can start during the copy
+        if pending {
+            pending = !slave.with_registers(|regs| {
+                if slave.is_busy() {
+                    return false;
+                }
+                frames = frames.wrapping_add(1);
+                regs[REGS_LEN..].copy_from_slice(frame);
+                regs[REG_FRAME_COUNT..REG_FRAME_COUNT + 4].copy_from_slice(&frames.to_le_bytes());
+                true
+            });
+        }
+
+        let writes = CONTROL_WRITES.load(Ordering::SeqCst);
+        if writes != control_writes {
+            control_writes = writes;
+            writeln!(stdout, "Capture {}, {} commands, {} errors",
+                     if capture { "on" } else { "off" },
+                     COMMANDS.load(Ordering::SeqCst), ERRORS.load(Ordering::SeqCst)).unwrap();
+        }
+        usleep(10000);
+    }
+}
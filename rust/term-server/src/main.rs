@@ -11,17 +11,22 @@ use esp8266at::traits;
 use k210_hal::pac::Peripherals;
 use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
-use k210_shared::board::def::io;
+use k210_shared::board::def::{io, NS2009_ADDR_BITS, NS2009_CAL, NS2009_CLK, NS2009_SLV_ADDR};
 use k210_shared::board::lcd::{self, LCD, LCDHL};
+use k210_shared::board::ns2009::{EventKind, TouchScreen};
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::gpio;
 use k210_shared::soc::gpiohs;
+use k210_shared::soc::i2c::{I2CExt, I2C};
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
 use k210_shared::soc::sysctl;
 use riscv_rt::entry;
 use k210_console::console::{Console, ScreenImage, DISP_HEIGHT, DISP_WIDTH, DISP_PIXELS};
+use k210_console::keyboard::{self, Keyboard};
+use k210_console::mouse::TouchMouse;
 use k210_console::{cp437, cp437_8x8};
 use buffered_uart;
 
@@ -46,23 +51,19 @@ impl traits::Write for WriteAdapter {
 }
 
 /** Connect pins to internal functions */
-fn io_init() {
+fn io_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(
-        io::LCD_RST,
-        fpioa::function::gpiohs(lcd::RST_GPIONUM),
-    );
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
+
+    /* I2C0 for touch-screen */
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
 
     /* Set dvp and spi pin to 1.8V */
     sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    lcd_pins
 }
 
 #[entry]
@@ -74,7 +75,8 @@ fn main() -> ! {
     let clocks = k210_hal::clock::Clocks::new();
 
     usleep(200000);
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_init(&mut mux);
 
     // Configure UARTHS (→host)
     let serial = p.UARTHS.configure(DEFAULT_BAUD.bps(), &clocks);
@@ -84,10 +86,10 @@ fn main() -> ! {
     // Configure UART1 (→WIFI)
     sysctl::clock_enable(sysctl::clock::UART1);
     sysctl::reset(sysctl::reset::UART1);
-    fpioa::set_function(io::WIFI_RX, fpioa::function::UART1_TX);
-    fpioa::set_function(io::WIFI_TX, fpioa::function::UART1_RX);
-    fpioa::set_function(io::WIFI_EN, fpioa::function::GPIOHS8);
-    fpioa::set_io_pull(io::WIFI_EN, fpioa::pull::DOWN);
+    mux.set_function(io::WIFI_RX, fpioa::function::UART1_TX).unwrap();
+    mux.set_function(io::WIFI_TX, fpioa::function::UART1_RX).unwrap();
+    mux.set_function(io::WIFI_EN, fpioa::function::GPIOHS8).unwrap();
+    mux.set_io_pull(io::WIFI_EN, fpioa::pull::DOWN).unwrap();
     gpiohs::set_pin(8, true);
     gpiohs::set_direction(8, gpio::direction::OUTPUT);
 
@@ -98,11 +100,29 @@ fn main() -> ! {
     // LCD ini
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     let mut console: Console = Console::new(&cp437::to, &cp437_8x8::FONT, None);
 
+    // Touch screen, used to send mouse reports to the remote program if it requests them
+    let i2c = p.I2C0.constrain();
+    i2c.init(NS2009_SLV_ADDR, NS2009_ADDR_BITS, NS2009_CLK);
+    let mut ts = TouchScreen::init(i2c, NS2009_CAL);
+    if ts.is_none() {
+        writeln!(debug, "NS2009 init failure, continuing without touch").unwrap();
+    }
+    // On-screen keyboard at the bottom of the screen, the console uses the rows above it
+    let mut kbd = Keyboard::new(&cp437::to, &cp437_8x8::FONT, DISP_HEIGHT - keyboard::HEIGHT);
+    console.set_height((DISP_HEIGHT - keyboard::HEIGHT) / 8);
+    let mut mouse = TouchMouse::new(console.width(), console.height());
+    // Current touch started on the keyboard
+    let mut touch_kbd = false;
+    let mut link: Option<u32> = None;
+    // Input (key presses, mouse reports) waiting to be sent to the remote program
+    let mut input_buf = [0u8; 256];
+    let mut input_ofs: usize = 0;
+
     writeln!(console, "\x1b[48;2;128;192;255;38;5;0m TERMINAL \x1b[0m \x1b[38;2;128;128;128m\x1b[0m").unwrap();
 
     // Start off connection process state machine
@@ -113,11 +133,13 @@ fn main() -> ! {
     let mut ofs: usize = 0;
 
     loop {
-        if console.dirty {
+        if console.dirty || kbd.dirty {
             let mut image: ScreenImage = [0; DISP_PIXELS / 2];
             console.render(&mut image);
+            kbd.render(&mut image);
             lcd.draw_picture(0, 0, DISP_WIDTH, DISP_HEIGHT, &image);
             console.dirty = false;
+            kbd.dirty = false;
         }
 
         // Receive into buffer
@@ -146,13 +168,17 @@ fn main() -> ! {
                                 writeln!(console, "∙ Listening on {}.{}.{}.{}:{}",
                                          ip[0], ip[1], ip[2], ip[3], port).unwrap();
                             }
-                            NetworkEvent::ConnectionEstablished(_link) => {
+                            NetworkEvent::ConnectionEstablished(l) => {
+                                link = Some(l);
                             }
                             NetworkEvent::Data(_link, data) => {
                                 // write!(debug, "{}", str::from_utf8(data).unwrap());
                                 console.puts(str::from_utf8(data).unwrap_or("???"));
                             }
-                            NetworkEvent::ConnectionClosed(_link) => {
+                            NetworkEvent::ConnectionClosed(l) => {
+                                if link == Some(l) {
+                                    link = None;
+                                }
                             }
                             _ => { }
                         }
@@ -198,6 +224,37 @@ fn main() -> ! {
             ofs = 0;
         }
 
+        // Handle touches on the keyboard as key presses, translate other touch events to mouse
+        // reports if the remote program enabled these
+        if let Some(ts) = &mut ts {
+            if let Some(ev) = ts.poll() {
+                let queue = |seq: &[u8]| {
+                    // Drop the input if the buffer is full
+                    if input_ofs + seq.len() <= input_buf.len() {
+                        input_buf[input_ofs..input_ofs + seq.len()].copy_from_slice(seq);
+                        input_ofs += seq.len();
+                    }
+                };
+                if ev.kind == EventKind::Begin {
+                    touch_kbd = kbd.contains(ev.x, ev.y);
+                }
+                if touch_kbd {
+                    kbd.handle(&ev, queue);
+                } else {
+                    mouse.translate(console.mouse_mode(), &ev, queue);
+                }
+            }
+        }
+        if let Some(l) = link {
+            if input_ofs > 0 && sh.is_idle() {
+                traits::Write::write_all(&mut sh, &input_buf[0..input_ofs]).unwrap();
+                sh.send(l).unwrap();
+                input_ofs = 0;
+            }
+        } else {
+            input_ofs = 0;
+        }
+
         /*
         if let Ok(ch) = rx.read() {
             let _res = block!(wtx.write(ch));
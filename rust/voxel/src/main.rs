@@ -13,8 +13,9 @@ use k210_shared::board::lcd::{self, LCD, LCDHL};
 use k210_shared::board::lcd_colors;
 use k210_shared::board::lcd_render::{AsU16, ScreenImage};
 use k210_shared::board::msa300::Accelerometer;
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{dma_channel, DMACExt};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::i2c::{I2C,I2CExt};
 use k210_shared::soc::sleep::usleep;
 use k210_shared::soc::spi::SPIExt;
@@ -54,20 +55,15 @@ fn fmax(a: f32, b: f32) -> f32 {
 }
 
 /** Connect pins to internal functions */
-fn io_mux_init() {
+fn io_mux_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(io::LCD_RST, fpioa::function::gpiohs(lcd::RST_GPIONUM));
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* I2C0 for touch-screen */
-    fpioa::set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK);
-    fpioa::set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA);
+    mux.set_function(io::I2C1_SCL, fpioa::function::I2C0_SCLK).unwrap();
+    mux.set_function(io::I2C1_SDA, fpioa::function::I2C0_SDA).unwrap();
 
-    sysctl::set_spi0_dvp_data(true);
+    lcd_pins
 }
 
 /** Set correct voltage for pins */
@@ -176,7 +172,8 @@ fn main() -> ! {
 
     let mut stdout = Stdout(&mut tx);
 
-    io_mux_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_mux_init(&mut mux);
     io_set_power();
 
     writeln!(stdout, "Init DMAC").unwrap();
@@ -194,7 +191,7 @@ fn main() -> ! {
     let map = VoxelMap::new(map_data::WIDTH, map_data::HEIGHT, map_data::VOXEL_MAP);
 
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, chan);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, chan);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     lcd.clear(lcd_colors::PURPLE);
@@ -13,8 +13,9 @@ use k210_hal::prelude::*;
 use k210_hal::stdout::Stdout;
 use k210_shared::board::def::io;
 use k210_shared::board::lcd::{self, LCD, LCDHL};
+use k210_shared::board::pinout::{self, LcdPins};
 use k210_shared::soc::dmac::{DMACExt, dma_channel};
-use k210_shared::soc::fpioa;
+use k210_shared::soc::fpioa::{self, mux::PinMux};
 use k210_shared::soc::gpio;
 use k210_shared::soc::gpiohs;
 use k210_shared::soc::sleep::usleep;
@@ -46,23 +47,15 @@ impl traits::Write for WriteAdapter {
 }
 
 /** Connect pins to internal functions */
-fn io_init() {
+fn io_init(mux: &mut PinMux) -> LcdPins {
     /* Init SPI IO map and function settings */
-    fpioa::set_function(
-        io::LCD_RST,
-        fpioa::function::gpiohs(lcd::RST_GPIONUM),
-    );
-    fpioa::set_io_pull(io::LCD_RST, fpioa::pull::DOWN); // outputs must be pull-down
-    fpioa::set_function(io::LCD_DC, fpioa::function::gpiohs(lcd::DCX_GPIONUM));
-    fpioa::set_io_pull(io::LCD_DC, fpioa::pull::DOWN);
-    fpioa::set_function(io::LCD_CS, fpioa::function::SPI0_SS3);
-    fpioa::set_function(io::LCD_WR, fpioa::function::SPI0_SCLK);
-
-    sysctl::set_spi0_dvp_data(true);
+    let lcd_pins = pinout::MAIX_GO.lcd.route(mux).unwrap();
 
     /* Set dvp and spi pin to 1.8V */
     sysctl::set_power_mode(sysctl::power_bank::BANK6, sysctl::io_power_mode::V18);
     sysctl::set_power_mode(sysctl::power_bank::BANK7, sysctl::io_power_mode::V18);
+
+    lcd_pins
 }
 
 /** Show some information about Designware UART. */
@@ -113,7 +106,8 @@ fn main() -> ! {
     let clocks = k210_hal::clock::Clocks::new();
 
     usleep(200000);
-    io_init();
+    let mut mux = PinMux::new(p.FPIOA);
+    let lcd_pins = io_init(&mut mux);
 
     // Configure UARTHS (→host)
     let serial = p.UARTHS.configure(DEFAULT_BAUD.bps(), &clocks);
@@ -123,10 +117,10 @@ fn main() -> ! {
     // Configure UART1 (→WIFI)
     sysctl::clock_enable(sysctl::clock::UART1);
     sysctl::reset(sysctl::reset::UART1);
-    fpioa::set_function(io::WIFI_RX, fpioa::function::UART1_TX);
-    fpioa::set_function(io::WIFI_TX, fpioa::function::UART1_RX);
-    fpioa::set_function(io::WIFI_EN, fpioa::function::GPIOHS8);
-    fpioa::set_io_pull(io::WIFI_EN, fpioa::pull::DOWN);
+    mux.set_function(io::WIFI_RX, fpioa::function::UART1_TX).unwrap();
+    mux.set_function(io::WIFI_TX, fpioa::function::UART1_RX).unwrap();
+    mux.set_function(io::WIFI_EN, fpioa::function::GPIOHS8).unwrap();
+    mux.set_io_pull(io::WIFI_EN, fpioa::pull::DOWN).unwrap();
     gpiohs::set_pin(8, true);
     gpiohs::set_direction(8, gpio::direction::OUTPUT);
 
@@ -139,7 +133,7 @@ fn main() -> ! {
     // LCD init
     let dmac = p.DMAC.configure();
     let spi = p.SPI0.constrain();
-    let mut lcd = LCD::new(spi, &dmac, dma_channel::CHANNEL0);
+    let mut lcd = LCD::new(spi, lcd_pins, &dmac, dma_channel::CHANNEL0);
     lcd.init();
     lcd.set_direction(lcd::direction::YX_LRUD);
     let mut console: Console = Console::new(&cp437::to, &cp437_8x8::FONT, None);
@@ -330,6 +330,12 @@ where
         Ok(link)
     }
 
+    /** Return whether the handler is idle, that is, ready to accept a new command such as
+     * `send` or `connect`. */
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
     /** Send contents of send buffer to a connection */
     pub fn send(&mut self, link: u32) -> Result<(), S::Error> {
         assert!(self.state == State::Idle);